@@ -1,24 +1,36 @@
-CREATE TABLE user (
+CREATE TABLE IF NOT EXISTS user (
 	id integer not null primary key,
 	name text not null unique,
 	email text,
 	password text not null
 ) strict;
-CREATE TABLE character (
+CREATE TABLE IF NOT EXISTS character (
 	id integer not null primary key,
 	user_id integer not null references user(id),
 	entity integer not null references entity(id)
 ) strict;
-CREATE TABLE entity (
+CREATE TABLE IF NOT EXISTS entity (
 	id integer not null primary key
 ) strict;
-CREATE TABLE component (
+CREATE TABLE IF NOT EXISTS component (
 	id integer not null primary key,
 	name text not null unique
 ) strict;
-CREATE TABLE entity_component (
+CREATE TABLE IF NOT EXISTS entity_component (
 	entity integer not null references entity(id) on delete cascade,
 	component integer not null references component(id),
 	data text not null,
+	version integer not null default 0,
 	primary key (entity, component)
 ) strict;
+CREATE TABLE IF NOT EXISTS ban (
+	target text not null primary key,
+	reason text not null,
+	banned_by text not null,
+	created integer not null
+) strict;
+CREATE TABLE IF NOT EXISTS resource (
+	name text not null primary key,
+	data text not null,
+	version integer not null default 0
+) strict;
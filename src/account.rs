@@ -20,12 +20,15 @@ use serde::{
 use crate::{
   character::{
     CharacterBundle,
+    LinkDead,
+    LinkDeadConfig,
     NewCharacterBundle,
     Player,
     Puppet,
   },
   core::Live,
   net::*,
+  prompt::PromptAppExt,
   savestate::components::Persistent,
 };
 
@@ -88,6 +91,48 @@ impl Command for StartLogin {
   }
 }
 
+/// Put a connection straight into `username`'s session, attached to their
+/// character, without logging in. Falls back to logging in if the account is
+/// gone. Whether it's an admin session comes from the connection's listener,
+/// as it does when logging in.
+pub struct ResumeSession {
+  pub conn: Entity,
+  pub username: String,
+}
+
+impl Command for ResumeSession {
+  fn apply(self, world: &mut World) {
+    let character = world
+      .resource::<UserDb>()
+      .users
+      .get(&self.username)
+      .map(|entry| entry.character)
+      .filter(|character| world.get_entity(*character).is_some());
+    let Some(character) = character else {
+      warn!(
+        username = self.username,
+        "can't resume session, logging in again"
+      );
+      StartLogin(self.conn).apply(world);
+      return;
+    };
+    let admin = world
+      .get::<ClientConn>(self.conn)
+      .and_then(|conn| world.get::<Listener>(conn.listener))
+      .is_some_and(|listener| listener.admin);
+    world
+      .entity_mut(character)
+      .insert((Live, CharacterBundle::default(), Player(self.conn)));
+    world.entity_mut(self.conn).insert((
+      Session {
+        username: self.username,
+        admin,
+      },
+      Puppet(character),
+    ));
+  }
+}
+
 pub struct AccountPlugin;
 
 impl Plugin for AccountPlugin {
@@ -97,24 +142,62 @@ impl Plugin for AccountPlugin {
       .register_type::<UserDb>()
       .register_type::<UserEntry>()
       .insert_resource(UserDb::default())
+      .prompt_token::<Session>("name", |session| session.username.clone())
       .add_systems(
         Update,
         login_system.run_if(any_with_component::<LoginState>),
-      );
+      )
+      .observe(session_ended_link_dead);
   }
 }
 
+/// When a session ends without its character being detached first, e.g.
+/// because the connection dropped, leave the character in the world as
+/// link-dead for a while.
+fn session_ended_link_dead(
+  trigger: Trigger<OnRemove, Session>,
+  sessions: Query<(&Session, &Puppet)>,
+  players: Query<&Player>,
+  config: Res<LinkDeadConfig>,
+  mut cmd: Commands,
+) {
+  let entity = trigger.entity();
+  let Ok((session, puppet)) = sessions.get(entity) else {
+    return;
+  };
+  // Someone else may have taken the character over already.
+  if !players.get(**puppet).is_ok_and(|player| **player == entity) {
+    return;
+  }
+  debug!(character = ?**puppet, username = session.username, "character is link-dead");
+  cmd
+    .entity(**puppet)
+    .insert(LinkDead::new(&session.username, config.grace()));
+}
+
 fn login_system(
   mut cmd: Commands,
   mut users: ResMut<UserDb>,
-  mut query: Query<(Entity, &mut LoginState, &mut TelnetIn, &TelnetOut)>,
+  bans: Res<BanList>,
+  link_dead: Query<(), With<LinkDead>>,
+  mut query: Query<(
+    Entity,
+    &mut LoginState,
+    &mut TelnetIn,
+    &TelnetOut,
+    &mut TelnetOptions,
+    &ClientConn,
+  )>,
+  listeners: Query<&Listener>,
 ) {
-  for (entity, mut state, mut input, output) in query.iter_mut() {
+  for (entity, mut state, mut input, output, mut opts, conn) in query.iter_mut() {
+    // Admins can only log in through listeners that allow it, e.g. a local
+    // Unix socket.
+    let admin = listeners.get(conn.listener).is_ok_and(|l| l.admin);
     match &mut *state {
       LoginState::Start => {
         *state = LoginState::Username;
-        output.string("Account name: ");
-        command!(output, GA);
+        output.prompt("Account name: ");
       }
       LoginState::Username => {
         let name = try_opt!(input.next_line(), continue);
@@ -124,23 +207,33 @@ fn login_system(
           *state = LoginState::Start;
           continue;
         }
+        if let Some(ban) = bans.account_ban(&name) {
+          info!(
+            account = name,
+            reason = ban.reason,
+            "banned account tried to log in"
+          );
+          output.line(throttle::Rejected::Banned(ban.reason.clone()).to_string());
+          output.close();
+          cmd.entity(entity).remove::<LoginState>();
+          continue;
+        }
         if users.users.contains_key(&name) {
           cmd.entity(entity).insert(LoginState::Password { name });
-          output.line("Password: ");
+          output.prompt("Password: ");
         } else {
           output.line("Creating new account.");
           cmd
             .entity(entity)
             .insert(LoginState::NewUserPassword { name });
 
-          output.string("Password: ");
-          command!(output, GA);
-          negotiate!(output, WILL, ECHO);
+          output.prompt("Password: ");
+          opts.enable_local(output, options::ECHO);
         }
       }
       LoginState::Password { name } => {
         let password = try_opt!(input.next_line(), continue);
-        negotiate!(output, WONT, ECHO);
+        opts.disable_local(output, options::ECHO);
         let username = mem::take(name);
 
         let Some(entry) = users.users.get(&username) else {
@@ -157,27 +250,29 @@ fn login_system(
         }
 
         output.line("Welcome back!");
-        command!(output, GA);
 
-        cmd
-          .entity(entry.character)
-          .insert((Live, CharacterBundle::default(), Player(entity)));
+        if link_dead.contains(entry.character) {
+          // Still in the world, so pick up where they left off.
+          debug!(username, character = ?entry.character, "reconnecting to link-dead character");
+          output.line("Reconnecting to your character.");
+          cmd
+            .entity(entry.character)
+            .remove::<LinkDead>()
+            .insert(Player(entity));
+        } else {
+          cmd
+            .entity(entry.character)
+            .insert((Live, CharacterBundle::default(), Player(entity)));
+        }
         cmd
           .entity(entity)
-          .insert((
-            Session {
-              username,
-              admin: true,
-            },
-            Puppet(entry.character),
-          ))
+          .insert((Session { username, admin }, Puppet(entry.character)))
           .remove::<LoginState>();
       }
 
       LoginState::NewUserPassword { name } => {
         let password = try_opt!(input.next_line(), continue);
-        output.string("\nConfirm password: ");
-        command!(output, GA);
+        output.prompt("\nConfirm password: ");
         *state = LoginState::NewUserConfirm {
           name: mem::take(name),
           password,
@@ -188,14 +283,13 @@ fn login_system(
         let confirm = try_opt!(input.next_line(), continue);
         if *password != confirm {
           output.line("\nPasswords do not match.");
-          negotiate!(output, WONT, ECHO);
+          opts.disable_local(output, options::ECHO);
           *state = LoginState::Start;
           continue;
         }
 
-        output.string("\nCreating account...");
-        command!(output, GA);
-        negotiate!(output, WONT, ECHO);
+        output.prompt("\nCreating account...");
+        opts.disable_local(output, options::ECHO);
 
         debug!(password = ?confirm, "hashing password");
         let hashed_password = hash(confirm, 4).unwrap();
@@ -206,7 +300,7 @@ fn login_system(
         cmd.entity(entity).remove::<LoginState>().insert((
           Session {
             username: name.clone(),
-            admin: true,
+            admin,
           },
           Puppet(character),
         ));
@@ -226,3 +320,94 @@ fn login_system(
 fn validate_name(_name: &str) -> bool {
   true
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    config::ServerConfig,
+    testing::TestMud,
+  };
+
+  #[test]
+  fn creates_account_and_logs_back_in() {
+    let mut mud = TestMud::new();
+    let mut client = mud.connect();
+    mud.create_account(&mut client, "alice", "hunter2");
+    let conn = mud.session("alice").unwrap();
+    let character = **mud.world().get::<Puppet>(conn).unwrap();
+    assert!(mud.world().resource::<UserDb>().users.contains_key("alice"));
+
+    // The character stays behind when the connection drops, and is picked up
+    // again on the next login.
+    drop(client);
+    mud.run_until("link-dead character", |world| {
+      world.get::<LinkDead>(character).is_some()
+    });
+    let mut client = mud.connect();
+    mud.expect(&mut client, "Account name: ");
+    client.send_line("alice");
+    mud.expect(&mut client, "Password: ");
+    client.send_line("hunter2");
+    mud.expect(&mut client, "Reconnecting to your character.");
+    mud.expect(&mut client, "> ");
+    let conn = mud.session("alice").unwrap();
+    assert_eq!(**mud.world().get::<Puppet>(conn).unwrap(), character);
+    assert!(mud.world().get::<LinkDead>(character).is_none());
+  }
+
+  #[test]
+  fn link_dead_character_is_unloaded_after_grace() {
+    let mut config = ServerConfig::default();
+    config.link_dead.grace = 2.0;
+    let mut mud = TestMud::with_config(config);
+    let mut client = mud.connect();
+    mud.create_account(&mut client, "alice", "pw");
+    let conn = mud.session("alice").unwrap();
+    let character = **mud.world().get::<Puppet>(conn).unwrap();
+    let mut bob = mud.connect();
+    mud.create_account(&mut bob, "bob", "pw");
+
+    drop(client);
+    mud.run_until("link-dead character", |world| {
+      world.get::<LinkDead>(character).is_some()
+    });
+    bob.send_line("who");
+    let who = mud.expect(&mut bob, "> ");
+    assert!(who.contains("alice (link-dead)"), "{who:?}");
+
+    mud.run_until("character unloaded", |world| {
+      world.get::<LinkDead>(character).is_none() && world.get::<Live>(character).is_none()
+    });
+    bob.send_line("who");
+    mud.expect(&mut bob, "It's just you!");
+
+    // Logging back in brings the character back rather than reconnecting.
+    let mut client = mud.connect();
+    mud.expect(&mut client, "Account name: ");
+    client.send_line("alice");
+    mud.expect(&mut client, "Password: ");
+    client.send_line("pw");
+    let out = mud.expect(&mut client, "> ");
+    assert!(out.contains("Welcome back!"), "{out:?}");
+    assert!(!out.contains("Reconnecting"), "{out:?}");
+    let conn = mud.session("alice").unwrap();
+    assert_eq!(**mud.world().get::<Puppet>(conn).unwrap(), character);
+    assert!(mud.world().get::<Live>(character).is_some());
+  }
+
+  #[test]
+  fn rejects_wrong_password() {
+    let mut mud = TestMud::new();
+    let mut client = mud.connect();
+    mud.create_account(&mut client, "bob", "right");
+
+    let mut other = mud.connect();
+    mud.expect(&mut other, "Account name: ");
+    other.send_line("bob");
+    mud.expect(&mut other, "Password: ");
+    other.send_line("wrong");
+    mud.expect(&mut other, "Invalid password.");
+    mud.expect(&mut other, "Account name: ");
+  }
+}
@@ -1,11 +1,21 @@
-use std::fmt::Debug;
+use std::{
+  fmt::Debug,
+  path::PathBuf,
+};
 
 use bevy::prelude::*;
 use bevy_mud::{
   account::StartLogin,
+  config::{
+    ConfigArgs,
+    ServerConfig,
+  },
   core::CorePlugin,
-  negotiate,
   net::*,
+  savestate::{
+    backend::Backend,
+    systems::migrate_saved_world,
+  },
 };
 use clap::Parser;
 #[cfg(feature = "otel")]
@@ -25,30 +35,43 @@ use opentelemetry_sdk::{
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-  #[arg(short, long, default_value = "sqlite://db.sqlite")]
-  db: String,
+  /// Server config file, in RON or TOML.
+  #[arg(short, long)]
+  config: Option<PathBuf>,
 
-  #[arg(short, long, default_value_t = 23840)]
-  port: u32,
+  #[command(flatten)]
+  overrides: ConfigArgs,
 
   #[arg(long, default_value_t = false)]
   otel: bool,
-}
 
-impl Plugin for Args {
-  fn build(&self, app: &mut App) {
-    app.insert_resource(PortArg(self.port));
-  }
+  /// Upgrade the saved world to the latest component versions and exit.
+  #[arg(long, default_value_t = false)]
+  migrate_only: bool,
 }
 
 fn main() -> anyhow::Result<()> {
   let args = Args::parse();
 
+  let mut config = match &args.config {
+    Some(path) => ServerConfig::load(path)?,
+    None => ServerConfig::default(),
+  };
+  args.overrides.apply(&mut config)?;
+
+  let backend = Backend::open(&config.db_url(), config.backups)?;
+
   let mut app = App::new();
 
-  app.add_plugins(CorePlugin);
+  app.add_plugins(CorePlugin::with_config(config));
 
-  app.add_plugins(args);
+  app.insert_resource(backend);
+
+  if args.migrate_only {
+    let entities = migrate_saved_world(app.world())?;
+    info!(entities, "migrated saved world");
+    return Ok(());
+  }
 
   app.add_systems(Update, greeter);
 
@@ -57,10 +80,12 @@ fn main() -> anyhow::Result<()> {
   Ok(())
 }
 
-fn greeter(mut cmd: Commands, mut query: Query<(Entity, &TelnetOut), Added<ClientConn>>) {
+fn greeter(
+  mut cmd: Commands,
+  mut query: Query<(Entity, &TelnetOut), (Added<ClientConn>, Without<Inherited>)>,
+) {
   for (entity, output) in query.iter_mut() {
     output.line("\x1b[1mWelcome!\x1b[0m");
-    negotiate!(output, WILL, GMCP);
     cmd.queue(StartLogin(entity));
   }
 }
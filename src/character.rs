@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use bevy::{
   prelude::*,
   utils::HashSet,
@@ -42,6 +44,45 @@ impl Default for Puppet {
   }
 }
 
+/// Marker for a player character whose connection dropped. It stays in the
+/// world until the timer runs out, so that the player can reconnect to it.
+#[derive(Component, Debug, Reflect)]
+#[reflect(Component)]
+pub struct LinkDead {
+  /// The account the character belongs to.
+  pub username: String,
+  pub timer: Timer,
+}
+
+impl LinkDead {
+  pub fn new(username: impl Into<String>, grace: Duration) -> Self {
+    LinkDead {
+      username: username.into(),
+      timer: Timer::new(grace, TimerMode::Once),
+    }
+  }
+}
+
+/// How long characters stay link-dead before being unloaded.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LinkDeadConfig {
+  /// Seconds to keep a character in the world after its player disconnects.
+  pub grace: f32,
+}
+
+impl Default for LinkDeadConfig {
+  fn default() -> Self {
+    Self { grace: 300.0 }
+  }
+}
+
+impl LinkDeadConfig {
+  pub fn grace(&self) -> Duration {
+    Duration::from_secs_f32(self.grace.max(0.0))
+  }
+}
+
 /// Marker for a character entity.
 /// This can be Player or Non-Player, as determined by the presence
 /// of the [Player] or [NonPlayer] component. [Player] will always take
@@ -54,14 +95,18 @@ pub struct CharacterPlugin;
 
 impl Plugin for CharacterPlugin {
   fn build(&self, app: &mut App) {
-    app.register_type::<Puppet>().register_type::<Player>();
+    app
+      .register_type::<Puppet>()
+      .register_type::<Player>()
+      .register_type::<LinkDead>()
+      .init_resource::<LinkDeadConfig>();
 
     app.persist::<Character>();
     app.persist::<NonPlayer>();
 
     app.register_type::<Character>();
     app.register_type::<NonPlayer>();
-    app.add_systems(Update, despawn_system);
+    app.add_systems(Update, (link_dead_system, despawn_system).chain());
     app
       .observe(puppet_removed_unplayer)
       .observe(player_removed_unpuppet);
@@ -82,7 +127,15 @@ pub struct CharacterBundle {
 
 fn despawn_system(
   mut cmd: Commands,
-  query: Query<(Entity, &Character), (Without<Player>, Without<NonPlayer>, With<Live>)>,
+  query: Query<
+    (Entity, &Character),
+    (
+      Without<Player>,
+      Without<NonPlayer>,
+      Without<LinkDead>,
+      With<Live>,
+    ),
+  >,
 ) {
   for (ent, _) in query.iter() {
     debug!(?ent, "unloading controllerless character");
@@ -90,6 +143,19 @@ fn despawn_system(
   }
 }
 
+fn link_dead_system(mut cmd: Commands, time: Res<Time>, mut query: Query<(Entity, &mut LinkDead)>) {
+  for (ent, mut link_dead) in query.iter_mut() {
+    if link_dead.timer.tick(time.delta()).finished() {
+      debug!(
+        ?ent,
+        username = link_dead.username,
+        "link-dead character timed out"
+      );
+      cmd.entity(ent).remove::<LinkDead>();
+    }
+  }
+}
+
 // When Puppet is removed from an entity, find the Player that points to it and detach.
 fn puppet_removed_unplayer(
   trigger: Trigger<OnRemove, Puppet>,
@@ -1,6 +1,8 @@
 use anyhow::bail;
 pub mod admin;
 pub mod debug;
+pub mod help;
+pub mod pager;
 pub mod parse;
 pub mod player;
 
@@ -21,6 +23,15 @@ use radix_trie::{
 
 use self::{
   admin::admin_commands,
+  pager::{
+    Pager,
+    PagerConfig,
+    PagerInput,
+  },
+  parse::{
+    ArgSpec,
+    ArgValues,
+  },
   player::PlayerCommands,
 };
 use crate::{
@@ -36,6 +47,7 @@ pub struct GameCommandsPlugin;
 impl Plugin for GameCommandsPlugin {
   fn build(&self, app: &mut App) {
     app
+      .init_resource::<PagerConfig>()
       .add_systems(PreUpdate, game_commands_system)
       .add_systems(PreUpdate, add_command_sets_system);
   }
@@ -52,44 +64,85 @@ pub fn add_command_sets_system(
       ecmd.add_game_commands(admin_commands());
       output.line("admin commands enabled");
     }
-    output.string("> ");
-    command!(output, GA);
   }
 }
 
 pub fn game_commands_system(world: &mut World) {
-  let mut query = world.query::<(Entity, &mut TelnetIn, &TelnetOut, &CommandSet)>();
+  let mut query = world.query::<(
+    Entity,
+    &mut TelnetIn,
+    &TelnetOut,
+    &CommandSet,
+    Option<&mut Pager>,
+  )>();
   let mut cmds = vec![];
-  for (entity, mut telnet_in, telnet_out, cmdset) in query.iter_mut(world) {
+  let mut done_paging = vec![];
+  for (entity, mut telnet_in, telnet_out, cmdset, mut pager) in query.iter_mut(world) {
     while let Some(line) = telnet_in.next_line() {
+      if let Some(p) = pager.as_mut() {
+        match p.input(&line, telnet_out) {
+          PagerInput::More => continue,
+          PagerInput::Done => {
+            pager = None;
+            done_paging.push(entity);
+            telnet_out.reprompt();
+            continue;
+          }
+          PagerInput::Command => {
+            pager = None;
+            done_paging.push(entity);
+          }
+        }
+      }
       let (cmd_str, args) = line
         .split_once(' ')
         .map(|(cmd, rest)| (cmd.trim(), rest.trim()))
         .unwrap_or_else(|| (line.as_str().trim(), ""));
       match cmdset
         .lookup(cmd_str)
-        .ok_or_else(|| anyhow::format_err!("What?"))
+        .ok_or_else(|| match help::did_you_mean(&cmdset.suggest(cmd_str)) {
+          Some(suggestion) => anyhow::format_err!("What? {suggestion}"),
+          None => anyhow::format_err!("What?"),
+        })
         .and_then(|cmd| {
-          cmd.build(CommandArgs {
-            caller: Some(entity),
-            owner: Some(entity),
-            matched: cmd_str,
-            args,
-          })
+          let paged = cmd.paged();
+          cmd
+            .build(CommandArgs {
+              caller: Some(entity),
+              owner: Some(entity),
+              matched: cmd_str,
+              args,
+            })
+            .map(|built| (built, paged))
         }) {
-        Ok(cmd) => cmds.push((entity, cmd)),
+        Ok((cmd, paged)) => cmds.push((entity, cmd, paged)),
         Err(err) => {
-          telnet_out.line(format!("{}", err)).string("> ");
-          command!(telnet_out, GA);
+          telnet_out.line(format!("{}", err));
         }
       }
     }
   }
-  for (entity, cmd) in cmds {
+  for entity in done_paging {
+    world.entity_mut(entity).remove::<Pager>();
+  }
+  for (entity, cmd, paged) in cmds {
+    let out = paged
+      .then(|| world.get::<TelnetOut>(entity).cloned())
+      .flatten();
+    let Some(out) = out else {
+      cmd(world);
+      continue;
+    };
+    out.capture();
     cmd(world);
-    let out = world.get_mut::<TelnetOut>(entity).unwrap();
-    out.string("> ");
-    command!(out, GA);
+    let Some(text) = out.release() else {
+      continue;
+    };
+    if let Some(pager) = pager::page(world, entity, &out, text) {
+      if let Some(mut entity) = world.get_entity_mut(entity) {
+        entity.insert(pager);
+      }
+    }
   }
 }
 
@@ -146,6 +199,35 @@ pub trait GameCommand: Send + Sync {
   fn build(&self, args: CommandArgs) -> anyhow::Result<WorldCommand> {
     bail!("What?");
   }
+
+  /// Whether the command's output to the caller should be held until it's
+  /// done and paged if it's long. See [pager].
+  fn paged(&self) -> bool {
+    true
+  }
+
+  /// The arguments the command takes, if it declares them.
+  fn arg_spec(&self) -> Option<&ArgSpec> {
+    None
+  }
+
+  /// A one-line description for command lists.
+  fn summary(&self) -> &str {
+    ""
+  }
+
+  /// Anything else players should know, shown by `help <command>`.
+  fn help(&self) -> Option<&str> {
+    None
+  }
+
+  /// How to use the command, e.g. `@parent <entity> [<parent>]`.
+  fn usage(&self) -> String {
+    match self.arg_spec().map(ArgSpec::usage) {
+      Some(args) if !args.is_empty() => format!("{} {args}", self.key()),
+      _ => self.key().into(),
+    }
+  }
 }
 
 impl<'a, C> GameCommand for &'a C
@@ -159,8 +241,128 @@ where
   fn build(&self, args: CommandArgs) -> anyhow::Result<WorldCommand> {
     C::build(self, args)
   }
+
+  fn paged(&self) -> bool {
+    C::paged(self)
+  }
+
+  fn arg_spec(&self) -> Option<&ArgSpec> {
+    C::arg_spec(self)
+  }
+
+  fn summary(&self) -> &str {
+    C::summary(self)
+  }
+
+  fn help(&self) -> Option<&str> {
+    C::help(self)
+  }
+
+  fn usage(&self) -> String {
+    C::usage(self)
+  }
+}
+
+/// A command whose output is sent as it happens rather than paged.
+pub struct Unpaged<C>(pub C);
+
+impl<C> GameCommand for Unpaged<C>
+where
+  C: GameCommand,
+{
+  fn key(&self) -> &str {
+    self.0.key()
+  }
+
+  fn build(&self, args: CommandArgs) -> anyhow::Result<WorldCommand> {
+    self.0.build(args)
+  }
+
+  fn paged(&self) -> bool {
+    false
+  }
+
+  fn arg_spec(&self) -> Option<&ArgSpec> {
+    self.0.arg_spec()
+  }
+
+  fn summary(&self) -> &str {
+    self.0.summary()
+  }
+
+  fn help(&self) -> Option<&str> {
+    self.0.help()
+  }
+
+  fn usage(&self) -> String {
+    self.0.usage()
+  }
+}
+
+/// A command with help text. See [DescribeExt::describe].
+pub struct Described<C> {
+  command: C,
+  summary: &'static str,
+  help: Option<&'static str>,
+}
+
+impl<C> Described<C> {
+  /// Add longer help for `help <command>`.
+  pub fn with_help(self, help: &'static str) -> Self {
+    Self {
+      help: Some(help),
+      ..self
+    }
+  }
 }
 
+impl<C> GameCommand for Described<C>
+where
+  C: GameCommand,
+{
+  fn key(&self) -> &str {
+    self.command.key()
+  }
+
+  fn build(&self, args: CommandArgs) -> anyhow::Result<WorldCommand> {
+    self.command.build(args)
+  }
+
+  fn paged(&self) -> bool {
+    self.command.paged()
+  }
+
+  fn arg_spec(&self) -> Option<&ArgSpec> {
+    self.command.arg_spec()
+  }
+
+  fn summary(&self) -> &str {
+    self.summary
+  }
+
+  fn help(&self) -> Option<&str> {
+    self.help
+  }
+
+  fn usage(&self) -> String {
+    self.command.usage()
+  }
+}
+
+pub trait DescribeExt: GameCommand + Sized {
+  /// Give the command a summary for command lists. Longer help can be added
+  /// with [Described::with_help].
+  fn describe(self, summary: &'static str) -> Described<Self> {
+    Described {
+      command: self,
+      summary,
+      help: None,
+    }
+  }
+}
+
+impl<C> DescribeExt for C where C: GameCommand {}
+
 impl<'a, F> GameCommand for (&'a str, F)
 where
   F: Fn(CommandArgs<'_>) -> anyhow::Result<WorldCommand> + Send + Sync,
@@ -174,6 +376,25 @@ where
   }
 }
 
+/// A command with declared arguments, which are parsed before it's built.
+impl<'a, F> GameCommand for (&'a str, ArgSpec, F)
+where
+  F: Fn(CommandArgs<'_>, ArgValues) -> anyhow::Result<WorldCommand> + Send + Sync,
+{
+  fn key(&self) -> &str {
+    self.0
+  }
+
+  fn build(&self, args: CommandArgs) -> anyhow::Result<WorldCommand> {
+    let values = self.1.parse(self.0, &args)?;
+    (self.2)(args, values)
+  }
+
+  fn arg_spec(&self) -> Option<&ArgSpec> {
+    Some(&self.1)
+  }
+}
+
 #[derive(Component, Default, Clone, Debug)]
 pub struct CommandSet {
   commands: Trie<String, DynamicCommand>,
@@ -190,6 +411,11 @@ impl CommandSet {
     subtrie.and_then(|st| st.value())
   }
 
+  /// Commands that `input` might be a typo of.
+  pub fn suggest(&self, input: &str) -> Vec<&str> {
+    help::suggest(input, self.commands.keys().map(String::as_str))
+  }
+
   pub fn commands(&self) -> impl Iterator<Item = DynamicCommand> + '_ {
     self.commands.iter().map(|(_, val)| val.clone())
   }
@@ -1,42 +1,45 @@
 use anyhow::anyhow;
-use bevy::ecs::entity::Entity;
+use bevy::ecs::{
+  entity::Entity,
+  world::World,
+};
 use hexx::{
-  hex,
   EdgeDirection,
+  Hex,
 };
 
 use super::{
   debug::DebugCommands,
+  parse::{
+    Arg,
+    ArgSpec,
+    ArgValues,
+  },
   CommandArgs,
+  DescribeExt,
   DynamicCommand,
+  Unpaged,
   WorldCommand,
 };
 use crate::{
+  account::Session,
   map::Transform,
-  net::TelnetOut,
+  net::{
+    BanEntry,
+    BanList,
+    BanTarget,
+    ClientConn,
+    TelnetOut,
+  },
 };
 
 pub fn admin_commands() -> impl Iterator<Item = DynamicCommand> {
   DebugCommands.into_iter().chain(AdminCommands)
 }
 
-fn rotate_ent(args: CommandArgs) -> anyhow::Result<WorldCommand> {
-  let cmd_args = args
-    .args
-    .split(' ')
-    .map(|s| s.trim())
-    .filter(|s| !s.is_empty())
-    .collect::<Vec<_>>();
-
-  let usage = || anyhow!("Usage: @rotate <entity id> <r>");
-
-  if cmd_args.len() < 2 {
-    return Err(usage());
-  }
-
-  let entity_id = Entity::from_bits(cmd_args[0].parse::<u64>()?);
-
-  let rotation = cmd_args[1].parse::<i8>()?;
+fn rotate_ent(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let entity_id = values.get::<Entity>("entity")?;
+  let rotation = values.get::<i8>("r")?;
 
   Ok(Box::new(move |world| {
     let out = world
@@ -50,8 +53,8 @@ fn rotate_ent(args: CommandArgs) -> anyhow::Result<WorldCommand> {
     });
     out.line(format!("Rotating {:?} by {}", entity_id, rotation));
     if let Some(mut xform) = entity.get_mut::<Transform>() {
-      xform.facing =
-        EdgeDirection::ALL_DIRECTIONS[((xform.facing.index() as i8 + rotation) % 6) as usize];
+      xform.facing = EdgeDirection::ALL_DIRECTIONS
+        [(xform.facing.index() as i8 + rotation).rem_euclid(6) as usize];
     } else {
       entity.insert(Transform {
         // facing: rotation,
@@ -61,23 +64,9 @@ fn rotate_ent(args: CommandArgs) -> anyhow::Result<WorldCommand> {
   }))
 }
 
-fn move_ent(args: CommandArgs) -> anyhow::Result<WorldCommand> {
-  let cmd_args = args
-    .args
-    .split(' ')
-    .map(|s| s.trim())
-    .filter(|s| !s.is_empty())
-    .collect::<Vec<_>>();
-
-  let usage = || anyhow!("Usage: @move <entity id> <q> <r> <s>");
-
-  if cmd_args.len() < 4 {
-    return Err(usage());
-  }
-
-  let entity_id = Entity::from_bits(cmd_args[0].parse::<u64>()?);
-
-  let coords = hex(cmd_args[1].parse()?, cmd_args[2].parse()?);
+fn move_ent(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let entity_id = values.get::<Entity>("entity")?;
+  let coords = values.get::<Hex>("offset")?;
 
   Ok(Box::new(move |world| {
     let out = world
@@ -101,26 +90,11 @@ fn move_ent(args: CommandArgs) -> anyhow::Result<WorldCommand> {
   }))
 }
 
-fn teleport_ent(args: CommandArgs) -> anyhow::Result<WorldCommand> {
-  let cmd_args = args
-    .args
-    .split(' ')
-    .map(|s| s.trim())
-    .filter(|s| !s.is_empty())
-    .collect::<Vec<_>>();
-
-  let usage = || anyhow!("Usage: @teleport <entity id> <q> <r> <s> [<map name>]");
-
-  if cmd_args.len() < 4 {
-    return Err(usage());
-  }
-
-  let entity_id = Entity::from_bits(cmd_args[0].parse::<u64>()?);
-
-  let coords = hex(cmd_args[1].parse()?, cmd_args[2].parse()?);
-
-  let map_name = Some(cmd_args[4..].join(" "))
-    .filter(|s| !s.is_empty())
+fn teleport_ent(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let entity_id = values.get::<Entity>("entity")?;
+  let coords = values.get::<Hex>("coords")?;
+  let map_name = values
+    .opt::<String>("map")
     .map(|s| if s == "None" { "".into() } else { s });
 
   Ok(Box::new(move |world| {
@@ -152,8 +126,163 @@ fn teleport_ent(args: CommandArgs) -> anyhow::Result<WorldCommand> {
   }))
 }
 
+fn ban(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let target = values.get::<String>("target")?.parse::<BanTarget>()?;
+  let reason = values.opt::<String>("reason").unwrap_or_default();
+  let caller = args.caller.ok_or_else(|| anyhow!("missing caller"))?;
+
+  Ok(Box::new(move |world| {
+    let out = try_opt!(world.get::<TelnetOut>(caller), return).clone();
+    let banned_by = world
+      .get::<Session>(caller)
+      .map(|session| session.username.clone())
+      .unwrap_or_default();
+    world
+      .resource_mut::<BanList>()
+      .ban(&target, BanEntry::new(reason, banned_by));
+    out.line(format!("Banned {target}."));
+
+    // Kick anyone already connected who the ban covers.
+    let kicked = kick_banned(world, &target);
+    if kicked > 0 {
+      out.line(format!("Disconnected {kicked} connection(s)."));
+    }
+  }))
+}
+
+/// Close every connection covered by `target`, returning how many there were.
+fn kick_banned(world: &mut World, target: &BanTarget) -> usize {
+  let mut conns = world.query::<(&ClientConn, &TelnetOut, Option<&Session>)>();
+  let mut kicked = 0;
+  for (conn, out, session) in conns.iter(world) {
+    let by_ip = conn
+      .remote_addr
+      .is_some_and(|addr| target.matches_ip(addr.ip()));
+    let by_account = session.is_some_and(|s| target.matches_account(&s.username));
+    if by_ip || by_account {
+      out.line("You have been banned.");
+      out.close();
+      kicked += 1;
+    }
+  }
+  kicked
+}
+
+fn unban(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let target = values.get::<String>("target")?.parse::<BanTarget>()?;
+  let caller = args.caller.ok_or_else(|| anyhow!("missing caller"))?;
+
+  Ok(Box::new(move |world| {
+    let out = try_opt!(world.get::<TelnetOut>(caller), return).clone();
+    match world.resource_mut::<BanList>().unban(&target) {
+      Some(_) => out.line(format!("Unbanned {target}.")),
+      None => out.line(format!("{target} isn't banned.")),
+    }
+  }))
+}
+
+fn bans(args: CommandArgs) -> anyhow::Result<WorldCommand> {
+  let caller = args.caller.ok_or_else(|| anyhow!("missing caller"))?;
+
+  Ok(Box::new(move |world| {
+    let out = try_opt!(world.get::<TelnetOut>(caller), return).clone();
+    let bans = world.resource::<BanList>();
+    if bans.bans.is_empty() {
+      out.line("No bans.");
+      return;
+    }
+    let mut bans = bans.bans.iter().collect::<Vec<_>>();
+    bans.sort_by_key(|(target, _)| *target);
+    for (target, entry) in bans {
+      let reason = if entry.reason.is_empty() {
+        "no reason given"
+      } else {
+        &entry.reason
+      };
+      out.line(format!("{target}: {reason} (by {})", entry.banned_by));
+    }
+  }))
+}
+
+fn start_copyover(args: CommandArgs) -> anyhow::Result<WorldCommand> {
+  Ok(Box::new(move |world| {
+    crate::copyover::request(world, args.caller)
+  }))
+}
+
+const TELEPORT_HELP: &str = "\
+Coordinates are q,r or, in cube form, q r s. A map of None takes the entity off
+the map it's on.";
+
+const MOVE_HELP: &str = "The offset is q,r or, in cube form, q r s.";
+
+const BAN_HELP: &str = "\
+The target is an address like 10.0.0.1, a network like 10.0.0.0/16, or
+account:<name> for an account.";
+
 command_set! { AdminCommands =>
-  ("@teleport", teleport_ent),
-  ("@move", move_ent),
-  ("@rotate", rotate_ent),
+  (
+    "@teleport",
+    ArgSpec::new([
+      Arg::entity("entity"),
+      Arg::hex("coords"),
+      Arg::rest("map").optional(),
+    ]),
+    teleport_ent,
+  )
+    .describe("Move an entity to a place, and optionally another map.")
+    .with_help(TELEPORT_HELP),
+  (
+    "@move",
+    ArgSpec::new([Arg::entity("entity"), Arg::hex("offset")]),
+    move_ent,
+  )
+    .describe("Move an entity by an offset.")
+    .with_help(MOVE_HELP),
+  (
+    "@rotate",
+    ArgSpec::new([Arg::entity("entity"), Arg::int_in("r", -5..=5)]),
+    rotate_ent,
+  )
+    .describe("Turn an entity by some number of sixths."),
+  (
+    "@ban",
+    ArgSpec::new([
+      Arg::string("target"),
+      Arg::rest("reason").optional(),
+    ]),
+    ban,
+  )
+    .describe("Ban an IP address, network or account, and kick anyone it covers.")
+    .with_help(BAN_HELP),
+  (
+    "@unban",
+    ArgSpec::new([Arg::string("target")]),
+    unban,
+  )
+    .describe("Lift a ban."),
+  ("@bans", bans).describe("List bans."),
+  // Connections are handed over mid-command, so nothing can be held back.
+  Unpaged(("@copyover", start_copyover))
+    .describe("Restart the server without disconnecting anyone."),
+}
+
+#[cfg(test)]
+mod test {
+  use crate::testing::TestMud;
+
+  #[test]
+  fn reports_bad_arguments() {
+    let mut mud = TestMud::new();
+    let mut client = mud.connect();
+    mud.create_account(&mut client, "admin", "pw");
+
+    client.send_line("@rot 1v1 7");
+    let report = mud.expect(&mut client, "usage: @rotate <entity> <r>");
+    assert!(report.contains("@rot 1v1 7"), "{report:?}");
+    assert!(
+      report.contains("expected a number from -5 to 5"),
+      "{report:?}"
+    );
+  }
 }
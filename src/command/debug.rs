@@ -19,23 +19,35 @@ use bevy::{
 use serde::de::DeserializeSeed;
 
 use super::{
+  parse::{
+    Arg,
+    ArgSpec,
+    ArgValues,
+  },
   CommandArgs,
+  DescribeExt,
   WorldCommand,
 };
 use crate::{
-  net::TelnetOut,
-  savestate::assets::SavedEntity,
+  character::Puppet,
+  map::Transform,
+  net::{
+    ClientConn,
+    TelnetOut,
+  },
+  prototype::{
+    prototype_path,
+    FromPrototype,
+    PrototypePending,
+  },
+  savestate::{
+    assets::SavedEntity,
+    components::Persistent,
+  },
 };
 
-fn entities(args: CommandArgs) -> anyhow::Result<WorldCommand> {
-  let mut entities = args
-    .args
-    .split(' ')
-    .map(|s| s.trim())
-    .filter(|s| !s.is_empty())
-    .filter_map(|s| s.parse::<u64>().ok())
-    .map(Entity::from_bits)
-    .collect::<Vec<_>>();
+fn entities(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let mut entities = values.all::<Entity>("entity");
   args
     .caller
     .map(|caller| {
@@ -68,27 +80,9 @@ fn entities(args: CommandArgs) -> anyhow::Result<WorldCommand> {
     .ok_or_else(|| anyhow!("missing caller"))
 }
 
-fn parent(args: CommandArgs) -> anyhow::Result<WorldCommand> {
-  let cmd_args = args
-    .args
-    .split(' ')
-    .map(|s| s.trim())
-    .filter(|s| !s.is_empty())
-    .collect::<Vec<_>>();
-
-  let usage = || anyhow!("Usage: @parent <entity id> [<new parent id>]");
-
-  let ent = Entity::from_bits(
-    cmd_args
-      .first()
-      .ok_or_else(usage)
-      .and_then(|s| Ok(s.parse::<u64>()?))?,
-  );
-  let parent = cmd_args
-    .get(1)
-    .map(|s| s.parse::<u64>())
-    .transpose()?
-    .map(Entity::from_bits);
+fn parent(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let ent = values.get::<Entity>("entity")?;
+  let parent = values.opt::<Entity>("parent");
 
   Ok(Box::new(move |world: &mut World| {
     let out = world
@@ -108,24 +102,10 @@ fn parent(args: CommandArgs) -> anyhow::Result<WorldCommand> {
   }))
 }
 
-fn insert(args: CommandArgs) -> anyhow::Result<WorldCommand> {
-  let cmd_args = args
-    .args
-    .split(' ')
-    .map(|s| s.trim())
-    .filter(|s| !s.is_empty())
-    .collect::<Vec<_>>();
-
-  let usage = || anyhow!("Usage: @insert <entity id> <component name> <component data>");
-
-  let ent = Entity::from_bits(
-    cmd_args
-      .first()
-      .ok_or_else(usage)
-      .and_then(|s| Ok(s.parse::<u64>()?))?,
-  );
-  let component = cmd_args.get(1).ok_or_else(usage)?.to_string();
-  let data = cmd_args.get(2).ok_or_else(usage)?.to_string();
+fn insert(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let ent = values.get::<Entity>("entity")?;
+  let component = values.get::<String>("component")?;
+  let data = values.get::<String>("data")?;
   ron::Deserializer::from_str(&data)?;
 
   Ok(Box::new(move |world: &mut World| {
@@ -167,23 +147,9 @@ fn insert(args: CommandArgs) -> anyhow::Result<WorldCommand> {
   }))
 }
 
-fn remove(args: CommandArgs) -> anyhow::Result<WorldCommand> {
-  let cmd_args = args
-    .args
-    .split(' ')
-    .map(|s| s.trim())
-    .filter(|s| !s.is_empty())
-    .collect::<Vec<_>>();
-
-  let usage = || anyhow!("Usage: @remove <entity id> <component name>");
-
-  let entity = Entity::from_bits(
-    cmd_args
-      .first()
-      .ok_or_else(usage)
-      .and_then(|s| Ok(s.parse::<u64>()?))?,
-  );
-  let component = cmd_args.get(1).ok_or_else(usage)?.to_string();
+fn remove(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let entity = values.get::<Entity>("entity")?;
+  let component = values.get::<String>("component")?;
 
   Ok(Box::new(move |world| {
     let out = world
@@ -210,22 +176,9 @@ fn remove(args: CommandArgs) -> anyhow::Result<WorldCommand> {
   }))
 }
 
-fn spawn_scene(args: CommandArgs) -> anyhow::Result<WorldCommand> {
-  let cmd_args = args
-    .args
-    .split(' ')
-    .map(|s| s.trim())
-    .filter(|s| !s.is_empty())
-    .collect::<Vec<_>>();
-
-  let usage = || anyhow!("Usage: @spawn_scene <path>");
-
-  let path = cmd_args.first().ok_or_else(usage)?.to_string();
-
-  let ent = cmd_args
-    .get(1)
-    .and_then(|s| s.parse::<u64>().ok())
-    .map(Entity::from_bits);
+fn spawn_scene(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let path = values.get::<String>("path")?;
+  let ent = values.opt::<Entity>("entity");
 
   Ok(Box::new(move |world| {
     let out = world
@@ -246,17 +199,8 @@ fn spawn_scene(args: CommandArgs) -> anyhow::Result<WorldCommand> {
     };
   }))
 }
-fn spawn_entity(args: CommandArgs) -> anyhow::Result<WorldCommand> {
-  let cmd_args = args
-    .args
-    .split(' ')
-    .map(|s| s.trim())
-    .filter(|s| !s.is_empty())
-    .collect::<Vec<_>>();
-
-  let usage = || anyhow!("Usage: @spawn_entity <path>");
-
-  let path = cmd_args.first().ok_or_else(usage)?.to_string();
+fn spawn_entity(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let path = values.get::<String>("path")?;
 
   Ok(Box::new(move |world| {
     let out = world
@@ -271,22 +215,8 @@ fn spawn_entity(args: CommandArgs) -> anyhow::Result<WorldCommand> {
     out.line(format!("spawned entity with id {}", entity));
   }))
 }
-fn despawn(args: CommandArgs) -> anyhow::Result<WorldCommand> {
-  let cmd_args = args
-    .args
-    .split(' ')
-    .map(|s| s.trim())
-    .filter(|s| !s.is_empty())
-    .collect::<Vec<_>>();
-
-  let usage = || anyhow!("Usage: @despawn <entity id>");
-
-  let ent = Entity::from_bits(
-    cmd_args
-      .first()
-      .ok_or_else(usage)
-      .and_then(|s| Ok(s.parse::<u64>()?))?,
-  );
+fn despawn(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let ent = values.get::<Entity>("entity")?;
   Ok(Box::new(move |world| {
     let out = world
       .get::<TelnetOut>(args.caller.unwrap())
@@ -303,14 +233,62 @@ fn despawn(args: CommandArgs) -> anyhow::Result<WorldCommand> {
     }
   }))
 }
-fn spawn(args: CommandArgs) -> anyhow::Result<WorldCommand> {
+fn spawn(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let prototype = values.opt::<String>("prototype").unwrap_or_default();
+  if !prototype.is_empty() {
+    prototype_path(&prototype)?;
+  }
+  Ok(Box::new(move |world| {
+    let caller = args.caller.unwrap();
+    let out = world.get::<TelnetOut>(caller).unwrap().clone();
+    if prototype.is_empty() {
+      let id = world.spawn_empty().id();
+      writeln!(&out, "Spawned new entity: {:?}", id).unwrap();
+      return;
+    }
+
+    // Drop it at the caller's feet, unless the prototype says otherwise.
+    let location = world
+      .get::<Puppet>(caller)
+      .and_then(|puppet| world.get::<Transform>(**puppet))
+      .cloned();
+    let mut entity = world.spawn((
+      FromPrototype(prototype.clone()),
+      PrototypePending,
+      Persistent,
+    ));
+    if let Some(location) = location {
+      entity.insert(location);
+    }
+    // It's despawned again if the prototype fails to load.
+    writeln!(
+      &out,
+      "Spawning {} from prototype {}",
+      entity.id(),
+      prototype
+    )
+    .unwrap();
+  }))
+}
+
+fn connections(args: CommandArgs) -> anyhow::Result<WorldCommand> {
   Ok(Box::new(move |world| {
     let out = world
       .get::<TelnetOut>(args.caller.unwrap())
       .unwrap()
       .clone();
-    let id = world.spawn_empty().id();
-    writeln!(&out, "Spawned new entity: {:?}", id).unwrap();
+    let mut query = world.query::<(Entity, &ClientConn, &TelnetOut)>();
+    out.line("Connections:");
+    for (entity, conn, conn_out) in query.iter(world) {
+      let stats = conn_out.queue_stats();
+      let addr = conn
+        .remote_addr
+        .map_or_else(|| "local".into(), |addr| addr.to_string());
+      out.line(format!(
+        "  {entity} {addr} via {}: {} queued ({} bytes), {} dropped",
+        conn.listener, stats.events, stats.bytes, stats.dropped
+      ));
+    }
   }))
 }
 
@@ -328,16 +306,10 @@ fn find_entities_recursive(world: &World, entity: Entity) -> impl Iterator<Item
   })
 }
 
-fn dump_to_file(args: CommandArgs) -> anyhow::Result<WorldCommand> {
+fn dump_to_file(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
   let caller = args.caller.unwrap();
-  let mut split = args.args.split(' ');
-  let entity = Entity::from_bits(
-    split
-      .next()
-      .ok_or_else(|| anyhow!("missing entity argument"))?
-      .parse::<u64>()?,
-  );
-  let filename = split.next().ok_or_else(|| anyhow!("missing file arg"))?;
+  let entity = values.get::<Entity>("entity")?;
+  let filename = values.get::<String>("file")?;
   debug!("dumping {:?} and children to {}", entity, filename);
   let mut file = fs::File::create(filename)?;
   Ok(Box::new(move |world| {
@@ -362,13 +334,59 @@ fn dump_to_file(args: CommandArgs) -> anyhow::Result<WorldCommand> {
 }
 
 command_set! { DebugCommands =>
-  ("@entities", entities),
-  ("@insert", insert),
-  ("@remove", remove),
-  ("@spawn", spawn),
-  ("@dump", dump_to_file),
-  ("@despawn", despawn),
-  ("@spawn_scene", spawn_scene),
-  ("@spawn_entity", spawn_entity),
-  ("@parent", parent),
+  (
+    "@entities",
+    ArgSpec::new([Arg::entity("entity").optional().repeated()]),
+    entities,
+  )
+    .describe("Show entities and their components, or all of them."),
+  (
+    "@insert",
+    ArgSpec::new([
+      Arg::entity("entity"),
+      Arg::string("component"),
+      Arg::rest("data"),
+    ]),
+    insert,
+  )
+    .describe("Insert a component, given as RON, or change an existing one."),
+  (
+    "@remove",
+    ArgSpec::new([Arg::entity("entity"), Arg::string("component")]),
+    remove,
+  )
+    .describe("Remove a component from an entity."),
+  (
+    "@spawn",
+    ArgSpec::new([Arg::rest("prototype").optional()]),
+    spawn,
+  )
+    .describe("Spawn an entity from a prototype, or an empty one."),
+  (
+    "@dump",
+    ArgSpec::new([Arg::entity("entity"), Arg::string("file")]),
+    dump_to_file,
+  )
+    .describe("Save an entity and its children to a file."),
+  ("@despawn", ArgSpec::new([Arg::entity("entity")]), despawn)
+    .describe("Despawn an entity and its children."),
+  (
+    "@spawn_scene",
+    ArgSpec::new([Arg::string("path"), Arg::entity("entity").optional()]),
+    spawn_scene,
+  )
+    .describe("Spawn a scene, or load it into an entity."),
+  (
+    "@spawn_entity",
+    ArgSpec::new([Arg::string("path")]),
+    spawn_entity,
+  )
+    .describe("Spawn an entity from an asset."),
+  (
+    "@parent",
+    ArgSpec::new([Arg::entity("entity"), Arg::entity("parent").optional()]),
+    parent,
+  )
+    .describe("Set or clear the parent of an entity."),
+  ("@connections", connections).describe("List connections and their output queues."),
 }
@@ -0,0 +1,320 @@
+//! Help for players.
+//!
+//! `help` lists the commands in the caller's [CommandSet] with their
+//! summaries, and `help <topic>` shows a command's usage and long help, or a
+//! help file. Help files are plain text assets at `help/<topic>.txt`, for
+//! topics that aren't commands. Their first line doubles as a summary.
+//!
+//! Unknown commands and topics get "did you mean" suggestions from
+//! [suggest].
+
+use anyhow::anyhow;
+use bevy::{
+  asset::{
+    io::Reader,
+    AssetLoader,
+    LoadContext,
+    LoadedFolder,
+  },
+  prelude::*,
+  utils::ConditionalSendFuture,
+};
+
+use super::{
+  parse::ArgValues,
+  CommandArgs,
+  CommandSet,
+  WorldCommand,
+};
+use crate::net::TelnetOut;
+
+/// Where help files live in the asset directory.
+pub const HELP_DIR: &str = "help";
+
+/// The most suggestions to offer at once.
+const MAX_SUGGESTIONS: usize = 3;
+
+pub struct HelpPlugin;
+
+impl Plugin for HelpPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .init_asset::<HelpFile>()
+      .init_asset_loader::<HelpFileLoader>()
+      .add_systems(Startup, load_help_files)
+      .add_systems(PreUpdate, help_files_loaded);
+  }
+}
+
+/// A help topic that isn't a command.
+#[derive(Asset, TypePath, Debug, Clone)]
+pub struct HelpFile {
+  pub topic: String,
+  pub text: String,
+}
+
+impl HelpFile {
+  /// The first line of the file.
+  pub fn summary(&self) -> &str {
+    self
+      .text
+      .lines()
+      .map(str::trim)
+      .find(|line| !line.is_empty())
+      .unwrap_or_default()
+  }
+}
+
+#[derive(Default)]
+pub struct HelpFileLoader;
+
+impl AssetLoader for HelpFileLoader {
+  type Asset = HelpFile;
+  type Settings = ();
+  type Error = anyhow::Error;
+
+  fn load<'a>(
+    &'a self,
+    reader: &'a mut dyn Reader,
+    _settings: &'a Self::Settings,
+    load_context: &'a mut LoadContext,
+  ) -> impl ConditionalSendFuture<Output = Result<Self::Asset, Self::Error>> {
+    async {
+      let mut bytes = vec![];
+      reader.read_to_end(&mut bytes).await?;
+      let topic = load_context
+        .path()
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_lowercase())
+        .ok_or_else(|| anyhow!("help file has no name"))?;
+      Ok(HelpFile {
+        topic,
+        text: String::from_utf8(bytes)?,
+      })
+    }
+  }
+
+  fn extensions(&self) -> &[&str] {
+    &["txt"]
+  }
+}
+
+/// Keeps the help files loaded.
+#[derive(Resource)]
+struct HelpFolder(Handle<LoadedFolder>);
+
+fn load_help_files(mut cmd: Commands, asset_server: Res<AssetServer>) {
+  cmd.insert_resource(HelpFolder(asset_server.load_folder(HELP_DIR)));
+}
+
+fn help_files_loaded(
+  mut events: EventReader<AssetEvent<LoadedFolder>>,
+  folder: Option<Res<HelpFolder>>,
+  folders: Res<Assets<LoadedFolder>>,
+) {
+  let Some(folder) = folder else {
+    return;
+  };
+  for event in events.read() {
+    if event.is_loaded_with_dependencies(&folder.0) {
+      let files = folders.get(&folder.0).map_or(0, |f| f.handles.len());
+      debug!(files, "loaded help files");
+    }
+  }
+}
+
+pub fn help(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let caller = args.caller.ok_or_else(|| anyhow!("missing caller"))?;
+  let topic = values.opt::<String>("topic");
+  Ok(Box::new(move |world| {
+    let out = try_opt!(world.get::<TelnetOut>(caller), return).clone();
+    let commands = try_opt!(world.get::<CommandSet>(caller), return);
+    let mut files = world
+      .get_resource::<Assets<HelpFile>>()
+      .map(|files| files.iter().map(|(_, file)| file).collect::<Vec<_>>())
+      .unwrap_or_default();
+    files.sort_by(|a, b| a.topic.cmp(&b.topic));
+    match topic {
+      Some(topic) => show_topic(&out, commands, &files, &topic),
+      None => list_topics(&out, commands, &files),
+    }
+  }))
+}
+
+fn list_topics(out: &TelnetOut, commands: &CommandSet, files: &[&HelpFile]) {
+  let commands = commands.commands().collect::<Vec<_>>();
+  let width = commands
+    .iter()
+    .map(|cmd| cmd.key().len())
+    .chain(files.iter().map(|file| file.topic.len()))
+    .max()
+    .unwrap_or_default();
+  out.line("Commands:");
+  for cmd in &commands {
+    out.line(format!("  {:width$}  {}", cmd.key(), cmd.summary()).trim_end());
+  }
+  if !files.is_empty() {
+    out.line("Other topics:");
+    for file in files {
+      out.line(format!("  {:width$}  {}", file.topic, file.summary()).trim_end());
+    }
+  }
+  out.line("Type 'help <topic>' for more.");
+}
+
+fn show_topic(out: &TelnetOut, commands: &CommandSet, files: &[&HelpFile], topic: &str) {
+  if let Some(cmd) = commands.lookup(topic) {
+    out.line(format!("Usage: {}", cmd.usage()));
+    if let Some(help) = cmd.help() {
+      out.line(help);
+    }
+    return;
+  }
+
+  let topic = topic.to_lowercase();
+  let file = files
+    .iter()
+    .find(|file| file.topic == topic)
+    .or_else(|| files.iter().find(|file| file.topic.starts_with(&topic)));
+  if let Some(file) = file {
+    out.line(file.text.trim_end());
+    return;
+  }
+
+  let commands = commands.commands().collect::<Vec<_>>();
+  let candidates = commands
+    .iter()
+    .map(|cmd| cmd.key())
+    .chain(files.iter().map(|file| file.topic.as_str()));
+  match did_you_mean(&suggest(&topic, candidates)) {
+    Some(suggestion) => out.line(format!("No help for '{topic}'. {suggestion}")),
+    None => out.line(format!("No help for '{topic}'.")),
+  };
+}
+
+/// The candidates that `input` is most likely a typo of, closest first.
+pub fn suggest<'a>(input: &str, candidates: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+  // Short words are a typo away from too many others to guess.
+  let max_distance = input.chars().count() / 3;
+  if max_distance == 0 {
+    return vec![];
+  }
+  let mut close = candidates
+    .into_iter()
+    .map(|candidate| (edit_distance(input, candidate), candidate))
+    .filter(|(distance, _)| *distance <= max_distance)
+    .collect::<Vec<_>>();
+  close.sort();
+  close.dedup();
+  close
+    .into_iter()
+    .take(MAX_SUGGESTIONS)
+    .map(|(_, candidate)| candidate)
+    .collect()
+}
+
+/// Offer the suggestions to the player, if there are any.
+pub fn did_you_mean(suggestions: &[&str]) -> Option<String> {
+  match suggestions {
+    [] => None,
+    [one] => Some(format!("Did you mean '{one}'?")),
+    [rest @ .., last] => Some(format!("Did you mean '{}' or '{last}'?", rest.join("', '"))),
+  }
+}
+
+/// The number of insertions, deletions, substitutions and swaps of adjacent
+/// characters it takes to get from `a` to `b`, ignoring case.
+fn edit_distance(a: &str, b: &str) -> usize {
+  let a = a.chars().flat_map(char::to_lowercase).collect::<Vec<_>>();
+  let b = b.chars().flat_map(char::to_lowercase).collect::<Vec<_>>();
+  // The last three rows of the distance matrix.
+  let mut before = vec![0; b.len() + 1];
+  let mut prev = (0..=b.len()).collect::<Vec<_>>();
+  for i in 1..=a.len() {
+    let mut row = vec![i; b.len() + 1];
+    for j in 1..=b.len() {
+      let substitution = prev[j - 1] + usize::from(a[i - 1] != b[j - 1]);
+      row[j] = (prev[j] + 1).min(row[j - 1] + 1).min(substitution);
+      if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+        row[j] = row[j].min(before[j - 2] + 1);
+      }
+    }
+    before = std::mem::replace(&mut prev, row);
+  }
+  prev[b.len()]
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    config::ServerConfig,
+    testing::TestMud,
+  };
+
+  #[test]
+  fn edit_distances() {
+    assert_eq!(edit_distance("help", "help"), 0);
+    assert_eq!(edit_distance("hlep", "help"), 1);
+    assert_eq!(edit_distance("WHO", "who"), 0);
+    assert_eq!(edit_distance("nrth", "north"), 1);
+    assert_eq!(edit_distance("", "who"), 3);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+  }
+
+  #[test]
+  fn suggestions() {
+    let candidates = ["north", "northeast", "help", "who", "n"];
+    assert_eq!(suggest("nrth", candidates), vec!["north"]);
+    assert_eq!(suggest("northest", candidates), vec!["northeast"]);
+    assert_eq!(suggest("x", candidates), Vec::<&str>::new());
+    assert_eq!(suggest("dance", candidates), Vec::<&str>::new());
+
+    assert_eq!(did_you_mean(&[]), None);
+    assert_eq!(did_you_mean(&["who"]).unwrap(), "Did you mean 'who'?");
+    assert_eq!(
+      did_you_mean(&["a", "b", "c"]).unwrap(),
+      "Did you mean 'a', 'b' or 'c'?"
+    );
+  }
+
+  #[test]
+  fn help_command() {
+    let mut config = ServerConfig::default();
+    config.pager.height = 0;
+    let mut mud = TestMud::with_config(config);
+    let _rules = mud
+      .world_mut()
+      .resource_mut::<Assets<HelpFile>>()
+      .add(HelpFile {
+        topic: "rules".into(),
+        text: "The rules of the game.\n\nBe nice.\n".into(),
+      });
+    let mut client = mud.connect();
+    mud.create_account(&mut client, "alice", "pw");
+
+    client.send_line("help");
+    let list = mud.expect(&mut client, "Type 'help <topic>' for more.");
+    assert!(
+      list.contains("List the players who are online."),
+      "{list:?}"
+    );
+    assert!(list.contains("rules"), "{list:?}");
+    assert!(list.contains("The rules of the game."), "{list:?}");
+
+    client.send_line("help who");
+    let who = mud.expect(&mut client, "link-dead");
+    assert!(who.contains("Usage: who"), "{who:?}");
+    assert!(!who.contains("List the players"), "{who:?}");
+    client.send_line("help comp");
+    mud.expect(&mut client, "Usage: compress [<on|off>]");
+    mud.expect(&mut client, "Compression (MCCP2)");
+    client.send_line("help rul");
+    mud.expect(&mut client, "Be nice.");
+    client.send_line("help rulse");
+    mud.expect(&mut client, "No help for 'rulse'. Did you mean 'rules'?");
+
+    client.send_line("hlep");
+    mud.expect(&mut client, "What? Did you mean 'help'?");
+  }
+}
@@ -0,0 +1,245 @@
+//! Paging long command output.
+//!
+//! Text a command sends to the player who ran it is held back until the
+//! command finishes. If it's longer than a page, which is the client's window
+//! height from NAWS if it reports one or [PagerConfig::height] otherwise, it's
+//! shown a page at a time with a `[more]` prompt, and the player's next input
+//! moves through it.
+//! Anything that isn't a pager command puts the rest away and runs as a
+//! command as usual.
+//!
+//! Commands that send output over time rather than all at once can opt out
+//! with [GameCommand::paged](super::GameCommand::paged).
+
+use bevy::prelude::*;
+use bytes::BytesMut;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+
+use crate::net::{
+  TelnetOut,
+  WindowSize,
+};
+
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PagerConfig {
+  /// Lines per page, including the `[more]` prompt, for clients that don't
+  /// report their window size. 0 turns paging off for them.
+  pub height: u16,
+}
+
+impl Default for PagerConfig {
+  fn default() -> Self {
+    Self { height: 24 }
+  }
+}
+
+/// Output that's being paged through.
+#[derive(Component, Debug, Clone)]
+pub struct Pager {
+  lines: Vec<String>,
+  /// Lines per page.
+  height: usize,
+  /// The first line of the page being shown.
+  top: usize,
+}
+
+/// What to do with a line of input while paging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagerInput {
+  /// It was a pager command, and there's more to see.
+  More,
+  /// It was a pager command, and paging is over.
+  Done,
+  /// It wasn't a pager command. Paging is over, and the input should be run
+  /// as a command.
+  Command,
+}
+
+impl Pager {
+  /// Page `text` if it's longer than a page of `height` lines, including the
+  /// prompt. A `height` of 0 turns paging off.
+  pub fn new(text: &[u8], height: u16) -> Option<Pager> {
+    if height == 0 {
+      return None;
+    }
+    // Leave room for the prompt, but always show at least a line.
+    let height = usize::from(height - 1).max(1);
+    let text = String::from_utf8_lossy(text);
+    let lines = text
+      .split_inclusive('\n')
+      .map(String::from)
+      .collect::<Vec<_>>();
+    (lines.len() > height).then_some(Pager {
+      lines,
+      height,
+      top: 0,
+    })
+  }
+
+  fn bottom(&self) -> usize {
+    (self.top + self.height).min(self.lines.len())
+  }
+
+  /// Whether the page being shown is the last one.
+  pub fn at_end(&self) -> bool {
+    self.bottom() == self.lines.len()
+  }
+
+  /// The `[more]` prompt for the page being shown.
+  pub fn status(&self) -> String {
+    format!(
+      "[more] {}-{} of {} (Enter: next, prev, all, quit) ",
+      self.top + 1,
+      self.bottom(),
+      self.lines.len()
+    )
+  }
+
+  /// Send the page being shown, followed by the prompt if there's more.
+  pub fn show(&self, out: &TelnetOut) {
+    self.send(out, self.top..self.bottom());
+    if !self.at_end() {
+      out.prompt(self.status());
+    }
+  }
+
+  fn send(&self, out: &TelnetOut, range: std::ops::Range<usize>) {
+    let text = self.lines[range].concat();
+    out.formatted(BytesMut::from(text.as_bytes()));
+  }
+
+  /// Handle a line of input from the player.
+  pub fn input(&mut self, line: &str, out: &TelnetOut) -> PagerInput {
+    // No single letters, since those are commands too, like n for north.
+    match line.trim().to_ascii_lowercase().as_str() {
+      "" | "next" => {
+        self.top = self.bottom();
+        self.show(out);
+      }
+      "prev" | "previous" => {
+        self.top = self.top.saturating_sub(self.height);
+        self.show(out);
+      }
+      "all" => {
+        self.send(out, self.bottom()..self.lines.len());
+        return PagerInput::Done;
+      }
+      "quit" => return PagerInput::Done,
+      _ => return PagerInput::Command,
+    }
+    if self.at_end() {
+      PagerInput::Done
+    } else {
+      PagerInput::More
+    }
+  }
+}
+
+/// Send `text` to `entity`, paging it if it's too long. Returns the pager if
+/// it was.
+pub(super) fn page(
+  world: &World,
+  entity: Entity,
+  out: &TelnetOut,
+  text: BytesMut,
+) -> Option<Pager> {
+  let height = world
+    .get::<WindowSize>(entity)
+    .map(|size| size.height)
+    // NAWS sends 0 when the client doesn't know its height.
+    .filter(|&height| height > 0)
+    .or_else(|| {
+      world
+        .get_resource::<PagerConfig>()
+        .map(|config| config.height)
+    })
+    .unwrap_or_default();
+  match Pager::new(&text, height) {
+    Some(pager) => {
+      pager.show(out);
+      Some(pager)
+    }
+    None => {
+      out.formatted(text);
+      None
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    config::ServerConfig,
+    testing::TestMud,
+  };
+
+  #[test]
+  fn only_long_output_is_paged() {
+    let text = "one\r\ntwo\r\nthree\r\n";
+    assert!(Pager::new(text.as_bytes(), 4).is_none());
+    assert!(Pager::new(text.as_bytes(), 0).is_none());
+    let pager = Pager::new(text.as_bytes(), 3).unwrap();
+    assert_eq!(
+      pager.status(),
+      "[more] 1-2 of 3 (Enter: next, prev, all, quit) "
+    );
+    let pager = Pager::new(text.as_bytes(), 1).unwrap();
+    assert_eq!(
+      pager.status(),
+      "[more] 1-1 of 3 (Enter: next, prev, all, quit) "
+    );
+  }
+
+  #[test]
+  fn pages_through_output() {
+    let mut config = ServerConfig::default();
+    config.pager.height = 5;
+    let mut mud = TestMud::with_config(config);
+    let mut client = mud.connect();
+    mud.create_account(&mut client, "admin", "pw");
+
+    client.send_line("@entities");
+    let first = mud.expect(&mut client, "[more] 1-4 of ");
+    assert!(first.contains("Entities:"), "{first:?}");
+    client.send_line("");
+    mud.expect(&mut client, "[more] 5-8 of ");
+    client.send_line("prev");
+    mud.expect(&mut client, "[more] 1-4 of ");
+
+    // Anything else runs as a command.
+    client.send_line("who");
+    mud.expect(&mut client, "It's just you!");
+    mud.expect(&mut client, "> ");
+    client.send_line("q");
+    mud.expect(&mut client, "What?");
+  }
+
+  #[test]
+  fn unknown_window_height_uses_config() {
+    let mut config = ServerConfig::default();
+    config.pager.height = 5;
+    let mut mud = TestMud::with_config(config);
+    let mut client = mud.connect();
+    mud.create_account(&mut client, "admin", "pw");
+
+    let world = mud.world_mut();
+    let conns = world
+      .query_filtered::<Entity, With<TelnetOut>>()
+      .iter(world)
+      .collect::<Vec<_>>();
+    for conn in conns {
+      world.entity_mut(conn).insert(WindowSize {
+        width: 80,
+        height: 0,
+      });
+    }
+
+    client.send_line("@entities");
+    mud.expect(&mut client, "[more] 1-4 of ");
+  }
+}
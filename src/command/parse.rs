@@ -1,4 +1,36 @@
+//! Command line grammar.
+//!
+//! [command] splits a line into words, quoted strings and the space between
+//! them. On top of that, an [ArgSpec] describes the arguments a command takes,
+//! and generates the parser that turns its argument string into [ArgValues],
+//! the usage string shown to players, and a report pointing at what's wrong
+//! when the arguments don't parse.
+
+use std::{
+  fmt,
+  ops::RangeInclusive,
+};
+
+use anyhow::anyhow;
+use ariadne::{
+  CharSet,
+  Config,
+  IndexType,
+  Label,
+  Report,
+  ReportKind,
+  Source,
+};
+use bevy::prelude::Entity;
 use chumsky::prelude::*;
+use hexx::{
+  hex,
+  Hex,
+};
+
+use super::CommandArgs;
+
+type Extra<'a> = extra::Err<Rich<'a, char>>;
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub enum Node {
@@ -7,46 +39,428 @@ pub enum Node {
   Space,
 }
 
-pub fn command<'a>() -> impl Parser<'a, &'a str, Vec<Node>, extra::Err<Rich<'a, char>>> {
-  let word = any()
+fn word<'a>() -> impl Parser<'a, &'a str, String, Extra<'a>> + Clone {
+  any()
     .filter(|c: &char| !c.is_whitespace() && *c != '"')
     .repeated()
     .at_least(1)
     .collect()
-    .map(Node::Word);
+}
 
+fn quoted<'a>() -> impl Parser<'a, &'a str, String, Extra<'a>> + Clone {
   let escaped = just("\\\"").to('"');
-  let quoted = escaped
+  escaped
     .or(none_of("\""))
     .repeated()
     .collect()
     .delimited_by(just('"'), just('"'))
-    .map(Node::Quoted);
+}
 
-  let space = any()
+fn space<'a>() -> impl Parser<'a, &'a str, (), Extra<'a>> + Clone {
+  any()
     .filter(|c: &char| c.is_whitespace())
     .repeated()
     .at_least(1)
-    .map(|_| Node::Space);
+}
 
-  word
-    .or(quoted)
-    .or(space)
+/// Cube coordinates, `q r s`, as three numbers with space between them. They
+/// come out as `q,r,s`.
+fn cube<'a>() -> impl Parser<'a, &'a str, String, Extra<'a>> + Clone {
+  any()
+    .filter(|c: &char| c.is_ascii_digit() || *c == '-')
+    .repeated()
+    .at_least(1)
+    .collect::<String>()
+    .separated_by(space())
+    .exactly(3)
+    .collect::<Vec<_>>()
+    .map(|coords| coords.join(","))
+}
+
+pub fn command<'a>() -> impl Parser<'a, &'a str, Vec<Node>, Extra<'a>> {
+  word()
+    .map(Node::Word)
+    .or(quoted().map(Node::Quoted))
+    .or(space().to(Node::Space))
     .repeated()
     .collect()
     .then_ignore(end())
 }
 
+/// What an argument can be.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ArgKind {
+  /// An entity, as `<index>v<generation>`, a bare index or its raw bits.
+  Entity,
+  /// Hex coordinates, as `q,r`, or in cube form as `q r s` or `q,r,s`.
+  Hex,
+  /// An integer in the range.
+  Int(RangeInclusive<i64>),
+  /// One of a fixed set of words, ignoring case.
+  Choice(&'static [&'static str]),
+  /// A single word, or anything in double quotes.
+  String,
+  /// Everything left on the line.
+  Rest,
+}
+
+/// A value parsed from an argument.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+  Entity(Entity),
+  Hex(Hex),
+  Int(i64),
+  Text(String),
+}
+
+impl ArgKind {
+  fn convert(&self, text: String) -> Result<Value, String> {
+    match self {
+      ArgKind::Entity => parse_entity(&text)
+        .map(Value::Entity)
+        .ok_or_else(|| format!("expected an entity id like 12v1, found `{text}`")),
+      ArgKind::Hex => parse_hex(&text)
+        .map(Value::Hex)
+        .ok_or_else(|| format!("expected coordinates like 3,-2 or 3 -2 -1, found `{text}`")),
+      ArgKind::Int(range) => text
+        .parse::<i64>()
+        .ok()
+        .filter(|n| range.contains(n))
+        .map(Value::Int)
+        .ok_or_else(|| {
+          format!(
+            "expected a number from {} to {}, found `{text}`",
+            range.start(),
+            range.end()
+          )
+        }),
+      ArgKind::Choice(choices) => choices
+        .iter()
+        .find(|choice| choice.eq_ignore_ascii_case(&text))
+        .map(|choice| Value::Text(choice.to_string()))
+        .ok_or_else(|| format!("expected one of {}, found `{text}`", choices.join(", "))),
+      ArgKind::String => Ok(Value::Text(text)),
+      ArgKind::Rest => Ok(Value::Text(text.trim_end().into())),
+    }
+  }
+}
+
+/// Parse an entity the way it's displayed, `12v1`, or from its bits. Plain
+/// numbers too small to be valid bits are taken as an index in the first
+/// generation. Generations start at 1, so real bits never are, and ids
+/// copied from older output as bits still mean the same entity.
+fn parse_entity(text: &str) -> Option<Entity> {
+  let bits = match text.split_once('v') {
+    Some((index, generation)) => {
+      (u64::from(generation.parse::<u32>().ok()?) << 32) | u64::from(index.parse::<u32>().ok()?)
+    }
+    None => {
+      let bits = text.parse::<u64>().ok()?;
+      if bits <= u64::from(u32::MAX) {
+        return Some(Entity::from_raw(bits as u32));
+      }
+      bits
+    }
+  };
+  Entity::try_from_bits(bits).ok()
+}
+
+/// Parse axial `q,r` or cube `q,r,s` coordinates. Cube coordinates have to
+/// add up to 0.
+fn parse_hex(text: &str) -> Option<Hex> {
+  let coords = text
+    .split(',')
+    .map(|n| n.trim().parse::<i32>().ok())
+    .collect::<Option<Vec<_>>>()?;
+  match coords[..] {
+    [q, r] => Some(hex(q, r)),
+    [q, r, s] if q + r + s == 0 => Some(hex(q, r)),
+    _ => None,
+  }
+}
+
+/// A single argument in an [ArgSpec].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Arg {
+  pub name: &'static str,
+  pub kind: ArgKind,
+  pub optional: bool,
+  pub repeated: bool,
+}
+
+impl Arg {
+  pub fn new(name: &'static str, kind: ArgKind) -> Self {
+    Arg {
+      name,
+      kind,
+      optional: false,
+      repeated: false,
+    }
+  }
+
+  pub fn entity(name: &'static str) -> Self {
+    Arg::new(name, ArgKind::Entity)
+  }
+
+  pub fn hex(name: &'static str) -> Self {
+    Arg::new(name, ArgKind::Hex)
+  }
+
+  pub fn int(name: &'static str) -> Self {
+    Arg::int_in(name, i64::MIN..=i64::MAX)
+  }
+
+  pub fn int_in(name: &'static str, range: RangeInclusive<i64>) -> Self {
+    Arg::new(name, ArgKind::Int(range))
+  }
+
+  pub fn choice(name: &'static str, choices: &'static [&'static str]) -> Self {
+    Arg::new(name, ArgKind::Choice(choices))
+  }
+
+  pub fn string(name: &'static str) -> Self {
+    Arg::new(name, ArgKind::String)
+  }
+
+  pub fn rest(name: &'static str) -> Self {
+    Arg::new(name, ArgKind::Rest)
+  }
+
+  /// Allow the argument to be left out.
+  pub fn optional(mut self) -> Self {
+    self.optional = true;
+    self
+  }
+
+  /// Take as many of the argument as are given. Combined with
+  /// [Arg::optional], that includes none.
+  pub fn repeated(mut self) -> Self {
+    self.repeated = true;
+    self
+  }
+
+  fn parser<'a>(&self) -> impl Parser<'a, &'a str, (&'static str, Value), Extra<'a>> + Clone {
+    let name = self.name;
+    let kind = self.kind.clone();
+    let text = match kind {
+      ArgKind::Rest => any().repeated().at_least(1).collect::<String>().boxed(),
+      ArgKind::Hex => cube().or(word()).or(quoted()).boxed(),
+      _ => word().or(quoted()).boxed(),
+    };
+    text
+      .try_map(move |text, span| kind.convert(text).map_err(|msg| Rich::custom(span, msg)))
+      .labelled(name)
+      .then_ignore(space().or_not())
+      .map(move |value| (name, value))
+  }
+}
+
+impl fmt::Display for Arg {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let arg = match &self.kind {
+      ArgKind::Choice(choices) => format!("<{}>", choices.join("|")),
+      ArgKind::Rest => format!("<{}...>", self.name),
+      _ => format!("<{}>", self.name),
+    };
+    match (self.optional, self.repeated) {
+      (false, false) => write!(f, "{arg}"),
+      (false, true) => write!(f, "{arg}..."),
+      (true, false) => write!(f, "[{arg}]"),
+      (true, true) => write!(f, "[{arg}...]"),
+    }
+  }
+}
+
+/// The arguments a command takes, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArgSpec {
+  args: Vec<Arg>,
+}
+
+impl ArgSpec {
+  pub fn new(args: impl IntoIterator<Item = Arg>) -> Self {
+    ArgSpec {
+      args: args.into_iter().collect(),
+    }
+  }
+
+  pub fn args(&self) -> &[Arg] {
+    &self.args
+  }
+
+  /// The arguments as they'd be shown in a usage string, e.g.
+  /// `<entity> [<parent>]`.
+  pub fn usage(&self) -> String {
+    self
+      .args
+      .iter()
+      .map(Arg::to_string)
+      .collect::<Vec<_>>()
+      .join(" ")
+  }
+
+  fn parser<'a>(&self) -> impl Parser<'a, &'a str, Vec<(&'static str, Value)>, Extra<'a>> {
+    let mut parser = empty().to(Vec::new()).boxed();
+    for arg in &self.args {
+      let value = arg.parser();
+      let values = match (arg.optional, arg.repeated) {
+        (false, false) => value.map(|value| vec![value]).boxed(),
+        (true, false) => value
+          .or_not()
+          .map(|value| value.into_iter().collect())
+          .boxed(),
+        (optional, true) => value
+          .repeated()
+          .at_least(if optional { 0 } else { 1 })
+          .collect::<Vec<_>>()
+          .boxed(),
+      };
+      parser = parser
+        .then(values)
+        .map(|(mut all, values)| {
+          all.extend(values);
+          all
+        })
+        .boxed();
+    }
+    space().or_not().ignore_then(parser).then_ignore(end())
+  }
+
+  /// Parse the arguments to the `key` command, or explain what's wrong with
+  /// them.
+  pub fn parse(&self, key: &str, args: &CommandArgs) -> anyhow::Result<ArgValues> {
+    self
+      .parser()
+      .parse(args.args)
+      .into_result()
+      .map(ArgValues)
+      .map_err(|errors| {
+        let line = format!("{} {}", args.matched, args.args);
+        let usage = format!("{key} {}", self.usage());
+        anyhow!(report(
+          &line,
+          args.matched.len() + 1,
+          &errors,
+          usage.trim_end()
+        ))
+      })
+  }
+}
+
+/// Render parse errors in `line`, whose arguments start at `offset`.
+fn report(line: &str, offset: usize, errors: &[Rich<char>], usage: &str) -> String {
+  let mut out = Vec::new();
+  for err in errors {
+    let span = err.span().start + offset..err.span().end + offset;
+    let config = Config::default()
+      .with_color(false)
+      .with_compact(true)
+      .with_char_set(CharSet::Ascii)
+      .with_index_type(IndexType::Byte);
+    let written = Report::build(ReportKind::Error, (), span.start)
+      .with_config(config)
+      .with_message(err.reason().to_string())
+      .with_label(Label::new(span))
+      .with_note(format!("usage: {usage}"))
+      .finish()
+      .write(Source::from(line), &mut out);
+    if written.is_err() {
+      return format!("{}\nUsage: {usage}", err.reason());
+    }
+  }
+  String::from_utf8_lossy(&out).trim_end().into()
+}
+
+/// Types that can be taken out of [ArgValues].
+pub trait FromValue: Sized {
+  fn from_value(value: &Value) -> Option<Self>;
+}
+
+impl FromValue for Value {
+  fn from_value(value: &Value) -> Option<Self> {
+    Some(value.clone())
+  }
+}
+
+impl FromValue for Entity {
+  fn from_value(value: &Value) -> Option<Self> {
+    match value {
+      Value::Entity(entity) => Some(*entity),
+      _ => None,
+    }
+  }
+}
+
+impl FromValue for Hex {
+  fn from_value(value: &Value) -> Option<Self> {
+    match value {
+      Value::Hex(coords) => Some(*coords),
+      _ => None,
+    }
+  }
+}
+
+impl FromValue for String {
+  fn from_value(value: &Value) -> Option<Self> {
+    match value {
+      Value::Text(text) => Some(text.clone()),
+      _ => None,
+    }
+  }
+}
+
+macro_rules! int_from_value {
+  ($($ty:ty),*) => {
+    $(
+      impl FromValue for $ty {
+        fn from_value(value: &Value) -> Option<Self> {
+          match value {
+            Value::Int(n) => (*n).try_into().ok(),
+            _ => None,
+          }
+        }
+      }
+    )*
+  };
+}
+
+int_from_value!(i8, i16, i32, i64, u8, u16, u32, u64, usize);
+
+/// The arguments to a command, parsed according to its [ArgSpec].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ArgValues(Vec<(&'static str, Value)>);
+
+impl ArgValues {
+  /// The value of a required argument.
+  pub fn get<T: FromValue>(&self, name: &str) -> anyhow::Result<T> {
+    self
+      .opt(name)
+      .ok_or_else(|| anyhow!("missing argument <{name}>"))
+  }
+
+  /// The value of an optional argument, if it was given.
+  pub fn opt<T: FromValue>(&self, name: &str) -> Option<T> {
+    self
+      .0
+      .iter()
+      .find(|(arg, _)| *arg == name)
+      .and_then(|(_, value)| T::from_value(value))
+  }
+
+  /// Every value given for a repeated argument.
+  pub fn all<T: FromValue>(&self, name: &str) -> Vec<T> {
+    self
+      .0
+      .iter()
+      .filter(|(arg, _)| *arg == name)
+      .filter_map(|(_, value)| T::from_value(value))
+      .collect()
+  }
+}
+
 #[cfg(test)]
 mod test {
-  use ariadne::{
-    Color,
-    Config,
-    Label,
-    Report,
-    ReportKind,
-    Source,
-  };
+  use ariadne::Color;
 
   use super::*;
 
@@ -94,4 +508,105 @@ mod test {
       ]
     );
   }
+
+  fn spec() -> ArgSpec {
+    ArgSpec::new([
+      Arg::entity("entity"),
+      Arg::hex("coords"),
+      Arg::int_in("r", -5..=5),
+      Arg::choice("mode", &["on", "off"]),
+      Arg::string("name").optional(),
+      Arg::rest("note").optional(),
+    ])
+  }
+
+  fn parse(spec: &ArgSpec, args: &str) -> anyhow::Result<ArgValues> {
+    spec.parse(
+      "test",
+      &CommandArgs {
+        caller: None,
+        owner: None,
+        matched: "te",
+        args,
+      },
+    )
+  }
+
+  #[test]
+  fn usage() {
+    assert_eq!(
+      spec().usage(),
+      "<entity> <coords> <r> <on|off> [<name>] [<note...>]"
+    );
+    let repeated = ArgSpec::new([
+      Arg::entity("entity").repeated(),
+      Arg::string("tag").optional().repeated(),
+    ]);
+    assert_eq!(repeated.usage(), "<entity>... [<tag>...]");
+  }
+
+  #[test]
+  fn typed_args() {
+    let values = parse(&spec(), "12v3 3,-2 -4 OFF \"a name\" the rest  of it ").unwrap();
+    assert_eq!(
+      values.get::<Entity>("entity").unwrap(),
+      Entity::try_from_bits((3 << 32) | 12).unwrap()
+    );
+    assert_eq!(values.get::<Hex>("coords").unwrap(), hex(3, -2));
+    assert_eq!(values.get::<i8>("r").unwrap(), -4);
+    assert_eq!(values.get::<String>("mode").unwrap(), "off");
+    assert_eq!(values.get::<String>("name").unwrap(), "a name");
+    assert_eq!(values.get::<String>("note").unwrap(), "the rest  of it");
+
+    let values = parse(&spec(), "7 0,0 0 on").unwrap();
+    assert_eq!(values.get::<Entity>("entity").unwrap(), Entity::from_raw(7));
+    assert_eq!(values.opt::<String>("name"), None);
+    assert_eq!(values.opt::<String>("note"), None);
+
+    // Ids as bits, the way they used to be given.
+    let bits = Entity::from_raw(7).to_bits().to_string();
+    let values = parse(&spec(), &format!("{bits} 0,0 0 on")).unwrap();
+    assert_eq!(values.get::<Entity>("entity").unwrap(), Entity::from_raw(7));
+
+    // Cube coordinates, the other way they used to be given.
+    let values = parse(&spec(), "7 3 -2 -1 -4 on").unwrap();
+    assert_eq!(values.get::<Hex>("coords").unwrap(), hex(3, -2));
+    assert_eq!(values.get::<i8>("r").unwrap(), -4);
+    let values = parse(&spec(), "7 3,-2,-1 -4 on").unwrap();
+    assert_eq!(values.get::<Hex>("coords").unwrap(), hex(3, -2));
+
+    let repeated = ArgSpec::new([Arg::entity("entity").optional().repeated()]);
+    assert_eq!(
+      parse(&repeated, "").unwrap().all::<Entity>("entity"),
+      vec![]
+    );
+    assert_eq!(
+      parse(&repeated, "1 2").unwrap().all::<Entity>("entity"),
+      vec![Entity::from_raw(1), Entity::from_raw(2)]
+    );
+  }
+
+  #[test]
+  fn reports_errors() {
+    let err = parse(&spec(), "12v3 3,-2 9 on").unwrap_err().to_string();
+    assert!(
+      err.contains("expected a number from -5 to 5, found `9`"),
+      "{err}"
+    );
+    assert!(err.contains("te 12v3 3,-2 9 on"), "{err}");
+    assert!(err.contains("usage: test <entity> <coords> <r>"), "{err}");
+
+    let err = parse(&spec(), "12v3").unwrap_err().to_string();
+    assert!(err.contains("coords"), "{err}");
+
+    let err = parse(&spec(), "12v3 3 -2 5 0 on").unwrap_err().to_string();
+    assert!(
+      err.contains("expected coordinates like 3,-2 or 3 -2 -1, found `3,-2,5`"),
+      "{err}"
+    );
+
+    let no_args = ArgSpec::default();
+    assert!(parse(&no_args, "").is_ok());
+    assert!(parse(&no_args, "extra").is_err());
+  }
 }
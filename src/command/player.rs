@@ -5,7 +5,14 @@ use hexx::{
 use tracing::warn;
 
 use super::{
+  help::help,
+  parse::{
+    Arg,
+    ArgSpec,
+    ArgValues,
+  },
   CommandArgs,
+  DescribeExt,
   WorldCommand,
 };
 use crate::{
@@ -14,18 +21,36 @@ use crate::{
     Queue,
     StopEvent,
   },
-  character::Puppet,
+  character::{
+    LinkDead,
+    Puppet,
+  },
   movement::MoveAction,
-  net::TelnetOut,
+  net::{
+    mccp,
+    TelnetOptions,
+    TelnetOut,
+  },
+  prompt::{
+    Prompt,
+    PromptTokens,
+    DEFAULT_PROMPT,
+  },
 };
 
 fn who(args: CommandArgs) -> anyhow::Result<WorldCommand> {
   Ok(Box::new(move |world| {
-    let players = world
+    let mut players = world
       .query::<&Session>()
       .iter(world)
       .map(|s| s.username.clone())
       .collect::<Vec<_>>();
+    players.extend(
+      world
+        .query::<&LinkDead>()
+        .iter(world)
+        .map(|l| format!("{} (link-dead)", l.username)),
+    );
     let out = if players.len() > 1 {
       let mut out = format!("There are {} players online:", players.len());
       for player in players {
@@ -107,6 +132,60 @@ fn stop(args: CommandArgs) -> anyhow::Result<WorldCommand> {
   }))
 }
 
+fn compress(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let enabled = values.opt::<String>("mode").as_deref() != Some("off");
+  Ok(Box::new(move |world| {
+    let caller = try_opt!(args.caller, return);
+    let out = try_opt!(world.get::<TelnetOut>(caller), return).clone();
+    let mut options = try_opt!(world.get_mut::<TelnetOptions>(caller), return);
+    mccp::set_compression(&mut options, &out, enabled);
+    out.line(if enabled {
+      "Compression requested."
+    } else {
+      "Compression disabled."
+    });
+  }))
+}
+
+fn prompt(args: CommandArgs, values: ArgValues) -> anyhow::Result<WorldCommand> {
+  let caller = args.caller;
+  let mut template = values.opt::<String>("template").unwrap_or_default();
+  // Arguments come trimmed, but prompts look better with some space after
+  // them.
+  if !template.is_empty() && template != "default" {
+    template.push(' ');
+  }
+  Ok(Box::new(move |world| {
+    let caller = try_opt!(caller, return);
+    let out = try_opt!(world.get::<TelnetOut>(caller), return).clone();
+    let puppet = try_opt!(world.get::<Puppet>(caller), return).0;
+    match template.as_str() {
+      "" => {
+        let current = world
+          .get::<Prompt>(puppet)
+          .map_or(DEFAULT_PROMPT, |prompt| prompt.as_str());
+        let mut tokens = world
+          .resource::<PromptTokens>()
+          .names()
+          .map(|name| format!("{{{name}}}"))
+          .collect::<Vec<_>>();
+        tokens.sort();
+        out.line(format!("Your prompt is {current:?}."));
+        out.line(format!("Available tokens: {}", tokens.join(" ")));
+        out.line("Usage: prompt <template>|default");
+      }
+      "default" => {
+        world.entity_mut(puppet).remove::<Prompt>();
+        out.line("Prompt reset.");
+      }
+      _ => {
+        world.entity_mut(puppet).insert(Prompt(template));
+        out.line("Prompt set.");
+      }
+    }
+  }))
+}
+
 const N: EdgeDirection = EdgeDirection::FLAT_NORTH;
 const NE: EdgeDirection = EdgeDirection::FLAT_NORTH_EAST;
 const SE: EdgeDirection = EdgeDirection::FLAT_SOUTH_EAST;
@@ -121,27 +200,120 @@ const BACK: EdgeDirection = EdgeDirection::ALL_DIRECTIONS[3];
 const BACK_LEFT: EdgeDirection = EdgeDirection::ALL_DIRECTIONS[4];
 const FWD_LEFT: EdgeDirection = EdgeDirection::ALL_DIRECTIONS[5];
 
+const HELP_HELP: &str = "\
+A topic can be a command or anything listed under other topics, and the start
+of one is enough, like 'help comp' for compress.";
+
+const WHO_HELP: &str = "\
+Players whose connection dropped are shown as link-dead until they come back
+or their character is put away.";
+
+const MOVE_HELP: &str = "\
+The world is made of hexes, so there's no east or west. Moves are queued and
+taken one at a time, as fast as you can go, without changing which way you're
+facing. Use stop to forget the rest.";
+
+const RELATIVE_MOVE_HELP: &str = "\
+Directions are relative to the way you're facing, which only turning changes.
+Moves and turns are queued and taken one at a time. Use stop to forget the
+rest.";
+
+const COMPRESS_HELP: &str = "\
+Compression (MCCP2) saves bandwidth if your client supports it. With no
+argument, or on, it's offered to your client and starts once the client
+agrees. Turn it off if your client has trouble with it.";
+
+const PROMPT_HELP: &str = "\
+Each {token} in the template is replaced with its current value, and the
+prompt command on its own lists the tokens there are. Use {{ and }} for
+literal braces, and 'prompt default' to go back to the default.";
+
 command_set! { PlayerCommands =>
-  ("who", who),
-  ("forward", move_relative(FWD)),
-  ("forwardright", move_relative(FWD_RIGHT)),
-  ("forwardleft", move_relative(FWD_LEFT)),
-  ("backward", move_relative(BACK)),
-  ("backwardright", move_relative(BACK_RIGHT)),
-  ("backwardleft", move_relative(BACK_LEFT)),
-  ("right", turn_direction(1)),
-  ("left", turn_direction(-1)),
-  ("north", move_absolute(N)),
-  ("northeast", move_absolute(NE)),
-  ("southeast", move_absolute(SE)),
-  ("south", move_absolute(S)),
-  ("southwest", move_absolute(SW)),
-  ("northwest", move_absolute(NW)),
-  ("n", move_absolute(N)),
-  ("ne", move_absolute(NE)),
-  ("se", move_absolute(SE)),
-  ("s", move_absolute(S)),
-  ("sw", move_absolute(SW)),
-  ("nw", move_absolute(NW)),
-  ("stop", stop),
+  (
+    "help",
+    ArgSpec::new([Arg::rest("topic").optional()]),
+    help,
+  )
+    .describe("List commands and topics, or read about one.")
+    .with_help(HELP_HELP),
+  ("who", who).describe("List the players who are online.").with_help(WHO_HELP),
+  ("forward", move_relative(FWD))
+    .describe("Move the way you're facing.")
+    .with_help(RELATIVE_MOVE_HELP),
+  ("forwardright", move_relative(FWD_RIGHT))
+    .describe("Move ahead and to the right.")
+    .with_help(RELATIVE_MOVE_HELP),
+  ("forwardleft", move_relative(FWD_LEFT))
+    .describe("Move ahead and to the left.")
+    .with_help(RELATIVE_MOVE_HELP),
+  ("backward", move_relative(BACK))
+    .describe("Move back the way you came.")
+    .with_help(RELATIVE_MOVE_HELP),
+  ("backwardright", move_relative(BACK_RIGHT))
+    .describe("Move back and to the right.")
+    .with_help(RELATIVE_MOVE_HELP),
+  ("backwardleft", move_relative(BACK_LEFT))
+    .describe("Move back and to the left.")
+    .with_help(RELATIVE_MOVE_HELP),
+  ("right", turn_direction(1)).describe("Turn to your right.").with_help(RELATIVE_MOVE_HELP),
+  ("left", turn_direction(-1)).describe("Turn to your left.").with_help(RELATIVE_MOVE_HELP),
+  ("north", move_absolute(N)).describe("Move north.").with_help(MOVE_HELP),
+  ("northeast", move_absolute(NE)).describe("Move northeast.").with_help(MOVE_HELP),
+  ("southeast", move_absolute(SE)).describe("Move southeast.").with_help(MOVE_HELP),
+  ("south", move_absolute(S)).describe("Move south.").with_help(MOVE_HELP),
+  ("southwest", move_absolute(SW)).describe("Move southwest.").with_help(MOVE_HELP),
+  ("northwest", move_absolute(NW)).describe("Move northwest.").with_help(MOVE_HELP),
+  ("n", move_absolute(N)).describe("Move north.").with_help(MOVE_HELP),
+  ("ne", move_absolute(NE)).describe("Move northeast.").with_help(MOVE_HELP),
+  ("se", move_absolute(SE)).describe("Move southeast.").with_help(MOVE_HELP),
+  ("s", move_absolute(S)).describe("Move south.").with_help(MOVE_HELP),
+  ("sw", move_absolute(SW)).describe("Move southwest.").with_help(MOVE_HELP),
+  ("nw", move_absolute(NW)).describe("Move northwest.").with_help(MOVE_HELP),
+  ("stop", stop).describe("Stop what you're doing and forget what's queued."),
+  (
+    "compress",
+    ArgSpec::new([Arg::choice("mode", &["on", "off"]).optional()]),
+    compress,
+  )
+    .describe("Turn compression of what you're sent on or off.")
+    .with_help(COMPRESS_HELP),
+  (
+    "prompt",
+    ArgSpec::new([Arg::rest("template").optional()]),
+    prompt,
+  )
+    .describe("Show or change your prompt.")
+    .with_help(PROMPT_HELP),
+}
+
+#[cfg(test)]
+mod test {
+  use crate::testing::TestMud;
+
+  #[test]
+  fn who_lists_players() {
+    let mut mud = TestMud::new();
+    let mut alice = mud.connect();
+    mud.create_account(&mut alice, "alice", "pw");
+
+    alice.send_line("who");
+    mud.expect(&mut alice, "It's just you!");
+
+    let mut bob = mud.connect();
+    mud.create_account(&mut bob, "bob", "pw");
+    bob.send_line("who");
+    let out = mud.expect(&mut bob, "> ");
+    assert!(out.contains("There are 2 players online:"), "{out:?}");
+    assert!(out.contains("    alice"), "{out:?}");
+  }
+
+  #[test]
+  fn unknown_command() {
+    let mut mud = TestMud::new();
+    let mut client = mud.connect();
+    mud.create_account(&mut client, "alice", "pw");
+    client.send_line("dance wildly");
+    mud.expect(&mut client, "What?");
+    mud.expect(&mut client, "> ");
+  }
 }
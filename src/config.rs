@@ -0,0 +1,463 @@
+//! Server configuration.
+//!
+//! Everything that used to be scattered across constants lives in a single
+//! [ServerConfig], which can be loaded from a RON or TOML file and then
+//! overridden from the command line with [ConfigArgs].
+
+use std::{
+  fs,
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use anyhow::{
+  anyhow,
+  bail,
+};
+use bevy::prelude::*;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use tracing::Level;
+
+use crate::{
+  character::LinkDeadConfig,
+  command::pager::PagerConfig,
+  map::MapConfig,
+  net::{
+    BindAddr,
+    IdleConfig,
+    ListenAddr,
+    ListenAddrs,
+    MccpConfig,
+    OutputLimits,
+    ThrottleConfig,
+    TlsConfig,
+    WebSocketConfig,
+  },
+};
+
+/// The port the default listener binds.
+pub const DEFAULT_PORT: u16 = 23840;
+/// The default save backend.
+pub const DEFAULT_DB: &str = "sqlite://db.sqlite";
+/// How many backups of the save to keep by default.
+pub const DEFAULT_BACKUPS: usize = 3;
+/// The default entity asset directory.
+pub const DEFAULT_ASSETS: &str = "assets";
+/// The default number of seconds between world saves.
+pub const DEFAULT_SAVE_INTERVAL: f32 = 30.0;
+
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+  /// The server's name, as reported to MSSP crawlers.
+  pub name: String,
+  /// Directory that relative save and asset paths are resolved against.
+  pub data_dir: PathBuf,
+  /// The save backend, in the format accepted by
+  /// [Backend::open](crate::savestate::backend::Backend::open).
+  pub db: String,
+  /// Number of backups of the save to keep.
+  pub backups: usize,
+  /// Directory containing entity assets.
+  pub assets: PathBuf,
+  /// Addresses to accept telnet connections on.
+  pub listeners: Vec<ListenAddr>,
+  /// Encrypted listeners and their certificate.
+  pub tls: TlsConfig,
+  /// Listeners for browser clients.
+  pub websocket: WebSocketConfig,
+  /// Limits on how much output may be waiting for each client.
+  pub output: OutputLimits,
+  /// Stream compression for clients that support it.
+  pub mccp: MccpConfig,
+  /// Limits on connections and input to keep a single client from flooding
+  /// the server.
+  pub throttle: ThrottleConfig,
+  /// When to disconnect clients that stop sending input.
+  pub idle: IdleConfig,
+  /// How long characters stay in the world after their player disconnects.
+  pub link_dead: LinkDeadConfig,
+  /// How long command output can be before it's paged.
+  pub pager: PagerConfig,
+  /// Seconds between world saves.
+  pub save_interval: f32,
+  pub map: MapConfig,
+  pub log: LogConfig,
+}
+
+impl Default for ServerConfig {
+  fn default() -> Self {
+    Self {
+      name: "bevy_mud".into(),
+      data_dir: ".".into(),
+      db: DEFAULT_DB.into(),
+      backups: DEFAULT_BACKUPS,
+      assets: DEFAULT_ASSETS.into(),
+      listeners: ListenAddrs::default().0,
+      tls: TlsConfig::default(),
+      websocket: WebSocketConfig::default(),
+      output: OutputLimits::default(),
+      mccp: MccpConfig::default(),
+      throttle: ThrottleConfig::default(),
+      idle: IdleConfig::default(),
+      link_dead: LinkDeadConfig::default(),
+      pager: PagerConfig::default(),
+      save_interval: DEFAULT_SAVE_INTERVAL,
+      map: MapConfig::default(),
+      log: LogConfig::default(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+  /// The minimum level to log.
+  pub level: String,
+  /// Extra filters in the [EnvFilter](tracing_subscriber::EnvFilter) format.
+  pub filter: String,
+}
+
+impl Default for LogConfig {
+  fn default() -> Self {
+    Self {
+      level: "info".into(),
+      filter: "wgpu=error,naga=warn".into(),
+    }
+  }
+}
+
+impl LogConfig {
+  pub fn level(&self) -> Level {
+    self.level.parse().unwrap_or(Level::INFO)
+  }
+}
+
+impl ServerConfig {
+  /// Load the config from a file. Files ending in `.toml` are parsed as TOML,
+  /// everything else as RON.
+  pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+    let path = path.as_ref();
+    let data = fs::read_to_string(path)
+      .map_err(|error| anyhow!("error reading {}: {error}", path.display()))?;
+    let config: Self = match path.extension().and_then(|e| e.to_str()) {
+      Some("toml") => toml::from_str(&data)?,
+      _ => ron::from_str(&data)?,
+    };
+    config.validate()?;
+    Ok(config)
+  }
+
+  fn validate(&self) -> anyhow::Result<()> {
+    if self.log.level.parse::<Level>().is_err() {
+      bail!("invalid log level: {}", self.log.level);
+    }
+    if self.save_interval <= 0.0 {
+      bail!("save interval must be positive");
+    }
+    if self.throttle.lines_per_second < 0.0 {
+      bail!("lines per second can't be negative");
+    }
+    if self.idle.timeout < 0.0 || self.link_dead.grace < 0.0 {
+      bail!("timeouts can't be negative");
+    }
+    Ok(())
+  }
+
+  /// The save backend url with its path resolved against the data directory.
+  pub fn db_url(&self) -> String {
+    match self.db.split_once("://") {
+      Some((scheme, path)) => format!("{scheme}://{}", self.data_dir.join(path).display()),
+      None => self.data_dir.join(&self.db).display().to_string(),
+    }
+  }
+
+  /// The entity asset directory resolved against the data directory.
+  pub fn assets_dir(&self) -> PathBuf {
+    self.data_dir.join(&self.assets)
+  }
+
+  /// The TLS config with its certificate and key paths resolved against the
+  /// data directory.
+  pub fn tls_config(&self) -> TlsConfig {
+    TlsConfig {
+      listeners: self.tls.listeners.clone(),
+      cert: self.data_dir.join(&self.tls.cert),
+      key: self.data_dir.join(&self.tls.key),
+    }
+  }
+}
+
+/// Command line overrides for the [ServerConfig].
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct ConfigArgs {
+  /// Directory that relative save and asset paths are resolved against.
+  #[arg(long)]
+  pub data_dir: Option<PathBuf>,
+
+  /// Save backend, e.g. `sqlite://db.sqlite` or `ron://world.ron`.
+  #[arg(short, long)]
+  pub db: Option<String>,
+
+  /// Number of backups of the save to keep.
+  #[arg(long)]
+  pub backups: Option<usize>,
+
+  /// Directory containing entity assets.
+  #[arg(long)]
+  pub assets: Option<PathBuf>,
+
+  /// Address to accept telnet connections on, or `unix:<path>` for a Unix
+  /// socket. May be repeated.
+  #[arg(short, long = "listen")]
+  pub listeners: Vec<BindAddr>,
+
+  /// Port for the first listener, which must be a TCP one.
+  #[arg(short, long)]
+  pub port: Option<u16>,
+
+  /// Address to accept TLS connections on. May be repeated.
+  #[arg(long = "tls-listen")]
+  pub tls_listeners: Vec<BindAddr>,
+
+  /// PEM file containing the TLS certificate chain.
+  #[arg(long)]
+  pub tls_cert: Option<PathBuf>,
+
+  /// PEM file containing the TLS private key.
+  #[arg(long)]
+  pub tls_key: Option<PathBuf>,
+
+  /// Address to accept WebSocket connections on. May be repeated.
+  #[arg(long = "ws-listen")]
+  pub ws_listeners: Vec<BindAddr>,
+
+  /// The most events that may be waiting to be sent to a client.
+  #[arg(long)]
+  pub output_max_events: Option<usize>,
+
+  /// The most bytes that may be waiting to be sent to a client.
+  #[arg(long)]
+  pub output_max_bytes: Option<usize>,
+
+  /// Offer MCCP compression to clients.
+  #[arg(long)]
+  pub mccp: Option<bool>,
+
+  /// The most connections a single address may have open, or 0 for no limit.
+  #[arg(long)]
+  pub max_connections_per_ip: Option<usize>,
+
+  /// The most new connections a single address may make in a minute, or 0
+  /// for no limit.
+  #[arg(long)]
+  pub max_connects_per_minute: Option<usize>,
+
+  /// How many lines a client may send per second, or 0 for no limit.
+  #[arg(long)]
+  pub max_lines_per_second: Option<f32>,
+
+  /// Seconds without input before a client is disconnected, or 0 to never
+  /// disconnect idle clients.
+  #[arg(long)]
+  pub idle_timeout: Option<f32>,
+
+  /// Seconds to keep a character in the world after its player disconnects.
+  #[arg(long)]
+  pub link_dead_grace: Option<f32>,
+
+  /// Lines per page of command output for clients that don't report their
+  /// window size, or 0 to never page for them.
+  #[arg(long)]
+  pub page_height: Option<u16>,
+
+  /// Seconds between world saves.
+  #[arg(long)]
+  pub save_interval: Option<f32>,
+
+  #[arg(long)]
+  pub map_init_res_power: Option<u32>,
+
+  #[arg(long)]
+  pub map_extra_resolutions: Option<usize>,
+
+  /// How far away players can see.
+  #[arg(long)]
+  pub map_view_radius: Option<u32>,
+
+  /// The minimum level to log.
+  #[arg(long)]
+  pub log_level: Option<String>,
+
+  /// Extra log filters in the `RUST_LOG` format.
+  #[arg(long)]
+  pub log_filter: Option<String>,
+}
+
+impl ConfigArgs {
+  /// Replace every value in `config` that was given on the command line.
+  pub fn apply(self, config: &mut ServerConfig) -> anyhow::Result<()> {
+    let ConfigArgs {
+      data_dir,
+      db,
+      backups,
+      assets,
+      listeners,
+      port,
+      tls_listeners,
+      tls_cert,
+      tls_key,
+      ws_listeners,
+      output_max_events,
+      output_max_bytes,
+      mccp,
+      max_connections_per_ip,
+      max_connects_per_minute,
+      max_lines_per_second,
+      idle_timeout,
+      link_dead_grace,
+      page_height,
+      save_interval,
+      map_init_res_power,
+      map_extra_resolutions,
+      map_view_radius,
+      log_level,
+      log_filter,
+    } = self;
+    macro_rules! set {
+      ($($arg:ident => $($field:ident).+),* $(,)?) => {
+        $(
+          if let Some(value) = $arg {
+            config.$($field).+ = value;
+          }
+        )*
+      };
+    }
+    set! {
+      data_dir => data_dir,
+      db => db,
+      backups => backups,
+      assets => assets,
+      tls_cert => tls.cert,
+      tls_key => tls.key,
+      output_max_events => output.max_events,
+      output_max_bytes => output.max_bytes,
+      mccp => mccp.enabled,
+      max_connections_per_ip => throttle.max_per_ip,
+      max_connects_per_minute => throttle.connects_per_minute,
+      max_lines_per_second => throttle.lines_per_second,
+      idle_timeout => idle.timeout,
+      link_dead_grace => link_dead.grace,
+      page_height => pager.height,
+      save_interval => save_interval,
+      map_init_res_power => map.init_res_power,
+      map_extra_resolutions => map.extra_resolutions,
+      map_view_radius => map.view_radius,
+      log_level => log.level,
+      log_filter => log.filter,
+    }
+    let addrs = |addrs: Vec<BindAddr>| addrs.into_iter().map(ListenAddr::from).collect();
+    if !listeners.is_empty() {
+      config.listeners = addrs(listeners);
+    }
+    if let Some(port) = port {
+      if config.listeners.is_empty() {
+        config.listeners.push(BindAddr::default().into());
+      }
+      match &mut config.listeners[0].addr {
+        BindAddr::Tcp(addr) => addr.set_port(port),
+        addr => bail!("can't set the port of {addr}"),
+      }
+    }
+    if !tls_listeners.is_empty() {
+      config.tls.listeners = addrs(tls_listeners);
+    }
+    if !ws_listeners.is_empty() {
+      config.websocket.listeners = addrs(ws_listeners);
+    }
+    config.validate()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn toml_and_ron_agree() {
+    let toml: ServerConfig = toml::from_str(
+      r#"
+        data_dir = "/srv/mud"
+        db = "ron://world.ron"
+        listeners = ["127.0.0.1:4000", "[::1]:4000", { addr = "unix:mud.sock", admin = true }]
+
+        [map]
+        view_radius = 12
+
+        [log]
+        level = "debug"
+      "#,
+    )
+    .unwrap();
+    let ron: ServerConfig = ron::from_str(
+      r#"(
+        data_dir: "/srv/mud",
+        db: "ron://world.ron",
+        listeners: ["127.0.0.1:4000", "[::1]:4000", "unix:mud.sock"],
+        map: (view_radius: 12),
+        log: (level: "debug"),
+      )"#,
+    )
+    .unwrap();
+
+    assert_eq!(toml, ron);
+    assert_eq!(toml.map.init_res_power, MapConfig::default().init_res_power);
+    assert_eq!(toml.db_url(), "ron:///srv/mud/world.ron");
+    assert_eq!(toml.assets_dir(), Path::new("/srv/mud/assets"));
+    assert_eq!(toml.tls_config().cert, Path::new("/srv/mud/cert.pem"));
+  }
+
+  #[test]
+  fn args_override_config() {
+    let mut config = ServerConfig::default();
+    ConfigArgs {
+      db: Some("sqlite://other.sqlite".into()),
+      map_view_radius: Some(3),
+      listeners: vec!["127.0.0.1:4000".parse().unwrap()],
+      ..Default::default()
+    }
+    .apply(&mut config)
+    .unwrap();
+
+    assert_eq!(config.db, "sqlite://other.sqlite");
+    assert_eq!(config.map.view_radius, 3);
+    assert_eq!(config.listeners.len(), 1);
+    assert_eq!(config.backups, ServerConfig::default().backups);
+
+    let mut config = ServerConfig::default();
+    ConfigArgs {
+      port: Some(4000),
+      ..Default::default()
+    }
+    .apply(&mut config)
+    .unwrap();
+    assert_eq!(config.listeners[0].addr.port(), Some(4000));
+    let unix = ConfigArgs {
+      listeners: vec!["unix:mud.sock".parse().unwrap()],
+      port: Some(4000),
+      ..Default::default()
+    };
+    assert!(unix.apply(&mut config).is_err());
+
+    let bad = ConfigArgs {
+      log_level: Some("loud".into()),
+      ..Default::default()
+    };
+    assert!(bad.apply(&mut config).is_err());
+  }
+}
@@ -0,0 +1,533 @@
+//! Hot reboots that keep players connected, also known as copyover.
+//!
+//! A copyover saves the world and then replaces the running server with a
+//! fresh copy of its binary. The listening sockets and plain telnet
+//! connections survive the exec, and are described to the new process in a
+//! handoff file named by [HANDOFF_ENV], along with each connection's account
+//! and negotiated options. The file is created fresh in the data directory,
+//! readable only by the server, and deleted as soon as it's read. The new
+//! process binds its listeners from the inherited sockets and picks the
+//! connections back up once the world is loaded, so players only see a
+//! pause.
+//!
+//! TLS and WebSocket connections keep protocol state in the old process, so
+//! they're told to reconnect instead.
+//!
+//! This relies on fds surviving an exec, so it's only available on Unix.
+
+use std::{
+  env,
+  fs::{
+    self,
+    OpenOptions,
+  },
+  io::{
+    self,
+    Write,
+  },
+  net::SocketAddr,
+  os::{
+    fd::{
+      FromRawFd,
+      OwnedFd,
+      RawFd,
+    },
+    unix::{
+      fs::OpenOptionsExt,
+      process::CommandExt,
+    },
+  },
+  path::{
+    Path,
+    PathBuf,
+  },
+  process,
+  time::{
+    Duration,
+    Instant,
+  },
+};
+
+use anyhow::Context;
+use bevy::{
+  ecs::world::Command,
+  prelude::*,
+  utils::HashMap,
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+
+use crate::{
+  account::{
+    ResumeSession,
+    Session,
+    StartLogin,
+  },
+  config::ServerConfig,
+  core::MudStartup,
+  net::{
+    self,
+    mccp,
+    options,
+    ttype,
+    BindAddr,
+    ClientConn,
+    CoreHello,
+    GmcpModules,
+    InheritedListeners,
+    Listener,
+    MccpConfig,
+    OptionChanged,
+    TelnetOptions,
+    TelnetOut,
+    Terminal,
+    Transport,
+    WindowSize,
+  },
+  savestate::{
+    systems::save_now,
+    SaveSet,
+  },
+};
+
+/// Environment variable holding the path of the handoff file, set for the new
+/// process.
+pub const HANDOFF_ENV: &str = "BEVY_MUD_COPYOVER";
+
+/// How long to give clients to see the warning and turn compression off
+/// before handing over.
+const WARNING_DELAY: Duration = Duration::from_millis(500);
+
+/// The longest to wait for queued output to be written before handing over
+/// anyway.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Resource, Debug)]
+struct CopyoverRequest {
+  /// Who to tell if it fails.
+  requested_by: Option<Entity>,
+  /// When to start waiting for output to be written.
+  at: Instant,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Handoff {
+  listeners: Vec<ListenerHandoff>,
+  conns: Vec<ConnHandoff>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ListenerHandoff {
+  addr: BindAddr,
+  transport: Transport,
+  fd: RawFd,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ConnHandoff {
+  fd: RawFd,
+  remote_addr: Option<SocketAddr>,
+  secure: bool,
+  /// The listener it came in on, as an index into [Handoff::listeners].
+  listener: Option<usize>,
+  /// The account name, if logged in. Whether it's an admin isn't handed
+  /// over, but worked out again from the listener.
+  username: Option<String>,
+  options: TelnetOptions,
+  window: Option<WindowSize>,
+  terminal: Option<Terminal>,
+  hello: Option<CoreHello>,
+  modules: Option<GmcpModules>,
+}
+
+/// Connections waiting for the world to load before being resumed.
+#[derive(Resource, Debug)]
+struct PendingConns {
+  listeners: Vec<(BindAddr, Transport)>,
+  conns: Vec<ConnHandoff>,
+}
+
+pub struct CopyoverPlugin;
+
+impl Plugin for CopyoverPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .add_systems(Startup, load_handoff.in_set(MudStartup::System))
+      .add_systems(Startup, resume_conns.after(MudStartup::World))
+      .add_systems(
+        Last,
+        copyover_system
+          .after(SaveSet::Save)
+          .run_if(resource_exists::<CopyoverRequest>),
+      );
+  }
+}
+
+/// Warn everyone and start a copyover once they've had a moment to see it.
+/// `requested_by` is told if it fails.
+pub fn request(world: &mut World, requested_by: Option<Entity>) {
+  if world.contains_resource::<CopyoverRequest>() {
+    tell(world, requested_by, "A copyover is already under way.");
+    return;
+  }
+  info!(?requested_by, "copyover requested");
+
+  let mut conns = world.query::<(&ClientConn, &TelnetOut, &mut TelnetOptions)>();
+  for (conn, out, mut opts) in conns.iter_mut(world) {
+    if conn.socket.is_some() {
+      out.line("Copyover in progress, please wait...");
+      // The compression state can't be handed over, so end it cleanly.
+      opts.disable_local(out, mccp::MCCP2);
+      opts.disable_local(out, mccp::MCCP3);
+    } else {
+      out.line("The server is restarting, please reconnect in a moment.");
+    }
+  }
+  world.insert_resource(CopyoverRequest {
+    requested_by,
+    at: Instant::now() + WARNING_DELAY,
+  });
+}
+
+fn copyover_system(world: &mut World) {
+  let now = Instant::now();
+  let at = world.resource::<CopyoverRequest>().at;
+  if at > now {
+    return;
+  }
+  // Whatever is still queued at the exec is lost, so give it a chance to be
+  // written.
+  if output_pending(world) {
+    if now < at + FLUSH_TIMEOUT {
+      return;
+    }
+    warn!("gave up waiting for output to be written");
+  }
+  let request = world.remove_resource::<CopyoverRequest>().unwrap();
+  // Only comes back if something went wrong.
+  let Err(err) = copyover(world) else {
+    return;
+  };
+
+  error!(?err, "copyover failed");
+  tell(
+    world,
+    request.requested_by,
+    format!("Copyover failed: {err:#}"),
+  );
+  let compression = *world.resource::<MccpConfig>();
+  let mut conns = world.query::<(&ClientConn, &TelnetOut, &mut TelnetOptions)>();
+  for (conn, out, mut opts) in conns.iter_mut(world) {
+    if conn.socket.is_some() {
+      out.line("Copyover aborted.");
+      offer_compression(&mut opts, out, compression);
+    }
+  }
+}
+
+/// Save, hand over and exec. Only returns if that fails.
+fn copyover(world: &mut World) -> anyhow::Result<()> {
+  info!("starting copyover");
+  save_now(world).context("error saving world")?;
+  let (handoff, fds) = extract(world).context("error duplicating sockets")?;
+
+  let path = world
+    .resource::<ServerConfig>()
+    .data_dir
+    .join(format!(".copyover-{}.ron", process::id()));
+  write_handoff(&path, &handoff).with_context(|| format!("error writing {}", path.display()))?;
+  info!(
+    listeners = handoff.listeners.len(),
+    conns = handoff.conns.len(),
+    "handing over"
+  );
+
+  let err = process::Command::new(env::current_exe()?)
+    .args(env::args_os().skip(1))
+    .env(HANDOFF_ENV, &path)
+    .exec();
+  let _ = fs::remove_file(&path);
+  drop(fds);
+  Err(err).context("error starting new server")
+}
+
+/// Write the handoff to a new file that only the server can read. It mustn't
+/// already exist, so that nobody else can have it open or have put a link
+/// there.
+fn write_handoff(path: &Path, handoff: &Handoff) -> anyhow::Result<()> {
+  let data = ron::to_string(handoff)?;
+  let mut file = OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .mode(0o600)
+    .open(path)?;
+  if let Err(err) = file.write_all(data.as_bytes()) {
+    let _ = fs::remove_file(path);
+    return Err(err.into());
+  }
+  Ok(())
+}
+
+/// Describe every socket that can be handed over. Each one is duplicated,
+/// since the duplicates aren't closed on exec while the originals are. They're
+/// closed again when the returned fds are dropped, if the exec fails. Sockets
+/// that have closed since they were opened are left out.
+fn extract(world: &mut World) -> io::Result<(Handoff, Vec<OwnedFd>)> {
+  let mut fds = vec![];
+  let mut dup = |fd: RawFd| {
+    let dup = unsafe { libc::dup(fd) };
+    if dup < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    // Safety: dup just gave us a new fd that nothing else owns.
+    fds.push(unsafe { OwnedFd::from_raw_fd(dup) });
+    Ok(dup)
+  };
+
+  let mut listeners = vec![];
+  let mut indices = HashMap::default();
+  for (entity, listener) in world.query::<(Entity, &Listener)>().iter(world) {
+    let Some(fd) = listener.socket.with_fd(&mut dup).transpose()? else {
+      continue;
+    };
+    indices.insert(entity, listeners.len());
+    listeners.push(ListenerHandoff {
+      addr: listener.addr.clone(),
+      transport: listener.transport,
+      fd,
+    });
+  }
+
+  let mut conns = vec![];
+  let mut query = world.query::<(
+    &ClientConn,
+    &TelnetOptions,
+    Option<&Session>,
+    Option<&WindowSize>,
+    Option<&Terminal>,
+    Option<&CoreHello>,
+    Option<&GmcpModules>,
+  )>();
+  for (conn, options, session, window, terminal, hello, modules) in query.iter(world) {
+    let fd = conn
+      .socket
+      .as_ref()
+      .and_then(|socket| socket.with_fd(&mut dup))
+      .transpose()?;
+    let Some(fd) = fd else {
+      continue;
+    };
+    conns.push(ConnHandoff {
+      fd,
+      remote_addr: conn.remote_addr,
+      secure: conn.secure,
+      listener: indices.get(&conn.listener).copied(),
+      username: session.map(|session| session.username.clone()),
+      options: options.clone(),
+      window: window.copied(),
+      terminal: terminal.cloned(),
+      hello: hello.cloned(),
+      modules: modules.cloned(),
+    });
+  }
+
+  Ok((Handoff { listeners, conns }, fds))
+}
+
+/// Whether any connection has output that hasn't been written yet.
+fn output_pending(world: &mut World) -> bool {
+  world
+    .query::<&TelnetOut>()
+    .iter(world)
+    .any(|out| !out.closed() && out.queue_stats().events > 0)
+}
+
+/// In the new process, read the handoff file if there is one. Listeners are
+/// passed on to be bound, and connections are kept for once the world is
+/// loaded.
+fn load_handoff(world: &mut World) {
+  let Some(path) = env::var_os(HANDOFF_ENV).map(PathBuf::from) else {
+    return;
+  };
+  // Don't pass it on to anything we start.
+  env::remove_var(HANDOFF_ENV);
+  let handoff = fs::read_to_string(&path)
+    .map_err(anyhow::Error::from)
+    .and_then(|data| Ok(ron::from_str::<Handoff>(&data)?));
+  let _ = fs::remove_file(&path);
+  let handoff = match handoff {
+    Ok(handoff) => handoff,
+    Err(err) => {
+      error!(?err, path = %path.display(), "error reading copyover handoff");
+      return;
+    }
+  };
+  info!(
+    listeners = handoff.listeners.len(),
+    conns = handoff.conns.len(),
+    "resuming after copyover"
+  );
+
+  let inherited = handoff
+    .listeners
+    .iter()
+    .map(|l| (l.addr.clone(), l.transport, inherit_fd(l.fd)))
+    .collect();
+  world.insert_resource(InheritedListeners(inherited));
+  world.insert_resource(PendingConns {
+    listeners: handoff
+      .listeners
+      .into_iter()
+      .map(|l| (l.addr, l.transport))
+      .collect(),
+    conns: handoff.conns,
+  });
+}
+
+/// Take ownership of an fd from the handoff, making sure it won't leak into
+/// the next exec.
+fn inherit_fd(fd: RawFd) -> OwnedFd {
+  // Safety: the old process duplicated these for us alone, and nothing else
+  // in this one knows about them.
+  unsafe {
+    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    OwnedFd::from_raw_fd(fd)
+  }
+}
+
+/// Pick up inherited connections now that their characters are loaded.
+fn resume_conns(world: &mut World) {
+  if let Some(leftover) = world.remove_resource::<InheritedListeners>() {
+    for (addr, transport, _) in leftover.0 {
+      warn!(%addr, ?transport, "inherited listener isn't configured anymore, closing it");
+    }
+  }
+  let Some(pending) = world.remove_resource::<PendingConns>() else {
+    return;
+  };
+
+  let listeners = world
+    .query::<(Entity, &Listener)>()
+    .iter(world)
+    .map(|(entity, l)| ((l.addr.clone(), l.transport), entity))
+    .collect::<HashMap<_, _>>();
+  let compression = *world.resource::<MccpConfig>();
+
+  for conn in pending.conns {
+    let fd = inherit_fd(conn.fd);
+    let listener = conn
+      .listener
+      .and_then(|i| pending.listeners.get(i))
+      .and_then(|key| listeners.get(key).copied());
+    let Some(listener) = listener else {
+      warn!(remote_addr = ?conn.remote_addr, "listener for inherited connection is gone, dropping it");
+      continue;
+    };
+    match net::adopt_conn(
+      world,
+      fd,
+      conn.remote_addr,
+      conn.secure,
+      listener,
+      conn.options.clone(),
+    ) {
+      Ok(entity) => resume(world, entity, conn, compression),
+      Err(err) => warn!(?err, remote_addr = ?conn.remote_addr, "error resuming connection"),
+    }
+  }
+}
+
+fn resume(world: &mut World, entity: Entity, conn: ConnHandoff, compression: MccpConfig) {
+  // Replay the options in effect so that whatever depends on them is set up
+  // again. Compression was being turned off, and asking for the terminal type
+  // again would start its cycle over, so the terminal is restored instead.
+  for (side, opt) in conn.options.enabled() {
+    if ![ttype::TTYPE, mccp::MCCP2, mccp::MCCP3].contains(&opt) {
+      world.trigger_targets(
+        OptionChanged {
+          opt,
+          side,
+          enabled: true,
+        },
+        entity,
+      );
+    }
+  }
+  world.flush();
+
+  let out = world.get::<TelnetOut>(entity).unwrap().clone();
+  let mut conn_entity = world.entity_mut(entity);
+  if let Some(size) = conn.window {
+    out.set_width(size.width);
+    conn_entity.insert(size);
+  }
+  if let Some(terminal) = conn.terminal {
+    out.set_colors(terminal.color_level());
+    conn_entity.insert(terminal);
+  }
+  if let Some(hello) = conn.hello {
+    conn_entity.insert(hello);
+  }
+  if let Some(modules) = conn.modules {
+    conn_entity.insert(modules);
+  }
+  let mut opts = conn_entity.get_mut::<TelnetOptions>().unwrap();
+  offer_compression(&mut opts, &out, compression);
+
+  match conn.username {
+    Some(username) => {
+      debug!(?entity, username, "resuming session");
+      out.line("Copyover complete.");
+      ResumeSession {
+        conn: entity,
+        username,
+      }
+      .apply(world);
+    }
+    None => {
+      // They may have been in the middle of typing a password.
+      opts.disable_local(&out, options::ECHO);
+      out.line("The server restarted, please log in again.");
+      StartLogin(entity).apply(world);
+    }
+  }
+}
+
+fn offer_compression(opts: &mut TelnetOptions, out: &TelnetOut, compression: MccpConfig) {
+  if compression.enabled {
+    opts.enable_local(out, mccp::MCCP2);
+    opts.enable_local(out, mccp::MCCP3);
+  }
+}
+
+fn tell(world: &World, entity: Option<Entity>, message: impl AsRef<str>) {
+  if let Some(out) = entity.and_then(|entity| world.get::<TelnetOut>(entity)) {
+    out.line(message);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::os::unix::fs::PermissionsExt;
+
+  use super::*;
+
+  #[test]
+  fn handoff_file_is_private_and_fresh() {
+    let path = env::temp_dir().join(format!("bevy_mud_handoff_{}.ron", process::id()));
+    let _ = fs::remove_file(&path);
+    let handoff = Handoff {
+      listeners: vec![],
+      conns: vec![],
+    };
+
+    write_handoff(&path, &handoff).unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // Never reuse a file that's already there.
+    assert!(write_handoff(&path, &handoff).is_err());
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -28,12 +28,23 @@ use crate::{
   account::AccountPlugin,
   action::ActionPlugin,
   character::CharacterPlugin,
-  command::GameCommandsPlugin,
+  command::{
+    help::HelpPlugin,
+    GameCommandsPlugin,
+  },
+  config::ServerConfig,
+  copyover::CopyoverPlugin,
   framerate::LogFrameRatePlugin,
   map::MapPlugin,
   movement::MovementPlugin,
-  net::TelnetPlugin,
+  net::{
+    ListenAddrs,
+    TelnetPlugin,
+  },
+  prompt::PromptPlugin,
+  prototype::PrototypePlugin,
   savestate::{
+    resources::AssetDir,
     traits::AppWorldExt,
     SaveStatePlugin,
   },
@@ -142,10 +153,34 @@ pub enum MudUpdate {
   Output,
 }
 
-pub struct CorePlugin;
+#[derive(Default)]
+pub struct CorePlugin {
+  config: ServerConfig,
+}
+
+impl CorePlugin {
+  pub fn with_config(config: ServerConfig) -> Self {
+    Self { config }
+  }
+}
 
 impl Plugin for CorePlugin {
   fn build(&self, app: &mut App) {
+    let config = &self.config;
+    app
+      .insert_resource(config.clone())
+      .insert_resource(config.map)
+      .insert_resource(ListenAddrs(config.listeners.clone()))
+      .insert_resource(config.output)
+      .insert_resource(config.mccp)
+      .insert_resource(config.throttle)
+      .insert_resource(config.idle.clone())
+      .insert_resource(config.link_dead)
+      .insert_resource(config.pager)
+      .insert_resource(config.tls_config())
+      .insert_resource(config.websocket.clone())
+      .insert_resource(AssetDir(config.assets_dir()));
+
     app.configure_sets(
       Startup,
       (
@@ -166,27 +201,37 @@ impl Plugin for CorePlugin {
       .register_type::<Live>();
 
     app.add_plugins((
-      LogPlugin::default(),
+      LogPlugin {
+        filter: config.log.filter.clone(),
+        level: config.log.level(),
+      },
       MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
         1.0 / 60.0,
       ))),
       HierarchyPlugin,
       DiagnosticsPlugin,
-      AssetPlugin::default(),
+      AssetPlugin {
+        file_path: config.assets_dir().to_string_lossy().into_owned(),
+        ..default()
+      },
       ScenePlugin,
       SignalPlugin,
       LogFrameRatePlugin::<10>,
     ));
 
     app.add_plugins((
-      SaveStatePlugin::default(),
+      SaveStatePlugin::with_interval(config.save_interval),
       TelnetPlugin,
       CharacterPlugin,
       MapPlugin,
       ActionPlugin,
       AccountPlugin,
       GameCommandsPlugin,
+      HelpPlugin,
       MovementPlugin,
+      PrototypePlugin,
+      PromptPlugin,
+      CopyoverPlugin,
     ));
 
     app.persist::<Live>();
@@ -273,6 +318,10 @@ impl Plugin for LogPlugin {
 
     finished_subscriber = subscriber;
 
-    finished_subscriber.init();
+    // There can only be one, and another app in the same process may have
+    // gotten there first, e.g. in tests.
+    if finished_subscriber.try_init().is_err() {
+      debug!("global tracing subscriber already set, keeping it");
+    }
   }
 }
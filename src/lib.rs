@@ -1,5 +1,10 @@
 #![allow(clippy::type_complexity)]
 
+// Listeners, connections and copyovers are all built on Unix file
+// descriptors.
+#[cfg(not(unix))]
+compile_error!("bevy_mud only supports Unix.");
+
 #[macro_use]
 pub mod macros;
 
@@ -12,15 +17,21 @@ pub mod account;
 pub mod action;
 pub mod character;
 pub mod command;
+pub mod copyover;
 pub mod item;
 pub mod movement;
 pub mod output;
+pub mod prompt;
+pub mod prototype;
 
+pub mod config;
 pub mod coords;
 pub mod core;
 pub mod framerate;
 pub mod oneshot;
 pub mod signal;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 pub mod ascii_map;
 pub mod map;
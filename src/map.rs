@@ -43,16 +43,27 @@ use crate::{
       Style,
     },
   },
-  character::Player,
+  character::{
+    Player,
+    Puppet,
+  },
   core::{
     Live,
     LiveQuery,
     MudStartup,
   },
   net::{
+    color,
+    gmcp,
+    GmcpPackage,
+    MsdpAppExt,
+    MsdpValue,
     TelnetOut,
+    WindowResized,
+    WindowSize,
     GMCP,
   },
+  prompt::PromptAppExt,
   savestate::traits::AppWorldExt,
   util::{
     debug_trigger,
@@ -64,11 +75,14 @@ const MAP_RADIUS: u32 = 9;
 
 pub struct MapPlugin;
 
-#[derive(Resource, Debug, Reflect, Clone, Copy, Eq, PartialEq)]
+#[derive(Resource, Debug, Reflect, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 #[reflect(Resource)]
+#[serde(default)]
 pub struct MapConfig {
   pub init_res_power: u32,
   pub extra_resolutions: usize,
+  /// How far away players can see.
+  pub view_radius: u32,
 }
 
 impl Default for MapConfig {
@@ -76,6 +90,7 @@ impl Default for MapConfig {
     Self {
       init_res_power: 4,
       extra_resolutions: 4,
+      view_radius: MAP_RADIUS,
     }
   }
 }
@@ -129,6 +144,22 @@ impl Plugin for MapPlugin {
 
     app.insert_resource(Maps::default());
 
+    app
+      .msdp_variable::<GlobalTransform>("MAP", |xform| xform.map.as_str().into())
+      .msdp_variable::<GlobalTransform>("COORDS", |xform| {
+        MsdpValue::Table(vec![
+          ("X".into(), xform.coords.x.into()),
+          ("Y".into(), xform.coords.y.into()),
+        ])
+      });
+
+    app
+      .prompt_token::<GlobalTransform>("map", |xform| xform.map.clone())
+      .prompt_token::<GlobalTransform>("coords", |xform| {
+        format!("{},{}", xform.coords.x, xform.coords.y)
+      })
+      .prompt_token::<GlobalTransform>("facing", |xform| compass(xform.facing).into());
+
     app
       .add_systems(
         Startup,
@@ -145,6 +176,7 @@ impl Plugin for MapPlugin {
       .observe(debug_trigger::<RenderRequest>)
       .observe(render_map_system)
       .observe(moved_to_render)
+      .observe(resized_to_render)
       .observe(live_added)
       .observe(live_removed)
       .observe(map_added)
@@ -165,6 +197,21 @@ impl Plugin for MapPlugin {
   }
 }
 
+/// The compass direction that `direction` points in on a flat topped map.
+pub fn compass(direction: EdgeDirection) -> &'static str {
+  [
+    (EdgeDirection::FLAT_NORTH, "north"),
+    (EdgeDirection::FLAT_NORTH_EAST, "northeast"),
+    (EdgeDirection::FLAT_SOUTH_EAST, "southeast"),
+    (EdgeDirection::FLAT_SOUTH, "south"),
+    (EdgeDirection::FLAT_SOUTH_WEST, "southwest"),
+    (EdgeDirection::FLAT_NORTH_WEST, "northwest"),
+  ]
+  .into_iter()
+  .find_map(|(dir, name)| (dir == direction).then_some(name))
+  .unwrap_or("nowhere")
+}
+
 #[derive(Resource, Default)]
 pub struct Maps {
   pub by_name: HashMap<String, Entity>,
@@ -543,6 +590,7 @@ fn propagate_transform(
 
 fn moved_to_render(
   trigger: Trigger<Moved>,
+  cfg: Res<MapConfig>,
   mut cmd: Commands,
   mut targets: Local<EntityHashSet>,
   map_entities: MapEntities,
@@ -558,7 +606,7 @@ fn moved_to_render(
   if rendered.contains(entity) {
     for xform in prev.iter().chain(new) {
       let (_, map) = try_opt!(map_entities.by_name(&xform.map), continue);
-      for coords in xform.coords.spiral_range(0..MAP_RADIUS) {
+      for coords in xform.coords.spiral_range(0..cfg.view_radius) {
         for other in map.by_coords[0]
           .get(&coords)
           .into_iter()
@@ -578,14 +626,51 @@ fn moved_to_render(
   cmd.trigger_targets(RenderRequest, targets.drain().collect::<Vec<Entity>>());
 }
 
+fn resized_to_render(trigger: Trigger<WindowResized>, puppets: Query<&Puppet>, mut cmd: Commands) {
+  if let Ok(puppet) = puppets.get(trigger.entity()) {
+    cmd.trigger_targets(RenderRequest, puppet.0);
+  }
+}
+
 #[derive(Event, Debug)]
 pub struct RenderRequest;
 
+/// The rendered map, zstd compressed and base64 encoded.
+#[derive(Serialize)]
+struct MapUpdate(String);
+
+impl GmcpPackage for MapUpdate {
+  const PACKAGE: &'static str = "map";
+}
+
+/// Used until the client tells us its window size.
+const DEFAULT_MAP_AREA: Rect = Rect {
+  width: 55,
+  height: 23,
+  x: 0,
+  y: 0,
+};
+
+/// The area to render a player's map into. The map takes the full width of the
+/// client's window and the top half of it, leaving the rest for text.
+pub fn map_area(size: Option<&WindowSize>) -> Rect {
+  match size {
+    Some(size) if size.width > 0 && size.height > 1 => Rect {
+      width: size.width,
+      height: size.height / 2,
+      x: 0,
+      y: 0,
+    },
+    _ => DEFAULT_MAP_AREA,
+  }
+}
+
 fn render_map_system(
   trigger: Trigger<RenderRequest>,
+  cfg: Res<MapConfig>,
   map_entities: MapEntities,
   mut puppet_query: LiveQuery<(&GlobalTransform, &Player, &mut MapWidget)>,
-  player_query: Query<&TelnetOut, With<GMCP>>,
+  player_query: Query<(&TelnetOut, Option<&WindowSize>), With<GMCP>>,
   render_query: LiveQuery<&Render>,
 ) {
   debug!(entity = ?trigger.entity(), "got render request");
@@ -594,7 +679,7 @@ fn render_map_system(
     return;
   };
 
-  let Ok(out) = player_query.get(player.0) else {
+  let Ok((out, size)) = player_query.get(player.0) else {
     debug!("player doesn't have gmcp enabled, returning");
     return;
   };
@@ -608,8 +693,9 @@ fn render_map_system(
   widget.clear();
   widget.center(center);
   widget.up_direction(xform.facing);
-  for coord in xform.coords.spiral_range(0..MAP_RADIUS) {
-    if !is_visible(xform, coord, MAP_RADIUS) && coord != center {
+  let radius = cfg.view_radius;
+  for coord in xform.coords.spiral_range(0..radius) {
+    if !is_visible(xform, coord, radius) && coord != center {
       continue;
     }
     let tile = widget.tile(coord);
@@ -629,8 +715,8 @@ fn render_map_system(
       }
     }
   }
-  for (entity, location) in map.find_within(xform.coords, MAP_RADIUS) {
-    if !is_visible(xform, location.coords, MAP_RADIUS) {
+  for (entity, location) in map.find_within(xform.coords, radius) {
+    if !is_visible(xform, location.coords, radius) {
       continue;
     }
 
@@ -652,27 +738,25 @@ fn render_map_system(
     }
   }
   let mut renderer = Ansi::default();
-  let map_area = Rect {
-    width: 55,
-    height: 23,
-    x: 0,
-    y: 0,
-  };
-  renderer.resize(map_area);
-  widget.render(map_area, &mut renderer);
+  let area = map_area(size);
+  renderer.resize(area);
+  widget.render(area, &mut renderer);
   let out = out.clone();
   bevy::tasks::AsyncComputeTaskPool::get()
     .spawn(async move {
+      let rendered = renderer.to_string();
+      let rendered = color::downgrade(&rendered, out.colors());
       let mut compressor = zstd::Encoder::new(Vec::new(), 0).unwrap();
-      write!(compressor, "{}", renderer).unwrap();
+      compressor.write_all(rendered.as_bytes()).unwrap();
       let bytes = compressor.finish().unwrap();
       let encoded = base64::prelude::BASE64_STANDARD.encode(bytes);
-      negotiate!(
-        out,
-        sub,
-        GMCP,
-        format!("map {:?}", encoded).as_bytes().into()
-      );
+      match gmcp::encode(&MapUpdate(encoded)) {
+        // Only the latest map matters if the client is falling behind.
+        Ok(event) => {
+          out.telnet_coalesced("map", event);
+        }
+        Err(error) => warn!(%error, "failed to encode map"),
+      }
     })
     .detach();
 }
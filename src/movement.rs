@@ -364,3 +364,41 @@ fn stop_moving(trigger: Trigger<StopEvent>, mut cmd: Commands) {
   let entity = trigger.entity();
   cmd.entity(entity).remove::<(Busy, Moving, MoveAction)>();
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    character::Puppet,
+    testing::TestMud,
+  };
+
+  #[test]
+  fn turning_and_moving() {
+    let mut mud = TestMud::new();
+    let mut client = mud.connect();
+    mud.create_account(&mut client, "alice", "pw");
+    let conn = mud.session("alice").unwrap();
+    let character = **mud.world().get::<Puppet>(conn).unwrap();
+    mud.world_mut().entity_mut(character).insert((
+      Transform::default(),
+      // Fast enough to not have to wait around.
+      Speed {
+        movement: 600.0,
+        rotation: 600.0,
+      },
+    ));
+
+    client.send_line("right");
+    mud.expect(&mut client, "Adding movement to queue.");
+    mud.expect(&mut client, "You turn to your right.");
+    mud.run_until("turn", |world| {
+      world.get::<Transform>(character).unwrap().facing == EdgeDirection::default().rotate_cw(1)
+    });
+
+    client.send_line("forward");
+    mud.expect(&mut client, "You move forward.");
+    let xform = mud.world().get::<Transform>(character).unwrap();
+    assert_eq!(Hex::default().distance_to(xform.coords), 1);
+  }
+}
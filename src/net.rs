@@ -3,29 +3,48 @@ use std::{
     Debug,
     Write,
   },
+  mem,
   net::SocketAddr,
+  os::fd::{
+    AsRawFd,
+    OwnedFd,
+  },
+  sync::{
+    atomic::{
+      AtomicBool,
+      AtomicU16,
+      AtomicU8,
+      Ordering,
+    },
+    Arc,
+    Mutex,
+  },
+  time::{
+    Duration,
+    Instant,
+  },
 };
 
-use async_std::{
-  net::TcpListener,
-  task::block_on,
-};
+use anyhow::anyhow;
 use bevy::{
-  app::AppExit,
   prelude::*,
   tasks::IoTaskPool,
+  time::common_conditions::on_timer,
 };
 use bytes::BytesMut;
 use futures::{
+  future::{
+    self,
+    Either,
+  },
   prelude::*,
   StreamExt,
 };
-use tellem::{
-  Cmd,
-  Event,
-  KnownOpt,
-  Opt,
+use serde::{
+  Deserialize,
+  Serialize,
 };
+use tellem::Event;
 use tokio::{
   io::{
     AsyncRead,
@@ -36,12 +55,10 @@ use tokio::{
     self,
     error::TryRecvError,
     UnboundedReceiver,
-    UnboundedSender,
   },
 };
-use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_util::{
-  codec::Decoder,
+  codec::Framed,
   compat::FuturesAsyncReadCompatExt,
 };
 use tracing::{
@@ -50,25 +67,127 @@ use tracing::{
   Instrument,
 };
 
+pub use self::{
+  ban::{
+    BanEntry,
+    BanList,
+    BanTarget,
+  },
+  color::ColorLevel,
+  gmcp::{
+    CoreHello,
+    GmcpIn,
+    GmcpModules,
+    GmcpOut,
+    GmcpPackage,
+    GMCP,
+  },
+  idle::IdleConfig,
+  listen::{
+    BindAddr,
+    ListenAddr,
+    SocketHandle,
+  },
+  loopback::{
+    LoopbackClient,
+    LoopbackListener,
+  },
+  mccp::{
+    Mccp,
+    MccpConfig,
+  },
+  msdp::{
+    MsdpAppExt,
+    MsdpValue,
+  },
+  mssp::StartTime,
+  naws::{
+    WindowResized,
+    WindowSize,
+  },
+  options::{
+    OptionChanged,
+    Side,
+    TelnetOptions,
+  },
+  queue::{
+    OutputLimits,
+    QueueStats,
+  },
+  throttle::ThrottleConfig,
+  tls::TlsConfig,
+  ttype::{
+    Mtts,
+    Terminal,
+  },
+  websocket::WebSocketConfig,
+};
+use self::{
+  listen::{
+    conn_from_fd,
+    Bound,
+    Conn,
+  },
+  mccp::{
+    Mccp3Agreed,
+    MccpCodec,
+  },
+  queue::{
+    output_queue,
+    OutputSender,
+  },
+  throttle::{
+    Admitted,
+    ConnectionGate,
+    FloodLimiter,
+    LineBuffer,
+    Rejected,
+  },
+};
 use crate::{
   core::MudStartup,
-  oneshot::run_system,
   util::HierEntity,
 };
 
+pub mod ban;
+pub mod color;
+pub mod eor;
+pub mod gmcp;
+pub mod idle;
+pub mod listen;
+pub mod loopback;
+pub mod mccp;
+pub mod msdp;
+pub mod mssp;
+pub mod naws;
+pub mod options;
+pub mod queue;
+pub mod throttle;
+pub mod tls;
+pub mod ttype;
+pub mod websocket;
+
+/// Send a telnet command.
+///
+/// The three-argument form sends a raw option negotiation, and is only kept
+/// for compatibility. Negotiations sent this way aren't tracked, so use
+/// [TelnetOptions] instead.
 #[macro_export]
 macro_rules! command {
   ($out:expr, $cmd:tt) => {
     $out.telnet(tellem::Event::Cmd(tellem::Cmd::$cmd))
   };
   ($out:expr, $cmd:tt, $opt:tt) => {
-    $out.telnet(tellem::Event::Negotiation(
-      tellem::Cmd::$cmd,
-      tellem::Opt::Known(tellem::KnownOpt::$opt),
-    ))
+    $crate::negotiate!($out, $cmd, $opt)
   };
 }
 
+/// Send a raw option negotiation or subnegotiation.
+///
+/// Negotiations sent this way bypass [TelnetOptions], so it can't keep track
+/// of what the client agreed to. Use its `enable_*`/`disable_*` methods for
+/// negotiations and [TelnetOut::telnet] for subnegotiations instead.
+#[deprecated(note = "use TelnetOptions to negotiate, or TelnetOut::telnet for subnegotiations")]
 #[macro_export]
 macro_rules! negotiate {
   ($out:expr, sub, $opt:tt, $data:expr) => {
@@ -95,12 +214,54 @@ impl Plugin for TelnetPlugin {
       .register_type::<TelnetOut>()
       .register_type::<Listener>()
       .register_type::<ClientConn>()
-      .add_systems(Startup, start_listener.in_set(MudStartup::Io))
+      .register_type::<Inherited>()
+      .register_type::<Mccp>()
+      .register_type::<WindowSize>()
+      .register_type::<GMCP>()
+      .register_type::<idle::Idle>()
+      .register_type::<BanList>()
+      .register_type::<BanEntry>()
+      .init_resource::<BanList>()
+      .init_resource::<ThrottleConfig>()
+      .init_resource::<IdleConfig>()
+      .init_resource::<ConnectionGate>()
+      .init_resource::<ListenAddrs>()
+      .init_resource::<OutputLimits>()
+      .init_resource::<MccpConfig>()
+      .init_resource::<TlsConfig>()
+      .init_resource::<WebSocketConfig>()
+      .init_resource::<StartTime>()
+      .init_resource::<msdp::MsdpVariables>()
+      .add_systems(
+        Startup,
+        (spawn_listeners, throttle::sync_gate, bind_listeners)
+          .chain()
+          .in_set(MudStartup::Io),
+      )
+      .add_systems(First, bind_listeners.run_if(on_timer(REBIND_INTERVAL)))
+      .add_systems(First, throttle::sync_gate.before(new_conns))
       .add_systems(First, new_conns)
       .add_systems(First, telnet_handler)
+      .add_systems(
+        Update,
+        idle::idle_system.run_if(on_timer(idle::CHECK_INTERVAL)),
+      )
       .add_systems(Last, reap_conns)
       .add_systems(Last, print_reaped_conns.after(reap_conns))
-      .observe(gmcp_observer);
+      .observe(options::options_observer)
+      .observe(eor::eor_option_observer)
+      .observe(gmcp::gmcp_option_observer)
+      .observe(gmcp::gmcp_observer)
+      .observe(gmcp::gmcp_core_observer)
+      .observe(mccp::mccp_option_observer)
+      .observe(mccp::mccp_observer)
+      .observe(naws::naws_option_observer)
+      .observe(naws::naws_observer)
+      .observe(ttype::ttype_option_observer)
+      .observe(ttype::ttype_observer)
+      .observe(msdp::msdp_option_observer)
+      .observe(msdp::msdp_observer)
+      .observe(mssp::mssp_observer);
   }
 }
 
@@ -110,8 +271,24 @@ pub struct TelnetEvent(Event);
 #[derive(Deref, Event, Debug, Clone)]
 pub struct LineEvent(String);
 
-#[derive(Resource, Debug, Copy, Clone)]
-pub struct PortArg(pub u32);
+/// Addresses to accept telnet connections on.
+#[derive(Resource, Debug, Clone, Deref)]
+pub struct ListenAddrs(pub Vec<ListenAddr>);
+
+impl Default for ListenAddrs {
+  fn default() -> Self {
+    ListenAddrs(vec![BindAddr::default().into()])
+  }
+}
+
+pub use crate::config::DEFAULT_PORT;
+
+/// How long to wait before trying to bind listeners that failed or closed
+/// again.
+const REBIND_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a TLS or WebSocket client gets to finish its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Component, Debug, Reflect)]
 #[reflect(from_reflect = false)]
@@ -120,28 +297,79 @@ struct NewConns {
   channel: UnboundedReceiver<ClientBundle>,
 }
 
-#[derive(Component, Reflect)]
+/// A socket accepting connections, each of which is spawned as its child.
+#[derive(Component, Debug, Clone, Default, Reflect)]
 #[reflect(Component)]
-struct Listener {
-  #[allow(dead_code)]
-  port: u32,
+pub struct Listener {
+  #[reflect(ignore)]
+  pub addr: BindAddr,
+  pub transport: Transport,
+  /// Whether players connected through this listener may log in as admins.
+  pub admin: bool,
+  /// The bound socket, which is open while the listener is running.
+  #[reflect(ignore)]
+  pub socket: SocketHandle,
 }
 
-impl Default for Listener {
-  fn default() -> Self {
-    Listener { port: 23840 }
+/// How clients talk to a listener.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Reflect, Serialize, Deserialize)]
+pub enum Transport {
+  /// Plain telnet.
+  #[default]
+  Telnet,
+  /// Telnet over TLS.
+  Tls,
+  /// JSON frames over a WebSocket, for browsers.
+  WebSocket,
+  /// In-process connections made through a [LoopbackListener].
+  Loopback,
+}
+
+/// Listening sockets inherited from the process before a copyover, used in
+/// place of binding new ones.
+#[derive(Resource, Debug, Default)]
+pub struct InheritedListeners(pub Vec<(BindAddr, Transport, OwnedFd)>);
+
+impl InheritedListeners {
+  fn take(&mut self, addr: &BindAddr, transport: Transport) -> Option<OwnedFd> {
+    let i = self
+      .0
+      .iter()
+      .position(|(a, t, _)| a == addr && *t == transport)?;
+    Some(self.0.swap_remove(i).2)
   }
 }
 
-fn start_tcp(port: u32) -> anyhow::Result<UnboundedReceiver<ClientBundle>> {
-  let l = block_on(TcpListener::bind(format!("0.0.0.0:{port}")))?;
-  info!(port, "started tcp listener");
+/// Per-connection settings, copied into each new connection.
+#[derive(Debug, Clone, Copy)]
+struct ConnSettings {
+  limits: OutputLimits,
+  throttle: ThrottleConfig,
+}
+
+fn start_telnet(
+  l: Bound,
+  settings: ConnSettings,
+  gate: ConnectionGate,
+) -> UnboundedReceiver<ClientBundle> {
   let (new_tx, new_rx) = mpsc::unbounded_channel();
 
   IoTaskPool::get()
     .spawn(async move {
       while let Ok((conn, addr)) = l.accept().await {
-        if new_tx.send(handle_conn(conn.compat(), addr)?).is_err() {
+        let admitted = match gate.admit(addr) {
+          Ok(admitted) => admitted,
+          Err(rejected) => {
+            reject(conn, addr, rejected);
+            continue;
+          }
+        };
+        let socket = SocketHandle::new(conn.as_raw_fd());
+        let conn = socket.track(conn);
+        let mut bundle = handle_conn(conn.compat(), addr, settings, false)?;
+        bundle.conn.socket = Some(socket);
+        bundle.conn.admitted = admitted;
+        if new_tx.send(bundle).is_err() {
           break;
         }
       }
@@ -149,24 +377,107 @@ fn start_tcp(port: u32) -> anyhow::Result<UnboundedReceiver<ClientBundle>> {
     })
     .detach();
 
-  Ok(new_rx)
+  new_rx
 }
 
-fn start_listener(arg: Res<PortArg>, mut cmd: Commands, mut exit: EventWriter<AppExit>) {
-  let port = arg.0;
+/// Tell a telnet client why it was turned away and hang up, without holding
+/// up the listener.
+///
+/// TLS and WebSocket connections are closed without a message: the client
+/// can't read anything until its handshake is done, and doing the handshake
+/// for a connection we're turning away is the work the gate is there to save.
+fn reject(mut conn: Conn, addr: Option<SocketAddr>, rejected: Rejected) {
+  info!(remote_addr = ?addr, %rejected, "rejecting connection");
+  IoTaskPool::get()
+    .spawn(async move {
+      let _ = conn.write_all(format!("{rejected}\r\n").as_bytes()).await;
+      let _ = conn.close().await;
+    })
+    .detach();
+}
 
-  let res = start_tcp(port);
-  let l = match res {
-    Ok(l) => l,
-    Err(err) => {
-      warn!(?err, "failed to start tcp listener, exiting.");
-      exit.send(AppExit::Error(1u8.try_into().unwrap()));
-      return;
-    }
+fn spawn_listeners(
+  addrs: Res<ListenAddrs>,
+  tls: Res<TlsConfig>,
+  websocket: Res<WebSocketConfig>,
+  mut cmd: Commands,
+) {
+  let telnet = addrs.iter().map(|addr| (Transport::Telnet, addr));
+  let tls = tls.listeners.iter().map(|addr| (Transport::Tls, addr));
+  let ws = websocket
+    .listeners
+    .iter()
+    .map(|addr| (Transport::WebSocket, addr));
+
+  for (transport, listen) in telnet.chain(tls).chain(ws) {
+    let listener_id = cmd
+      .spawn(Listener {
+        addr: listen.addr.clone(),
+        transport,
+        admin: listen.admin,
+        socket: default(),
+      })
+      .id();
+    debug!(?listener_id, addr = %listen.addr, ?transport, "spawning listener");
+  }
+}
+
+/// Start every listener that isn't already running. Ones that fail are left
+/// for the next try.
+fn bind_listeners(
+  mut query: Query<(Entity, &mut Listener), Without<NewConns>>,
+  mut inherited: Option<ResMut<InheritedListeners>>,
+  limits: Res<OutputLimits>,
+  throttle: Res<ThrottleConfig>,
+  tls: Res<TlsConfig>,
+  gate: Res<ConnectionGate>,
+  mut cmd: Commands,
+) {
+  let settings = ConnSettings {
+    limits: *limits,
+    throttle: *throttle,
   };
-  debug!("adding listener spawn command");
-  let listener_id = cmd.spawn((Listener { port }, NewConns { channel: l })).id();
-  debug!(?listener_id, "spawning listener");
+  let mut acceptor = None;
+
+  for (listener_id, mut listener) in query.iter_mut() {
+    let Listener {
+      addr, transport, ..
+    } = &*listener;
+    // These aren't bound to anything, and only get connections through
+    // their handle.
+    if *transport == Transport::Loopback {
+      continue;
+    }
+    let bound = match inherited
+      .as_mut()
+      .and_then(|inherited| inherited.take(addr, *transport))
+    {
+      Some(fd) => Bound::from_fd(addr, fd),
+      None => Bound::bind(addr),
+    };
+    let socket = bound.as_ref().map(Bound::handle).unwrap_or_default();
+    let started = bound.map_err(anyhow::Error::from).and_then(|l| {
+      Ok(match transport {
+        Transport::Telnet => start_telnet(l, settings, gate.clone()),
+        Transport::Tls => match acceptor.get_or_insert_with(|| tls.acceptor()) {
+          Ok(acceptor) => tls::start_tls(l, acceptor.clone(), settings, gate.clone()),
+          Err(err) => return Err(anyhow!("failed to load tls certificate: {err:#}")),
+        },
+        Transport::WebSocket => websocket::start_websocket(l, settings, gate.clone()),
+        Transport::Loopback => return Err(anyhow!("loopback listeners can't be bound")),
+      })
+    });
+    match started {
+      Ok(channel) => {
+        info!(%addr, ?transport, "started listener");
+        cmd.entity(listener_id).insert(NewConns { channel });
+        listener.socket = socket;
+      }
+      Err(err) => {
+        warn!(?err, %addr, ?transport, retry = ?REBIND_INTERVAL, "failed to start listener");
+      }
+    }
+  }
 }
 
 #[derive(Component, Reflect)]
@@ -177,6 +488,9 @@ pub struct TelnetIn {
   #[reflect(ignore)]
   peek: Option<tellem::Event>,
   closed: bool,
+  /// When the client last sent a line.
+  #[reflect(ignore)]
+  last_input: Instant,
 }
 
 #[derive(Copy, Clone, Debug, Reflect, Default)]
@@ -188,6 +502,7 @@ impl TelnetIn {
       channel,
       peek: None,
       closed: false,
+      last_input: Instant::now(),
     }
   }
   fn update(&mut self) {
@@ -195,7 +510,12 @@ impl TelnetIn {
       return;
     }
     match self.channel.try_recv() {
-      Ok(v) => self.peek = Some(v),
+      Ok(v) => {
+        if let tellem::Event::Data(_) = v {
+          self.last_input = Instant::now();
+        }
+        self.peek = Some(v);
+      }
       Err(TryRecvError::Disconnected) => self.closed = true,
       _ => (),
     }
@@ -205,6 +525,11 @@ impl TelnetIn {
     self.closed && self.peek.is_none()
   }
 
+  /// How long it's been since the client sent a line.
+  pub fn idle(&self) -> Duration {
+    self.last_input.elapsed()
+  }
+
   pub fn next<F, T>(&mut self, f: F) -> Option<T>
   where
     F: FnOnce(tellem::Event) -> Result<T, tellem::Event>,
@@ -248,19 +573,167 @@ impl TelnetIn {
 #[reflect(from_reflect = false)]
 pub struct TelnetOut {
   #[reflect(ignore)]
-  channel: UnboundedSender<tellem::Event>,
+  queue: Arc<OutputSender>,
+  /// Column to wrap lines at, or 0 to leave them alone.
+  #[reflect(ignore)]
+  width: Arc<AtomicU16>,
+  /// The [ColorLevel] that styling is downgraded to.
+  #[reflect(ignore)]
+  colors: Arc<AtomicU8>,
+  /// Whether the client agreed to EOR, which then marks the end of prompts
+  /// instead of GA.
+  #[reflect(ignore)]
+  eor: Arc<AtomicBool>,
+  /// Whether any text has been sent since the last prompt.
+  #[reflect(ignore)]
+  dirty: Arc<AtomicBool>,
+  /// Text held back by [TelnetOut::capture].
+  #[reflect(ignore)]
+  captured: Arc<Mutex<Option<BytesMut>>>,
 }
 
 impl TelnetOut {
-  fn new(channel: UnboundedSender<tellem::Event>) -> Self {
-    Self { channel }
+  fn new(queue: OutputSender) -> Self {
+    Self {
+      queue: Arc::new(queue),
+      width: default(),
+      colors: Arc::new(AtomicU8::new(ColorLevel::default() as u8)),
+      eor: default(),
+      dirty: default(),
+      captured: default(),
+    }
   }
 
   pub fn telnet(&self, event: tellem::Event) -> &Self {
-    let _ = self.channel.send(event);
+    self.flush_captured();
+    self.queue.push(event);
+    self
+  }
+
+  /// Send an event that replaces any earlier one with the same `key` that
+  /// hasn't been written yet. Used for things like map updates, where only the
+  /// latest one matters and the rest can be dropped if the client falls
+  /// behind.
+  pub fn telnet_coalesced(&self, key: &'static str, event: tellem::Event) -> &Self {
+    self.flush_captured();
+    self.queue.push_coalesced(key, event);
+    self
+  }
+
+  /// Wrap lines sent with [TelnetOut::line] at `width` columns. Zero turns
+  /// wrapping off.
+  pub fn set_width(&self, width: u16) {
+    self.width.store(width, Ordering::Relaxed);
+  }
+
+  pub fn width(&self) -> u16 {
+    self.width.load(Ordering::Relaxed)
+  }
+
+  /// Send a GMCP message, whether or not the client has subscribed to its
+  /// module.
+  pub fn gmcp<T: GmcpPackage>(&self, message: &T) -> &Self {
+    match gmcp::encode(message) {
+      Ok(event) => {
+        self.telnet(event);
+      }
+      Err(error) => warn!(%error, package = T::PACKAGE, "failed to encode GMCP message"),
+    }
     self
   }
 
+  /// Downgrade styling in lines and strings to `level`.
+  pub fn set_colors(&self, level: ColorLevel) {
+    self.colors.store(level as u8, Ordering::Relaxed);
+  }
+
+  pub fn colors(&self) -> ColorLevel {
+    ColorLevel::from_u8(self.colors.load(Ordering::Relaxed))
+  }
+
+  /// End prompts with EOR rather than GA.
+  pub fn set_eor(&self, eor: bool) {
+    self.eor.store(eor, Ordering::Relaxed);
+  }
+
+  pub fn eor(&self) -> bool {
+    self.eor.load(Ordering::Relaxed)
+  }
+
+  /// Whether any text has been sent since the last [TelnetOut::prompt].
+  pub fn needs_prompt(&self) -> bool {
+    self.dirty.load(Ordering::Relaxed)
+  }
+
+  /// Send a prompt, which isn't followed by a newline, and mark the end of
+  /// it so that the client knows to show it.
+  pub fn prompt(&self, s: impl AsRef<str>) -> &Self {
+    if self.closed() {
+      return self;
+    }
+
+    let s = color::downgrade(s.as_ref(), self.colors());
+    self.telnet(tellem::Event::Data(TelnetOut::normalize_string(s)));
+    if self.eor() {
+      command!(self, EOR);
+    } else {
+      command!(self, GA);
+    }
+    self.dirty.store(false, Ordering::Relaxed);
+    self
+  }
+
+  /// Draw the prompt again at the end of the frame, even if nothing's been
+  /// sent.
+  pub fn reprompt(&self) {
+    self.dirty.store(true, Ordering::Relaxed);
+  }
+
+  /// Hold back text sent with [TelnetOut::line] and [TelnetOut::string]
+  /// instead of sending it, until it's released. Everything else is still
+  /// sent right away, after the text held back so far so that the client
+  /// sees it all in order.
+  pub fn capture(&self) {
+    *self.captured.lock().unwrap() = Some(BytesMut::new());
+  }
+
+  /// Stop capturing text, and return what was held back since the last
+  /// frame that wasn't.
+  pub fn release(&self) -> Option<BytesMut> {
+    self.captured.lock().unwrap().take()
+  }
+
+  /// Send the text held back so far, ahead of a frame that isn't captured.
+  fn flush_captured(&self) {
+    let mut captured = self.captured.lock().unwrap();
+    if let Some(text) = captured.as_mut().filter(|text| !text.is_empty()) {
+      self.queue.push(tellem::Event::Data(text.split()));
+    }
+  }
+
+  /// Send text that's already been formatted for this client, like the text
+  /// from [TelnetOut::release].
+  pub fn formatted(&self, data: BytesMut) -> &Self {
+    if self.closed() {
+      return self;
+    }
+    self.send_text(data)
+  }
+
+  fn send_text(&self, data: BytesMut) -> &Self {
+    self.dirty.store(true, Ordering::Relaxed);
+    if let Some(captured) = &mut *self.captured.lock().unwrap() {
+      captured.extend_from_slice(&data);
+      return self;
+    }
+    self.telnet(tellem::Event::Data(data))
+  }
+
+  /// The current state of the output queue.
+  pub fn queue_stats(&self) -> QueueStats {
+    self.queue.stats()
+  }
+
   fn normalize_string(s: impl AsRef<str>) -> BytesMut {
     let mut s: &str = s.as_ref();
     let mut data = BytesMut::with_capacity(s.len());
@@ -295,13 +768,22 @@ impl TelnetOut {
       return self;
     }
 
-    let mut data = TelnetOut::normalize_string(s);
+    let s = color::downgrade(s.as_ref(), self.colors());
+    let mut data = match self.width() {
+      0 => TelnetOut::normalize_string(s),
+      width => TelnetOut::normalize_string(
+        s.split('\n')
+          .map(|l| naws::wrap(l.trim_end_matches('\r'), width.into()))
+          .collect::<Vec<_>>()
+          .join("\n"),
+      ),
+    };
 
     if !matches!(data.last(), Some(b'\n')) {
       data.extend_from_slice("\r\n".as_bytes());
     }
 
-    self.telnet(tellem::Event::Data(data))
+    self.send_text(data)
   }
 
   pub fn string(&self, s: impl AsRef<str>) -> &Self {
@@ -309,11 +791,17 @@ impl TelnetOut {
       return self;
     }
 
-    self.telnet(tellem::Event::Data(TelnetOut::normalize_string(s)))
+    let s = color::downgrade(s.as_ref(), self.colors());
+    self.send_text(TelnetOut::normalize_string(s))
   }
 
   pub fn closed(&self) -> bool {
-    self.channel.is_closed()
+    self.queue.is_closed()
+  }
+
+  /// Disconnect the client once everything already sent has been written.
+  pub fn close(&self) {
+    self.queue.close();
   }
 }
 
@@ -327,52 +815,189 @@ impl<'a> Write for &'a TelnetOut {
 #[derive(Component, Reflect)]
 #[reflect(from_reflect = false)]
 pub struct ClientConn {
+  /// The client's address, or `None` for local connections over a Unix
+  /// socket.
+  #[reflect(ignore)]
+  pub remote_addr: Option<SocketAddr>,
+  /// Whether the connection is encrypted.
+  pub secure: bool,
+  /// Whether the connection is from this machine, over a Unix socket or a
+  /// loopback client.
+  pub local: bool,
+  /// The [Listener] the connection came in on.
+  pub listener: Entity,
+  /// The socket, for plain telnet connections that a copyover can hand
+  /// over.
+  #[reflect(ignore)]
+  pub socket: Option<SocketHandle>,
+  /// Counts the connection against its address's limit for as long as it's
+  /// open.
   #[reflect(ignore)]
-  pub remote_addr: SocketAddr,
+  admitted: Option<Admitted>,
 }
 
+/// Marks connections inherited from the process before a copyover, which
+/// pick up where they left off rather than starting fresh.
+#[derive(Component, Debug, Default, Clone, Copy, Reflect)]
+#[reflect(Component)]
+pub struct Inherited;
+
 #[derive(Bundle)]
 struct ClientBundle {
   conn: ClientConn,
   input: TelnetIn,
   output: TelnetOut,
+  options: TelnetOptions,
+  idle: idle::Idle,
+  mccp3: Mccp3Agreed,
+}
+
+impl ClientBundle {
+  /// Offer the options we support to a new client.
+  fn offer_options(&mut self, compression: &MccpConfig) {
+    let out = &self.output;
+    let opts = &mut self.options;
+    opts.enable_remote(out, naws::NAWS);
+    opts.enable_remote(out, ttype::TTYPE);
+    opts.enable_local(out, options::GMCP);
+    opts.enable_local(out, eor::EOR);
+    opts.enable_local(out, mssp::MSSP);
+    opts.enable_local(out, msdp::MSDP);
+    if compression.enabled {
+      opts.enable_local(out, mccp::MCCP2);
+      opts.enable_local(out, mccp::MCCP3);
+    }
+  }
+}
+
+/// Start handling a connection inherited from the process before a
+/// copyover, whose options were already negotiated. It's spawned under
+/// `listener`.
+pub fn adopt_conn(
+  world: &mut World,
+  fd: OwnedFd,
+  remote_addr: Option<SocketAddr>,
+  secure: bool,
+  listener: Entity,
+  options: TelnetOptions,
+) -> anyhow::Result<Entity> {
+  let settings = ConnSettings {
+    limits: *world.resource::<OutputLimits>(),
+    throttle: *world.resource::<ThrottleConfig>(),
+  };
+  let socket = SocketHandle::new(fd.as_raw_fd());
+  let conn = socket.track(conn_from_fd(fd, remote_addr)?);
+  let mut bundle = handle_conn(conn.compat(), remote_addr, settings, secure)?;
+  bundle.conn.socket = Some(socket);
+  bundle.conn.admitted = world.resource::<ConnectionGate>().track(remote_addr);
+  bundle.conn.listener = listener;
+  bundle.mccp3.set(options.local_enabled(mccp::MCCP3));
+  bundle.options = options;
+
+  let entity = world.spawn((bundle, Inherited)).set_parent(listener).id();
+  Ok(entity)
 }
 
-fn handle_conn<C>(conn: C, remote_addr: SocketAddr) -> anyhow::Result<ClientBundle>
+fn handle_conn<C>(
+  conn: C,
+  remote_addr: Option<SocketAddr>,
+  settings: ConnSettings,
+  secure: bool,
+) -> anyhow::Result<ClientBundle>
 where
   C: AsyncRead + AsyncWrite + Send + 'static,
+{
+  let mccp3 = Mccp3Agreed::default();
+  let codec = MccpCodec::new(mccp3.clone());
+  let (twrite, tread) = Framed::new(BufStream::new(conn), codec).split();
+  let mut bundle = handle_events(tread, twrite, remote_addr, settings, secure)?;
+  bundle.mccp3 = mccp3;
+  Ok(bundle)
+}
+
+/// Like [handle_conn], but for transports that have already been turned into
+/// telnet events.
+fn handle_events<R, W, E>(
+  tread: R,
+  twrite: W,
+  remote_addr: Option<SocketAddr>,
+  settings: ConnSettings,
+  secure: bool,
+) -> anyhow::Result<ClientBundle>
+where
+  R: Stream<Item = Result<Event, E>> + Send + Unpin + 'static,
+  W: Sink<Event> + Send + Unpin + 'static,
+  W::Error: std::error::Error + Send + Sync + 'static,
+  E: Debug + Send,
 {
   let (read_tx, read_rx) = mpsc::unbounded_channel();
-  let (write_tx, write_rx) = mpsc::unbounded_channel();
+  let (write_tx, mut write_rx, shutdown) = output_queue(settings.limits);
 
-  let bundle = ClientBundle {
-    conn: ClientConn { remote_addr },
+  let mut bundle = ClientBundle {
+    conn: ClientConn {
+      remote_addr,
+      secure,
+      local: remote_addr.is_none(),
+      // Filled in once the connection is spawned.
+      listener: Entity::PLACEHOLDER,
+      socket: None,
+      admitted: None,
+    },
     input: TelnetIn::new(read_rx),
-    output: TelnetOut::new(write_tx.clone()),
+    output: TelnetOut::new(write_tx),
+    options: TelnetOptions::default(),
+    idle: default(),
+    mccp3: default(),
   };
 
-  let _span = info_span!("client", ?remote_addr).entered();
+  let _span = info_span!("client", ?remote_addr, secure).entered();
 
   info!("got new connection");
 
-  let (twrite, tread) = tellem::Parser::default()
-    .framed(BufStream::new(conn))
-    .split();
+  // Weak so that the reader doesn't keep the connection open by itself.
+  let notices = Arc::downgrade(&bundle.output.queue);
+  let notice = move |message: &str| {
+    if let Some(queue) = notices.upgrade() {
+      queue.push(Event::Data(TelnetOut::normalize_string(message)));
+    }
+  };
+  let throttle = settings.throttle;
 
   IoTaskPool::get()
     .spawn(
       async move {
         let mut tread = tread;
-        let mut buffer = BytesMut::new();
-        'read: while let Some(res) = tread.next().await {
+        let mut shutdown = shutdown;
+        let mut lines = LineBuffer::new(throttle.max_line_length);
+        let mut flood = FloodLimiter::new(
+          throttle.lines_per_second,
+          throttle.line_burst,
+          Instant::now(),
+        );
+        let mut flooding = false;
+        'read: loop {
+          // Stop reading once the output side is gone, since that's where
+          // slow clients get disconnected.
+          let res = match future::select(tread.next(), &mut shutdown).await {
+            Either::Left((Some(res), _)) => res,
+            Either::Left((None, _)) | Either::Right(_) => break,
+          };
           let event = match res {
             Ok(Event::Data(data)) => {
-              buffer.extend_from_slice(&data);
-              while let Some(i) = buffer.iter().position(|b| *b == b'\n') {
-                let mut line = buffer.split_to(i + 1);
-                while let Some(b'\r' | b'\n') = line.last() {
-                  line.truncate(line.len() - 1);
+              for line in lines.push(&data) {
+                let Ok(line) = line else {
+                  notice("Line too long, ignored.\n");
+                  continue;
+                };
+                if !flood.allow(Instant::now()) {
+                  // Only say so once per flood.
+                  if !mem::replace(&mut flooding, true) {
+                    debug!("client is flooding, dropping input");
+                    notice("You're sending too fast, input is being dropped.\n");
+                  }
+                  continue;
                 }
+                flooding = false;
                 if read_tx.send(Event::Data(line)).is_err() {
                   break 'read;
                 }
@@ -399,8 +1024,19 @@ where
     .spawn(
       async move {
         let mut twrite = twrite;
-        let write_rx = UnboundedReceiverStream::new(write_rx);
-        write_rx.map(Ok).forward(&mut twrite).await?;
+        while let Some(event) = write_rx.next().await {
+          twrite.feed(event).await?;
+          if write_rx.is_empty() {
+            twrite.flush().await?;
+          }
+        }
+        match write_rx.close_reason() {
+          Some(reason @ queue::CloseReason::Overflow) => {
+            let stats = write_rx.stats();
+            warn!(%reason, dropped = stats.dropped, "disconnecting slow client");
+          }
+          reason => debug!(?reason, "client output closed"),
+        }
         twrite.flush().await?;
         twrite.close().await?;
         Ok::<(), anyhow::Error>(())
@@ -412,21 +1048,25 @@ where
 }
 
 #[instrument(skip_all)]
-fn new_conns(mut cmd: Commands, mut query: Query<(Entity, &mut NewConns, Option<&Children>)>) {
-  for (listener_id, mut conns, children) in query.iter_mut() {
-    let bundle = match conns.channel.try_recv() {
+fn new_conns(
+  mut cmd: Commands,
+  mut query: Query<(Entity, &mut NewConns), With<Listener>>,
+  mccp: Res<MccpConfig>,
+) {
+  for (listener_id, mut conns) in query.iter_mut() {
+    let mut bundle = match conns.channel.try_recv() {
       Ok(v) => v,
       Err(TryRecvError::Empty) => continue,
       Err(TryRecvError::Disconnected) => {
-        warn!("listener closed, restarting it");
-        for child in children.iter().flat_map(|c| c.iter()) {
-          cmd.entity(*child).remove_parent();
-        }
-        cmd.entity(listener_id).despawn();
-        cmd.queue(run_system(start_listener));
+        // Its connections stay put, and it gets bound again along with any
+        // others that are down.
+        warn!(?listener_id, "listener closed, restarting it");
+        cmd.entity(listener_id).remove::<NewConns>();
         continue;
       }
     };
+    bundle.offer_options(&mccp);
+    bundle.conn.listener = listener_id;
 
     let entity_id = cmd.spawn(bundle).set_parent(listener_id).id();
 
@@ -450,10 +1090,6 @@ fn print_reaped_conns(mut conns: RemovedComponents<TelnetIn>) {
   }
 }
 
-#[derive(Copy, Clone, Default, Debug, Component, Reflect)]
-#[reflect(Component)]
-pub struct GMCP;
-
 pub fn telnet_handler(cmd: ParallelCommands, mut query: Query<(Entity, &mut TelnetIn)>) {
   query.par_iter_mut().for_each(|(entity, mut input)| {
     while input.peek().is_some() {
@@ -473,17 +1109,28 @@ pub fn telnet_handler(cmd: ParallelCommands, mut query: Query<(Entity, &mut Teln
   })
 }
 
-fn gmcp_observer(trigger: Trigger<TelnetEvent>, mut cmd: Commands) {
-  let event = trigger.event();
-  let entity = trigger.entity();
-  match **event {
-    Event::Negotiation(Cmd::DONT, Opt::Known(KnownOpt::GMCP)) => {
-      debug!(?entity, "not enabling GMCP");
-    }
-    Event::Negotiation(Cmd::DO, Opt::Known(KnownOpt::GMCP)) => {
-      debug!(?entity, "enabling GMCP");
-      cmd.entity(entity).insert(GMCP);
-    }
-    _ => {}
+#[cfg(test)]
+mod test {
+  use futures::FutureExt;
+  use tellem::Cmd;
+
+  use super::*;
+
+  #[test]
+  fn capture_keeps_frames_in_order() {
+    let (tx, mut rx, _) = output_queue(OutputLimits::default());
+    let out = TelnetOut::new(tx);
+    out.capture();
+    out.line("one");
+    out.telnet(Event::Cmd(Cmd::GA));
+    out.line("two");
+    assert_eq!(out.release().unwrap(), "two\r\n");
+
+    assert!(matches!(rx.next().now_or_never(), Some(Some(Event::Data(d))) if d == "one\r\n"));
+    assert!(matches!(
+      rx.next().now_or_never(),
+      Some(Some(Event::Cmd(Cmd::GA)))
+    ));
+    assert!(rx.next().now_or_never().is_none());
   }
 }
@@ -0,0 +1,191 @@
+//! Bans on addresses, networks and accounts.
+//!
+//! Bans are kept in the [BanList] resource, which is saved along with the
+//! world. Each ban is keyed by its normalized target, which is an IP address,
+//! a CIDR network or `account:<name>`. Account bans need the prefix so that a
+//! mistyped address can't quietly become a ban on an account by that name.
+
+use std::{
+  fmt,
+  net::IpAddr,
+  str::FromStr,
+  time::{
+    SystemTime,
+    UNIX_EPOCH,
+  },
+};
+
+use anyhow::bail;
+use bevy::{
+  prelude::*,
+  utils::HashMap,
+};
+use ipnet::IpNet;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+
+/// The prefix that marks a ban target as an account name.
+pub const ACCOUNT_PREFIX: &str = "account:";
+
+/// What a ban applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BanTarget {
+  /// An address or network. Single addresses are stored as a /32 or /128.
+  Net(IpNet),
+  /// An account name, matched case insensitively.
+  Account(String),
+}
+
+impl FromStr for BanTarget {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.trim();
+    if let Some(name) = s.strip_prefix(ACCOUNT_PREFIX) {
+      let name = name.trim();
+      if name.is_empty() {
+        bail!("missing account name");
+      }
+      Ok(BanTarget::Account(name.to_lowercase()))
+    } else if let Ok(net) = s.parse::<IpNet>() {
+      Ok(BanTarget::Net(net.trunc()))
+    } else if let Ok(addr) = s.parse::<IpAddr>() {
+      Ok(BanTarget::Net(addr.into()))
+    } else {
+      bail!("'{s}' isn't an IP address or network. Use {ACCOUNT_PREFIX}<name> to ban an account.")
+    }
+  }
+}
+
+impl BanTarget {
+  pub fn matches_ip(&self, ip: IpAddr) -> bool {
+    // IPv4 clients on an IPv6 socket show up as mapped addresses.
+    let ip = match ip {
+      IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+      ip => ip,
+    };
+    matches!(self, BanTarget::Net(net) if net.contains(&ip))
+  }
+
+  pub fn matches_account(&self, name: &str) -> bool {
+    matches!(self, BanTarget::Account(account) if account.eq_ignore_ascii_case(name))
+  }
+}
+
+impl fmt::Display for BanTarget {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BanTarget::Net(net) if net.prefix_len() == net.max_prefix_len() => net.addr().fmt(f),
+      BanTarget::Net(net) => net.fmt(f),
+      BanTarget::Account(name) => write!(f, "{ACCOUNT_PREFIX}{name}"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Reflect, Serialize, Deserialize)]
+#[reflect(Serialize, Deserialize)]
+pub struct BanEntry {
+  pub reason: String,
+  /// The admin who added the ban.
+  pub banned_by: String,
+  /// When the ban was added, in seconds since the epoch.
+  pub created: u64,
+}
+
+impl BanEntry {
+  pub fn new(reason: impl Into<String>, banned_by: impl Into<String>) -> Self {
+    BanEntry {
+      reason: reason.into(),
+      banned_by: banned_by.into(),
+      created: SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default(),
+    }
+  }
+}
+
+#[derive(Debug, Resource, Reflect, Serialize, Deserialize, Default, Clone)]
+#[reflect(Resource, FromWorld, Serialize, Deserialize)]
+pub struct BanList {
+  /// Bans keyed by the normalized form of their [BanTarget].
+  pub bans: HashMap<String, BanEntry>,
+}
+
+impl BanList {
+  /// Add a ban. Replaces any existing ban on the same target.
+  pub fn ban(&mut self, target: &BanTarget, entry: BanEntry) {
+    self.bans.insert(target.to_string(), entry);
+  }
+
+  /// Remove a ban, returning it if there was one.
+  pub fn unban(&mut self, target: &BanTarget) -> Option<BanEntry> {
+    self.bans.remove(&target.to_string())
+  }
+
+  /// The bans, skipping any whose target can't be read.
+  pub fn iter(&self) -> impl Iterator<Item = (BanTarget, &BanEntry)> {
+    self
+      .bans
+      .iter()
+      .filter_map(|(target, entry)| Some((target.parse().ok()?, entry)))
+  }
+
+  /// The ban covering `ip`, if any.
+  pub fn ip_ban(&self, ip: IpAddr) -> Option<&BanEntry> {
+    self
+      .iter()
+      .find_map(|(target, entry)| target.matches_ip(ip).then_some(entry))
+  }
+
+  /// The ban on the account `name`, if any.
+  pub fn account_ban(&self, name: &str) -> Option<&BanEntry> {
+    self
+      .iter()
+      .find_map(|(target, entry)| target.matches_account(name).then_some(entry))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn matches_targets() {
+    let target = |s: &str| s.parse::<BanTarget>().unwrap();
+    let mut bans = BanList::default();
+    assert_eq!(target("10.1.2.3/16").to_string(), "10.1.0.0/16");
+    bans.ban(&target("10.1.2.3/16"), BanEntry::new("spam", "admin"));
+    bans.ban(&target("2001:db8::1"), BanEntry::new("", "admin"));
+    bans.ban(&target("account:Griefer"), BanEntry::new("", "admin"));
+
+    assert!(bans.ip_ban("10.1.200.4".parse().unwrap()).is_some());
+    assert!(bans.ip_ban("::ffff:10.1.0.1".parse().unwrap()).is_some());
+    assert!(bans.ip_ban("10.2.0.1".parse().unwrap()).is_none());
+    assert!(bans.ip_ban("2001:db8::1".parse().unwrap()).is_some());
+    assert!(bans.ip_ban("2001:db8::2".parse().unwrap()).is_none());
+    assert!(bans.account_ban("griefer").is_some());
+    assert!(bans.account_ban("someone").is_none());
+
+    assert!(bans.unban(&target("account:GRIEFER")).is_some());
+    assert!(bans.account_ban("griefer").is_none());
+  }
+
+  #[test]
+  fn parses_targets() {
+    assert_eq!(
+      "account:Alice".parse::<BanTarget>().unwrap(),
+      BanTarget::Account("alice".into())
+    );
+    assert_eq!(
+      "10.0.0.1".parse::<BanTarget>().unwrap().to_string(),
+      "10.0.0.1"
+    );
+    // A typo in an address isn't taken for an account name.
+    assert!("10.0.0.300".parse::<BanTarget>().is_err());
+    assert!("alice".parse::<BanTarget>().is_err());
+    assert!("account:".parse::<BanTarget>().is_err());
+  }
+}
@@ -0,0 +1,245 @@
+//! Downgrading ANSI styling to what a client can display.
+//!
+//! Output is written with whatever colors it likes, and [downgrade] rewrites
+//! the SGR escape sequences in it to fit the client's [ColorLevel]: true color
+//! becomes the nearest of the 256 color palette, which in turn becomes the
+//! nearest of the basic 16, and clients without color support have styling
+//! stripped entirely.
+
+use std::{
+  borrow::Cow,
+  fmt::Write,
+};
+
+use serde::{
+  Deserialize,
+  Serialize,
+};
+
+/// How much color a client can handle.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum ColorLevel {
+  /// No styling at all.
+  None = 0,
+  /// The 16 basic colors, plus bold, underline, etc.
+  #[default]
+  Ansi16 = 1,
+  /// The xterm 256 color palette.
+  Ansi256 = 2,
+  /// 24-bit color.
+  TrueColor = 3,
+}
+
+impl ColorLevel {
+  pub fn from_u8(level: u8) -> Self {
+    match level {
+      0 => ColorLevel::None,
+      1 => ColorLevel::Ansi16,
+      2 => ColorLevel::Ansi256,
+      _ => ColorLevel::TrueColor,
+    }
+  }
+}
+
+/// The xterm defaults for the 16 basic colors.
+const BASIC: [(u8, u8, u8); 16] = [
+  (0, 0, 0),
+  (205, 0, 0),
+  (0, 205, 0),
+  (205, 205, 0),
+  (0, 0, 238),
+  (205, 0, 205),
+  (0, 205, 205),
+  (229, 229, 229),
+  (127, 127, 127),
+  (255, 0, 0),
+  (0, 255, 0),
+  (255, 255, 0),
+  (92, 92, 255),
+  (255, 0, 255),
+  (0, 255, 255),
+  (255, 255, 255),
+];
+
+const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// The RGB value of a color in the 256 color palette.
+pub fn indexed_to_rgb(index: u8) -> (u8, u8, u8) {
+  match index {
+    0..=15 => BASIC[index as usize],
+    16..=231 => {
+      let i = index - 16;
+      (
+        CUBE[(i / 36) as usize],
+        CUBE[(i / 6 % 6) as usize],
+        CUBE[(i % 6) as usize],
+      )
+    }
+    _ => {
+      let v = 8 + (index - 232) * 10;
+      (v, v, v)
+    }
+  }
+}
+
+/// The closest color in the 256 color palette, ignoring the basic 16 since
+/// clients are free to redefine them.
+pub fn rgb_to_indexed(r: u8, g: u8, b: u8) -> u8 {
+  fn cube(v: u8) -> u8 {
+    match v {
+      0..=47 => 0,
+      48..=114 => 1,
+      _ => (v - 35) / 40,
+    }
+  }
+  let cubed = 16 + 36 * cube(r) + 6 * cube(g) + cube(b);
+
+  let avg = ((r as u16 + g as u16 + b as u16) / 3) as u8;
+  let gray = match avg {
+    0..=7 => 232,
+    238..=255 => 255,
+    _ => 232 + (avg - 8) / 10,
+  };
+
+  if distance((r, g, b), indexed_to_rgb(gray)) < distance((r, g, b), indexed_to_rgb(cubed)) {
+    gray
+  } else {
+    cubed
+  }
+}
+
+/// The closest of the 16 basic colors.
+pub fn rgb_to_basic(r: u8, g: u8, b: u8) -> u8 {
+  (0..16)
+    .min_by_key(|i| distance((r, g, b), BASIC[*i as usize]))
+    .unwrap_or(7)
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+  let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+  d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+/// Rewrite the SGR sequences in `s` to only use what `level` supports. Other
+/// escape sequences are left alone, unless the level is [ColorLevel::None].
+pub fn downgrade(s: &str, level: ColorLevel) -> Cow<'_, str> {
+  if level == ColorLevel::TrueColor || !s.contains('\x1b') {
+    return Cow::Borrowed(s);
+  }
+
+  let mut out = String::with_capacity(s.len());
+  let mut rest = s;
+  while let Some(start) = rest.find('\x1b') {
+    out.push_str(&rest[..start]);
+    rest = &rest[start..];
+
+    let Some(body) = rest.strip_prefix("\x1b[") else {
+      // Not a CSI sequence, so there's nothing to downgrade.
+      out.push('\x1b');
+      rest = &rest[1..];
+      continue;
+    };
+    let Some(end) = body.find(|c: char| ('\x40'..='\x7e').contains(&c)) else {
+      // Unterminated, pass it through as-is.
+      out.push_str(rest);
+      return Cow::Owned(out);
+    };
+    let (params, terminator) = (&body[..end], &body[end..end + 1]);
+    rest = &body[end + 1..];
+
+    if level == ColorLevel::None {
+      continue;
+    }
+    if terminator != "m" {
+      out.push_str("\x1b[");
+      out.push_str(params);
+      out.push_str(terminator);
+      continue;
+    }
+    let params = downgrade_sgr(params, level);
+    if !params.is_empty() {
+      let _ = write!(out, "\x1b[{params}m");
+    }
+  }
+  out.push_str(rest);
+  Cow::Owned(out)
+}
+
+fn downgrade_sgr(params: &str, level: ColorLevel) -> String {
+  let codes = params
+    .split(';')
+    .map(|p| p.parse::<u8>().unwrap_or(0))
+    .collect::<Vec<_>>();
+  let mut out = Vec::with_capacity(codes.len());
+  let mut i = 0;
+  while i < codes.len() {
+    let code = codes[i];
+    i += 1;
+    if code != 38 && code != 48 {
+      out.push(code.to_string());
+      continue;
+    }
+    let background = code == 48;
+    let index = match codes.get(i..) {
+      Some([5, n, ..]) => {
+        i += 2;
+        *n
+      }
+      Some([2, r, g, b, ..]) => {
+        i += 4;
+        match level {
+          ColorLevel::Ansi256 => rgb_to_indexed(*r, *g, *b),
+          _ => rgb_to_basic(*r, *g, *b),
+        }
+      }
+      // Malformed, so drop what's left rather than guess.
+      _ => break,
+    };
+    out.push(color_code(index, background, level));
+  }
+  out.join(";")
+}
+
+fn color_code(index: u8, background: bool, level: ColorLevel) -> String {
+  let base = if background { 40 } else { 30 };
+  match (level, index) {
+    (ColorLevel::Ansi256, _) => format!("{};5;{index}", base + 8),
+    (_, 0..=7) => (base + index).to_string(),
+    (_, 8..=15) => (base + 60 + index - 8).to_string(),
+    (_, _) => {
+      let (r, g, b) = indexed_to_rgb(index);
+      color_code(rgb_to_basic(r, g, b), background, level)
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const RGB_RED: &str = "\x1b[1;38;2;250;10;10mgoblin\x1b[0m";
+
+  #[test]
+  fn downgrades_colors() {
+    assert_eq!(downgrade(RGB_RED, ColorLevel::TrueColor), RGB_RED);
+    assert_eq!(
+      downgrade(RGB_RED, ColorLevel::Ansi256),
+      "\x1b[1;38;5;196mgoblin\x1b[0m"
+    );
+    assert_eq!(
+      downgrade(RGB_RED, ColorLevel::Ansi16),
+      "\x1b[1;91mgoblin\x1b[0m"
+    );
+    assert_eq!(
+      downgrade("\x1b[48;5;4m \x1b[38;5;10mx", ColorLevel::Ansi16),
+      "\x1b[44m \x1b[92mx"
+    );
+  }
+
+  #[test]
+  fn strips_styling() {
+    assert_eq!(downgrade(RGB_RED, ColorLevel::None), "goblin");
+    assert_eq!(downgrade("plain", ColorLevel::None), "plain");
+  }
+}
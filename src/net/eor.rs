@@ -0,0 +1,25 @@
+//! TELNET END-OF-RECORD (RFC 885).
+//!
+//! Prompts don't end in a newline, so clients need to be told when one is
+//! complete. We offer `WILL EOR` to every client, and those that agree get
+//! `IAC EOR` after each prompt. Everyone else gets `IAC GA`.
+
+use bevy::prelude::*;
+
+use super::{
+  options::OptionChanged,
+  TelnetOut,
+};
+
+pub const EOR: u8 = 25;
+
+pub fn eor_option_observer(trigger: Trigger<OptionChanged>, query: Query<&TelnetOut>) {
+  let Some(enabled) = trigger.event().local(EOR) else {
+    return;
+  };
+  let entity = trigger.entity();
+  if let Ok(out) = query.get(entity) {
+    debug!(?entity, enabled, "EOR changed");
+    out.set_eor(enabled);
+  }
+}
@@ -0,0 +1,441 @@
+//! Typed GMCP (Generic Mud Communication Protocol).
+//!
+//! GMCP messages are `Package.Message <json>` sent in a subnegotiation. Inbound
+//! messages are parsed and triggered on the connection as a [GmcpIn], and the
+//! `Core.*` ones are used to keep track of the client's [CoreHello] and the
+//! [GmcpModules] it's subscribed to.
+//!
+//! Outbound messages are any [GmcpPackage], sent either unconditionally with
+//! [TelnetOut::gmcp] or only to subscribers with [GmcpOut::send].
+
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use bevy::{
+  ecs::system::SystemParam,
+  prelude::*,
+  utils::HashMap,
+};
+use bytes::BytesMut;
+use serde::{
+  de::DeserializeOwned,
+  Deserialize,
+  Serialize,
+};
+use serde_json::Value;
+use tellem::{
+  Event,
+  KnownOpt,
+  Opt,
+};
+
+use super::{
+  options::{
+    self,
+    OptionChanged,
+  },
+  TelnetEvent,
+  TelnetOut,
+};
+
+/// Marks connections that have agreed to GMCP.
+#[derive(Copy, Clone, Default, Debug, Component, Reflect)]
+#[reflect(Component)]
+pub struct GMCP;
+
+/// A type that can be sent as a GMCP message.
+pub trait GmcpPackage: Serialize {
+  /// The full package and message name, e.g. `Char.Vitals`.
+  const PACKAGE: &'static str;
+}
+
+/// A GMCP message that hasn't been given a type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GmcpMessage {
+  pub package: String,
+  pub data: Value,
+}
+
+impl GmcpMessage {
+  pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+    let data = std::str::from_utf8(data)?;
+    let (package, json) = match data.split_once(|c: char| c.is_ascii_whitespace()) {
+      Some((package, json)) => (package, json.trim()),
+      None => (data, ""),
+    };
+    if package.is_empty() {
+      return Err(anyhow!("empty GMCP package"));
+    }
+    let data = if json.is_empty() {
+      Value::Null
+    } else {
+      serde_json::from_str(json)?
+    };
+    Ok(GmcpMessage {
+      package: package.into(),
+      data,
+    })
+  }
+
+  /// Whether this is the message for `package`. Package names are case
+  /// insensitive.
+  pub fn is(&self, package: &str) -> bool {
+    self.package.eq_ignore_ascii_case(package)
+  }
+
+  /// Deserialize the message body.
+  pub fn decode<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+    Ok(T::deserialize(&self.data)?)
+  }
+
+  /// Build the subnegotiation for this message.
+  pub fn to_event(&self) -> Event {
+    encode_as(&self.package, &self.data).expect("JSON values always serialize")
+  }
+}
+
+/// Build the subnegotiation for a GMCP message.
+pub fn encode<T: GmcpPackage>(message: &T) -> anyhow::Result<Event> {
+  encode_as(T::PACKAGE, message)
+}
+
+/// Build the subnegotiation for a message to `package`. Messages without a
+/// body are sent as just the package name.
+fn encode_as<T: Serialize + ?Sized>(package: &str, message: &T) -> anyhow::Result<Event> {
+  let json = serde_json::to_string(message)?;
+  let body = match json.as_str() {
+    "null" => package.to_string(),
+    json => format!("{package} {json}"),
+  };
+  Ok(Event::Subnegotiation(
+    Opt::Known(KnownOpt::GMCP),
+    BytesMut::from(body.as_bytes()),
+  ))
+}
+
+/// A parsed inbound GMCP message, triggered on the connection it came from.
+#[derive(Event, Debug, Clone)]
+pub enum GmcpIn {
+  Hello(CoreHello),
+  SupportsSet(Vec<(String, u32)>),
+  SupportsAdd(Vec<(String, u32)>),
+  SupportsRemove(Vec<String>),
+  /// Anything that isn't handled here.
+  Message(GmcpMessage),
+}
+
+impl GmcpIn {
+  fn parse(data: &[u8]) -> anyhow::Result<Self> {
+    let message = GmcpMessage::parse(data)?;
+    Ok(if message.is("Core.Hello") {
+      GmcpIn::Hello(message.decode()?)
+    } else if message.is("Core.Supports.Set") {
+      GmcpIn::SupportsSet(parse_modules(message.decode()?))
+    } else if message.is("Core.Supports.Add") {
+      GmcpIn::SupportsAdd(parse_modules(message.decode()?))
+    } else if message.is("Core.Supports.Remove") {
+      let modules: Vec<String> = message.decode()?;
+      GmcpIn::SupportsRemove(
+        parse_modules(modules)
+          .into_iter()
+          .map(|(name, _)| name)
+          .collect(),
+      )
+    } else {
+      GmcpIn::Message(message)
+    })
+  }
+}
+
+/// Parse `"Module version"` strings, defaulting to version 1.
+fn parse_modules(modules: Vec<String>) -> Vec<(String, u32)> {
+  modules
+    .into_iter()
+    .filter_map(|module| {
+      let mut parts = module.split_whitespace();
+      let name = parts.next()?.to_lowercase();
+      let version = parts.next().and_then(|v| v.parse().ok()).unwrap_or(1);
+      Some((name, version))
+    })
+    .collect()
+}
+
+/// The client's `Core.Hello`.
+#[derive(Component, Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoreHello {
+  pub client: String,
+  pub version: String,
+}
+
+/// The modules a client has subscribed to with `Core.Supports`, and their
+/// versions. Names are stored lowercase.
+#[derive(Component, Debug, Clone, Default, Deref, Serialize, Deserialize)]
+pub struct GmcpModules(HashMap<String, u32>);
+
+impl GmcpModules {
+  /// Whether a package falls under one of the subscribed modules, e.g.
+  /// `Char.Vitals` is covered by either `Char` or `Char.Vitals`.
+  pub fn covers(&self, package: &str) -> bool {
+    let package = package.to_lowercase();
+    let mut prefix = package.as_str();
+    loop {
+      if self.0.contains_key(prefix) {
+        return true;
+      }
+      match prefix.rsplit_once('.') {
+        Some((parent, _)) => prefix = parent,
+        None => return false,
+      }
+    }
+  }
+
+  fn update(&mut self, event: &GmcpIn) {
+    match event {
+      GmcpIn::SupportsSet(modules) => {
+        self.0.clear();
+        self.0.extend(modules.iter().cloned());
+      }
+      GmcpIn::SupportsAdd(modules) => self.0.extend(modules.iter().cloned()),
+      GmcpIn::SupportsRemove(modules) => {
+        for module in modules {
+          self.0.remove(module);
+        }
+      }
+      _ => {}
+    }
+  }
+}
+
+/// Sends GMCP messages to the connections that have subscribed to them.
+#[derive(SystemParam)]
+pub struct GmcpOut<'w, 's> {
+  conns: Query<'w, 's, (&'static TelnetOut, &'static GmcpModules), With<GMCP>>,
+}
+
+impl<'w, 's> GmcpOut<'w, 's> {
+  /// Send `message` to `entity` if it's subscribed to the message's module.
+  /// Returns whether it was sent.
+  pub fn send<T: GmcpPackage>(&self, entity: Entity, message: &T) -> bool {
+    let Ok((out, modules)) = self.conns.get(entity) else {
+      return false;
+    };
+    if !modules.covers(T::PACKAGE) {
+      return false;
+    }
+    out.gmcp(message);
+    true
+  }
+
+  /// Send `message` to every subscribed connection.
+  pub fn broadcast<T: GmcpPackage>(&self, message: &T) {
+    let Ok(event) = encode(message) else {
+      return;
+    };
+    for (out, modules) in self.conns.iter() {
+      if modules.covers(T::PACKAGE) {
+        out.telnet(event.clone());
+      }
+    }
+  }
+}
+
+pub(super) fn gmcp_option_observer(trigger: Trigger<OptionChanged>, mut cmd: Commands) {
+  let entity = trigger.entity();
+  match trigger.event().local(options::GMCP) {
+    Some(true) => {
+      debug!(?entity, "enabling GMCP");
+      cmd.entity(entity).insert((GMCP, GmcpModules::default()));
+    }
+    Some(false) => {
+      debug!(?entity, "disabling GMCP");
+      cmd.entity(entity).remove::<(GMCP, GmcpModules)>();
+    }
+    None => {}
+  }
+}
+
+pub(super) fn gmcp_observer(trigger: Trigger<TelnetEvent>, mut cmd: Commands) {
+  let entity = trigger.entity();
+  let Event::Subnegotiation(Opt::Known(KnownOpt::GMCP), data) = &**trigger.event() else {
+    return;
+  };
+  match GmcpIn::parse(data) {
+    Ok(event) => cmd.trigger_targets(event, entity),
+    Err(error) => debug!(?entity, %error, "invalid GMCP message"),
+  }
+}
+
+pub(super) fn gmcp_core_observer(
+  trigger: Trigger<GmcpIn>,
+  mut modules: Query<&mut GmcpModules>,
+  mut cmd: Commands,
+) {
+  let entity = trigger.entity();
+  match trigger.event() {
+    GmcpIn::Hello(hello) => {
+      debug!(?entity, ?hello, "GMCP hello");
+      cmd.entity(entity).insert(hello.clone());
+    }
+    GmcpIn::Message(_) => {}
+    event => {
+      if let Ok(mut modules) = modules.get_mut(entity) {
+        modules.update(event);
+        debug!(?entity, modules = ?modules.0, "GMCP modules updated");
+      }
+    }
+  }
+}
+
+macro_rules! packages {
+  ($($ty:ty => $package:literal),* $(,)?) => {
+    $(
+      impl GmcpPackage for $ty {
+        const PACKAGE: &'static str = $package;
+      }
+    )*
+  };
+}
+
+packages! {
+  CoreHello => "Core.Hello",
+  CharVitals => "Char.Vitals",
+  CharStatus => "Char.Status",
+  RoomInfo => "Room.Info",
+  CommChannelText => "Comm.Channel.Text",
+  CommChannelList => "Comm.Channel.List",
+}
+
+/// `Char.Vitals`: the character's current and maximum health, mana, etc.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CharVitals {
+  pub hp: u32,
+  pub maxhp: u32,
+  pub mp: u32,
+  pub maxmp: u32,
+  /// Game specific vitals.
+  #[serde(flatten)]
+  pub other: BTreeMap<String, Value>,
+}
+
+/// `Char.Status`: information about the character that changes rarely.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CharStatus {
+  pub name: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub fullname: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub level: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub race: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub class: Option<String>,
+  #[serde(flatten)]
+  pub other: BTreeMap<String, Value>,
+}
+
+/// `Room.Info`: the room or location the character is in.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RoomInfo {
+  pub num: u64,
+  pub name: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub area: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub environment: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub coords: Option<String>,
+  /// Exit directions to room numbers.
+  pub exits: BTreeMap<String, u64>,
+}
+
+/// `Comm.Channel.Text`: something said on a channel.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CommChannelText {
+  pub channel: String,
+  pub talker: String,
+  pub text: String,
+}
+
+/// `Comm.Channel.List`: the channels the character can use.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CommChannelList(pub Vec<ChannelInfo>);
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChannelInfo {
+  pub name: String,
+  pub caption: String,
+  pub command: String,
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::testing::TestMud;
+
+  #[test]
+  fn parses_core_messages() {
+    let hello = GmcpIn::parse(br#"Core.Hello { "client": "Mudlet", "version": "4.17" }"#);
+    assert!(matches!(hello, Ok(GmcpIn::Hello(h)) if h.client == "Mudlet"));
+
+    let mut modules = GmcpModules::default();
+    modules.update(&GmcpIn::parse(br#"core.supports.set ["Char 1", "Room 2"]"#).unwrap());
+    modules.update(&GmcpIn::parse(br#"Core.Supports.Remove ["Room"]"#).unwrap());
+    assert!(modules.covers("Char.Vitals"));
+    assert!(!modules.covers("Room.Info"));
+    assert!(!modules.covers("Charisma"));
+
+    let custom = GmcpIn::parse(b"External.Discord.Hello").unwrap();
+    assert!(matches!(custom, GmcpIn::Message(m) if m.data.is_null()));
+  }
+
+  #[test]
+  fn encodes_package_and_json() {
+    let vitals = CharVitals {
+      hp: 10,
+      maxhp: 12,
+      ..default()
+    };
+    let Event::Subnegotiation(_, data) = encode(&vitals).unwrap() else {
+      panic!("expected a subnegotiation");
+    };
+    assert_eq!(
+      &data[..],
+      br#"Char.Vitals {"hp":10,"maxhp":12,"mp":0,"maxmp":0}"#
+    );
+  }
+
+  #[test]
+  fn over_loopback() {
+    let mut mud = TestMud::new();
+    let mut client = mud.connect();
+    client.accept(options::GMCP);
+    mud.expect(&mut client, "Account name: ");
+
+    for message in [
+      br#"Core.Hello {"client": "test", "version": "1.0"}"#.as_slice(),
+      br#"Core.Supports.Set ["Char 1"]"#,
+    ] {
+      client.send(GmcpMessage::parse(message).unwrap().to_event());
+    }
+    mud.run_until("client subscribed to Char", |world| {
+      world
+        .query_filtered::<&GmcpModules, (With<GMCP>, With<CoreHello>)>()
+        .get_single(world)
+        .is_ok_and(|modules| modules.covers("Char.Vitals"))
+    });
+    assert!(client.remote_enabled(options::GMCP));
+
+    let (conn, out) = mud
+      .world_mut()
+      .query_filtered::<(Entity, &TelnetOut), With<GMCP>>()
+      .single(mud.world());
+    assert_eq!(mud.world().get::<CoreHello>(conn).unwrap().client, "test");
+    out.gmcp(&CharVitals {
+      hp: 10,
+      maxhp: 12,
+      ..default()
+    });
+    let vitals = mud.expect_gmcp(&mut client, "Char.Vitals");
+    assert_eq!(vitals.decode::<CharVitals>().unwrap().hp, 10);
+  }
+}
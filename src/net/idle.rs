@@ -0,0 +1,137 @@
+//! Disconnecting idle clients.
+//!
+//! A connection counts as idle when it hasn't sent a line of input, so
+//! option negotiation and window size updates don't keep it alive. Clients
+//! are warned a configurable amount of time before they're disconnected.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+
+use super::{
+  TelnetIn,
+  TelnetOut,
+};
+
+/// How often to check for idle connections.
+pub(super) const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IdleConfig {
+  /// Seconds without input before a client is disconnected, or 0 to never
+  /// disconnect idle clients.
+  pub timeout: f32,
+  /// How many seconds before the timeout to warn the client. Each one is
+  /// sent once per idle period.
+  pub warnings: Vec<f32>,
+}
+
+impl Default for IdleConfig {
+  fn default() -> Self {
+    Self {
+      timeout: 3600.0,
+      warnings: vec![300.0, 60.0],
+    }
+  }
+}
+
+/// How many idle warnings a connection has been sent.
+#[derive(Component, Debug, Default, Clone, Copy, Reflect)]
+#[reflect(Component)]
+pub struct Idle {
+  warned: usize,
+}
+
+pub(super) fn idle_system(
+  config: Res<IdleConfig>,
+  mut conns: Query<(Entity, &TelnetIn, &TelnetOut, &mut Idle)>,
+) {
+  if config.timeout <= 0.0 {
+    return;
+  }
+  let timeout = Duration::from_secs_f32(config.timeout);
+
+  for (entity, input, output, mut idle) in conns.iter_mut() {
+    if output.closed() {
+      continue;
+    }
+    let idle_for = input.idle();
+    if idle_for >= timeout {
+      debug!(?entity, ?idle_for, "disconnecting idle client");
+      output.line("You've been idle for too long, disconnecting.");
+      output.close();
+      continue;
+    }
+
+    let left = timeout - idle_for;
+    let due = config
+      .warnings
+      .iter()
+      .filter(|warning| left.as_secs_f32() <= **warning)
+      .count();
+    // Fewer warnings are due after the client sends something, so start
+    // over.
+    if due > idle.warned {
+      output.line(format!(
+        "You will be disconnected for idling in {}.",
+        describe(left)
+      ));
+    }
+    idle.warned = due;
+  }
+}
+
+/// Describe a duration the way a person would, to the nearest second or
+/// minute.
+fn describe(duration: Duration) -> String {
+  let secs = duration.as_secs_f32().ceil() as u64;
+  match secs {
+    1 => "1 second".into(),
+    0..=119 => format!("{secs} seconds"),
+    _ => format!("{} minutes", (secs + 30) / 60),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    config::ServerConfig,
+    testing::TestMud,
+  };
+
+  #[test]
+  fn warns_then_disconnects() {
+    let mut config = ServerConfig::default();
+    // Far enough apart that a check lands between each of them.
+    config.idle = IdleConfig {
+      timeout: 4.0,
+      warnings: vec![3.0, 1.5],
+    };
+    let mut mud = TestMud::with_config(config);
+    let mut client = mud.connect();
+    mud.create_account(&mut client, "alice", "pw");
+
+    let warning = "You will be disconnected for idling in";
+    mud.expect(&mut client, warning);
+    mud.expect(&mut client, warning);
+    let last = mud.expect(&mut client, "You've been idle for too long, disconnecting.");
+    assert!(!last.contains(warning), "{last:?}");
+    mud.wait_for(&mut client, "disconnect", |client, _| {
+      client.closed().then_some(())
+    });
+  }
+
+  #[test]
+  fn describes_time_left() {
+    assert_eq!(describe(Duration::from_millis(500)), "1 second");
+    assert_eq!(describe(Duration::from_secs(59)), "59 seconds");
+    assert_eq!(describe(Duration::from_secs(299)), "5 minutes");
+    assert_eq!(describe(Duration::from_secs(3600)), "60 minutes");
+  }
+}
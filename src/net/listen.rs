@@ -0,0 +1,471 @@
+//! Addresses to listen on, and binding them.
+//!
+//! A [BindAddr] is either a TCP socket address, which covers IPv4, IPv6 and
+//! specific interfaces, or a Unix domain socket path written as
+//! `unix:/path/to/socket`. Each one is started as its own listener.
+
+use std::{
+  fmt,
+  io,
+  net::{
+    AddrParseError,
+    SocketAddr,
+  },
+  os::fd::{
+    AsRawFd,
+    OwnedFd,
+    RawFd,
+  },
+  path::PathBuf,
+  pin::Pin,
+  str::FromStr,
+  sync::{
+    Arc,
+    Mutex,
+  },
+  task::{
+    Context,
+    Poll,
+  },
+};
+
+use async_std::net::TcpListener;
+use futures::{
+  AsyncRead,
+  AsyncWrite,
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use socket2::{
+  Domain,
+  Socket,
+  Type,
+};
+
+use super::DEFAULT_PORT;
+
+/// Somewhere to accept connections.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum BindAddr {
+  Tcp(SocketAddr),
+  /// A Unix domain socket, for local access.
+  Unix(PathBuf),
+}
+
+impl Default for BindAddr {
+  fn default() -> Self {
+    BindAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)))
+  }
+}
+
+impl BindAddr {
+  pub fn port(&self) -> Option<u16> {
+    match self {
+      BindAddr::Tcp(addr) => Some(addr.port()),
+      BindAddr::Unix(_) => None,
+    }
+  }
+
+  pub fn is_unix(&self) -> bool {
+    matches!(self, BindAddr::Unix(_))
+  }
+}
+
+impl FromStr for BindAddr {
+  type Err = AddrParseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.strip_prefix("unix:") {
+      Some(path) => Ok(BindAddr::Unix(path.into())),
+      None => s.parse().map(BindAddr::Tcp),
+    }
+  }
+}
+
+impl fmt::Display for BindAddr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BindAddr::Tcp(addr) => addr.fmt(f),
+      BindAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+    }
+  }
+}
+
+impl TryFrom<String> for BindAddr {
+  type Error = AddrParseError;
+
+  fn try_from(s: String) -> Result<Self, Self::Error> {
+    s.parse()
+  }
+}
+
+impl From<BindAddr> for String {
+  fn from(addr: BindAddr) -> Self {
+    addr.to_string()
+  }
+}
+
+impl From<SocketAddr> for BindAddr {
+  fn from(addr: SocketAddr) -> Self {
+    BindAddr::Tcp(addr)
+  }
+}
+
+/// A configured listener address.
+///
+/// In config files this is either just the address, or a table with the
+/// address and options.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "ListenAddrRepr")]
+pub struct ListenAddr {
+  pub addr: BindAddr,
+  /// Whether players connected through this listener may log in as admins.
+  pub admin: bool,
+}
+
+impl From<BindAddr> for ListenAddr {
+  fn from(addr: BindAddr) -> Self {
+    ListenAddr { addr, admin: true }
+  }
+}
+
+impl From<SocketAddr> for ListenAddr {
+  fn from(addr: SocketAddr) -> Self {
+    BindAddr::from(addr).into()
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListenAddrRepr {
+  Short(BindAddr),
+  Full {
+    addr: BindAddr,
+    #[serde(default = "admin_default")]
+    admin: bool,
+  },
+}
+
+fn admin_default() -> bool {
+  true
+}
+
+impl From<ListenAddrRepr> for ListenAddr {
+  fn from(repr: ListenAddrRepr) -> Self {
+    match repr {
+      ListenAddrRepr::Short(addr) => addr.into(),
+      ListenAddrRepr::Full { addr, admin } => ListenAddr { addr, admin },
+    }
+  }
+}
+
+/// A connection from any kind of listener. The socket is kept accessible so
+/// that it can be handed over by a copyover.
+pub trait ConnStream: AsyncRead + AsyncWrite + AsRawFd + Send + Unpin {}
+
+impl<T> ConnStream for T where T: AsyncRead + AsyncWrite + AsRawFd + Send + Unpin {}
+
+pub type Conn = Box<dyn ConnStream>;
+
+/// A socket's fd, for as long as the socket is open. A copyover duplicates
+/// sockets through these rather than holding on to the raw fd, which could
+/// have been closed and reused for something else by then.
+#[derive(Debug, Clone, Default)]
+pub struct SocketHandle(Arc<Mutex<Option<RawFd>>>);
+
+impl SocketHandle {
+  pub fn new(fd: RawFd) -> Self {
+    SocketHandle(Arc::new(Mutex::new(Some(fd))))
+  }
+
+  /// Wrap whatever owns the socket, so that the handle is closed when it's
+  /// dropped.
+  pub fn track<T>(&self, inner: T) -> Tracked<T> {
+    Tracked {
+      inner,
+      handle: self.clone(),
+    }
+  }
+
+  /// Call `f` with the fd if the socket is still open. It stays open until
+  /// `f` returns.
+  pub fn with_fd<R>(&self, f: impl FnOnce(RawFd) -> R) -> Option<R> {
+    let fd = self
+      .0
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner());
+    fd.map(f)
+  }
+
+  pub fn is_open(&self) -> bool {
+    self.with_fd(|_| ()).is_some()
+  }
+
+  fn close(&self) {
+    *self
+      .0
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+  }
+}
+
+/// A socket's owner, which closes its [SocketHandle] before the socket.
+pub struct Tracked<T> {
+  inner: T,
+  handle: SocketHandle,
+}
+
+impl<T> Drop for Tracked<T> {
+  fn drop(&mut self) {
+    self.handle.close();
+  }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Tracked<T> {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.inner).poll_read(cx, buf)
+  }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Tracked<T> {
+  fn poll_write(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.inner).poll_write(cx, buf)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_close(cx)
+  }
+}
+
+/// Take over a connected socket inherited from the process before a copyover.
+/// Unix socket connections have no peer address.
+pub fn conn_from_fd(fd: OwnedFd, remote_addr: Option<SocketAddr>) -> io::Result<Conn> {
+  match remote_addr {
+    Some(_) => {
+      let conn = std::net::TcpStream::from(fd);
+      conn.set_nonblocking(true)?;
+      Ok(Box::new(async_std::net::TcpStream::from(conn)))
+    }
+    None => {
+      let conn = std::os::unix::net::UnixStream::from(fd);
+      conn.set_nonblocking(true)?;
+      Ok(Box::new(async_std::os::unix::net::UnixStream::from(conn)))
+    }
+  }
+}
+
+/// A bound listener, ready to accept connections.
+pub struct Bound {
+  socket: BoundSocket,
+  handle: SocketHandle,
+}
+
+enum BoundSocket {
+  Tcp(TcpListener),
+  Unix(async_std::os::unix::net::UnixListener),
+}
+
+impl Drop for Bound {
+  fn drop(&mut self) {
+    self.handle.close();
+  }
+}
+
+impl Bound {
+  fn new(socket: BoundSocket) -> Self {
+    let handle = SocketHandle::new(match &socket {
+      BoundSocket::Tcp(l) => l.as_raw_fd(),
+      BoundSocket::Unix(l) => l.as_raw_fd(),
+    });
+    Bound { socket, handle }
+  }
+
+  /// A handle on the socket that's closed along with the listener.
+  pub fn handle(&self) -> SocketHandle {
+    self.handle.clone()
+  }
+
+  pub fn bind(addr: &BindAddr) -> io::Result<Self> {
+    match addr {
+      BindAddr::Tcp(addr) => Ok(Bound::new(BoundSocket::Tcp(bind_tcp(*addr)?.into()))),
+      BindAddr::Unix(path) => {
+        use std::os::unix::fs::FileTypeExt;
+        // A socket left behind by an earlier run would make the bind fail.
+        if std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+          std::fs::remove_file(path)?;
+        }
+        let listener = std::os::unix::net::UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Bound::new(BoundSocket::Unix(listener.into())))
+      }
+    }
+  }
+
+  /// Take over a socket that's already bound to `addr`, e.g. one inherited
+  /// from the process before a copyover.
+  pub fn from_fd(addr: &BindAddr, fd: OwnedFd) -> io::Result<Self> {
+    match addr {
+      BindAddr::Tcp(_) => {
+        let listener = std::net::TcpListener::from(fd);
+        listener.set_nonblocking(true)?;
+        Ok(Bound::new(BoundSocket::Tcp(listener.into())))
+      }
+      BindAddr::Unix(_) => {
+        let listener = std::os::unix::net::UnixListener::from(fd);
+        listener.set_nonblocking(true)?;
+        Ok(Bound::new(BoundSocket::Unix(listener.into())))
+      }
+    }
+  }
+
+  /// The address actually bound, which differs from the requested one when
+  /// binding to port 0.
+  pub fn local_addr(&self) -> io::Result<BindAddr> {
+    match &self.socket {
+      BoundSocket::Tcp(l) => l.local_addr().map(BindAddr::Tcp),
+      BoundSocket::Unix(l) => Ok(BindAddr::Unix(
+        l.local_addr()?
+          .as_pathname()
+          .map(Into::into)
+          .unwrap_or_default(),
+      )),
+    }
+  }
+
+  /// Wait for the next connection, returning it along with the peer address
+  /// for TCP connections.
+  pub async fn accept(&self) -> io::Result<(Conn, Option<SocketAddr>)> {
+    match &self.socket {
+      BoundSocket::Tcp(l) => {
+        let (conn, addr) = l.accept().await?;
+        Ok((Box::new(conn), Some(addr)))
+      }
+      BoundSocket::Unix(l) => {
+        let (conn, _) = l.accept().await?;
+        Ok((Box::new(conn), None))
+      }
+    }
+  }
+}
+
+impl AsRawFd for Bound {
+  fn as_raw_fd(&self) -> RawFd {
+    match &self.socket {
+      BoundSocket::Tcp(l) => l.as_raw_fd(),
+      BoundSocket::Unix(l) => l.as_raw_fd(),
+    }
+  }
+}
+
+/// Bind a TCP listener. IPv6 listeners only accept IPv6, so that the same
+/// port can be bound separately for IPv4.
+fn bind_tcp(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+  let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+  if addr.is_ipv6() {
+    socket.set_only_v6(true)?;
+  }
+  socket.set_reuse_address(true)?;
+  socket.set_nonblocking(true)?;
+  socket.bind(&addr.into())?;
+  socket.listen(1024)?;
+  Ok(socket.into())
+}
+
+#[cfg(test)]
+mod test {
+  use futures::{
+    AsyncReadExt,
+    AsyncWriteExt,
+  };
+
+  use super::*;
+
+  #[test]
+  fn parses_addrs() {
+    assert_eq!(
+      "[::1]:4000".parse::<BindAddr>().unwrap(),
+      BindAddr::Tcp("[::1]:4000".parse().unwrap())
+    );
+    let unix: BindAddr = "unix:/run/mud.sock".parse().unwrap();
+    assert_eq!(unix, BindAddr::Unix("/run/mud.sock".into()));
+    assert_eq!(unix.to_string(), "unix:/run/mud.sock");
+    assert!("localhost".parse::<BindAddr>().is_err());
+
+    #[derive(Deserialize)]
+    struct Config {
+      addrs: Vec<ListenAddr>,
+    }
+    let config: Config = toml::from_str(
+      r#"
+        addrs = ["0.0.0.0:4000", { addr = "unix:/run/mud.sock", admin = false }]
+      "#,
+    )
+    .unwrap();
+    assert_eq!(
+      config.addrs,
+      vec![
+        ListenAddr {
+          addr: BindAddr::Tcp("0.0.0.0:4000".parse().unwrap()),
+          admin: true,
+        },
+        ListenAddr {
+          addr: BindAddr::Unix("/run/mud.sock".into()),
+          admin: false,
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn inherits_sockets() {
+    async_std::task::block_on(async {
+      let bound = Bound::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+      let addr = bound.local_addr().unwrap();
+      let BindAddr::Tcp(tcp) = addr else {
+        unreachable!();
+      };
+      let handle = bound.handle();
+      let fd = handle
+        .with_fd(|fd| unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) }.try_clone_to_owned())
+        .unwrap()
+        .unwrap();
+      drop(bound);
+      assert!(!handle.is_open());
+
+      let inherited = Bound::from_fd(&addr, fd).unwrap();
+      let mut client = async_std::net::TcpStream::connect(tcp).await.unwrap();
+      let (conn, peer) = inherited.accept().await.unwrap();
+      assert_eq!(peer, Some(client.local_addr().unwrap()));
+
+      // The copy keeps the connection open after the original is gone.
+      let socket = SocketHandle::new(conn.as_raw_fd());
+      let conn = socket.track(conn);
+      let fd = socket
+        .with_fd(|fd| unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) }.try_clone_to_owned())
+        .unwrap()
+        .unwrap();
+      drop(conn);
+      assert!(!socket.is_open());
+      let mut conn = conn_from_fd(fd, peer).unwrap();
+      conn.write_all(b"hello").await.unwrap();
+      let mut buf = [0; 5];
+      client.read_exact(&mut buf).await.unwrap();
+      assert_eq!(&buf, b"hello");
+    });
+  }
+}
@@ -0,0 +1,323 @@
+//! In-process connections.
+//!
+//! A [LoopbackListener] hands out connections over in-memory pipes instead of
+//! sockets. The server end goes through the same connection handling and
+//! admission as any other transport, so a [LoopbackClient] on the other end
+//! sees exactly what a telnet client would: text, negotiation and
+//! subnegotiations. This is mostly for driving the game from tests.
+
+use std::collections::VecDeque;
+
+use anyhow::anyhow;
+use bevy::prelude::*;
+use futures::{
+  executor::block_on,
+  stream::{
+    SplitSink,
+    SplitStream,
+  },
+  FutureExt,
+  SinkExt,
+  StreamExt,
+};
+use tellem::{
+  Cmd,
+  Event,
+};
+use tokio::{
+  io::{
+    self,
+    DuplexStream,
+  },
+  sync::mpsc::{
+    self,
+    UnboundedSender,
+  },
+};
+use tokio_util::codec::Framed;
+
+use super::{
+  handle_conn,
+  options::{
+    Side,
+    TelnetOptions,
+  },
+  throttle::ConnectionGate,
+  ClientBundle,
+  ConnSettings,
+  Listener,
+  NewConns,
+  OutputLimits,
+  ThrottleConfig,
+  Transport,
+};
+
+/// How much can be in flight in either direction of a pipe before writes
+/// wait.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// A handle for making in-process connections to a [Listener].
+pub struct LoopbackListener {
+  entity: Entity,
+  channel: UnboundedSender<ClientBundle>,
+  settings: ConnSettings,
+  gate: ConnectionGate,
+}
+
+impl LoopbackListener {
+  /// Spawn a [Listener] for in-process connections. Players connected through
+  /// it may log in as admins if `admin` is set.
+  ///
+  /// The listener stops accepting connections once the handle is dropped.
+  pub fn spawn(world: &mut World, admin: bool) -> Self {
+    let (channel, new_rx) = mpsc::unbounded_channel();
+    let settings = ConnSettings {
+      limits: *world.resource::<OutputLimits>(),
+      throttle: *world.resource::<ThrottleConfig>(),
+    };
+    let entity = world
+      .spawn((
+        Listener {
+          transport: Transport::Loopback,
+          admin,
+          ..default()
+        },
+        NewConns { channel: new_rx },
+      ))
+      .id();
+    debug!(listener_id = ?entity, "spawned loopback listener");
+    LoopbackListener {
+      entity,
+      channel,
+      settings,
+      gate: world.resource::<ConnectionGate>().clone(),
+    }
+  }
+
+  /// The [Listener] entity that connections are spawned under.
+  pub fn entity(&self) -> Entity {
+    self.entity
+  }
+
+  /// Open a new connection. It's spawned the next time the app updates.
+  pub fn connect(&self) -> anyhow::Result<LoopbackClient> {
+    // With no remote address, it counts as local.
+    let admitted = self
+      .gate
+      .admit(None)
+      .map_err(|rejected| anyhow!("connection rejected: {rejected}"))?;
+    let (client, server) = io::duplex(BUFFER_SIZE);
+    let mut bundle = handle_conn(server, None, self.settings, false)?;
+    bundle.conn.admitted = admitted;
+    self
+      .channel
+      .send(bundle)
+      .map_err(|_| anyhow!("loopback listener is gone"))?;
+    Ok(LoopbackClient::new(client))
+  }
+}
+
+type ClientStream = Framed<DuplexStream, tellem::Parser>;
+
+/// The client end of a loopback connection.
+///
+/// Nothing is read until [LoopbackClient::poll] is called. Option requests
+/// from the server are answered as they're read, following the same
+/// [TelnetOptions] rules as the server: options passed to
+/// [LoopbackClient::accept] are agreed to, and everything else is refused.
+pub struct LoopbackClient {
+  read: SplitStream<ClientStream>,
+  write: SplitSink<ClientStream, Event>,
+  /// Our view of the options, so "local" is our side.
+  options: TelnetOptions,
+  /// Text that hasn't been taken yet.
+  text: String,
+  /// Everything other than text, in the order it was received.
+  events: VecDeque<Event>,
+  closed: bool,
+}
+
+impl LoopbackClient {
+  fn new(conn: DuplexStream) -> Self {
+    let (write, read) = Framed::new(conn, tellem::Parser::default()).split();
+    LoopbackClient {
+      read,
+      write,
+      options: default(),
+      text: String::new(),
+      events: VecDeque::new(),
+      closed: false,
+    }
+  }
+
+  /// Agree to `opt` on either side if the server asks.
+  pub fn accept(&mut self, opt: u8) -> &mut Self {
+    self.options.accept(Side::Local, opt);
+    self.options.accept(Side::Remote, opt);
+    self
+  }
+
+  /// Whether we've agreed to `opt` on our side.
+  pub fn local_enabled(&self, opt: u8) -> bool {
+    self.options.local_enabled(opt)
+  }
+
+  /// Whether we've agreed to `opt` on the server's side.
+  pub fn remote_enabled(&self, opt: u8) -> bool {
+    self.options.remote_enabled(opt)
+  }
+
+  /// Send a line of input.
+  pub fn send_line(&mut self, line: impl AsRef<str>) {
+    let line = format!("{}\r\n", line.as_ref());
+    self.send(Event::Data(line.as_bytes().into()));
+  }
+
+  /// Send a telnet event.
+  pub fn send(&mut self, event: Event) {
+    if block_on(self.write.send(event)).is_err() {
+      self.closed = true;
+    }
+  }
+
+  /// Read everything the server has sent so far, without waiting for more.
+  pub fn poll(&mut self) {
+    while let Some(next) = self.read.next().now_or_never() {
+      let event = match next {
+        Some(Ok(event)) => event,
+        Some(Err(err)) => {
+          debug!(?err, "error reading from loopback connection");
+          self.closed = true;
+          return;
+        }
+        None => {
+          self.closed = true;
+          return;
+        }
+      };
+      match event {
+        Event::Data(data) => self.text.push_str(&String::from_utf8_lossy(&data)),
+        event => {
+          if let Event::Negotiation(cmd, opt) = &event {
+            self.negotiate(*cmd, u8::from(*opt));
+          }
+          self.events.push_back(event);
+        }
+      }
+    }
+  }
+
+  /// Answer a request from the server, if it changes anything.
+  fn negotiate(&mut self, cmd: Cmd, opt: u8) {
+    if let (Some(reply), _) = self.options.answer(cmd, opt) {
+      self.send(reply);
+    }
+  }
+
+  /// Text received so far that hasn't been taken.
+  pub fn text(&self) -> &str {
+    &self.text
+  }
+
+  /// Take everything up to and including the first `pattern` in the text
+  /// received so far, if it's there.
+  pub fn take_until(&mut self, pattern: &str) -> Option<String> {
+    let end = self.text.find(pattern)? + pattern.len();
+    let rest = self.text.split_off(end);
+    Some(std::mem::replace(&mut self.text, rest))
+  }
+
+  /// Take the first event received that matches `f`, leaving the rest.
+  pub fn take_event<F>(&mut self, f: F) -> Option<Event>
+  where
+    F: FnMut(&Event) -> bool,
+  {
+    let i = self.events.iter().position(f)?;
+    self.events.remove(i)
+  }
+
+  /// Everything other than text received so far that hasn't been taken.
+  pub fn events(&self) -> impl Iterator<Item = &Event> {
+    self.events.iter()
+  }
+
+  /// Whether the server has closed the connection.
+  pub fn closed(&self) -> bool {
+    self.closed
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    net::{
+      naws::NAWS,
+      options::ECHO,
+      ttype::TTYPE,
+      TelnetOptions,
+      TelnetOut,
+    },
+    testing::TestMud,
+  };
+
+  fn is_negotiation(event: &Event, cmd: Cmd, opt: u8) -> bool {
+    matches!(event, Event::Negotiation(c, o) if *c == cmd && u8::from(*o) == opt)
+  }
+
+  #[test]
+  fn negotiates_options() {
+    let mut mud = TestMud::new();
+    let mut client = mud.connect();
+    client.accept(ECHO);
+    mud.expect(&mut client, "Account name: ");
+    for opt in [NAWS, TTYPE] {
+      mud.expect_event(&mut client, ("DO", opt), |event| {
+        is_negotiation(event, Cmd::DO, opt)
+      });
+      assert!(!client.local_enabled(opt));
+    }
+
+    // Passwords aren't echoed.
+    client.send_line("alice");
+    mud.expect(&mut client, "Password: ");
+    mud.expect_event(&mut client, "WILL ECHO", |event| {
+      is_negotiation(event, Cmd::WILL, ECHO)
+    });
+    assert!(client.remote_enabled(ECHO));
+    client.send_line("pw");
+    mud.expect(&mut client, "Confirm password: ");
+    client.send_line("pw");
+    mud.expect(&mut client, " done!");
+    mud.expect_event(&mut client, "WONT ECHO", |event| {
+      is_negotiation(event, Cmd::WONT, ECHO)
+    });
+    assert!(!client.remote_enabled(ECHO));
+
+    // Refused options stay off.
+    let options = mud
+      .world_mut()
+      .query::<&TelnetOptions>()
+      .single(mud.world());
+    assert!(!options.remote_enabled(NAWS));
+    assert!(!options.local_enabled(ECHO));
+  }
+
+  #[test]
+  fn closes_with_the_server() {
+    let mut mud = TestMud::new();
+    let mut client = mud.connect();
+    mud.expect(&mut client, "Account name: ");
+    let out = mud
+      .world_mut()
+      .query::<&TelnetOut>()
+      .single(mud.world())
+      .clone();
+    out.line("bye");
+    out.close();
+    mud.expect(&mut client, "bye");
+    mud.wait_for(&mut client, "connection closed", |client, _| {
+      client.closed().then_some(())
+    });
+  }
+}
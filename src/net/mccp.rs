@@ -0,0 +1,466 @@
+//! MCCP2 and MCCP3, the Mud Client Compression Protocol.
+//!
+//! Compression happens below the telnet parser, in [MccpCodec], so that it
+//! can switch on at exactly the right byte:
+//!
+//! * MCCP2 (server to client): once the client agrees to `WILL MCCP2`, we send
+//!   `IAC SB MCCP2 IAC SE` and everything after it is a zlib stream. Sending
+//!   `WONT MCCP2` ends the stream first, which turns compression back off.
+//! * MCCP3 (client to server): once MCCP3 is agreed and the client sends
+//!   `IAC SB MCCP3 IAC SE`, everything it sends after is a zlib stream, until
+//!   the client finishes it. The subnegotiation is ignored otherwise.
+
+use std::{
+  fmt::Debug,
+  io,
+  sync::{
+    atomic::{
+      AtomicBool,
+      Ordering,
+    },
+    Arc,
+  },
+};
+
+use bevy::prelude::*;
+use bytes::{
+  Buf,
+  BytesMut,
+};
+use flate2::{
+  Compress,
+  Compression,
+  Decompress,
+  FlushCompress,
+  FlushDecompress,
+  Status,
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use tellem::{
+  Cmd,
+  Event,
+  Opt,
+};
+use tokio_util::codec::{
+  Decoder,
+  Encoder,
+};
+
+use super::{
+  options::{
+    OptionChanged,
+    TelnetOptions,
+  },
+  TelnetEvent,
+  TelnetOut,
+};
+
+pub const MCCP2: u8 = 86;
+pub const MCCP3: u8 = 87;
+
+/// Whether to offer compression to new connections.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MccpConfig {
+  pub enabled: bool,
+}
+
+impl Default for MccpConfig {
+  fn default() -> Self {
+    Self { enabled: true }
+  }
+}
+
+/// The compression state of a connection.
+#[derive(Component, Debug, Default, Clone, Copy, Reflect)]
+#[reflect(Component)]
+pub struct Mccp {
+  /// We're compressing what we send.
+  pub outgoing: bool,
+  /// The client is compressing what it sends.
+  pub incoming: bool,
+}
+
+/// Whether MCCP3 is agreed on a connection, shared between its [MccpCodec]
+/// and the world.
+///
+/// Clients start compressing right after agreeing, sooner than the world
+/// hears about it, so the codec follows the negotiation itself.
+/// [mccp_option_observer] keeps it in line with [TelnetOptions] on top of
+/// that.
+#[derive(Component, Debug, Clone, Default)]
+pub struct Mccp3Agreed(Arc<AtomicBool>);
+
+impl Mccp3Agreed {
+  pub fn get(&self) -> bool {
+    self.0.load(Ordering::Relaxed)
+  }
+
+  pub fn set(&self, agreed: bool) {
+    self.0.store(agreed, Ordering::Relaxed);
+  }
+}
+
+/// Turn outgoing compression on or off for a connection. Turning it on only
+/// offers it, and it starts once the client agrees.
+pub fn set_compression(options: &mut TelnetOptions, out: &TelnetOut, enabled: bool) {
+  if enabled {
+    options.enable_local(out, MCCP2);
+  } else {
+    options.disable_local(out, MCCP2);
+  }
+}
+
+pub fn mccp_option_observer(
+  trigger: Trigger<OptionChanged>,
+  query: Query<(&TelnetOut, Option<&Mccp>)>,
+  agreed: Query<&Mccp3Agreed>,
+  mut cmd: Commands,
+) {
+  let entity = trigger.entity();
+  if let Some(enabled) = trigger.event().local(MCCP3) {
+    if let Ok(agreed) = agreed.get(entity) {
+      agreed.set(enabled);
+    }
+    return;
+  }
+  let Some(enabled) = trigger.event().local(MCCP2) else {
+    return;
+  };
+  let Ok((out, state)) = query.get(entity) else {
+    return;
+  };
+  let mut state = state.copied().unwrap_or_default();
+  if enabled {
+    debug!(?entity, "starting outgoing compression");
+    // The codec starts compressing right after this.
+    out.telnet(Event::Subnegotiation(Opt::from(MCCP2), BytesMut::new()));
+  } else {
+    // The WONT that got us here already ended the stream.
+    debug!(?entity, "stopped outgoing compression");
+  }
+  state.outgoing = enabled;
+  cmd.entity(entity).insert(state);
+}
+
+pub fn mccp_observer(
+  trigger: Trigger<TelnetEvent>,
+  query: Query<(Option<&Mccp>, &TelnetOptions), With<TelnetOut>>,
+  mut cmd: Commands,
+) {
+  let entity = trigger.entity();
+  if !is_start(trigger.event(), MCCP3) {
+    return;
+  }
+  let Ok((state, options)) = query.get(entity) else {
+    return;
+  };
+  if !options.local_enabled(MCCP3) {
+    debug!(?entity, "ignoring MCCP3 start that wasn't agreed");
+    return;
+  }
+  debug!(?entity, "client started compressing");
+  let mut state = state.copied().unwrap_or_default();
+  state.incoming = true;
+  cmd.entity(entity).insert(state);
+}
+
+fn is_start(event: &Event, opt: u8) -> bool {
+  matches!(event, Event::Subnegotiation(o, _) if u8::from(*o) == opt)
+}
+
+fn is_negotiation(event: &Event, cmd: Cmd, opt: u8) -> bool {
+  matches!(event, Event::Negotiation(c, o) if *c == cmd && u8::from(*o) == opt)
+}
+
+fn codec_error(error: impl Debug) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("{error:?}"))
+}
+
+/// Wraps the telnet parser with MCCP compression.
+pub struct MccpCodec {
+  inner: tellem::Parser,
+  deflate: Option<Compress>,
+  inflate: Option<Decompress>,
+  /// Decompressed input that hasn't been parsed yet.
+  plain: BytesMut,
+  /// We've sent `WILL MCCP3`.
+  offered: bool,
+  /// The client has sent `DO MCCP3`.
+  requested: bool,
+  agreed: Mccp3Agreed,
+}
+
+impl Default for MccpCodec {
+  fn default() -> Self {
+    Self::new(Mccp3Agreed::default())
+  }
+}
+
+impl MccpCodec {
+  pub fn new(agreed: Mccp3Agreed) -> Self {
+    Self {
+      inner: tellem::Parser::default(),
+      deflate: None,
+      inflate: None,
+      plain: BytesMut::new(),
+      offered: false,
+      requested: false,
+      agreed,
+    }
+  }
+
+  /// Follow the MCCP3 negotiation in either direction.
+  fn negotiate(&mut self, event: &Event) {
+    if is_negotiation(event, Cmd::WILL, MCCP3) {
+      self.offered = true;
+    } else if is_negotiation(event, Cmd::DO, MCCP3) {
+      self.requested = true;
+    } else if is_negotiation(event, Cmd::WONT, MCCP3) || is_negotiation(event, Cmd::DONT, MCCP3) {
+      self.offered = false;
+      self.requested = false;
+      self.agreed.set(false);
+      return;
+    } else {
+      return;
+    }
+    if self.offered && self.requested {
+      self.agreed.set(true);
+    }
+  }
+}
+
+impl Decoder for MccpCodec {
+  type Item = Event;
+  type Error = io::Error;
+
+  fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Event>> {
+    if let Some(inflate) = &mut self.inflate {
+      if inflate_into(inflate, src, &mut self.plain)? {
+        // The client finished its stream, so the rest is uncompressed.
+        self.inflate = None;
+      }
+    }
+    if self.inflate.is_none() && !self.plain.is_empty() {
+      // Still working through what was decompressed, so keep new input
+      // behind it.
+      self.plain.extend_from_slice(&src.split());
+    }
+
+    let staged = self.inflate.is_some() || !self.plain.is_empty();
+    let buf = if staged { &mut self.plain } else { &mut *src };
+    let event = self.inner.decode(buf).map_err(codec_error)?;
+    if let Some(event) = &event {
+      self.negotiate(event);
+    }
+
+    let start = event.as_ref().is_some_and(|e| is_start(e, MCCP3));
+    if self.inflate.is_none() && start && self.agreed.get() {
+      // Everything after this is compressed, including anything we've
+      // already moved over to the plain buffer.
+      if !self.plain.is_empty() {
+        let mut rest = self.plain.split();
+        rest.extend_from_slice(src);
+        *src = rest;
+      }
+      self.inflate = Some(Decompress::new(true));
+    }
+
+    Ok(event)
+  }
+}
+
+impl Encoder<Event> for MccpCodec {
+  type Error = io::Error;
+
+  fn encode(&mut self, item: Event, dst: &mut BytesMut) -> io::Result<()> {
+    self.negotiate(&item);
+    let stop = matches!(&item, Event::Negotiation(Cmd::WONT, o) if u8::from(*o) == MCCP2);
+    if stop {
+      if let Some(mut deflate) = self.deflate.take() {
+        deflate_into(&mut deflate, &[], dst, FlushCompress::Finish)?;
+      }
+    }
+
+    let start = self.deflate.is_none() && is_start(&item, MCCP2);
+
+    match &mut self.deflate {
+      Some(deflate) => {
+        let mut plain = BytesMut::new();
+        self.inner.encode(item, &mut plain).map_err(codec_error)?;
+        deflate_into(deflate, &plain, dst, FlushCompress::Sync)?;
+      }
+      None => self.inner.encode(item, dst).map_err(codec_error)?,
+    }
+
+    if start {
+      self.deflate = Some(Compress::new(Compression::default(), true));
+    }
+
+    Ok(())
+  }
+}
+
+/// Decompress as much of `src` as possible into `dst`. Returns true if the
+/// end of the compressed stream was reached.
+fn inflate_into(
+  inflate: &mut Decompress,
+  src: &mut BytesMut,
+  dst: &mut BytesMut,
+) -> io::Result<bool> {
+  loop {
+    let mut out = Vec::with_capacity(4096.max(src.len() * 4));
+    let before = inflate.total_in();
+    let status = inflate
+      .decompress_vec(src, &mut out, FlushDecompress::None)
+      .map_err(codec_error)?;
+    let consumed = (inflate.total_in() - before) as usize;
+    src.advance(consumed);
+    dst.extend_from_slice(&out);
+
+    if status == Status::StreamEnd {
+      return Ok(true);
+    }
+    let filled = out.len() == out.capacity();
+    if !filled && (src.is_empty() || consumed == 0) {
+      return Ok(false);
+    }
+  }
+}
+
+fn deflate_into(
+  deflate: &mut Compress,
+  mut input: &[u8],
+  dst: &mut BytesMut,
+  flush: FlushCompress,
+) -> io::Result<()> {
+  loop {
+    let mut out = Vec::with_capacity(input.len() + 64);
+    let before = deflate.total_in();
+    let status = deflate
+      .compress_vec(input, &mut out, flush)
+      .map_err(codec_error)?;
+    input = &input[(deflate.total_in() - before) as usize..];
+    dst.extend_from_slice(&out);
+
+    if status == Status::StreamEnd {
+      return Ok(());
+    }
+    if input.is_empty() && out.len() < out.capacity() {
+      return Ok(());
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::io::Write;
+
+  use flate2::write::ZlibEncoder;
+  use futures::{
+    executor::block_on,
+    SinkExt,
+    StreamExt,
+  };
+  use tokio::io::{
+    AsyncReadExt,
+    AsyncWriteExt,
+  };
+  use tokio_util::codec::Framed;
+
+  use super::*;
+  use crate::net::options::{
+    negotiation,
+    Side,
+  };
+
+  const IAC: u8 = 255;
+  const SB: u8 = 250;
+  const SE: u8 = 240;
+  const WONT: u8 = 252;
+  const DO: u8 = 253;
+
+  #[test]
+  fn loopback() {
+    block_on(async {
+      let (server, mut client) = tokio::io::duplex(1 << 16);
+      let mut server = Framed::new(server, MccpCodec::default());
+      let mut buf = vec![0; 4096];
+
+      // Server to client.
+      server
+        .send(Event::Subnegotiation(Opt::from(MCCP2), BytesMut::new()))
+        .await
+        .unwrap();
+      server.send(Event::Data("hello".into())).await.unwrap();
+      server
+        .send(negotiation(Side::Local, MCCP2, false))
+        .await
+        .unwrap();
+
+      let n = client.read(&mut buf).await.unwrap();
+      let received = &buf[..n];
+      assert_eq!(&received[..5], &[IAC, SB, MCCP2, IAC, SE]);
+
+      let mut inflate = Decompress::new(true);
+      let mut plain = Vec::with_capacity(1024);
+      let status = inflate
+        .decompress_vec(&received[5..], &mut plain, FlushDecompress::None)
+        .unwrap();
+      assert_eq!(status, Status::StreamEnd);
+      assert_eq!(plain, b"hello");
+      // Back to plain telnet after the stream ends.
+      let rest = &received[5 + inflate.total_in() as usize..];
+      assert_eq!(rest, &[IAC, WONT, MCCP2]);
+
+      // Client to server, which has to be agreed first.
+      server
+        .send(negotiation(Side::Local, MCCP3, true))
+        .await
+        .unwrap();
+      client.read(&mut buf).await.unwrap();
+      client.write_all(&[IAC, DO, MCCP3]).await.unwrap();
+      server.next().await.unwrap().unwrap();
+      let mut compressed = ZlibEncoder::new(vec![], Compression::default());
+      compressed.write_all(b"look\r\n").unwrap();
+      compressed.flush().unwrap();
+      let mut sent = vec![IAC, SB, MCCP3, IAC, SE];
+      sent.extend(compressed.get_ref());
+      client.write_all(&sent).await.unwrap();
+
+      let started = server.next().await.unwrap().unwrap();
+      assert!(is_start(&started, MCCP3));
+      match server.next().await.unwrap().unwrap() {
+        Event::Data(data) => assert_eq!(&data[..], b"look\r\n"),
+        other => panic!("unexpected event: {other:?}"),
+      }
+    });
+  }
+
+  #[test]
+  fn ignores_mccp3_start_unless_agreed() {
+    block_on(async {
+      let (server, mut client) = tokio::io::duplex(1 << 16);
+      let agreed = Mccp3Agreed::default();
+      let mut server = Framed::new(server, MccpCodec::new(agreed.clone()));
+
+      // Asking for it isn't enough without the offer.
+      client
+        .write_all(&[IAC, DO, MCCP3, IAC, SB, MCCP3, IAC, SE])
+        .await
+        .unwrap();
+      client.write_all(b"look\r\n").await.unwrap();
+      server.next().await.unwrap().unwrap();
+      let started = server.next().await.unwrap().unwrap();
+      assert!(is_start(&started, MCCP3));
+      assert!(!agreed.get());
+      match server.next().await.unwrap().unwrap() {
+        Event::Data(data) => assert_eq!(&data[..], b"look\r\n"),
+        other => panic!("unexpected event: {other:?}"),
+      }
+    });
+  }
+}
@@ -0,0 +1,447 @@
+//! MSDP, the Mud Server Data Protocol.
+//!
+//! Clients ask for variables by name with `LIST`, `SEND`, `REPORT` and
+//! `UNREPORT`. The variables themselves come from game plugins, which register
+//! them with [MsdpAppExt::msdp_variable] as a function of some component.
+//! Reported variables are sent again whenever that component changes, either
+//! on the connection itself or on the character it's playing.
+
+use std::sync::Arc;
+
+use anyhow::{
+  anyhow,
+  bail,
+};
+use bevy::{
+  prelude::*,
+  utils::{
+    HashMap,
+    HashSet,
+  },
+};
+use bytes::BytesMut;
+use tellem::{
+  Event,
+  Opt,
+};
+
+use super::{
+  options::OptionChanged,
+  TelnetEvent,
+  TelnetOut,
+};
+use crate::character::{
+  Player,
+  Puppet,
+};
+
+pub const MSDP: u8 = 69;
+
+const VAR: u8 = 1;
+const VAL: u8 = 2;
+const TABLE_OPEN: u8 = 3;
+const TABLE_CLOSE: u8 = 4;
+const ARRAY_OPEN: u8 = 5;
+const ARRAY_CLOSE: u8 = 6;
+
+/// How deeply tables and arrays can be nested in what clients send.
+const MAX_DEPTH: usize = 16;
+
+const COMMANDS: [&str; 5] = ["LIST", "REPORT", "RESET", "SEND", "UNREPORT"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MsdpValue {
+  String(String),
+  Array(Vec<MsdpValue>),
+  Table(Vec<(String, MsdpValue)>),
+}
+
+impl MsdpValue {
+  fn encode(&self, buf: &mut BytesMut) {
+    match self {
+      MsdpValue::String(s) => buf.extend_from_slice(s.as_bytes()),
+      MsdpValue::Array(values) => {
+        buf.extend_from_slice(&[ARRAY_OPEN]);
+        for value in values {
+          buf.extend_from_slice(&[VAL]);
+          value.encode(buf);
+        }
+        buf.extend_from_slice(&[ARRAY_CLOSE]);
+      }
+      MsdpValue::Table(pairs) => {
+        buf.extend_from_slice(&[TABLE_OPEN]);
+        encode_pairs(pairs.iter().map(|(k, v)| (k.as_str(), v)), buf);
+        buf.extend_from_slice(&[TABLE_CLOSE]);
+      }
+    }
+  }
+
+  /// The value's strings, whether it's a single string or an array of them.
+  fn strings(&self) -> Vec<&str> {
+    match self {
+      MsdpValue::String(s) => vec![s.as_str()],
+      MsdpValue::Array(values) => values.iter().flat_map(|v| v.strings()).collect(),
+      MsdpValue::Table(_) => vec![],
+    }
+  }
+}
+
+impl From<String> for MsdpValue {
+  fn from(s: String) -> Self {
+    MsdpValue::String(s)
+  }
+}
+
+impl From<&str> for MsdpValue {
+  fn from(s: &str) -> Self {
+    MsdpValue::String(s.into())
+  }
+}
+
+macro_rules! from_display {
+  ($($ty:ty),*) => {
+    $(
+      impl From<$ty> for MsdpValue {
+        fn from(v: $ty) -> Self {
+          MsdpValue::String(v.to_string())
+        }
+      }
+    )*
+  };
+}
+
+from_display!(i32, i64, u32, u64, usize, f32, bool);
+
+impl<T: Into<MsdpValue>> From<Vec<T>> for MsdpValue {
+  fn from(values: Vec<T>) -> Self {
+    MsdpValue::Array(values.into_iter().map(Into::into).collect())
+  }
+}
+
+fn encode_pairs<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a MsdpValue)>, buf: &mut BytesMut) {
+  for (name, value) in pairs {
+    buf.extend_from_slice(&[VAR]);
+    buf.extend_from_slice(name.as_bytes());
+    buf.extend_from_slice(&[VAL]);
+    value.encode(buf);
+  }
+}
+
+/// Parse the body of an MSDP subnegotiation. A variable with more than one
+/// value is treated as an array. Tables and arrays nested more than
+/// [MAX_DEPTH] deep are an error.
+pub fn parse(data: &[u8]) -> anyhow::Result<Vec<(String, MsdpValue)>> {
+  let mut pos = 0;
+  parse_pairs(data, &mut pos, None, 0)
+}
+
+fn parse_pairs(
+  data: &[u8],
+  pos: &mut usize,
+  close: Option<u8>,
+  depth: usize,
+) -> anyhow::Result<Vec<(String, MsdpValue)>> {
+  let mut pairs = vec![];
+  loop {
+    let Some(&b) = data.get(*pos) else {
+      if close.is_some() {
+        bail!("unterminated table");
+      }
+      return Ok(pairs);
+    };
+    *pos += 1;
+    if Some(b) == close {
+      return Ok(pairs);
+    }
+    if b != VAR {
+      bail!("expected MSDP_VAR, got {b}");
+    }
+    let name = parse_text(data, pos);
+    let mut values = vec![];
+    while data.get(*pos) == Some(&VAL) {
+      *pos += 1;
+      values.push(parse_value(data, pos, depth)?);
+    }
+    let value = match values.len() {
+      0 => MsdpValue::String(String::new()),
+      1 => values.pop().unwrap(),
+      _ => MsdpValue::Array(values),
+    };
+    pairs.push((name, value));
+  }
+}
+
+fn parse_value(data: &[u8], pos: &mut usize, depth: usize) -> anyhow::Result<MsdpValue> {
+  if matches!(data.get(*pos), Some(&TABLE_OPEN | &ARRAY_OPEN)) && depth >= MAX_DEPTH {
+    bail!("tables and arrays nested too deeply");
+  }
+  match data.get(*pos) {
+    Some(&TABLE_OPEN) => {
+      *pos += 1;
+      Ok(MsdpValue::Table(parse_pairs(
+        data,
+        pos,
+        Some(TABLE_CLOSE),
+        depth + 1,
+      )?))
+    }
+    Some(&ARRAY_OPEN) => {
+      *pos += 1;
+      let mut values = vec![];
+      loop {
+        match data.get(*pos) {
+          Some(&ARRAY_CLOSE) => {
+            *pos += 1;
+            return Ok(MsdpValue::Array(values));
+          }
+          Some(&VAL) => {
+            *pos += 1;
+            values.push(parse_value(data, pos, depth + 1)?);
+          }
+          Some(b) => bail!("expected MSDP_VAL, got {b}"),
+          None => bail!("unterminated array"),
+        }
+      }
+    }
+    _ => Ok(MsdpValue::String(parse_text(data, pos))),
+  }
+}
+
+fn parse_text(data: &[u8], pos: &mut usize) -> String {
+  let start = *pos;
+  while data
+    .get(*pos)
+    .is_some_and(|b| !(VAR..=ARRAY_CLOSE).contains(b))
+  {
+    *pos += 1;
+  }
+  String::from_utf8_lossy(&data[start..*pos]).into_owned()
+}
+
+type Getter = Arc<dyn Fn(&World, Entity) -> Option<MsdpValue> + Send + Sync>;
+
+/// The variables that clients can ask for.
+#[derive(Resource, Default, Clone)]
+pub struct MsdpVariables(HashMap<String, Getter>);
+
+impl MsdpVariables {
+  /// Look up `name` for a connection, checking the connection first and then
+  /// its character.
+  pub fn get(&self, world: &World, conn: Entity, name: &str) -> Option<MsdpValue> {
+    let getter = self.0.get(name)?;
+    getter(world, conn).or_else(|| {
+      let puppet = world.get::<Puppet>(conn)?;
+      getter(world, puppet.0)
+    })
+  }
+
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.0.keys().map(String::as_str)
+  }
+}
+
+/// The variables a connection has asked to have reported.
+#[derive(Component, Debug, Clone, Default, Deref)]
+pub struct MsdpReported(HashSet<String>);
+
+pub trait MsdpAppExt {
+  /// Make `name` available over MSDP, computed from the `C` on either the
+  /// connection or its character.
+  fn msdp_variable<C: Component>(
+    &mut self,
+    name: &str,
+    value: impl Fn(&C) -> MsdpValue + Send + Sync + 'static,
+  ) -> &mut Self;
+}
+
+impl MsdpAppExt for App {
+  fn msdp_variable<C: Component>(
+    &mut self,
+    name: &str,
+    value: impl Fn(&C) -> MsdpValue + Send + Sync + 'static,
+  ) -> &mut Self {
+    let value = Arc::new(value);
+    let getter = value.clone();
+    self
+      .world_mut()
+      .get_resource_or_insert_with(MsdpVariables::default)
+      .0
+      .insert(
+        name.into(),
+        Arc::new(move |world: &World, entity| world.get::<C>(entity).map(|c| getter(c))),
+      );
+
+    let name = name.to_string();
+    self.add_systems(
+      PostUpdate,
+      move |changed: Query<(Entity, &C), Changed<C>>,
+            players: Query<&Player>,
+            conns: Query<(&TelnetOut, &MsdpReported)>| {
+        for (entity, component) in changed.iter() {
+          let conn = players.get(entity).map(|p| p.0).unwrap_or(entity);
+          let Ok((out, reported)) = conns.get(conn) else {
+            continue;
+          };
+          if reported.contains(&name) {
+            send(out, [(name.as_str(), &value(component))]);
+          }
+        }
+      },
+    )
+  }
+}
+
+fn send<'a>(out: &TelnetOut, pairs: impl IntoIterator<Item = (&'a str, &'a MsdpValue)>) {
+  let mut buf = BytesMut::new();
+  encode_pairs(pairs, &mut buf);
+  out.telnet(Event::Subnegotiation(Opt::from(MSDP), buf));
+}
+
+pub fn msdp_option_observer(trigger: Trigger<OptionChanged>, mut cmd: Commands) {
+  let entity = trigger.entity();
+  match trigger.event().local(MSDP) {
+    Some(true) => {
+      debug!(?entity, "enabling MSDP");
+      cmd.entity(entity).insert(MsdpReported::default());
+    }
+    Some(false) => {
+      cmd.entity(entity).remove::<MsdpReported>();
+    }
+    None => {}
+  }
+}
+
+pub fn msdp_observer(trigger: Trigger<TelnetEvent>, mut cmd: Commands) {
+  let entity = trigger.entity();
+  let Event::Subnegotiation(opt, data) = &**trigger.event() else {
+    return;
+  };
+  if u8::from(*opt) != MSDP {
+    return;
+  }
+  match parse(data) {
+    Ok(pairs) => {
+      cmd.queue(move |world: &mut World| {
+        for (command, args) in pairs {
+          if let Err(error) = handle_command(world, entity, &command, &args) {
+            debug!(?entity, %error, command, "bad MSDP command");
+          }
+        }
+      });
+    }
+    Err(error) => debug!(?entity, %error, "invalid MSDP subnegotiation"),
+  }
+}
+
+fn handle_command(
+  world: &mut World,
+  entity: Entity,
+  command: &str,
+  args: &MsdpValue,
+) -> anyhow::Result<()> {
+  let variables = world
+    .get_resource::<MsdpVariables>()
+    .cloned()
+    .unwrap_or_default();
+  let names = args.strings();
+
+  let mut reported = world
+    .get_mut::<MsdpReported>(entity)
+    .ok_or_else(|| anyhow!("MSDP not enabled"))?;
+  let mut send_values = false;
+  match command {
+    "REPORT" => {
+      reported.0.extend(
+        names
+          .iter()
+          .filter(|n| variables.0.contains_key(**n))
+          .map(|n| n.to_string()),
+      );
+      send_values = true;
+    }
+    "UNREPORT" => {
+      for name in &names {
+        reported.0.remove(*name);
+      }
+    }
+    "RESET" => reported.0.clear(),
+    "SEND" => send_values = true,
+    "LIST" => {}
+    _ => bail!("unknown command"),
+  }
+  let reported = reported.clone();
+
+  let out = world
+    .get::<TelnetOut>(entity)
+    .ok_or_else(|| anyhow!("not a connection"))?;
+
+  if command == "LIST" {
+    for list in names {
+      let value: MsdpValue = match list {
+        "COMMANDS" => COMMANDS.to_vec().into(),
+        "REPORTABLE_VARIABLES" | "VARIABLES" => {
+          let mut names = variables.names().collect::<Vec<_>>();
+          names.sort();
+          names.into()
+        }
+        "REPORTED_VARIABLES" => {
+          let mut names = reported.iter().map(String::as_str).collect::<Vec<_>>();
+          names.sort();
+          names.into()
+        }
+        _ => continue,
+      };
+      send(out, [(list, &value)]);
+    }
+  } else if send_values {
+    let values = names
+      .iter()
+      .filter_map(|name| Some((*name, variables.get(world, entity, name)?)))
+      .collect::<Vec<_>>();
+    send(out, values.iter().map(|(n, v)| (*n, v)));
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn round_trips_values() {
+    let value = MsdpValue::Table(vec![
+      ("NAME".into(), "goblin".into()),
+      ("EXITS".into(), vec!["n", "se"].into()),
+    ]);
+    let mut buf = BytesMut::new();
+    encode_pairs([("ROOM", &value)], &mut buf);
+    assert_eq!(parse(&buf).unwrap(), vec![("ROOM".into(), value)]);
+  }
+
+  #[test]
+  fn multiple_values_are_arrays() {
+    let report = parse(b"\x01REPORT\x02HEALTH\x02MANA").unwrap();
+    assert_eq!(report[0].0, "REPORT");
+    assert_eq!(report[0].1.strings(), ["HEALTH", "MANA"]);
+    assert!(parse(b"\x01ROOM\x02\x03\x01NAME\x02x").is_err());
+  }
+
+  #[test]
+  fn limits_nesting() {
+    let nested = |depth: usize| {
+      let mut data = b"\x01X\x02".to_vec();
+      data.extend([ARRAY_OPEN, VAL].repeat(depth));
+      data.extend(std::iter::repeat(ARRAY_CLOSE).take(depth));
+      data
+    };
+    assert!(parse(&nested(MAX_DEPTH)).is_ok());
+    let err = parse(&nested(MAX_DEPTH + 1)).unwrap_err();
+    assert!(err.to_string().contains("nested too deeply"), "{err}");
+
+    // Deep enough to overflow the stack without a limit.
+    let mut data = b"\x01X\x02".to_vec();
+    data.extend([TABLE_OPEN, VAR, VAL].repeat(1_000_000));
+    assert!(parse(&data).is_err());
+  }
+}
@@ -0,0 +1,114 @@
+//! MSSP, the Mud Server Status Protocol.
+//!
+//! Mud listing crawlers connect, wait for `WILL MSSP`, answer `DO MSSP` and
+//! read back a list of `MSSP_VAR <name> MSSP_VAL <value>` pairs describing the
+//! server.
+
+use std::time::{
+  SystemTime,
+  UNIX_EPOCH,
+};
+
+use bevy::prelude::*;
+use bytes::BytesMut;
+use tellem::{
+  Event,
+  Opt,
+};
+
+use super::{
+  options::OptionChanged,
+  ListenAddrs,
+  TelnetOut,
+};
+use crate::{
+  account::Session,
+  config::ServerConfig,
+};
+
+pub const MSSP: u8 = 70;
+
+const VAR: u8 = 1;
+const VAL: u8 = 2;
+
+/// When the server started, for reporting uptime.
+#[derive(Resource, Debug, Clone, Copy, Deref)]
+pub struct StartTime(pub SystemTime);
+
+impl Default for StartTime {
+  fn default() -> Self {
+    StartTime(SystemTime::now())
+  }
+}
+
+fn encode<'a>(vars: impl IntoIterator<Item = (&'a str, String)>) -> BytesMut {
+  let mut buf = BytesMut::new();
+  for (name, value) in vars {
+    buf.extend_from_slice(&[VAR]);
+    buf.extend_from_slice(name.as_bytes());
+    buf.extend_from_slice(&[VAL]);
+    buf.extend_from_slice(value.as_bytes());
+  }
+  buf
+}
+
+/// The current server status.
+fn status(world: &mut World) -> Vec<(&'static str, String)> {
+  let name = world
+    .get_resource::<ServerConfig>()
+    .map(|config| config.name.clone())
+    .unwrap_or_else(|| ServerConfig::default().name);
+  let players = world.query::<&Session>().iter(world).count();
+  let uptime = world
+    .get_resource::<StartTime>()
+    .and_then(|start| start.duration_since(UNIX_EPOCH).ok())
+    .map(|since| since.as_secs())
+    .unwrap_or_default();
+  let port = world
+    .get_resource::<ListenAddrs>()
+    .and_then(|addrs| addrs.iter().find_map(|listen| listen.addr.port()))
+    .unwrap_or(super::DEFAULT_PORT);
+
+  vec![
+    ("NAME", name),
+    ("PLAYERS", players.to_string()),
+    ("UPTIME", uptime.to_string()),
+    ("PORT", port.to_string()),
+    (
+      "CODEBASE",
+      format!("bevy_mud {}", env!("CARGO_PKG_VERSION")),
+    ),
+  ]
+}
+
+pub fn mssp_observer(trigger: Trigger<OptionChanged>, mut cmd: Commands) {
+  let entity = trigger.entity();
+  if trigger.event().local(MSSP) != Some(true) {
+    return;
+  }
+  cmd.queue(move |world: &mut World| {
+    let status = status(world);
+    debug!(?entity, ?status, "sending MSSP");
+    if let Some(out) = world.get::<TelnetOut>(entity) {
+      out.telnet(Event::Subnegotiation(Opt::from(MSSP), encode(status)));
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn reports_status() {
+    let mut world = World::new();
+    world.insert_resource(StartTime(UNIX_EPOCH + std::time::Duration::from_secs(42)));
+    world.spawn(Session::default());
+    world.spawn(Session::default());
+
+    let status = status(&mut world);
+    assert!(status.contains(&("PLAYERS", "2".into())));
+    assert!(status.contains(&("UPTIME", "42".into())));
+    assert_eq!(&encode(status)[..14], b"\x01NAME\x02bevy_mud");
+  }
+}
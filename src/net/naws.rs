@@ -0,0 +1,178 @@
+//! NAWS, Negotiate About Window Size (RFC 1073).
+//!
+//! We ask every client to `DO NAWS`, and clients that agree send their terminal
+//! size as `IAC SB NAWS <width> <height> IAC SE`, with each dimension as a big
+//! endian u16, and again whenever it changes. The latest size is kept in a
+//! [WindowSize] on the connection entity, and a [WindowResized] is triggered
+//! on it when the size changes.
+
+use bevy::prelude::*;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use tellem::Event;
+
+use super::{
+  options::OptionChanged,
+  TelnetEvent,
+  TelnetOut,
+};
+
+pub const NAWS: u8 = 31;
+
+/// The size of a client's terminal, in characters.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Reflect, Serialize, Deserialize)]
+#[reflect(Component)]
+pub struct WindowSize {
+  pub width: u16,
+  pub height: u16,
+}
+
+impl WindowSize {
+  /// Parse the body of a NAWS subnegotiation.
+  pub fn parse(data: &[u8]) -> Option<Self> {
+    let [w0, w1, h0, h1] = *data else {
+      return None;
+    };
+    Some(WindowSize {
+      width: u16::from_be_bytes([w0, w1]),
+      height: u16::from_be_bytes([h0, h1]),
+    })
+  }
+}
+
+/// Triggered on a connection when its [WindowSize] changes.
+#[derive(Event, Debug, Clone, Copy, Deref)]
+pub struct WindowResized(pub WindowSize);
+
+/// Forget the window size of clients that stop reporting it.
+pub fn naws_option_observer(
+  trigger: Trigger<OptionChanged>,
+  query: Query<&TelnetOut>,
+  mut cmd: Commands,
+) {
+  if trigger.event().remote(NAWS) != Some(false) {
+    return;
+  }
+  let entity = trigger.entity();
+  if let Ok(out) = query.get(entity) {
+    out.set_width(0);
+    cmd.entity(entity).remove::<WindowSize>();
+  }
+}
+
+pub fn naws_observer(
+  trigger: Trigger<TelnetEvent>,
+  query: Query<(&TelnetOut, Option<&WindowSize>)>,
+  mut cmd: Commands,
+) {
+  let Event::Subnegotiation(opt, data) = &**trigger.event() else {
+    return;
+  };
+  if u8::from(*opt) != NAWS {
+    return;
+  }
+  let entity = trigger.entity();
+  let Ok((out, current)) = query.get(entity) else {
+    return;
+  };
+  let Some(size) = WindowSize::parse(data) else {
+    debug!(?entity, ?data, "invalid NAWS subnegotiation");
+    return;
+  };
+  if current == Some(&size) {
+    return;
+  }
+  debug!(?entity, ?size, "client window resized");
+  // A dimension of zero means the client doesn't know, so don't wrap.
+  out.set_width(size.width);
+  cmd.entity(entity).insert(size);
+  cmd.trigger_targets(WindowResized(size), entity);
+}
+
+/// Word wrap `line` to `width` columns. ANSI escape sequences don't count
+/// towards the width, and words longer than a line are broken wherever they
+/// run out of room.
+pub fn wrap(line: &str, width: usize) -> String {
+  if width == 0 {
+    return line.into();
+  }
+
+  let mut out = String::with_capacity(line.len());
+  let mut column = 0;
+  for (i, word) in line.split(' ').enumerate() {
+    let len = visible_len(word);
+    if i > 0 {
+      if column > 0 && column + 1 + len > width {
+        out.push_str("\r\n");
+        column = 0;
+      } else {
+        out.push(' ');
+        column += 1;
+      }
+    }
+    let mut chars = word.chars().peekable();
+    while let Some(c) = chars.next() {
+      if c == '\x1b' {
+        out.push(c);
+        for c in chars.by_ref() {
+          out.push(c);
+          if c.is_ascii_alphabetic() {
+            break;
+          }
+        }
+        continue;
+      }
+      if column == width {
+        out.push_str("\r\n");
+        column = 0;
+      }
+      out.push(c);
+      column += 1;
+    }
+  }
+  out
+}
+
+fn visible_len(s: &str) -> usize {
+  let mut len = 0;
+  let mut escape = false;
+  for c in s.chars() {
+    match (escape, c) {
+      (false, '\x1b') => escape = true,
+      (false, _) => len += 1,
+      (true, c) if c.is_ascii_alphabetic() => escape = false,
+      _ => {}
+    }
+  }
+  len
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parses_window_size() {
+    assert_eq!(
+      WindowSize::parse(&[0, 120, 1, 0]),
+      Some(WindowSize {
+        width: 120,
+        height: 256,
+      })
+    );
+    assert_eq!(WindowSize::parse(&[0, 80, 24]), None);
+  }
+
+  #[test]
+  fn wraps_words() {
+    assert_eq!(wrap("the quick brown fox", 10), "the quick\r\nbrown fox");
+    assert_eq!(wrap("abcdefghij", 4), "abcd\r\nefgh\r\nij");
+    assert_eq!(
+      wrap("\x1b[1mhello\x1b[0m world", 11),
+      "\x1b[1mhello\x1b[0m world"
+    );
+    assert_eq!(wrap("unchanged", 0), "unchanged");
+  }
+}
@@ -0,0 +1,304 @@
+//! Telnet option negotiation, following the Q method from RFC 1143.
+//!
+//! Every option has two sides: local, which we enable with `WILL` and the
+//! client agrees to with `DO`, and remote, which is the other way around. Each
+//! side of each option is tracked separately in [TelnetOptions], which makes
+//! sure that requests are only sent when they'd change something, that every
+//! request gets exactly one answer, and that a request made while another is
+//! still in flight is queued rather than sent. This is what stops negotiation
+//! loops between us and clients that answer everything.
+//!
+//! Options are only considered enabled once the other side agrees. When that
+//! happens, or when an enabled option is turned off, an [OptionChanged] is
+//! triggered on the connection.
+
+use bevy::{
+  prelude::*,
+  utils::{
+    HashMap,
+    HashSet,
+  },
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use tellem::{
+  Cmd,
+  Event,
+  Opt,
+};
+
+use super::{
+  TelnetEvent,
+  TelnetOut,
+};
+
+pub const ECHO: u8 = 1;
+pub const GMCP: u8 = 201;
+
+/// The state of one side of an option. The `bool` in the `Want` states is the
+/// queue bit: whether the opposite request was made while waiting for an
+/// answer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum Q {
+  #[default]
+  No,
+  Yes,
+  WantNo(bool),
+  WantYes(bool),
+}
+
+impl Q {
+  /// Whether the option is in effect. An option that we've asked to disable
+  /// stays in effect until the other side agrees.
+  fn enabled(self) -> bool {
+    matches!(self, Q::Yes | Q::WantNo(_))
+  }
+
+  /// Ask for the option to be enabled or disabled. Returns the request to send,
+  /// if any.
+  fn request(&mut self, enable: bool) -> Option<bool> {
+    let (next, send) = match (*self, enable) {
+      (Q::No, true) => (Q::WantYes(false), Some(true)),
+      (Q::Yes, false) => (Q::WantNo(false), Some(false)),
+      (Q::WantNo(_), true) => (Q::WantNo(true), None),
+      (Q::WantNo(_), false) => (Q::WantNo(false), None),
+      (Q::WantYes(_), true) => (Q::WantYes(false), None),
+      (Q::WantYes(_), false) => (Q::WantYes(true), None),
+      (state, _) => (state, None),
+    };
+    *self = next;
+    send
+  }
+
+  /// Handle a request or answer from the other side. `accept` is whether we're
+  /// willing to enable the option if this is an unsolicited request. Returns
+  /// the reply to send, if any.
+  fn receive(&mut self, enable: bool, accept: bool) -> Option<bool> {
+    let (next, reply) = match (*self, enable) {
+      (Q::No, true) if accept => (Q::Yes, Some(true)),
+      (Q::No, true) => (Q::No, Some(false)),
+      (Q::Yes, false) => (Q::No, Some(false)),
+      // An answer to our request, which may have had the opposite queued.
+      (Q::WantYes(false), true) => (Q::Yes, None),
+      (Q::WantYes(true), true) => (Q::WantNo(false), Some(false)),
+      (Q::WantYes(_), false) => (Q::No, None),
+      (Q::WantNo(false), false) => (Q::No, None),
+      (Q::WantNo(true), false) => (Q::WantYes(false), Some(true)),
+      // A request to disable answered by enabling, which is an error on the
+      // other side.
+      (Q::WantNo(false), true) => (Q::No, None),
+      (Q::WantNo(true), true) => (Q::Yes, None),
+      (state, _) => (state, None),
+    };
+    *self = next;
+    reply
+  }
+}
+
+/// Which side of the connection an option applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+  /// Us, enabled with `WILL`.
+  Local,
+  /// The client, enabled with `DO`.
+  Remote,
+}
+
+/// Triggered on a connection when one of its options is enabled or disabled.
+#[derive(Event, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptionChanged {
+  pub opt: u8,
+  pub side: Side,
+  pub enabled: bool,
+}
+
+impl OptionChanged {
+  /// Whether our side of `opt` changed, and if so, to what.
+  pub fn local(&self, opt: u8) -> Option<bool> {
+    (self.opt == opt && self.side == Side::Local).then_some(self.enabled)
+  }
+
+  /// Whether the client's side of `opt` changed, and if so, to what.
+  pub fn remote(&self, opt: u8) -> Option<bool> {
+    (self.opt == opt && self.side == Side::Remote).then_some(self.enabled)
+  }
+}
+
+/// The negotiated options for a connection.
+#[derive(Component, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TelnetOptions {
+  local: HashMap<u8, Q>,
+  remote: HashMap<u8, Q>,
+  /// Options we'll agree to if the client asks first.
+  accept_local: HashSet<u8>,
+  accept_remote: HashSet<u8>,
+}
+
+impl TelnetOptions {
+  pub fn local_enabled(&self, opt: u8) -> bool {
+    self.local.get(&opt).is_some_and(|q| q.enabled())
+  }
+
+  pub fn remote_enabled(&self, opt: u8) -> bool {
+    self.remote.get(&opt).is_some_and(|q| q.enabled())
+  }
+
+  /// Every option in effect, on either side.
+  pub fn enabled(&self) -> impl Iterator<Item = (Side, u8)> + '_ {
+    let local = self.local.iter().map(|(opt, q)| (Side::Local, *opt, *q));
+    let remote = self.remote.iter().map(|(opt, q)| (Side::Remote, *opt, *q));
+    local
+      .chain(remote)
+      .filter(|(_, _, q)| q.enabled())
+      .map(|(side, opt, _)| (side, opt))
+  }
+
+  /// Agree to `opt` on `side` if the other end asks, without asking for it.
+  pub fn accept(&mut self, side: Side, opt: u8) {
+    match side {
+      Side::Local => self.accept_local.insert(opt),
+      Side::Remote => self.accept_remote.insert(opt),
+    };
+  }
+
+  /// Offer to enable `opt` on our side, and agree to it if the client asks.
+  pub fn enable_local(&mut self, out: &TelnetOut, opt: u8) {
+    self.accept_local.insert(opt);
+    self.request(out, Side::Local, opt, true);
+  }
+
+  /// Stop `opt` on our side, and refuse it if the client asks again.
+  pub fn disable_local(&mut self, out: &TelnetOut, opt: u8) {
+    self.accept_local.remove(&opt);
+    self.request(out, Side::Local, opt, false);
+  }
+
+  /// Ask the client to enable `opt`, and agree to it if the client offers.
+  pub fn enable_remote(&mut self, out: &TelnetOut, opt: u8) {
+    self.accept_remote.insert(opt);
+    self.request(out, Side::Remote, opt, true);
+  }
+
+  /// Ask the client to stop `opt`, and refuse it if the client offers again.
+  pub fn disable_remote(&mut self, out: &TelnetOut, opt: u8) {
+    self.accept_remote.remove(&opt);
+    self.request(out, Side::Remote, opt, false);
+  }
+
+  fn request(&mut self, out: &TelnetOut, side: Side, opt: u8, enable: bool) {
+    let q = match side {
+      Side::Local => self.local.entry(opt).or_default(),
+      Side::Remote => self.remote.entry(opt).or_default(),
+    };
+    if let Some(enable) = q.request(enable) {
+      out.telnet(negotiation(side, opt, enable));
+    }
+  }
+
+  /// Handle a `WILL`, `WONT`, `DO` or `DONT` from the client, replying to it
+  /// if needed. Returns the resulting change, if there was one.
+  pub fn receive(&mut self, out: &TelnetOut, cmd: Cmd, opt: u8) -> Option<OptionChanged> {
+    let (reply, change) = self.answer(cmd, opt);
+    if let Some(reply) = reply {
+      out.telnet(reply);
+    }
+    change
+  }
+
+  /// Like [TelnetOptions::receive], but returns the reply rather than sending
+  /// it, for when the other end isn't a [TelnetOut].
+  pub fn answer(&mut self, cmd: Cmd, opt: u8) -> (Option<Event>, Option<OptionChanged>) {
+    let (side, enable) = match cmd {
+      Cmd::DO => (Side::Local, true),
+      Cmd::DONT => (Side::Local, false),
+      Cmd::WILL => (Side::Remote, true),
+      Cmd::WONT => (Side::Remote, false),
+      _ => return (None, None),
+    };
+    let (q, accept) = match side {
+      Side::Local => (
+        self.local.entry(opt).or_default(),
+        self.accept_local.contains(&opt),
+      ),
+      Side::Remote => (
+        self.remote.entry(opt).or_default(),
+        self.accept_remote.contains(&opt),
+      ),
+    };
+    let was = q.enabled();
+    let reply = q
+      .receive(enable, accept)
+      .map(|reply| negotiation(side, opt, reply));
+    let enabled = q.enabled();
+    let change = (was != enabled).then_some(OptionChanged { opt, side, enabled });
+    (reply, change)
+  }
+}
+
+/// The request or answer to enable or disable `opt` on `side`.
+pub fn negotiation(side: Side, opt: u8, enable: bool) -> Event {
+  let cmd = match (side, enable) {
+    (Side::Local, true) => Cmd::WILL,
+    (Side::Local, false) => Cmd::WONT,
+    (Side::Remote, true) => Cmd::DO,
+    (Side::Remote, false) => Cmd::DONT,
+  };
+  Event::Negotiation(cmd, Opt::from(opt))
+}
+
+pub fn options_observer(
+  trigger: Trigger<TelnetEvent>,
+  mut query: Query<(&mut TelnetOptions, &TelnetOut)>,
+  mut cmd: Commands,
+) {
+  let Event::Negotiation(command, opt) = &**trigger.event() else {
+    return;
+  };
+  let entity = trigger.entity();
+  let Ok((mut options, out)) = query.get_mut(entity) else {
+    return;
+  };
+  if let Some(change) = options.receive(out, *command, u8::from(*opt)) {
+    debug!(?entity, ?change, "telnet option changed");
+    cmd.trigger_targets(change, entity);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn q_method() {
+    // We ask, they agree.
+    let mut q = Q::default();
+    assert_eq!(q.request(true), Some(true));
+    assert!(!q.enabled());
+    assert_eq!(q.receive(true, false), None);
+    assert_eq!(q, Q::Yes);
+
+    // Asking again doesn't send anything.
+    assert_eq!(q.request(true), None);
+
+    // Changing our mind while waiting queues the opposite request, which is
+    // sent once the first is answered.
+    assert_eq!(q.request(false), Some(false));
+    assert_eq!(q.request(true), None);
+    assert_eq!(q.receive(false, false), Some(true));
+    assert_eq!(q, Q::WantYes(false));
+
+    // Refused.
+    assert_eq!(q.receive(false, false), None);
+    assert_eq!(q, Q::No);
+
+    // Unsolicited requests are only agreed to if accepted, and never answered
+    // twice.
+    assert_eq!(q.receive(true, false), Some(false));
+    assert_eq!(q.receive(false, false), None);
+    assert_eq!(q.receive(true, true), Some(true));
+    assert_eq!(q.receive(true, true), None);
+    assert!(q.enabled());
+  }
+}
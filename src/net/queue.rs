@@ -0,0 +1,393 @@
+//! Bounded per-connection output queues.
+//!
+//! Everything sent to a client goes through an [OutputSender] and is written
+//! out by the connection's writer task. A client that stops reading will back
+//! up its queue, so rather than letting it grow forever, queues are limited by
+//! [OutputLimits]. When a push would go over the limit, frames that are going
+//! to be superseded anyway (see [OutputSender::push_coalesced]) are dropped
+//! first, and if that isn't enough the queue is closed and the client
+//! disconnected.
+
+use std::{
+  collections::VecDeque,
+  fmt,
+  pin::Pin,
+  sync::{
+    Arc,
+    Mutex,
+    PoisonError,
+  },
+  task::{
+    Context,
+    Poll,
+    Waker,
+  },
+};
+
+use bevy::prelude::*;
+use futures::{
+  channel::oneshot,
+  Stream,
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use tellem::Event;
+
+/// Limits for each connection's output queue.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputLimits {
+  /// The most events that may be waiting to be written.
+  pub max_events: usize,
+  /// The most bytes that may be waiting to be written.
+  pub max_bytes: usize,
+}
+
+impl Default for OutputLimits {
+  fn default() -> Self {
+    Self {
+      max_events: 4096,
+      max_bytes: 1 << 20,
+    }
+  }
+}
+
+/// A snapshot of a connection's output queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+  pub events: usize,
+  pub bytes: usize,
+  /// Frames that were dropped or replaced before they could be sent.
+  pub dropped: u64,
+}
+
+/// Why an output queue stopped accepting events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+  /// Every sender went away, so the queue is drained and then closed.
+  Finished,
+  /// The writer went away, usually because the connection died.
+  Disconnected,
+  /// The client wasn't keeping up.
+  Overflow,
+}
+
+impl fmt::Display for CloseReason {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      CloseReason::Finished => "finished",
+      CloseReason::Disconnected => "disconnected",
+      CloseReason::Overflow => "output queue full",
+    })
+  }
+}
+
+struct Frame {
+  event: Event,
+  size: usize,
+  coalesce: Option<&'static str>,
+}
+
+#[derive(Default)]
+struct State {
+  frames: VecDeque<Frame>,
+  bytes: usize,
+  dropped: u64,
+  closed: Option<CloseReason>,
+  waker: Option<Waker>,
+  /// Dropped on close to let the reader know it should stop too.
+  shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl State {
+  fn stats(&self) -> QueueStats {
+    QueueStats {
+      events: self.frames.len(),
+      bytes: self.bytes,
+      dropped: self.dropped,
+    }
+  }
+
+  fn over(&self, limits: &OutputLimits) -> bool {
+    self.frames.len() > limits.max_events || self.bytes > limits.max_bytes
+  }
+
+  fn close(&mut self, reason: CloseReason) {
+    if self.closed.is_some() {
+      return;
+    }
+    self.closed = Some(reason);
+    if reason != CloseReason::Finished {
+      self.frames.clear();
+      self.bytes = 0;
+    }
+    self.shutdown.take();
+    if let Some(waker) = self.waker.take() {
+      waker.wake();
+    }
+  }
+}
+
+struct Shared {
+  limits: OutputLimits,
+  state: Mutex<State>,
+}
+
+impl Shared {
+  fn state(&self) -> std::sync::MutexGuard<'_, State> {
+    self.state.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+}
+
+/// Create a new output queue.
+///
+/// The returned [oneshot::Receiver] resolves once the queue is closed for any
+/// reason, so that the connection's reader can stop as well.
+pub fn output_queue(limits: OutputLimits) -> (OutputSender, OutputReceiver, oneshot::Receiver<()>) {
+  let (shutdown_tx, shutdown_rx) = oneshot::channel();
+  let shared = Arc::new(Shared {
+    limits,
+    state: Mutex::new(State {
+      shutdown: Some(shutdown_tx),
+      ..default()
+    }),
+  });
+  (
+    OutputSender {
+      shared: shared.clone(),
+    },
+    OutputReceiver { shared },
+    shutdown_rx,
+  )
+}
+
+/// The sending half of an output queue. Closes the queue when dropped.
+pub struct OutputSender {
+  shared: Arc<Shared>,
+}
+
+impl OutputSender {
+  /// Queue an event. Returns false if the queue is closed, possibly as a result
+  /// of this event not fitting.
+  pub fn push(&self, event: Event) -> bool {
+    self.push_inner(event, None)
+  }
+
+  /// Queue an event which supersedes any earlier unsent event with the same
+  /// `key`. The earlier event is replaced in place, and events with keys are
+  /// the first to go when the queue is full.
+  pub fn push_coalesced(&self, key: &'static str, event: Event) -> bool {
+    self.push_inner(event, Some(key))
+  }
+
+  fn push_inner(&self, event: Event, coalesce: Option<&'static str>) -> bool {
+    let limits = &self.shared.limits;
+    let mut state = self.shared.state();
+    if state.closed.is_some() {
+      return false;
+    }
+
+    let size = event_size(&event);
+    let frame = Frame {
+      event,
+      size,
+      coalesce,
+    };
+
+    // Replace the frame this one supersedes, or add it to the end.
+    let superseded =
+      coalesce.and_then(|key| state.frames.iter().position(|f| f.coalesce == Some(key)));
+    let mut index = match superseded {
+      Some(i) => {
+        state.bytes = state.bytes - state.frames[i].size + size;
+        state.frames[i] = frame;
+        state.dropped += 1;
+        i
+      }
+      None => {
+        state.bytes += size;
+        state.frames.push_back(frame);
+        state.frames.len() - 1
+      }
+    };
+
+    // Make room by dropping other frames that would be superseded anyway.
+    while state.over(limits) {
+      let Some(i) = state
+        .frames
+        .iter()
+        .enumerate()
+        .position(|(i, f)| i != index && f.coalesce.is_some())
+      else {
+        break;
+      };
+      let frame = state.frames.remove(i).unwrap();
+      state.bytes -= frame.size;
+      state.dropped += 1;
+      if i < index {
+        index -= 1;
+      }
+    }
+
+    if state.over(limits) {
+      state.close(CloseReason::Overflow);
+      return false;
+    }
+
+    if let Some(waker) = state.waker.take() {
+      waker.wake();
+    }
+    true
+  }
+
+  pub fn stats(&self) -> QueueStats {
+    self.shared.state().stats()
+  }
+
+  pub fn is_closed(&self) -> bool {
+    self.shared.state().closed.is_some()
+  }
+
+  /// Stop accepting events. Whatever is already queued is still written.
+  pub fn close(&self) {
+    self.shared.state().close(CloseReason::Finished);
+  }
+}
+
+impl Drop for OutputSender {
+  fn drop(&mut self) {
+    self.shared.state().close(CloseReason::Finished);
+  }
+}
+
+/// The receiving half of an output queue, read by the connection's writer.
+pub struct OutputReceiver {
+  shared: Arc<Shared>,
+}
+
+impl OutputReceiver {
+  pub fn is_empty(&self) -> bool {
+    self.shared.state().frames.is_empty()
+  }
+
+  /// Why the queue was closed, if it has been.
+  pub fn close_reason(&self) -> Option<CloseReason> {
+    self.shared.state().closed
+  }
+
+  pub fn stats(&self) -> QueueStats {
+    self.shared.state().stats()
+  }
+}
+
+impl Stream for OutputReceiver {
+  type Item = Event;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    let mut state = self.shared.state();
+    if let Some(frame) = state.frames.pop_front() {
+      state.bytes -= frame.size;
+      return Poll::Ready(Some(frame.event));
+    }
+    if state.closed.is_some() {
+      return Poll::Ready(None);
+    }
+    state.waker = Some(cx.waker().clone());
+    Poll::Pending
+  }
+}
+
+impl Drop for OutputReceiver {
+  fn drop(&mut self) {
+    self.shared.state().close(CloseReason::Disconnected);
+  }
+}
+
+/// Roughly how many bytes an event will take on the wire.
+fn event_size(event: &Event) -> usize {
+  match event {
+    Event::Data(data) => data.len(),
+    Event::Subnegotiation(_, data) => data.len() + 5,
+    _ => 3,
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use bytes::BytesMut;
+  use futures::{
+    FutureExt,
+    StreamExt,
+  };
+
+  use super::*;
+
+  fn data(s: &str) -> Event {
+    Event::Data(BytesMut::from(s))
+  }
+
+  fn limits(max_events: usize) -> OutputLimits {
+    OutputLimits {
+      max_events,
+      ..default()
+    }
+  }
+
+  #[test]
+  fn coalesces_and_drops_superseded_frames() {
+    let (tx, mut rx, _) = output_queue(limits(2));
+    assert!(tx.push_coalesced("map", data("map 1")));
+    assert!(tx.push(data("hello")));
+    assert!(tx.push_coalesced("map", data("map 2")));
+    assert_eq!(tx.stats().events, 2);
+
+    // Full, so the map frame makes way.
+    assert!(tx.push(data("world")));
+    assert_eq!(tx.stats().dropped, 2);
+
+    assert!(matches!(rx.next().now_or_never(), Some(Some(Event::Data(d))) if d == "hello"));
+    assert!(matches!(rx.next().now_or_never(), Some(Some(Event::Data(d))) if d == "world"));
+    assert!(rx.next().now_or_never().is_none());
+  }
+
+  #[test]
+  fn merged_frames_count_against_byte_limit() {
+    let (tx, _rx, _) = output_queue(OutputLimits {
+      max_bytes: 10,
+      ..default()
+    });
+    assert!(tx.push_coalesced("a", data("12345")));
+    assert!(tx.push_coalesced("b", data("123")));
+    // Growing `a` pushes out `b`.
+    assert!(tx.push_coalesced("a", data("12345678")));
+    assert_eq!(tx.stats().events, 1);
+    assert_eq!(tx.stats().bytes, 8);
+    // And with nothing left to drop, it's too much.
+    assert!(!tx.push_coalesced("a", data("12345678901")));
+    assert!(tx.is_closed());
+  }
+
+  #[test]
+  fn overflow_disconnects() {
+    let (tx, mut rx, mut shutdown) = output_queue(limits(1));
+    assert!(tx.push(data("one")));
+    assert!(!tx.push(data("two")));
+    assert!(tx.is_closed());
+    assert_eq!(rx.close_reason(), Some(CloseReason::Overflow));
+    // Nothing more is written to a client that's being dropped.
+    assert!(matches!(rx.next().now_or_never(), Some(None)));
+    assert!(matches!(shutdown.try_recv(), Err(oneshot::Canceled)));
+  }
+
+  #[test]
+  fn drains_after_senders_drop() {
+    let (tx, mut rx, _) = output_queue(default());
+    tx.push(data("bye"));
+    drop(tx);
+    assert!(matches!(rx.next().now_or_never(), Some(Some(_))));
+    assert!(matches!(rx.next().now_or_never(), Some(None)));
+    assert_eq!(rx.close_reason(), Some(CloseReason::Finished));
+  }
+}
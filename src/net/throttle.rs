@@ -0,0 +1,370 @@
+//! Connection throttling and input flood protection.
+//!
+//! New connections go through the [ConnectionGate], which turns away banned
+//! addresses and ones that connect too often or have too many connections
+//! open. The listeners check with it as soon as they accept a connection,
+//! before any handshake, so a rejected client costs next to nothing. Once
+//! connected, each connection's input is split into lines by a
+//! [LineBuffer], which drops lines that are too long, and rate limited by a
+//! [FloodLimiter].
+
+use std::{
+  collections::VecDeque,
+  fmt,
+  mem,
+  net::{
+    IpAddr,
+    SocketAddr,
+  },
+  sync::{
+    Arc,
+    Mutex,
+  },
+  time::{
+    Duration,
+    Instant,
+  },
+};
+
+use bevy::{
+  prelude::*,
+  utils::HashMap,
+};
+use bytes::BytesMut;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+
+use super::ban::BanList;
+
+/// How far back [ThrottleConfig::connects_per_minute] looks.
+const CONNECT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Limits on connections and their input. Zero turns a limit off.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThrottleConfig {
+  /// The most connections a single address may have open at once.
+  pub max_per_ip: usize,
+  /// The most new connections a single address may make in a minute.
+  pub connects_per_minute: usize,
+  /// How many lines a connection may send per second, on average.
+  pub lines_per_second: f32,
+  /// How many lines a connection may send at once before being limited.
+  pub line_burst: u32,
+  /// The longest line a connection may send, in bytes.
+  pub max_line_length: usize,
+}
+
+impl Default for ThrottleConfig {
+  fn default() -> Self {
+    Self {
+      max_per_ip: 8,
+      connects_per_minute: 20,
+      lines_per_second: 10.0,
+      line_burst: 30,
+      max_line_length: 4096,
+    }
+  }
+}
+
+/// Why a connection was turned away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejected {
+  Banned(String),
+  TooManyConnections,
+  TooFast,
+}
+
+impl fmt::Display for Rejected {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Rejected::Banned(reason) if reason.is_empty() => f.write_str("You are banned."),
+      Rejected::Banned(reason) => write!(f, "You are banned: {reason}"),
+      Rejected::TooManyConnections => f.write_str("Too many connections from your address."),
+      Rejected::TooFast => f.write_str("You're connecting too often, try again later."),
+    }
+  }
+}
+
+/// Recent connection times for each address.
+#[derive(Debug, Default)]
+pub struct RecentConnections(HashMap<IpAddr, VecDeque<Instant>>);
+
+impl RecentConnections {
+  /// Record a connection from `ip`, returning false if it went over `limit`
+  /// connections in the last minute.
+  fn record(&mut self, ip: IpAddr, now: Instant, limit: usize) -> bool {
+    self.0.retain(|_, times| {
+      while times
+        .front()
+        .is_some_and(|t| now.duration_since(*t) > CONNECT_WINDOW)
+      {
+        times.pop_front();
+      }
+      !times.is_empty()
+    });
+    let times = self.0.entry(ip).or_default();
+    // Attempts count too, so hammering away keeps an address locked out.
+    times.push_back(now);
+    limit == 0 || times.len() <= limit
+  }
+}
+
+/// Decides whether to accept new connections. It's shared with the
+/// listeners, and kept up to date with the [ThrottleConfig] and [BanList] by
+/// [sync_gate].
+#[derive(Resource, Debug, Default, Clone)]
+pub struct ConnectionGate(Arc<Mutex<GateState>>);
+
+#[derive(Debug, Default)]
+struct GateState {
+  config: ThrottleConfig,
+  bans: BanList,
+  recent: RecentConnections,
+  /// Open connections from each address.
+  open: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionGate {
+  fn state(&self) -> std::sync::MutexGuard<'_, GateState> {
+    self
+      .0
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  /// Check a new connection from `addr`. Local connections are always let
+  /// in. The connection counts against its address until the returned
+  /// [Admitted] is dropped.
+  pub fn admit(&self, addr: Option<SocketAddr>) -> Result<Option<Admitted>, Rejected> {
+    let Some(ip) = addr.map(|addr| addr.ip()) else {
+      return Ok(None);
+    };
+    let mut state = self.state();
+    if let Some(ban) = state.bans.ip_ban(ip) {
+      return Err(Rejected::Banned(ban.reason.clone()));
+    }
+    let GateState {
+      config,
+      recent,
+      open,
+      ..
+    } = &mut *state;
+    if !recent.record(ip, Instant::now(), config.connects_per_minute) {
+      return Err(Rejected::TooFast);
+    }
+    let open = open.entry(ip).or_default();
+    if config.max_per_ip > 0 && *open >= config.max_per_ip {
+      return Err(Rejected::TooManyConnections);
+    }
+    *open += 1;
+    Ok(Some(Admitted {
+      gate: self.clone(),
+      ip,
+    }))
+  }
+
+  /// Count a connection that was let in some other way, like one inherited
+  /// in a copyover, against its address.
+  pub fn track(&self, addr: Option<SocketAddr>) -> Option<Admitted> {
+    let ip = addr?.ip();
+    *self.state().open.entry(ip).or_default() += 1;
+    Some(Admitted {
+      gate: self.clone(),
+      ip,
+    })
+  }
+}
+
+/// A connection let in by the [ConnectionGate]. It stops counting against
+/// its address once this is dropped.
+#[derive(Debug)]
+pub struct Admitted {
+  gate: ConnectionGate,
+  ip: IpAddr,
+}
+
+impl Drop for Admitted {
+  fn drop(&mut self) {
+    let mut state = self.gate.state();
+    if let Some(open) = state.open.get_mut(&self.ip) {
+      *open = open.saturating_sub(1);
+      if *open == 0 {
+        state.open.remove(&self.ip);
+      }
+    }
+  }
+}
+
+/// Copy the config and bans into the [ConnectionGate] when they change.
+pub fn sync_gate(gate: Res<ConnectionGate>, config: Res<ThrottleConfig>, bans: Res<BanList>) {
+  if !config.is_changed() && !bans.is_changed() {
+    return;
+  }
+  let mut state = gate.state();
+  state.config = *config;
+  state.bans = bans.clone();
+}
+
+/// A line that went over [ThrottleConfig::max_line_length].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineTooLong;
+
+/// Splits input into lines, without letting a line grow past a limit.
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+  buf: BytesMut,
+  max_len: usize,
+  /// Skipping the rest of a line that was already too long.
+  discarding: bool,
+}
+
+impl LineBuffer {
+  pub fn new(max_len: usize) -> Self {
+    LineBuffer {
+      max_len,
+      ..default()
+    }
+  }
+
+  /// Add input, returning the lines it completes without their line endings.
+  /// Each line that's too long is reported once.
+  pub fn push(&mut self, data: &[u8]) -> Vec<Result<BytesMut, LineTooLong>> {
+    let max_len = self.max_len;
+    let too_long = move |len: usize| max_len > 0 && len > max_len;
+    let mut lines = vec![];
+    self.buf.extend_from_slice(data);
+
+    while let Some(i) = self.buf.iter().position(|b| *b == b'\n') {
+      let mut line = self.buf.split_to(i + 1);
+      if mem::take(&mut self.discarding) {
+        continue;
+      }
+      while let Some(b'\r' | b'\n') = line.last() {
+        line.truncate(line.len() - 1);
+      }
+      lines.push(if too_long(line.len()) {
+        Err(LineTooLong)
+      } else {
+        Ok(line)
+      });
+    }
+
+    if too_long(self.buf.len()) {
+      self.buf.clear();
+      if !self.discarding {
+        self.discarding = true;
+        lines.push(Err(LineTooLong));
+      }
+    }
+
+    lines
+  }
+}
+
+/// A token bucket limiting how fast lines are accepted.
+#[derive(Debug)]
+pub struct FloodLimiter {
+  rate: f32,
+  burst: f32,
+  tokens: f32,
+  last: Instant,
+}
+
+impl FloodLimiter {
+  pub fn new(rate: f32, burst: u32, now: Instant) -> Self {
+    let burst = burst.max(1) as f32;
+    FloodLimiter {
+      rate,
+      burst,
+      tokens: burst,
+      last: now,
+    }
+  }
+
+  /// Whether another line may be accepted at `now`.
+  pub fn allow(&mut self, now: Instant) -> bool {
+    if self.rate <= 0.0 {
+      return true;
+    }
+    let elapsed = now.saturating_duration_since(self.last).as_secs_f32();
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+    self.last = now;
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::net::ban::BanEntry;
+
+  #[test]
+  fn limits_line_length() {
+    let mut lines = LineBuffer::new(8);
+    assert_eq!(lines.push(b"look\r\nno"), vec![Ok("look".into())]);
+    assert_eq!(lines.push(b"rth\n"), vec![Ok("north".into())]);
+    assert_eq!(lines.push(b"0123456789"), vec![Err(LineTooLong)]);
+    assert_eq!(lines.push(b"0123456789"), vec![]);
+    assert_eq!(lines.push(b"\nsay hi\n"), vec![Ok("say hi".into())]);
+  }
+
+  #[test]
+  fn limits_line_rate() {
+    let start = Instant::now();
+    let mut flood = FloodLimiter::new(2.0, 3, start);
+    assert!((0..3).all(|_| flood.allow(start)));
+    assert!(!flood.allow(start));
+    assert!(flood.allow(start + Duration::from_millis(500)));
+    assert!(!flood.allow(start + Duration::from_millis(600)));
+  }
+
+  #[test]
+  fn limits_connection_rate() {
+    let mut recent = RecentConnections::default();
+    let ip = IpAddr::from([10, 0, 0, 1]);
+    let start = Instant::now();
+    assert!(recent.record(ip, start, 2));
+    assert!(recent.record(ip, start, 2));
+    assert!(!recent.record(ip, start, 2));
+    assert!(recent.record(IpAddr::from([10, 0, 0, 2]), start, 2));
+    assert!(recent.record(ip, start + CONNECT_WINDOW * 2, 2));
+  }
+
+  #[test]
+  fn gates_connections() {
+    let gate = ConnectionGate::default();
+    {
+      let mut state = gate.state();
+      state.config.max_per_ip = 2;
+      state
+        .bans
+        .ban(&"10.0.0.9".parse().unwrap(), BanEntry::new("spam", "admin"));
+    }
+    let addr = |last: u8| Some(SocketAddr::from(([10, 0, 0, last], 4000)));
+
+    assert_eq!(
+      gate.admit(addr(9)).unwrap_err(),
+      Rejected::Banned("spam".into())
+    );
+    assert!(gate.admit(None).unwrap().is_none());
+
+    let first = gate.admit(addr(1)).unwrap();
+    let second = gate.admit(addr(1)).unwrap();
+    assert_eq!(
+      gate.admit(addr(1)).unwrap_err(),
+      Rejected::TooManyConnections
+    );
+    assert!(gate.admit(addr(2)).is_ok());
+    drop(first);
+    assert!(gate.admit(addr(1)).is_ok());
+    drop(second);
+  }
+}
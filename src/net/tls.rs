@@ -0,0 +1,259 @@
+//! TLS listeners.
+//!
+//! These accept connections like the plain telnet listeners, but do a TLS
+//! handshake with each one before handing it off to the same connection
+//! handling, so that passwords don't cross the wire in the clear. The
+//! certificate chain and private key are read from PEM files named in the
+//! [TlsConfig].
+
+use std::{
+  fs,
+  io::BufReader,
+  path::PathBuf,
+  sync::Arc,
+};
+
+use anyhow::{
+  anyhow,
+  Context,
+};
+use async_compat::Compat;
+use bevy::{
+  prelude::*,
+  tasks::IoTaskPool,
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use tokio::sync::mpsc::{
+  self,
+  UnboundedReceiver,
+};
+use tokio_rustls::{
+  rustls,
+  TlsAcceptor,
+};
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tracing::Instrument;
+
+use super::{
+  handle_conn,
+  listen::{
+    Bound,
+    ListenAddr,
+  },
+  throttle::ConnectionGate,
+  ClientBundle,
+  ConnSettings,
+  HANDSHAKE_TIMEOUT,
+};
+
+/// Where to accept TLS connections, and the certificate to present.
+#[derive(Resource, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+  /// Addresses to accept TLS connections on. No TLS listeners are started
+  /// if this is empty.
+  pub listeners: Vec<ListenAddr>,
+  /// PEM file containing the certificate chain, leaf first.
+  pub cert: PathBuf,
+  /// PEM file containing the private key.
+  pub key: PathBuf,
+}
+
+impl Default for TlsConfig {
+  fn default() -> Self {
+    Self {
+      listeners: vec![],
+      cert: "cert.pem".into(),
+      key: "key.pem".into(),
+    }
+  }
+}
+
+impl TlsConfig {
+  pub fn enabled(&self) -> bool {
+    !self.listeners.is_empty()
+  }
+
+  /// Load the certificate and key into an acceptor.
+  pub fn acceptor(&self) -> anyhow::Result<TlsAcceptor> {
+    let cert =
+      fs::read(&self.cert).with_context(|| format!("error reading {}", self.cert.display()))?;
+    let key =
+      fs::read(&self.key).with_context(|| format!("error reading {}", self.key.display()))?;
+    acceptor_from_pem(&cert, &key)
+  }
+}
+
+/// Build an acceptor from a PEM encoded certificate chain and private key.
+pub fn acceptor_from_pem(cert: &[u8], key: &[u8]) -> anyhow::Result<TlsAcceptor> {
+  let certs = rustls_pemfile::certs(&mut BufReader::new(cert))
+    .collect::<Result<Vec<_>, _>>()
+    .context("invalid certificate")?;
+  if certs.is_empty() {
+    return Err(anyhow!("no certificates found"));
+  }
+  let key = rustls_pemfile::private_key(&mut BufReader::new(key))
+    .context("invalid private key")?
+    .ok_or_else(|| anyhow!("no private key found"))?;
+
+  let config = rustls::ServerConfig::builder()
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+  Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub(super) fn start_tls(
+  l: Bound,
+  acceptor: TlsAcceptor,
+  settings: ConnSettings,
+  gate: ConnectionGate,
+) -> UnboundedReceiver<ClientBundle> {
+  let (new_tx, new_rx) = mpsc::unbounded_channel();
+
+  IoTaskPool::get()
+    .spawn(async move {
+      while let Ok((conn, addr)) = l.accept().await {
+        if new_tx.is_closed() {
+          break;
+        }
+        let admitted = match gate.admit(addr) {
+          Ok(admitted) => admitted,
+          Err(rejected) => {
+            // Closed without a message, see `reject`.
+            info!(remote_addr = ?addr, %rejected, "rejecting connection");
+            continue;
+          }
+        };
+        // Handshake in its own task so that a slow client can't hold up the
+        // ones behind it.
+        let acceptor = acceptor.clone();
+        let new_tx = new_tx.clone();
+        IoTaskPool::get()
+          .spawn(
+            async move {
+              // The timer needs a tokio runtime, which the task pool isn't.
+              let handshake = Compat::new(tokio::time::timeout(
+                HANDSHAKE_TIMEOUT,
+                acceptor.accept(conn.compat()),
+              ));
+              let conn = match handshake.await {
+                Ok(Ok(conn)) => conn,
+                Ok(Err(err)) => {
+                  debug!(?err, "tls handshake failed");
+                  return Ok(());
+                }
+                Err(_) => {
+                  debug!("tls handshake timed out");
+                  return Ok(());
+                }
+              };
+              let mut bundle = handle_conn(conn, addr, settings, true)?;
+              bundle.conn.admitted = admitted;
+              let _ = new_tx.send(bundle);
+              anyhow::Ok(())
+            }
+            .instrument(info_span!("tls", remote_addr = ?addr)),
+          )
+          .detach();
+      }
+      anyhow::Ok(())
+    })
+    .detach();
+
+  new_rx
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use async_std::{
+    net::TcpStream,
+    task::block_on,
+  };
+  use bevy::tasks::TaskPool;
+  use rustls::{
+    pki_types::ServerName,
+    ClientConfig,
+    RootCertStore,
+  };
+  use tokio::io::{
+    AsyncReadExt,
+    AsyncWriteExt,
+  };
+  use tokio_rustls::TlsConnector;
+
+  use super::*;
+  use crate::net::{
+    BindAddr,
+    OutputLimits,
+    ThrottleConfig,
+  };
+
+  #[test]
+  fn loopback() {
+    IoTaskPool::get_or_init(TaskPool::new);
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let acceptor = acceptor_from_pem(
+      cert.cert.pem().as_bytes(),
+      cert.key_pair.serialize_pem().as_bytes(),
+    )
+    .unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.cert.der().clone()).unwrap();
+    let connector = TlsConnector::from(Arc::new(
+      ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth(),
+    ));
+
+    let settings = ConnSettings {
+      limits: OutputLimits::default(),
+      throttle: ThrottleConfig::default(),
+    };
+    let l = Bound::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let BindAddr::Tcp(addr) = l.local_addr().unwrap() else {
+      unreachable!();
+    };
+    let mut conns = start_tls(l, acceptor, settings, ConnectionGate::default());
+
+    block_on(async {
+      let conn = TcpStream::connect(addr).await.unwrap();
+      let mut client = connector
+        .connect(ServerName::try_from("localhost").unwrap(), conn.compat())
+        .await
+        .unwrap();
+
+      let mut bundle = loop {
+        match conns.try_recv() {
+          Ok(bundle) => break bundle,
+          Err(_) => async_std::task::sleep(Duration::from_millis(10)).await,
+        }
+      };
+      assert!(bundle.conn.secure);
+      assert!(!bundle.conn.local);
+
+      client.write_all(b"look\r\n").await.unwrap();
+      let line = loop {
+        match bundle.input.next_line() {
+          Some(line) => break line,
+          None => async_std::task::sleep(Duration::from_millis(10)).await,
+        }
+      };
+      assert_eq!(line, "look");
+
+      bundle.output.line("hello");
+      let mut received = vec![];
+      let mut buf = [0; 1024];
+      while !received.ends_with(b"hello\r\n") {
+        let n = client.read(&mut buf).await.unwrap();
+        assert_ne!(n, 0, "connection closed early");
+        received.extend_from_slice(&buf[..n]);
+      }
+    });
+  }
+}
@@ -0,0 +1,214 @@
+//! Terminal type (RFC 1091) and MTTS capability detection.
+//!
+//! Clients that agree to `DO TTYPE` are asked for their terminal type up to
+//! three times. Following the [MTTS] convention, the first answer is the
+//! client's name, the second its terminal type, and the third `MTTS <flags>`
+//! with a bitfield of what it supports. Clients that don't follow the
+//! convention repeat their first answer, which ends the cycle early.
+//!
+//! [MTTS]: https://tintin.mudhalla.net/protocols/mtts/
+
+use bevy::prelude::*;
+use bitflags::bitflags;
+use bytes::BytesMut;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use tellem::{
+  Event,
+  Opt,
+};
+
+use super::{
+  color::ColorLevel,
+  options::OptionChanged,
+  TelnetEvent,
+  TelnetOut,
+};
+
+pub const TTYPE: u8 = 24;
+
+const IS: u8 = 0;
+const SEND: u8 = 1;
+
+bitflags! {
+  /// Capabilities reported with MTTS.
+  #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+  pub struct Mtts: u32 {
+    const ANSI = 1;
+    const VT100 = 2;
+    const UTF8 = 4;
+    const COLORS_256 = 8;
+    const MOUSE_TRACKING = 16;
+    const OSC_COLOR_PALETTE = 32;
+    const SCREEN_READER = 64;
+    const PROXY = 128;
+    const TRUECOLOR = 256;
+    const MNES = 512;
+    const MSLP = 1024;
+    const SSL = 2048;
+  }
+}
+
+/// What we know about a client's terminal.
+#[derive(Component, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Terminal {
+  /// The client's name, e.g. `TINTIN++` or `MUDLET`.
+  pub client: Option<String>,
+  /// The terminal type, e.g. `XTERM-256COLOR`.
+  pub terminal: Option<String>,
+  pub caps: Mtts,
+  /// Whether `caps` came from MTTS rather than guesswork.
+  pub mtts: bool,
+  /// How many answers we've had.
+  replies: u8,
+}
+
+impl Terminal {
+  /// The most color the client has told us it supports, or the basic 16 if it
+  /// hasn't said.
+  pub fn color_level(&self) -> ColorLevel {
+    if self.caps.contains(Mtts::TRUECOLOR) {
+      ColorLevel::TrueColor
+    } else if self.caps.contains(Mtts::COLORS_256) {
+      ColorLevel::Ansi256
+    } else if self.caps.intersects(Mtts::ANSI | Mtts::VT100) {
+      ColorLevel::Ansi16
+    } else if self.mtts || self.terminal.as_deref() == Some("DUMB") {
+      ColorLevel::None
+    } else {
+      ColorLevel::default()
+    }
+  }
+
+  pub fn screen_reader(&self) -> bool {
+    self.caps.contains(Mtts::SCREEN_READER)
+  }
+
+  /// Record the next answer to `SEND`. Returns true if we should ask again.
+  fn reply(&mut self, name: &str) -> bool {
+    let name = name.trim().to_uppercase();
+    self.replies += 1;
+    match self.replies {
+      1 => {
+        self.client = Some(name);
+        true
+      }
+      _ if self.client.as_ref() == Some(&name) || self.terminal.as_ref() == Some(&name) => false,
+      2 => {
+        self.caps |= terminal_caps(&name);
+        self.terminal = Some(name);
+        true
+      }
+      _ => {
+        if let Some(flags) = name
+          .strip_prefix("MTTS ")
+          .and_then(|n| n.trim().parse().ok())
+        {
+          self.caps = Mtts::from_bits_truncate(flags);
+          self.mtts = true;
+        }
+        false
+      }
+    }
+  }
+}
+
+/// Guess at capabilities from a terminal type.
+fn terminal_caps(terminal: &str) -> Mtts {
+  let mut caps = Mtts::empty();
+  if terminal.contains("TRUECOLOR") || terminal.contains("DIRECT") {
+    caps |= Mtts::TRUECOLOR;
+  }
+  if terminal.contains("256COLOR") {
+    caps |= Mtts::COLORS_256;
+  }
+  if ["ANSI", "XTERM", "VT100", "SCREEN", "TMUX"]
+    .iter()
+    .any(|t| terminal.contains(t))
+  {
+    caps |= Mtts::ANSI;
+  }
+  caps
+}
+
+fn send(out: &TelnetOut) {
+  out.telnet(Event::Subnegotiation(
+    Opt::from(TTYPE),
+    BytesMut::from(&[SEND][..]),
+  ));
+}
+
+pub fn ttype_observer(
+  trigger: Trigger<TelnetEvent>,
+  query: Query<(&TelnetOut, Option<&Terminal>)>,
+  mut cmd: Commands,
+) {
+  let entity = trigger.entity();
+  let Ok((out, terminal)) = query.get(entity) else {
+    return;
+  };
+  let Event::Subnegotiation(opt, data) = &**trigger.event() else {
+    return;
+  };
+  if u8::from(*opt) != TTYPE {
+    return;
+  }
+  let Some((&IS, name)) = data.split_first() else {
+    return;
+  };
+  let mut terminal = terminal.cloned().unwrap_or_default();
+  if terminal.reply(&String::from_utf8_lossy(name)) {
+    send(out);
+  } else {
+    debug!(?entity, ?terminal, "client terminal detected");
+  }
+  out.set_colors(terminal.color_level());
+  cmd.entity(entity).insert(terminal);
+}
+
+/// Start asking for the terminal type once the client agrees to send it.
+pub fn ttype_option_observer(
+  trigger: Trigger<OptionChanged>,
+  query: Query<&TelnetOut, Without<Terminal>>,
+  mut cmd: Commands,
+) {
+  if trigger.event().remote(TTYPE) != Some(true) {
+    return;
+  }
+  let entity = trigger.entity();
+  if let Ok(out) = query.get(entity) {
+    send(out);
+    cmd.entity(entity).insert(Terminal::default());
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn mtts_cycle() {
+    let mut terminal = Terminal::default();
+    assert!(terminal.reply("TinTin++"));
+    assert!(terminal.reply("XTERM-256COLOR"));
+    assert_eq!(terminal.color_level(), ColorLevel::Ansi256);
+    assert!(!terminal.reply("MTTS 333"));
+    assert_eq!(terminal.client.as_deref(), Some("TINTIN++"));
+    assert!(terminal.caps.contains(Mtts::UTF8 | Mtts::TRUECOLOR));
+    assert_eq!(terminal.color_level(), ColorLevel::TrueColor);
+
+    // Doesn't know about MTTS, so repeats itself.
+    let mut unknown = Terminal::default();
+    assert!(unknown.reply("SomeClient"));
+    assert!(!unknown.reply("SomeClient"));
+    assert_eq!(unknown.color_level(), ColorLevel::default());
+
+    let mut plain = Terminal::default();
+    assert!(plain.reply("SomeClient"));
+    assert!(plain.reply("DUMB"));
+    assert!(!plain.reply("MTTS 0"));
+    assert_eq!(plain.color_level(), ColorLevel::None);
+  }
+}
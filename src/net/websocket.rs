@@ -0,0 +1,313 @@
+//! A WebSocket gateway for browser clients.
+//!
+//! Browsers can't speak telnet, so WebSocket connections exchange JSON
+//! [Frame]s instead, one per message: game text, GMCP messages and echo
+//! changes. The gateway turns these into the same telnet events a telnet
+//! client would produce, so the connection ends up with the usual
+//! [TelnetIn]/[TelnetOut] and the rest of the game can't tell the difference.
+//!
+//! Since there's no telnet client on the other end to negotiate with, the
+//! gateway answers on its behalf, with its own [TelnetOptions] so that it
+//! follows the same rules as the server: it agrees to GMCP and ECHO and
+//! refuses everything else.
+//!
+//! There's no TLS here; put a reverse proxy in front for `wss://`.
+//!
+//! [TelnetIn]: super::TelnetIn
+//! [TelnetOut]: super::TelnetOut
+
+use async_compat::Compat;
+use async_tungstenite::tungstenite::Message;
+use bevy::{
+  prelude::*,
+  tasks::IoTaskPool,
+};
+use bytes::BytesMut;
+use futures::{
+  channel::mpsc as channel,
+  future,
+  stream,
+  SinkExt,
+  StreamExt,
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use serde_json::Value;
+use tellem::{
+  Cmd,
+  Event,
+  Opt,
+};
+use tokio::sync::mpsc::{
+  self,
+  UnboundedReceiver,
+};
+use tracing::Instrument;
+
+use super::{
+  gmcp::GmcpMessage,
+  handle_events,
+  listen::{
+    Bound,
+    ListenAddr,
+  },
+  options::{
+    Side,
+    TelnetOptions,
+    ECHO,
+    GMCP,
+  },
+  throttle::ConnectionGate,
+  ClientBundle,
+  ConnSettings,
+  HANDSHAKE_TIMEOUT,
+};
+
+/// Where to accept WebSocket connections.
+#[derive(Resource, Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebSocketConfig {
+  /// Addresses to accept WebSocket connections on. No WebSocket listeners are
+  /// started if this is empty.
+  pub listeners: Vec<ListenAddr>,
+}
+
+/// A message between the gateway and a browser.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Frame {
+  /// Game output, or a line of player input.
+  Text { data: String },
+  /// A GMCP message, in either direction.
+  Gmcp { package: String, data: Value },
+  /// Whether the client should show what the player types. Sent by the server
+  /// only, and turned off while entering passwords.
+  Echo { enabled: bool },
+}
+
+/// Translates between telnet events and [Frame]s, answering negotiations on
+/// behalf of the client.
+struct Gateway {
+  replies: channel::UnboundedSender<Event>,
+  /// The browser's view of the options, so "local" is the browser's side.
+  options: TelnetOptions,
+}
+
+impl Gateway {
+  fn new(replies: channel::UnboundedSender<Event>) -> Self {
+    let mut options = TelnetOptions::default();
+    options.accept(Side::Remote, GMCP);
+    options.accept(Side::Remote, ECHO);
+    Gateway { replies, options }
+  }
+
+  /// The frames to send for an event from the game.
+  fn outgoing(&mut self, event: Event) -> Option<Frame> {
+    match event {
+      Event::Data(data) => Some(Frame::Text {
+        data: String::from_utf8_lossy(&data).into_owned(),
+      }),
+      Event::Subnegotiation(opt, data) if u8::from(opt) == GMCP => {
+        match GmcpMessage::parse(&data) {
+          Ok(message) => Some(Frame::Gmcp {
+            package: message.package,
+            data: message.data,
+          }),
+          Err(error) => {
+            warn!(%error, "invalid outgoing GMCP message");
+            None
+          }
+        }
+      }
+      Event::Negotiation(cmd, opt) => self.negotiate(cmd, opt),
+      _ => None,
+    }
+  }
+
+  fn negotiate(&mut self, cmd: Cmd, opt: Opt) -> Option<Frame> {
+    let (reply, change) = self.options.answer(cmd, u8::from(opt));
+    if let Some(reply) = reply {
+      let _ = self.replies.unbounded_send(reply);
+    }
+    // When the server echoes, the client shouldn't.
+    let echoing = change?.remote(ECHO)?;
+    Some(Frame::Echo { enabled: !echoing })
+  }
+}
+
+/// The event for a frame from the client.
+fn incoming(frame: Frame) -> Option<Event> {
+  match frame {
+    Frame::Text { mut data } => {
+      if !data.ends_with('\n') {
+        data.push('\n');
+      }
+      Some(Event::Data(BytesMut::from(data.as_bytes())))
+    }
+    Frame::Gmcp { package, data } => Some(GmcpMessage { package, data }.to_event()),
+    Frame::Echo { .. } => None,
+  }
+}
+
+fn decode(message: Message) -> Option<Event> {
+  let text = match message {
+    Message::Text(text) => text,
+    _ => return None,
+  };
+  match serde_json::from_str(&text) {
+    Ok(frame) => incoming(frame),
+    Err(error) => {
+      debug!(%error, "invalid websocket frame");
+      None
+    }
+  }
+}
+
+pub(super) fn start_websocket(
+  l: Bound,
+  settings: ConnSettings,
+  gate: ConnectionGate,
+) -> UnboundedReceiver<ClientBundle> {
+  let (new_tx, new_rx) = mpsc::unbounded_channel();
+
+  IoTaskPool::get()
+    .spawn(async move {
+      while let Ok((conn, addr)) = l.accept().await {
+        if new_tx.is_closed() {
+          break;
+        }
+        let admitted = match gate.admit(addr) {
+          Ok(admitted) => admitted,
+          Err(rejected) => {
+            // Closed without a message, see `reject`.
+            info!(remote_addr = ?addr, %rejected, "rejecting connection");
+            continue;
+          }
+        };
+        let new_tx = new_tx.clone();
+        IoTaskPool::get()
+          .spawn(
+            async move {
+              // The timer needs a tokio runtime, which the task pool isn't.
+              let handshake = Compat::new(tokio::time::timeout(
+                HANDSHAKE_TIMEOUT,
+                async_tungstenite::accept_async(conn),
+              ));
+              let ws = match handshake.await {
+                Ok(Ok(ws)) => ws,
+                Ok(Err(err)) => {
+                  debug!(?err, "websocket handshake failed");
+                  return Ok(());
+                }
+                Err(_) => {
+                  debug!("websocket handshake timed out");
+                  return Ok(());
+                }
+              };
+              let (replies, replies_rx) = channel::unbounded();
+              let mut gateway = Gateway::new(replies);
+              let (sink, ws_read) = ws.split();
+
+              let twrite = Box::pin(sink.with_flat_map(move |event| {
+                let frame = gateway.outgoing(event).and_then(|frame| {
+                  serde_json::to_string(&frame)
+                    .inspect_err(|error| warn!(%error, "failed to encode websocket frame"))
+                    .ok()
+                });
+                stream::iter(frame.map(|text| Ok(Message::Text(text))))
+              }));
+              // Stop once the client is gone, even though the replies could
+              // keep going.
+              let ws_read = ws_read
+                .filter_map(|res| future::ready(res.map(decode).transpose()))
+                .map(Some)
+                .chain(stream::once(future::ready(None)));
+              let tread = stream::select(ws_read, replies_rx.map(|event| Some(Ok(event))))
+                .take_while(|res| future::ready(res.is_some()))
+                .filter_map(future::ready)
+                .boxed();
+
+              let mut bundle = handle_events(tread, twrite, addr, settings, false)?;
+              bundle.conn.admitted = admitted;
+              let _ = new_tx.send(bundle);
+              anyhow::Ok(())
+            }
+            .instrument(info_span!("websocket", remote_addr = ?addr)),
+          )
+          .detach();
+      }
+      anyhow::Ok(())
+    })
+    .detach();
+
+  new_rx
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn translates_frames() {
+    let (replies, mut replies_rx) = channel::unbounded();
+    let mut gateway = Gateway::new(replies);
+
+    assert_eq!(
+      gateway.outgoing(Event::Data("hello\r\n".into())),
+      Some(Frame::Text {
+        data: "hello\r\n".into()
+      })
+    );
+    assert_eq!(
+      gateway.outgoing(Event::Subnegotiation(
+        Opt::from(GMCP),
+        "Char.Vitals {\"hp\":10}".into()
+      )),
+      Some(Frame::Gmcp {
+        package: "Char.Vitals".into(),
+        data: serde_json::json!({ "hp": 10 }),
+      })
+    );
+
+    // GMCP is agreed to, everything else refused.
+    assert_eq!(
+      gateway.outgoing(Event::Negotiation(Cmd::WILL, Opt::from(GMCP))),
+      None
+    );
+    assert_eq!(
+      gateway.outgoing(Event::Negotiation(Cmd::DO, Opt::from(31))),
+      None
+    );
+    assert_eq!(
+      gateway.outgoing(Event::Negotiation(Cmd::WILL, Opt::from(ECHO))),
+      Some(Frame::Echo { enabled: false })
+    );
+    let replies: Vec<_> = std::iter::from_fn(|| replies_rx.try_next().ok().flatten())
+      .map(|event| match event {
+        Event::Negotiation(cmd, opt) => (cmd, u8::from(opt)),
+        other => panic!("unexpected reply: {other:?}"),
+      })
+      .collect();
+    assert_eq!(
+      replies,
+      vec![(Cmd::DO, GMCP), (Cmd::WONT, 31), (Cmd::DO, ECHO)]
+    );
+
+    let frame: Frame =
+      serde_json::from_str(r#"{"type":"gmcp","package":"Core.Hello","data":{"client":"web"}}"#)
+        .unwrap();
+    match incoming(frame) {
+      Some(Event::Subnegotiation(opt, data)) => {
+        assert_eq!(u8::from(opt), GMCP);
+        assert_eq!(&data[..], br#"Core.Hello {"client":"web"}"#);
+      }
+      other => panic!("unexpected event: {other:?}"),
+    }
+    match decode(Message::Text(r#"{"type":"text","data":"look"}"#.into())) {
+      Some(Event::Data(data)) => assert_eq!(&data[..], b"look\n"),
+      other => panic!("unexpected event: {other:?}"),
+    }
+  }
+}
@@ -0,0 +1,204 @@
+//! Player prompts.
+//!
+//! A player's prompt comes from the [Prompt] template on their character, with
+//! each `{token}` in it replaced by a value computed from a component on
+//! either the connection or the character. Plugins provide tokens with
+//! [PromptAppExt::prompt_token]. `{{` and `}}` stand for literal braces, and
+//! tokens that nobody provides are left as they are.
+//!
+//! Prompts are drawn at the end of the frame, and only for players who've
+//! been sent something since their last one.
+
+use std::sync::Arc;
+
+use bevy::{
+  prelude::*,
+  utils::HashMap,
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+
+use crate::{
+  account::Session,
+  character::Puppet,
+  command::pager::Pager,
+  net::TelnetOut,
+  savestate::traits::AppWorldExt,
+};
+
+/// The prompt for characters that haven't set their own.
+pub const DEFAULT_PROMPT: &str = "> ";
+
+/// What's shown in place of a token whose component is missing.
+const MISSING: &str = "-";
+
+/// A character's prompt template.
+#[derive(Component, Debug, Clone, PartialEq, Eq, Deref, Reflect, Serialize, Deserialize)]
+#[reflect(Component)]
+pub struct Prompt(pub String);
+
+type Getter = Arc<dyn Fn(&World, Entity) -> Option<String> + Send + Sync>;
+
+/// The tokens that prompts can use.
+#[derive(Resource, Default, Clone)]
+pub struct PromptTokens(HashMap<String, Getter>);
+
+impl PromptTokens {
+  /// Fill in `template` for a connection, looking tokens up on the connection
+  /// first and then its character.
+  pub fn render(&self, world: &World, conn: Entity, template: &str) -> String {
+    expand(template, |name| {
+      let getter = self.0.get(name)?;
+      let value = getter(world, conn).or_else(|| {
+        let puppet = world.get::<Puppet>(conn)?;
+        getter(world, puppet.0)
+      });
+      Some(value.unwrap_or_else(|| MISSING.into()))
+    })
+  }
+
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.0.keys().map(String::as_str)
+  }
+}
+
+pub trait PromptAppExt {
+  /// Make `{name}` available in prompts, computed from the `C` on either the
+  /// connection or its character.
+  fn prompt_token<C: Component>(
+    &mut self,
+    name: &str,
+    value: impl Fn(&C) -> String + Send + Sync + 'static,
+  ) -> &mut Self;
+}
+
+impl PromptAppExt for App {
+  fn prompt_token<C: Component>(
+    &mut self,
+    name: &str,
+    value: impl Fn(&C) -> String + Send + Sync + 'static,
+  ) -> &mut Self {
+    self
+      .world_mut()
+      .get_resource_or_insert_with(PromptTokens::default)
+      .0
+      .insert(
+        name.into(),
+        Arc::new(move |world: &World, entity| world.get::<C>(entity).map(&value)),
+      );
+    self
+  }
+}
+
+pub struct PromptPlugin;
+
+impl Plugin for PromptPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .init_resource::<PromptTokens>()
+      .persist::<Prompt>()
+      .add_systems(Last, prompt_system);
+  }
+}
+
+fn prompt_system(
+  world: &World,
+  tokens: Res<PromptTokens>,
+  sessions: Query<(Entity, &TelnetOut, Option<&Puppet>, Option<&Pager>), With<Session>>,
+  prompts: Query<&Prompt>,
+) {
+  for (conn, out, puppet, pager) in sessions.iter() {
+    if !out.needs_prompt() {
+      continue;
+    }
+    // Input goes to the pager until it's done with, so say so.
+    if let Some(pager) = pager {
+      out.prompt(pager.status());
+      continue;
+    }
+    let template = puppet
+      .and_then(|puppet| prompts.get(puppet.0).ok())
+      .map_or(DEFAULT_PROMPT, |prompt| prompt.as_str());
+    out.prompt(tokens.render(world, conn, template));
+  }
+}
+
+/// Replace the `{tokens}` in `template` with whatever `token` returns for
+/// them, leaving them alone if it returns `None`.
+fn expand(template: &str, mut token: impl FnMut(&str) -> Option<String>) -> String {
+  let mut out = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(i) = rest.find(['{', '}']) {
+    out.push_str(&rest[..i]);
+    rest = &rest[i..];
+    if let Some(after) = rest.strip_prefix("{{") {
+      out.push('{');
+      rest = after;
+    } else if let Some(after) = rest.strip_prefix("}}") {
+      out.push('}');
+      rest = after;
+    } else if let Some(end) = rest.find('}').filter(|_| rest.starts_with('{')) {
+      match token(&rest[1..end]) {
+        Some(value) => out.push_str(&value),
+        None => out.push_str(&rest[..=end]),
+      }
+      rest = &rest[end + 1..];
+    } else {
+      out.push_str(&rest[..1]);
+      rest = &rest[1..];
+    }
+  }
+  out.push_str(rest);
+  out
+}
+
+#[cfg(test)]
+mod test {
+  use tellem::{
+    Cmd,
+    Event,
+  };
+
+  use super::*;
+  use crate::{
+    net::eor::EOR,
+    testing::TestMud,
+  };
+
+  #[test]
+  fn expands_tokens() {
+    let token = |name: &str| (name == "hp").then(|| "10".to_string());
+    assert_eq!(expand("{hp}hp> ", token), "10hp> ");
+    assert_eq!(expand("{{hp}} {mp} {hp", token), "{hp} {mp} {hp");
+    assert_eq!(expand("} {}", token), "} {}");
+  }
+
+  #[test]
+  fn prompts_after_output() {
+    let mut mud = TestMud::new();
+    let mut client = mud.connect();
+    client.accept(EOR);
+    mud.create_account(&mut client, "alice", "pw");
+    mud.expect_event(&mut client, "EOR", |event| {
+      matches!(event, Event::Cmd(Cmd::EOR))
+    });
+
+    client.send_line("prompt {name} {nothing}>");
+    mud.expect(&mut client, "Prompt set.");
+    mud.expect(&mut client, "alice {nothing}> ");
+
+    // Nothing was said, so there's no new prompt.
+    client.send_line("stop");
+    for _ in 0..20 {
+      mud.update();
+    }
+    client.poll();
+    assert_eq!(client.text(), "");
+
+    client.send_line("prompt default");
+    mud.expect(&mut client, "Prompt reset.");
+    mud.expect(&mut client, DEFAULT_PROMPT);
+  }
+}
@@ -0,0 +1,544 @@
+//! # Prototypes
+//!
+//! A prototype is a named template for spawning entities, stored as
+//! `prototypes/<name>.ron` in the asset directory. Prototypes may name a parent
+//! to inherit components from:
+//!
+//! ```ron
+//! (
+//!   parent: Some("npc"),
+//!   components: {
+//!     "bevy_mud::map::Render": (icon: Some((text: "g", style: (...)))),
+//!   },
+//! )
+//! ```
+//!
+//! Components are merged down the chain, with each prototype's components
+//! applied over its parent's. Instances remember where they came from via
+//! [FromPrototype]. Whenever a prototype or one of its ancestors is modified,
+//! the components whose merged value changed are applied to its instances
+//! again, and the rest are left as the instances have them, e.g. their
+//! [Transform](crate::map::Transform).
+
+use std::fmt;
+
+use anyhow::{
+  anyhow,
+  bail,
+};
+use bevy::{
+  asset::{
+    io::Reader,
+    AssetLoader,
+    LoadContext,
+    LoadState,
+  },
+  prelude::*,
+  reflect::TypeRegistry,
+  utils::{
+    ConditionalSendFuture,
+    HashMap,
+  },
+};
+use serde::{
+  de::{
+    DeserializeSeed,
+    MapAccess,
+    Visitor,
+  },
+  Deserialize,
+  Serialize,
+};
+
+use crate::{
+  savestate::{
+    migration::{
+      MigratingMapSeed,
+      Versions,
+    },
+    traits::AppWorldExt,
+  },
+  util::DebugLifecycle,
+};
+
+pub struct PrototypePlugin;
+
+impl Plugin for PrototypePlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .persist::<FromPrototype>()
+      .init_asset::<Prototype>()
+      .init_asset_loader::<PrototypeLoader>()
+      .init_resource::<ResolvedPrototypes>()
+      .add_systems(
+        PreUpdate,
+        (apply_pending_prototypes, prototype_asset_events),
+      )
+      .debug_lifecycle::<FromPrototype>("FromPrototype")
+      .observe(from_prototype_added);
+  }
+}
+
+/// The asset path for the prototype called `name`.
+///
+/// Names may only contain lowercase letters, digits, `_` and `-`, which keeps
+/// them from reaching outside of the prototype directory.
+pub fn prototype_path(name: &str) -> anyhow::Result<String> {
+  let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-';
+  if name.is_empty() || !name.chars().all(valid) {
+    bail!("invalid prototype name: {name:?}");
+  }
+  Ok(format!("prototypes/{name}.ron"))
+}
+
+#[derive(Asset, Debug, TypePath)]
+pub struct Prototype {
+  pub parent_name: Option<String>,
+  #[dependency]
+  pub parent: Option<Handle<Prototype>>,
+  pub components: Vec<Box<dyn PartialReflect>>,
+}
+
+/// Records the prototype that an entity was spawned from.
+#[derive(Component, Debug, Clone, Default, Reflect, Deref, Serialize, Deserialize)]
+#[reflect(Component)]
+pub struct FromPrototype(pub String);
+
+/// Instances that haven't had their prototype applied yet.
+///
+/// Entities that are only loaded with a [FromPrototype], e.g. from a save,
+/// keep their saved components until the prototype is next modified.
+#[derive(Component, Debug, Default)]
+pub struct PrototypePending;
+
+/// The merged components of each loaded prototype, as they were last applied,
+/// to tell what a modification changed.
+#[derive(Resource, Default)]
+struct ResolvedPrototypes(HashMap<AssetId<Prototype>, Vec<Box<dyn PartialReflect>>>);
+
+/// Merge the components of a prototype and all of its ancestors.
+pub fn resolve(
+  assets: &Assets<Prototype>,
+  id: AssetId<Prototype>,
+) -> anyhow::Result<Vec<Box<dyn PartialReflect>>> {
+  let chain = ancestry(assets, id)?;
+
+  let mut merged: Vec<Box<dyn PartialReflect>> = vec![];
+  for proto in chain.iter().rev().filter_map(|id| assets.get(*id)) {
+    for component in &proto.components {
+      let path = component
+        .get_represented_type_info()
+        .ok_or_else(|| anyhow!("prototype component missing type info"))?
+        .type_path();
+      let existing = merged.iter_mut().find(|c| {
+        c.get_represented_type_info()
+          .is_some_and(|info| info.type_path() == path)
+      });
+      match existing {
+        Some(existing) => existing.try_apply(component.as_partial_reflect())?,
+        None => merged.push(component.clone_value()),
+      }
+    }
+  }
+
+  Ok(merged)
+}
+
+/// The prototype followed by its parent, grandparent, etc.
+fn ancestry(
+  assets: &Assets<Prototype>,
+  id: AssetId<Prototype>,
+) -> anyhow::Result<Vec<AssetId<Prototype>>> {
+  let mut chain = vec![];
+  let mut next = Some(id);
+  while let Some(id) = next {
+    if chain.contains(&id) {
+      bail!("prototype inherits from itself");
+    }
+    let proto = assets
+      .get(id)
+      .ok_or_else(|| anyhow!("prototype not loaded"))?;
+    chain.push(id);
+    next = proto.parent.as_ref().map(|parent| parent.id());
+  }
+  Ok(chain)
+}
+
+/// The components in `new` that aren't the same in `old`.
+fn changed(
+  old: &[Box<dyn PartialReflect>],
+  new: Vec<Box<dyn PartialReflect>>,
+) -> Vec<Box<dyn PartialReflect>> {
+  new
+    .into_iter()
+    .filter(|component| {
+      let path = type_path(&**component);
+      !old
+        .iter()
+        .any(|old| type_path(&**old) == path && old.reflect_partial_eq(&**component) == Some(true))
+    })
+    .collect()
+}
+
+fn type_path(component: &dyn PartialReflect) -> Option<&'static str> {
+  component
+    .get_represented_type_info()
+    .map(|info| info.type_path())
+}
+
+/// Apply the resolved prototype to `entity`, inserting or replacing its
+/// components.
+pub fn apply_prototype(entity: Entity, id: AssetId<Prototype>) -> impl FnOnce(&mut World) {
+  move |world: &mut World| match resolve(world.resource::<Assets<Prototype>>(), id) {
+    Ok(components) => apply_components(entity, id, components)(world),
+    Err(error) => warn!(%error, ?entity, %id, "failed to apply prototype"),
+  }
+}
+
+/// Insert or replace `components` on `entity`, which is an instance of the
+/// prototype `id`.
+fn apply_components(
+  entity: Entity,
+  id: AssetId<Prototype>,
+  components: Vec<Box<dyn PartialReflect>>,
+) -> impl FnOnce(&mut World) {
+  move |world: &mut World| {
+    let res = (|| {
+      let registry = world.resource::<AppTypeRegistry>().clone();
+      let registry = registry.read();
+      let Some(mut entity_mut) = world.get_entity_mut(entity) else {
+        return Ok(());
+      };
+      for component in components {
+        let path = type_path(&*component).unwrap_or_default();
+        let reflect_component = registry
+          .get_with_type_path(path)
+          .and_then(|reg| reg.data::<ReflectComponent>())
+          .ok_or_else(|| anyhow!("not a registered component: {path}"))?;
+        reflect_component.apply_or_insert(&mut entity_mut, &*component, &registry);
+      }
+      anyhow::Ok(())
+    })();
+    match res {
+      Ok(()) => debug!(?entity, %id, "applied prototype"),
+      Err(error) => warn!(%error, ?entity, %id, "failed to apply prototype"),
+    }
+  }
+}
+
+fn from_prototype_added(
+  trigger: Trigger<OnAdd, FromPrototype>,
+  query: Query<&FromPrototype>,
+  asset_server: Res<AssetServer>,
+  mut cmd: Commands,
+) {
+  let entity = trigger.entity();
+  let Ok(proto) = query.get(entity) else {
+    return;
+  };
+  let path = match prototype_path(proto) {
+    Ok(path) => path,
+    Err(error) => {
+      warn!(%error, ?entity, "not loading prototype");
+      return;
+    }
+  };
+  let handle = asset_server.load::<Prototype>(path);
+  cmd.entity(entity).insert(handle);
+}
+
+/// Apply prototypes to new instances once they're loaded. Instances whose
+/// prototype fails to load are despawned, since they'd be empty otherwise.
+fn apply_pending_prototypes(
+  asset_server: Res<AssetServer>,
+  pending: Query<(Entity, &FromPrototype, &Handle<Prototype>), With<PrototypePending>>,
+  mut cmd: Commands,
+) {
+  for (entity, name, handle) in pending.iter() {
+    if asset_server.is_loaded_with_dependencies(handle.id()) {
+      cmd.queue(apply_prototype(entity, handle.id()));
+      cmd.entity(entity).remove::<PrototypePending>();
+    } else if let Some(LoadState::Failed(error)) = asset_server.get_load_state(handle.id()) {
+      warn!(%error, ?entity, prototype = **name, "failed to load prototype, despawning instance");
+      cmd.entity(entity).despawn_recursive();
+    }
+  }
+}
+
+fn prototype_asset_events(
+  mut reader: EventReader<AssetEvent<Prototype>>,
+  assets: Res<Assets<Prototype>>,
+  mut resolved: ResMut<ResolvedPrototypes>,
+  instances: Query<(Entity, &Handle<Prototype>), Without<PrototypePending>>,
+  mut cmd: Commands,
+) {
+  let mut modified = vec![];
+  for event in reader.read() {
+    match event {
+      AssetEvent::LoadedWithDependencies { id } => {
+        if let Ok(components) = resolve(&assets, *id) {
+          resolved.0.entry(*id).or_insert(components);
+        }
+      }
+      AssetEvent::Modified { id } => {
+        debug!(%id, "prototype modified");
+        modified.push(*id);
+      }
+      AssetEvent::Removed { id } => {
+        resolved.0.remove(id);
+      }
+      _ => {}
+    }
+  }
+  if modified.is_empty() {
+    return;
+  }
+
+  // Work out what changed once per prototype, rather than per instance.
+  let mut changes = HashMap::default();
+  for (entity, handle) in instances.iter() {
+    let id = handle.id();
+    let components = changes
+      .entry(id)
+      .or_insert_with(|| prototype_changes(&assets, &mut resolved, id, &modified));
+    if !components.is_empty() {
+      cmd.queue(apply_components(entity, id, clone_all(components)));
+    }
+  }
+}
+
+/// The components of the prototype `id` that changed with the `modified`
+/// prototypes, if it's one of them or inherits from one.
+fn prototype_changes(
+  assets: &Assets<Prototype>,
+  resolved: &mut ResolvedPrototypes,
+  id: AssetId<Prototype>,
+  modified: &[AssetId<Prototype>],
+) -> Vec<Box<dyn PartialReflect>> {
+  let Ok(chain) = ancestry(assets, id) else {
+    return vec![];
+  };
+  if !modified.iter().any(|m| chain.contains(m)) {
+    return vec![];
+  }
+  let components = match resolve(assets, id) {
+    Ok(components) => components,
+    Err(error) => {
+      warn!(%error, %id, "failed to resolve modified prototype");
+      return vec![];
+    }
+  };
+  match resolved.0.insert(id, clone_all(&components)) {
+    Some(old) => changed(&old, components),
+    None => components,
+  }
+}
+
+fn clone_all(components: &[Box<dyn PartialReflect>]) -> Vec<Box<dyn PartialReflect>> {
+  components
+    .iter()
+    .map(|component| component.clone_value())
+    .collect()
+}
+
+pub struct PrototypeLoader {
+  registry: AppTypeRegistry,
+}
+
+impl FromWorld for PrototypeLoader {
+  fn from_world(world: &mut World) -> Self {
+    let registry = world.resource::<AppTypeRegistry>().clone();
+
+    PrototypeLoader { registry }
+  }
+}
+
+impl AssetLoader for PrototypeLoader {
+  type Asset = Prototype;
+  type Settings = ();
+  type Error = anyhow::Error;
+
+  fn load<'a>(
+    &'a self,
+    reader: &'a mut dyn Reader,
+    _settings: &'a Self::Settings,
+    load_context: &'a mut LoadContext,
+  ) -> impl ConditionalSendFuture<Output = Result<Self::Asset, Self::Error>> {
+    async {
+      let mut bytes = vec![];
+      reader.read_to_end(&mut bytes).await?;
+      let mut de = ron::Deserializer::from_bytes(&bytes)?;
+
+      let registry = self.registry.read();
+      let (parent_name, components) = PrototypeSeed {
+        registry: &registry,
+      }
+      .deserialize(&mut de)?;
+
+      let parent = parent_name
+        .as_deref()
+        .map(prototype_path)
+        .transpose()?
+        .map(|path| load_context.load::<Prototype>(path));
+
+      Ok(Prototype {
+        parent_name,
+        parent,
+        components,
+      })
+    }
+  }
+}
+
+struct PrototypeSeed<'a> {
+  registry: &'a TypeRegistry,
+}
+
+impl<'a, 'de> DeserializeSeed<'de> for PrototypeSeed<'a> {
+  type Value = (Option<String>, Vec<Box<dyn PartialReflect>>);
+
+  fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    deserializer.deserialize_struct(
+      "Prototype",
+      &["versions", "parent", "components"],
+      PrototypeVisitor {
+        registry: self.registry,
+      },
+    )
+  }
+}
+
+struct PrototypeVisitor<'a> {
+  registry: &'a TypeRegistry,
+}
+
+impl<'a, 'de> Visitor<'de> for PrototypeVisitor<'a> {
+  type Value = (Option<String>, Vec<Box<dyn PartialReflect>>);
+
+  fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    formatter.write_str("prototype struct")
+  }
+
+  fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+  where
+    A: MapAccess<'de>,
+  {
+    let mut versions = Versions::default();
+    let mut parent = None;
+    let mut components = vec![];
+
+    #[derive(Deserialize)]
+    #[serde(field_identifier, rename_all = "lowercase")]
+    enum PrototypeField {
+      Versions,
+      Parent,
+      Components,
+    }
+
+    while let Some(key) = map.next_key()? {
+      match key {
+        PrototypeField::Versions => {
+          versions = map.next_value()?;
+        }
+        PrototypeField::Parent => {
+          parent = map.next_value()?;
+        }
+        PrototypeField::Components => {
+          components.extend(map.next_value_seed(MigratingMapSeed {
+            registry: self.registry,
+            versions: &versions,
+          })?);
+        }
+      }
+    }
+
+    Ok((parent, components))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::map::{
+    Map,
+    Transform,
+  };
+
+  fn proto(
+    assets: &mut Assets<Prototype>,
+    parent: Option<&Handle<Prototype>>,
+    components: Vec<Box<dyn PartialReflect>>,
+  ) -> Handle<Prototype> {
+    assets.add(Prototype {
+      parent_name: None,
+      parent: parent.cloned(),
+      components,
+    })
+  }
+
+  #[test]
+  fn children_override_parents() {
+    let mut assets = Assets::<Prototype>::default();
+    let npc = proto(
+      &mut assets,
+      None,
+      vec![Box::new(Map("npc".into())), Box::new(Transform::default())],
+    );
+    let goblin = proto(
+      &mut assets,
+      Some(&npc),
+      vec![Box::new(Map("goblin".into()))],
+    );
+
+    let components = resolve(&assets, goblin.id()).unwrap();
+    assert_eq!(components.len(), 2);
+    let map = components
+      .iter()
+      .find_map(|c| Map::from_reflect(&**c))
+      .unwrap();
+    assert_eq!(*map, "goblin");
+    assert!(components
+      .iter()
+      .any(|c| Transform::from_reflect(&**c).is_some()));
+  }
+
+  #[test]
+  fn only_changed_components_are_reapplied() {
+    let old: Vec<Box<dyn PartialReflect>> = vec![
+      Box::new(Map("npc".into())),
+      Box::new(FromPrototype("goblin".into())),
+    ];
+    let new: Vec<Box<dyn PartialReflect>> = vec![
+      Box::new(Map("goblin".into())),
+      Box::new(FromPrototype("goblin".into())),
+    ];
+    let changed = changed(&old, new);
+    assert_eq!(changed.len(), 1);
+    assert_eq!(*Map::from_reflect(&*changed[0]).unwrap(), "goblin");
+  }
+
+  #[test]
+  fn prototype_names() {
+    assert_eq!(
+      prototype_path("goblin_2").unwrap(),
+      "prototypes/goblin_2.ron"
+    );
+    assert!(prototype_path("big-goblin").is_ok());
+    for name in ["", "../../secrets", "/etc/passwd", "Goblin", "a.b", "a b"] {
+      assert!(prototype_path(name).is_err(), "{name:?}");
+    }
+  }
+
+  #[test]
+  fn cycles_are_errors() {
+    let mut assets = Assets::<Prototype>::default();
+    let a = proto(&mut assets, None, vec![]);
+    let b = proto(&mut assets, Some(&a), vec![]);
+    assets.get_mut(&a).unwrap().parent = Some(b.clone());
+
+    assert!(resolve(&assets, b.id()).is_err());
+  }
+}
@@ -11,14 +11,17 @@ use self::{
   components::Save,
 };
 use crate::{
+  config::DEFAULT_SAVE_INTERVAL,
   core::MudStartup,
   util::DebugLifecycle,
 };
 
 pub mod assets;
+pub mod backend;
 pub mod components;
 pub mod events;
 pub mod helpers;
+pub mod migration;
 pub mod observers;
 pub mod resources;
 pub mod systems;
@@ -31,7 +34,7 @@ pub struct SaveStatePlugin(f32);
 
 impl Default for SaveStatePlugin {
   fn default() -> Self {
-    Self::with_interval(SAVE_INTERVAL)
+    Self::with_interval(DEFAULT_SAVE_INTERVAL)
   }
 }
 
@@ -41,23 +44,51 @@ impl SaveStatePlugin {
   }
 }
 
-const SAVE_INTERVAL: f32 = 30.0;
+#[derive(SystemSet, Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SaveSet {
+  /// Change tracking for persisted components.
+  Track,
+  /// Writing the world out.
+  Save,
+}
 
 impl Plugin for SaveStatePlugin {
   fn build(&self, app: &mut App) {
     app
       .init_resource::<resources::PersistentComponents>()
+      .init_resource::<resources::DirtyEntities>()
+      .init_resource::<resources::AssetDir>()
       .insert_resource(resources::SaveInterval(self.0))
       .register_type::<components::Persistent>()
       .add_event::<events::LoadFailed>()
       .debug_lifecycle::<components::Persistent>("Persistent")
+      .add_systems(
+        Startup,
+        (|world: &mut World| {
+          if !world.contains_resource::<backend::Backend>() {
+            world.insert_resource(backend::Backend::default());
+          }
+        })
+        .in_set(MudStartup::System),
+      )
       .add_systems(Startup, systems::load_system.in_set(MudStartup::World))
+      .configure_sets(Last, (SaveSet::Track, SaveSet::Save).chain())
+      .add_systems(
+        Last,
+        (systems::track_persistent, systems::track_hierarchy).in_set(SaveSet::Track),
+      )
       .add_systems(
         Last,
         systems::final_save_system
-          .run_if(on_event::<AppExit>.and(not(on_event::<events::LoadFailed>))),
+          .run_if(on_event::<AppExit>.and(not(on_event::<events::LoadFailed>)))
+          .in_set(SaveSet::Save),
       )
-      .add_systems(Last, systems::save_system.run_if(not(on_event::<AppExit>)));
+      .add_systems(
+        Last,
+        systems::save_system
+          .run_if(not(on_event::<AppExit>))
+          .in_set(SaveSet::Save),
+      );
 
     app
       .persist::<Save>()
@@ -65,6 +96,15 @@ impl Plugin for SaveStatePlugin {
       .init_asset_loader::<SavedEntityLoader>()
       .insert_resource(resources::SavedEntityStates::default())
       .add_systems(PreUpdate, systems::handle_asset_events)
+      .add_systems(
+        Last,
+        (
+          systems::final_write_back_system
+            .run_if(on_event::<AppExit>.and(not(on_event::<events::LoadFailed>))),
+          systems::write_back_system.run_if(not(on_event::<AppExit>)),
+        )
+          .in_set(SaveSet::Save),
+      )
       .debug_lifecycle::<Save>("Save")
       .debug_lifecycle::<Handle<SavedEntity>>("Handle<SavedEntity>")
       .observe(observers::saved_entity_added)
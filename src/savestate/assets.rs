@@ -3,6 +3,7 @@ use std::{
   fmt,
 };
 
+use anyhow::anyhow;
 use bevy::{
   asset::{
     io::Reader,
@@ -16,9 +17,12 @@ use bevy::{
     TypeInfo,
     TypeRegistry,
   },
-  scene::serde::{
-    SceneEntitiesDeserializer,
-    SceneMapDeserializer,
+  scene::{
+    serde::{
+      EntitiesSerializer,
+      SceneMapSerializer,
+    },
+    DynamicEntity,
   },
   utils::ConditionalSendFuture,
 };
@@ -29,10 +33,20 @@ use serde::{
     SeqAccess,
     Visitor,
   },
+  ser::SerializeStruct,
   Deserialize,
+  Serialize,
 };
 
-use super::components::Save;
+use super::{
+  components::Save,
+  migration::{
+    versions,
+    MigratingEntitiesSeed,
+    MigratingMapSeed,
+    Versions,
+  },
+};
 
 #[derive(Asset, Debug, Default, TypePath)]
 pub struct SavedEntity {
@@ -90,8 +104,13 @@ impl AssetLoader for SavedEntityLoader {
             continue;
           };
           if TypeInfo::type_id(type_info) == TypeId::of::<Save>() {
-            let mut save = Save::default();
-            save.apply(child_component.as_partial_reflect());
+            let save =
+              Save::from_reflect(child_component.as_partial_reflect()).ok_or_else(|| {
+                anyhow!(
+                  "invalid Save component in {}",
+                  load_context.path().display()
+                )
+              })?;
             child_handle = Some(load_context.load::<SavedEntity>(Clone::clone(&*save)));
           }
         }
@@ -118,7 +137,7 @@ impl<'de> DeserializeSeed<'de> for SavedEntitySeed<'de> {
   {
     deserializer.deserialize_struct(
       "SavedEntity",
-      &["components", "entities"],
+      &["versions", "components", "entities"],
       SavedEntityVisitor {
         registry: self.registry,
       },
@@ -140,15 +159,19 @@ impl<'de> Visitor<'de> for SavedEntityVisitor<'de> {
   where
     A: SeqAccess<'de>,
   {
+    let versions: Versions = seq.next_element()?.unwrap_or_default();
+
     let components = seq
-      .next_element_seed(SceneMapDeserializer {
+      .next_element_seed(MigratingMapSeed {
         registry: self.registry,
+        versions: &versions,
       })?
       .unwrap_or_default();
 
     let entities = seq
-      .next_element_seed(SceneEntitiesDeserializer {
-        type_registry: self.registry,
+      .next_element_seed(MigratingEntitiesSeed {
+        registry: self.registry,
+        versions: &versions,
       })?
       .unwrap_or_default();
 
@@ -165,28 +188,34 @@ impl<'de> Visitor<'de> for SavedEntityVisitor<'de> {
   where
     A: MapAccess<'de>,
   {
+    let mut versions = Versions::default();
     let mut components = vec![];
     let mut entities = vec![];
 
     #[derive(Deserialize)]
     #[serde(field_identifier, rename_all = "lowercase")]
     enum SavedEntityField {
+      Versions,
       Components,
       Entities,
     }
 
-    use SavedEntityField::*;
-
+    // Versions must come first to apply to the rest of the entity.
     while let Some(key) = map.next_key()? {
       match key {
-        Components => {
-          components.extend(map.next_value_seed(SceneMapDeserializer {
+        SavedEntityField::Versions => {
+          versions = map.next_value()?;
+        }
+        SavedEntityField::Components => {
+          components.extend(map.next_value_seed(MigratingMapSeed {
             registry: self.registry,
+            versions: &versions,
           })?);
         }
-        Entities => {
-          entities.extend(map.next_value_seed(SceneEntitiesDeserializer {
-            type_registry: self.registry,
+        SavedEntityField::Entities => {
+          entities.extend(map.next_value_seed(MigratingEntitiesSeed {
+            registry: self.registry,
+            versions: &versions,
           })?);
         }
       }
@@ -201,3 +230,45 @@ impl<'de> Visitor<'de> for SavedEntityVisitor<'de> {
     })
   }
 }
+
+/// Serializes a subtree in the [SavedEntity] format.
+///
+/// `components` belong to the root, and `entities` are keyed by their ids in
+/// the file.
+pub struct SavedEntitySerializer<'a> {
+  pub components: &'a [Box<dyn PartialReflect>],
+  pub entities: &'a [DynamicEntity],
+  pub registry: &'a TypeRegistry,
+}
+
+impl<'a> Serialize for SavedEntitySerializer<'a> {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    let versions = versions(
+      self
+        .components
+        .iter()
+        .chain(self.entities.iter().flat_map(|e| e.components.iter())),
+      self.registry,
+    );
+    let mut state = serializer.serialize_struct("SavedEntity", 3)?;
+    state.serialize_field("versions", &versions)?;
+    state.serialize_field(
+      "components",
+      &SceneMapSerializer {
+        entries: self.components,
+        registry: self.registry,
+      },
+    )?;
+    state.serialize_field(
+      "entities",
+      &EntitiesSerializer {
+        entities: self.entities,
+        registry: self.registry,
+      },
+    )?;
+    state.end()
+  }
+}
@@ -0,0 +1,118 @@
+use std::{
+  path::Path,
+  sync::Arc,
+};
+
+use anyhow::bail;
+use bevy::{
+  ecs::entity::EntityHashMap,
+  prelude::*,
+  reflect::TypeRegistry,
+};
+
+pub mod ron_file;
+pub mod sqlite;
+
+pub use self::{
+  ron_file::RonFileBackend,
+  sqlite::SqliteBackend,
+};
+
+/// The file name of the default [RonFileBackend] save.
+const RON_SAVE: &str = "world.ron";
+
+/// Storage for the persistent portion of the world.
+///
+/// Backends are handed a [DynamicScene] containing every [Persistent]
+/// entity (filtered down to the components registered with
+/// [AppWorldExt::persist]) plus the persisted resources, and are responsible
+/// for getting it onto disk and back.
+///
+/// Saves are run from the [AsyncComputeTaskPool], so implementations must be
+/// safe to call from outside of the main world.
+///
+/// [Persistent]: super::components::Persistent
+/// [AppWorldExt::persist]: super::traits::AppWorldExt::persist
+/// [AsyncComputeTaskPool]: bevy::tasks::AsyncComputeTaskPool
+pub trait SaveBackend: Send + Sync + 'static {
+  /// Load the previously saved world.
+  fn load(&self, registry: &TypeRegistry) -> anyhow::Result<DynamicScene>;
+
+  /// Move what's saved under the ids [SaveBackend::load] returned over to the
+  /// ids the entities were given in the world, old to new.
+  ///
+  /// Backends that keep data the world doesn't see, like rows for
+  /// components that aren't registered, need this to keep it attached to the
+  /// right entities. Ones that are always handed the whole world can ignore
+  /// it.
+  #[allow(unused_variables)]
+  fn remap(&self, entities: &EntityHashMap<Entity>) -> anyhow::Result<()> {
+    Ok(())
+  }
+
+  /// Replace the saved world with the contents of `scene`.
+  fn save(&self, scene: &DynamicScene, registry: &TypeRegistry) -> anyhow::Result<()>;
+
+  /// Whether this backend stores entities individually and supports
+  /// [SaveBackend::save_changes]. Backends that don't will always be handed the
+  /// full world.
+  fn incremental(&self) -> bool {
+    false
+  }
+
+  /// Update the saved world in place.
+  ///
+  /// `scene` contains only the entities that have changed since the last
+  /// save, which should replace their previously saved versions, along with
+  /// all of the persisted resources. Entities in `removed` are no longer
+  /// persistent and should be deleted.
+  #[allow(unused_variables)]
+  fn save_changes(
+    &self,
+    scene: &DynamicScene,
+    removed: &[Entity],
+    registry: &TypeRegistry,
+  ) -> anyhow::Result<()> {
+    bail!("incremental saves not supported by this backend")
+  }
+}
+
+/// The active [SaveBackend].
+#[derive(Resource, Clone, Deref)]
+pub struct Backend(pub Arc<dyn SaveBackend>);
+
+impl Backend {
+  pub fn new(backend: impl SaveBackend) -> Self {
+    Backend(Arc::new(backend))
+  }
+
+  /// Open a backend from a url-ish string.
+  ///
+  /// Supports `sqlite://<path>` and `ron://<path>`. A bare path ending in
+  /// `.ron` is treated as a ron file. `backups` is the number of backups of
+  /// the save to keep.
+  ///
+  /// A new sqlite database imports the `world.ron` next to it, if there is
+  /// one, for servers that were using the ron backend before.
+  pub fn open(url: &str, backups: usize) -> anyhow::Result<Self> {
+    Ok(match url.split_once("://") {
+      Some(("sqlite", path)) => {
+        let ron = Path::new(path).with_file_name(RON_SAVE);
+        Backend::new(
+          SqliteBackend::open(path)?
+            .with_backups(backups)
+            .with_import(ron),
+        )
+      }
+      Some(("ron", path)) => Backend::new(RonFileBackend::new(path).with_backups(backups)),
+      None if url.ends_with(".ron") => Backend::new(RonFileBackend::new(url).with_backups(backups)),
+      _ => bail!("unsupported save backend: {url}"),
+    })
+  }
+}
+
+impl Default for Backend {
+  fn default() -> Self {
+    Backend::new(RonFileBackend::default())
+  }
+}
@@ -0,0 +1,270 @@
+use std::{
+  ffi::OsString,
+  fs,
+  io::Write,
+  path::{
+    Path,
+    PathBuf,
+  },
+  sync::{
+    atomic::{
+      AtomicBool,
+      AtomicUsize,
+      Ordering,
+    },
+    Arc,
+  },
+  time::{
+    SystemTime,
+    UNIX_EPOCH,
+  },
+};
+
+use anyhow::anyhow;
+use bevy::{
+  prelude::*,
+  reflect::TypeRegistry,
+  scene::serialize_ron,
+};
+use serde::de::DeserializeSeed;
+
+use super::{
+  SaveBackend,
+  RON_SAVE,
+};
+use crate::{
+  config::DEFAULT_BACKUPS,
+  savestate::migration::{
+    VersionedSceneSeed,
+    VersionedSceneSerializer,
+  },
+};
+
+/// Saves the whole world as a single ron-serialized [DynamicScene], preceded
+/// by the schema versions of its components.
+///
+/// Writes go to a temporary file which is synced and then renamed over the
+/// real one, so a crash mid-save never leaves a truncated world behind. The
+/// previous `backups` saves are kept alongside it as
+/// `<file name>.<unix millis>.bak`, and are tried newest-first if the main
+/// file fails to load. A main file that failed to load isn't kept as a backup
+/// when it's replaced.
+#[derive(Debug, Clone)]
+pub struct RonFileBackend {
+  pub path: PathBuf,
+  pub backups: usize,
+  /// The main file failed to load last time.
+  broken: Arc<AtomicBool>,
+}
+
+impl RonFileBackend {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self {
+      path: path.into(),
+      backups: DEFAULT_BACKUPS,
+      broken: default(),
+    }
+  }
+
+  pub fn with_backups(mut self, backups: usize) -> Self {
+    self.backups = backups;
+    self
+  }
+
+  fn load_file(path: &Path, registry: &TypeRegistry) -> anyhow::Result<DynamicScene> {
+    let seed = VersionedSceneSeed { registry };
+    let serialized = fs::read_to_string(path)?;
+    let mut de = ron::Deserializer::from_str(&serialized)?;
+    Ok(seed.deserialize(&mut de)?)
+  }
+
+  /// All of the existing backups for this file, newest first.
+  pub fn backups(&self) -> anyhow::Result<Vec<PathBuf>> {
+    list_backups(&self.path)
+  }
+
+  fn backup_current(&self) -> anyhow::Result<()> {
+    if self.backups == 0 || !self.path.exists() || self.broken.load(Ordering::Relaxed) {
+      return Ok(());
+    }
+    let backup = new_backup_path(&self.path)?;
+    // The current save is about to be renamed over, so a hard link is enough
+    // to keep it around. Not every filesystem supports them though.
+    if fs::hard_link(&self.path, &backup).is_err() {
+      fs::copy(&self.path, &backup)?;
+    }
+    Ok(())
+  }
+}
+
+/// The existing backups of the save at `path`, newest first.
+pub(super) fn list_backups(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+  let dir = parent_dir(path);
+  let prefix = backup_prefix(path)?;
+  let mut backups = fs::read_dir(dir)?
+    .filter_map(|entry| entry.ok())
+    .filter_map(|entry| {
+      let name = entry.file_name().into_string().ok()?;
+      let stamp = name.strip_prefix(&prefix)?.strip_suffix(".bak")?;
+      Some((stamp.parse::<u128>().ok()?, entry.path()))
+    })
+    .collect::<Vec<_>>();
+  backups.sort_by(|a, b| b.0.cmp(&a.0));
+  Ok(backups.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Where to put a new backup of the save at `path`.
+pub(super) fn new_backup_path(path: &Path) -> anyhow::Result<PathBuf> {
+  let stamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+  let prefix = backup_prefix(path)?;
+  Ok(parent_dir(path).join(format!("{prefix}{stamp}.bak")))
+}
+
+/// Remove all but the newest `keep` backups of the save at `path`.
+pub(super) fn prune_backups(path: &Path, keep: usize) -> anyhow::Result<()> {
+  for old in list_backups(path)?.into_iter().skip(keep) {
+    debug!(path = %old.display(), "removing old world backup");
+    fs::remove_file(old)?;
+  }
+  Ok(())
+}
+
+impl Default for RonFileBackend {
+  fn default() -> Self {
+    Self::new(RON_SAVE)
+  }
+}
+
+fn parent_dir(path: &Path) -> &Path {
+  match path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => Path::new("."),
+  }
+}
+
+fn backup_prefix(path: &Path) -> anyhow::Result<String> {
+  let name = path
+    .file_name()
+    .and_then(|n| n.to_str())
+    .ok_or_else(|| anyhow!("invalid save path: {}", path.display()))?;
+  Ok(format!("{name}."))
+}
+
+/// Write `data` to `path` such that readers either see the old contents or the
+/// new ones, never a partial write.
+///
+/// The data goes to a temporary file next to `path` first, named after the
+/// process and a counter so that concurrent writers never share one.
+pub fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+  static COUNTER: AtomicUsize = AtomicUsize::new(0);
+  let mut tmp_name = path.as_os_str().to_owned();
+  tmp_name.push(OsString::from(format!(
+    ".{}.{}.tmp",
+    std::process::id(),
+    COUNTER.fetch_add(1, Ordering::Relaxed)
+  )));
+  let tmp = PathBuf::from(tmp_name);
+
+  let result = write_and_rename(&tmp, path, data);
+  if result.is_err() {
+    let _ = fs::remove_file(&tmp);
+  }
+  result
+}
+
+fn write_and_rename(tmp: &Path, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+  let mut f = fs::OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .open(tmp)?;
+  f.write_all(data)?;
+  f.sync_all()?;
+  drop(f);
+
+  // Make sure the directory entry for the new file is on disk before it
+  // replaces the old one, and that the rename itself survives a crash.
+  #[cfg(unix)]
+  fs::File::open(parent_dir(path))?.sync_all()?;
+  fs::rename(tmp, path)?;
+  #[cfg(unix)]
+  fs::File::open(parent_dir(path))?.sync_all()?;
+
+  Ok(())
+}
+
+impl SaveBackend for RonFileBackend {
+  fn load(&self, registry: &TypeRegistry) -> anyhow::Result<DynamicScene> {
+    let error = match Self::load_file(&self.path, registry) {
+      Ok(scene) => {
+        self.broken.store(false, Ordering::Relaxed);
+        return Ok(scene);
+      }
+      Err(error) => error,
+    };
+    self.broken.store(self.path.exists(), Ordering::Relaxed);
+    warn!(%error, path = %self.path.display(), "failed to load world, trying backups");
+
+    for backup in self.backups().unwrap_or_default() {
+      match Self::load_file(&backup, registry) {
+        Ok(scene) => {
+          warn!(path = %backup.display(), "loaded world from backup");
+          return Ok(scene);
+        }
+        Err(error) => {
+          warn!(%error, path = %backup.display(), "failed to load backup");
+        }
+      }
+    }
+
+    Err(error)
+  }
+
+  fn save(&self, scene: &DynamicScene, registry: &TypeRegistry) -> anyhow::Result<()> {
+    let serialized = serialize_ron(VersionedSceneSerializer { scene, registry })?;
+    self.backup_current()?;
+    write_atomic(&self.path, serialized.as_bytes())?;
+    self.broken.store(false, Ordering::Relaxed);
+    if let Err(error) = prune_backups(&self.path, self.backups) {
+      warn!(%error, "failed to prune old world backups");
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn rotates_backups() {
+    let dir = std::env::temp_dir().join(format!("bevy_mud_backups_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let backend = RonFileBackend::new(dir.join("world.ron")).with_backups(2);
+    let registry = TypeRegistry::default();
+
+    for _ in 0..4 {
+      backend.save(&DynamicScene::default(), &registry).unwrap();
+      std::thread::sleep(std::time::Duration::from_millis(2));
+    }
+
+    assert!(backend.path.exists());
+    assert_eq!(backend.backups().unwrap().len(), 2);
+    assert!(fs::read_dir(&dir).unwrap().all(|entry| !entry
+      .unwrap()
+      .path()
+      .to_string_lossy()
+      .ends_with(".tmp")));
+
+    // A truncated world should fall back to the newest backup.
+    fs::write(&backend.path, "(resources: {").unwrap();
+    assert!(backend.load(&registry).is_ok());
+
+    // It's replaced on the next save without pushing out a good backup.
+    let good = backend.backups().unwrap();
+    backend.save(&DynamicScene::default(), &registry).unwrap();
+    assert_eq!(backend.backups().unwrap(), good);
+    assert!(RonFileBackend::load_file(&backend.path, &registry).is_ok());
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,856 @@
+use std::{
+  fs,
+  path::{
+    Path,
+    PathBuf,
+  },
+  sync::{
+    Mutex,
+    PoisonError,
+  },
+};
+
+use anyhow::anyhow;
+use bevy::{
+  ecs::entity::EntityHashMap,
+  prelude::*,
+  reflect::{
+    serde::TypedReflectSerializer,
+    TypeInfo,
+    TypeRegistry,
+  },
+  scene::DynamicEntity,
+  utils::HashMap,
+};
+use rusqlite::{
+  params,
+  Connection,
+  Transaction,
+};
+use serde::de::DeserializeSeed;
+
+use super::{
+  ron_file::{
+    list_backups,
+    new_backup_path,
+    prune_backups,
+  },
+  RonFileBackend,
+  SaveBackend,
+};
+use crate::{
+  account::{
+    UserDb,
+    UserEntry,
+  },
+  config::DEFAULT_BACKUPS,
+  net::{
+    BanEntry,
+    BanList,
+  },
+  savestate::migration::{
+    current_version,
+    ComponentSeed,
+  },
+};
+
+/// The database schema. Kept in sync with the `schema.sql` at the root of the
+/// repo, which is what the `Makefile` uses to build fresh databases.
+const SCHEMA: &str = include_str!("../../../schema.sql");
+
+/// Stores the world in an sqlite database following `schema.sql`.
+///
+/// Every persisted component is stored as its own row in `entity_component`,
+/// keyed by the entity's bits and the component's type path, with the
+/// component data serialized as ron along with its schema version. The
+/// [UserDb] resource is split out into the `user` and `character` tables, and
+/// the [BanList] into `ban`. Any other resources go in `resource`, in the
+/// same format as components.
+///
+/// Rows for components and resources that aren't registered, say because the
+/// plugin that added them was removed, are skipped on load but left alone on
+/// save, so they come back if the type is registered again. Entities get new
+/// ids every time they're loaded, so [SaveBackend::remap] moves the rows over
+/// to keep them with their entities.
+///
+/// Since entities are stored individually, this backend supports incremental
+/// saves. Full saves, which happen once a run, first copy the database to
+/// `<file name>.<unix millis>.bak` with `VACUUM INTO`, keeping the newest
+/// `backups` of them. To go back to one, copy it over the database while the
+/// server is stopped.
+pub struct SqliteBackend {
+  conn: Mutex<Connection>,
+  import: Option<PathBuf>,
+  /// The database file, unless it's in memory.
+  path: Option<PathBuf>,
+  backups: usize,
+}
+
+impl SqliteBackend {
+  pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+    let path = path.as_ref();
+    let conn = Connection::open(path)?;
+    let backend = Self::from_connection(conn).map_err(|error| {
+      match list_backups(path)
+        .ok()
+        .and_then(|backups| backups.into_iter().next())
+      {
+        Some(backup) => error.context(format!(
+          "failed to open {}, the newest backup is {}",
+          path.display(),
+          backup.display()
+        )),
+        None => error,
+      }
+    })?;
+    Ok(Self {
+      path: Some(path.into()),
+      ..backend
+    })
+  }
+
+  pub fn open_in_memory() -> anyhow::Result<Self> {
+    Self::from_connection(Connection::open_in_memory()?)
+  }
+
+  fn from_connection(conn: Connection) -> anyhow::Result<Self> {
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    conn.execute_batch(SCHEMA)?;
+    // Databases created before components were versioned.
+    let has_version = conn
+      .prepare("SELECT 1 FROM pragma_table_info('entity_component') WHERE name = 'version'")?
+      .exists([])?;
+    if !has_version {
+      conn.execute_batch(
+        "ALTER TABLE entity_component ADD COLUMN version integer not null default 0;",
+      )?;
+    }
+    Ok(Self {
+      conn: Mutex::new(conn),
+      import: None,
+      path: None,
+      backups: DEFAULT_BACKUPS,
+    })
+  }
+
+  /// Keep `backups` previous copies of the database. 0 turns them off.
+  pub fn with_backups(mut self, backups: usize) -> Self {
+    self.backups = backups;
+    self
+  }
+
+  /// Copy the database as it is now to a new backup.
+  fn backup(&self, conn: &Connection) -> anyhow::Result<()> {
+    let Some(path) = self.path.as_ref().filter(|_| self.backups > 0) else {
+      return Ok(());
+    };
+    let backup = new_backup_path(path)?;
+    conn.execute("VACUUM INTO ?", params![backup.to_string_lossy()])?;
+    if let Err(error) = prune_backups(path, self.backups) {
+      warn!(%error, "failed to prune old database backups");
+    }
+    Ok(())
+  }
+
+  /// Import the world from the [RonFileBackend] save at `path` the first time
+  /// this database is loaded while it's still empty. The imported file is
+  /// renamed to `<file name>.imported` afterwards.
+  pub fn with_import(mut self, path: impl Into<PathBuf>) -> Self {
+    self.import = Some(path.into());
+    self
+  }
+
+  /// Load the ron save to import, if there's one and nothing has been saved
+  /// here yet.
+  fn import(&self, registry: &TypeRegistry) -> anyhow::Result<Option<DynamicScene>> {
+    let Some(path) = self.import.as_ref().filter(|path| path.exists()) else {
+      return Ok(None);
+    };
+    let empty = {
+      let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+      !conn.prepare("SELECT 1 FROM entity")?.exists([])?
+        && !conn.prepare("SELECT 1 FROM user")?.exists([])?
+    };
+    if !empty {
+      return Ok(None);
+    }
+
+    info!(path = %path.display(), "importing ron save into sqlite");
+    let scene = RonFileBackend::new(path).load(registry)?;
+    self.save(&scene, registry)?;
+    let mut imported = path.as_os_str().to_owned();
+    imported.push(".imported");
+    fs::rename(path, imported)?;
+    Ok(Some(scene))
+  }
+}
+
+fn entity_id(entity: Entity) -> i64 {
+  entity.to_bits() as i64
+}
+
+fn component_id(
+  tx: &Transaction,
+  cache: &mut HashMap<String, i64>,
+  name: &str,
+) -> anyhow::Result<i64> {
+  if let Some(id) = cache.get(name) {
+    return Ok(*id);
+  }
+  tx.prepare_cached("INSERT OR IGNORE INTO component (name) VALUES (?)")?
+    .execute(params![name])?;
+  let id = tx
+    .prepare_cached("SELECT id FROM component WHERE name = ?")?
+    .query_row(params![name], |row| row.get(0))?;
+  cache.insert(name.into(), id);
+  Ok(id)
+}
+
+fn write_entity(
+  tx: &Transaction,
+  cache: &mut HashMap<String, i64>,
+  entity: &DynamicEntity,
+  registry: &TypeRegistry,
+) -> anyhow::Result<()> {
+  let id = entity_id(entity.entity);
+  tx.prepare_cached("INSERT OR IGNORE INTO entity (id) VALUES (?)")?
+    .execute(params![id])?;
+  // Replace the components we know about, but keep the rows for any that
+  // aren't registered.
+  let saved = tx
+    .prepare_cached(
+      "SELECT c.id, c.name
+       FROM entity_component ec, component c
+       WHERE ec.entity = ? AND c.id = ec.component",
+    )?
+    .query_map(params![id], |row| {
+      Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+  for (component, name) in saved {
+    if registry.get_with_type_path(&name).is_some() {
+      tx.prepare_cached("DELETE FROM entity_component WHERE entity = ? AND component = ?")?
+        .execute(params![id, component])?;
+    }
+  }
+  for component in &entity.components {
+    let Some(info) = component.get_represented_type_info() else {
+      warn!(entity = ?entity.entity, "skipping component without type info");
+      continue;
+    };
+    let data = ron::to_string(&TypedReflectSerializer::new(
+      component.as_partial_reflect(),
+      registry,
+    ))?;
+    let version = current_version(registry, info.type_id());
+    let component = component_id(tx, cache, info.type_path())?;
+    tx.prepare_cached(
+      "INSERT INTO entity_component (entity, component, data, version) VALUES (?, ?, ?, ?)",
+    )?
+    .execute(params![id, component, data, version])?;
+  }
+  Ok(())
+}
+
+fn write_users(tx: &Transaction, users: &UserDb) -> anyhow::Result<()> {
+  tx.execute("DELETE FROM character", [])?;
+  let saved = tx
+    .prepare("SELECT name FROM user")?
+    .query_map([], |row| row.get::<_, String>(0))?
+    .collect::<Result<Vec<_>, _>>()?;
+  for name in saved {
+    if !users.users.contains_key(&name) {
+      tx.prepare_cached("DELETE FROM user WHERE name = ?")?
+        .execute(params![name])?;
+    }
+  }
+  for (name, entry) in &users.users {
+    tx.prepare_cached(
+      "INSERT INTO user (name, password) VALUES (?, ?)
+       ON CONFLICT (name) DO UPDATE SET password = excluded.password",
+    )?
+    .execute(params![name, entry.hashed_password])?;
+    let user_id: i64 = tx
+      .prepare_cached("SELECT id FROM user WHERE name = ?")?
+      .query_row(params![name], |row| row.get(0))?;
+    tx.prepare_cached("INSERT INTO character (user_id, entity) VALUES (?, ?)")?
+      .execute(params![user_id, entity_id(entry.character)])?;
+  }
+  Ok(())
+}
+
+fn read_users(conn: &Connection) -> anyhow::Result<UserDb> {
+  let mut stmt = conn.prepare(
+    "SELECT u.name, u.password, c.entity
+     FROM user u, character c
+     WHERE c.user_id = u.id",
+  )?;
+  let users = stmt
+    .query_map([], |row| {
+      Ok((
+        row.get::<_, String>(0)?,
+        UserEntry {
+          hashed_password: row.get(1)?,
+          character: Entity::from_bits(row.get::<_, i64>(2)? as u64),
+        },
+      ))
+    })?
+    .collect::<Result<_, _>>()?;
+  Ok(UserDb { users })
+}
+
+fn write_bans(tx: &Transaction, bans: &BanList) -> anyhow::Result<()> {
+  tx.execute("DELETE FROM ban", [])?;
+  for (target, entry) in &bans.bans {
+    tx.prepare_cached("INSERT INTO ban (target, reason, banned_by, created) VALUES (?, ?, ?, ?)")?
+      .execute(params![
+        target,
+        entry.reason,
+        entry.banned_by,
+        entry.created as i64
+      ])?;
+  }
+  Ok(())
+}
+
+fn read_bans(conn: &Connection) -> anyhow::Result<BanList> {
+  let mut stmt = conn.prepare("SELECT target, reason, banned_by, created FROM ban")?;
+  let bans = stmt
+    .query_map([], |row| {
+      Ok((
+        row.get::<_, String>(0)?,
+        BanEntry {
+          reason: row.get(1)?,
+          banned_by: row.get(2)?,
+          created: row.get::<_, i64>(3)? as u64,
+        },
+      ))
+    })?
+    .collect::<Result<_, _>>()?;
+  Ok(BanList { bans })
+}
+
+fn write_resource(
+  tx: &Transaction,
+  resource: &dyn PartialReflect,
+  info: &TypeInfo,
+  registry: &TypeRegistry,
+) -> anyhow::Result<()> {
+  let data = ron::to_string(&TypedReflectSerializer::new(resource, registry))?;
+  let version = current_version(registry, info.type_id());
+  tx.prepare_cached(
+    "INSERT INTO resource (name, data, version) VALUES (?, ?, ?)
+     ON CONFLICT (name) DO UPDATE SET data = excluded.data, version = excluded.version",
+  )?
+  .execute(params![info.type_path(), data, version])?;
+  Ok(())
+}
+
+fn read_resources(
+  conn: &Connection,
+  registry: &TypeRegistry,
+) -> anyhow::Result<Vec<Box<dyn PartialReflect>>> {
+  let mut resources = vec![];
+  let mut stmt = conn.prepare("SELECT name, data, version FROM resource")?;
+  let mut rows = stmt.query([])?;
+  while let Some(row) = rows.next()? {
+    let name: String = row.get(0)?;
+    let data: String = row.get(1)?;
+    let version: u32 = row.get(2)?;
+    if registry.get_with_type_path(&name).is_none() {
+      warn!(resource = name, "skipping unregistered resource");
+      continue;
+    }
+    let mut de = ron::Deserializer::from_str(&data)?;
+    let resource = ComponentSeed {
+      registry,
+      path: &name,
+      version,
+    }
+    .deserialize(&mut de)
+    .map_err(|error| anyhow!("error deserializing {name}: {error}"))?;
+    resources.push(resource);
+  }
+  Ok(resources)
+}
+
+fn read_entities(conn: &Connection, registry: &TypeRegistry) -> anyhow::Result<Vec<DynamicEntity>> {
+  let mut entities: Vec<DynamicEntity> = conn
+    .prepare("SELECT id FROM entity ORDER BY id")?
+    .query_map([], |row| row.get::<_, i64>(0))?
+    .map(|id| {
+      Ok(DynamicEntity {
+        entity: Entity::from_bits(id? as u64),
+        components: vec![],
+      })
+    })
+    .collect::<anyhow::Result<_>>()?;
+  let index = entities
+    .iter()
+    .enumerate()
+    .map(|(i, e)| (e.entity, i))
+    .collect::<HashMap<_, _>>();
+
+  let mut stmt = conn.prepare(
+    "SELECT ec.entity, c.name, ec.data, ec.version
+     FROM entity_component ec, component c
+     WHERE c.id = ec.component",
+  )?;
+  let mut rows = stmt.query([])?;
+  while let Some(row) = rows.next()? {
+    let entity = Entity::from_bits(row.get::<_, i64>(0)? as u64);
+    let name: String = row.get(1)?;
+    let data: String = row.get(2)?;
+    let version: u32 = row.get(3)?;
+    if registry.get_with_type_path(&name).is_none() {
+      warn!(?entity, component = name, "skipping unregistered component");
+      continue;
+    }
+    let mut de = ron::Deserializer::from_str(&data)?;
+    let component = ComponentSeed {
+      registry,
+      path: &name,
+      version,
+    }
+    .deserialize(&mut de)
+    .map_err(|error| anyhow!("error deserializing {name} for {entity:?}: {error}"))?;
+    let i = index
+      .get(&entity)
+      .copied()
+      .ok_or_else(|| anyhow!("component row for missing entity {entity:?}"))?;
+    entities[i].components.push(component);
+  }
+
+  Ok(entities)
+}
+
+impl SaveBackend for SqliteBackend {
+  fn load(&self, registry: &TypeRegistry) -> anyhow::Result<DynamicScene> {
+    if let Some(scene) = self.import(registry)? {
+      return Ok(scene);
+    }
+    let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+    let entities = read_entities(&conn, registry)?;
+    let mut resources: Vec<Box<dyn PartialReflect>> =
+      vec![Box::new(read_users(&conn)?), Box::new(read_bans(&conn)?)];
+    resources.extend(read_resources(&conn, registry)?);
+    Ok(DynamicScene {
+      resources,
+      entities,
+    })
+  }
+
+  fn remap(&self, entities: &EntityHashMap<Entity>) -> anyhow::Result<()> {
+    let mut conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+    let tx = conn.transaction()?;
+    // Ids are moved one at a time, so references are briefly out of sync.
+    tx.execute_batch("PRAGMA defer_foreign_keys = ON;")?;
+
+    const COLUMNS: [(&str, &str); 3] = [
+      ("entity", "id"),
+      ("entity_component", "entity"),
+      ("character", "entity"),
+    ];
+    // A new id can be an old id of another entity, so get them all out of the
+    // way first. Saved ids are never negative.
+    for (table, column) in COLUMNS {
+      tx.execute(&format!("UPDATE {table} SET {column} = -{column} - 1"), [])?;
+    }
+    for (old, new) in entities {
+      for (table, column) in COLUMNS {
+        tx.prepare_cached(&format!(
+          "UPDATE {table} SET {column} = ? WHERE {column} = ?"
+        ))?
+        .execute(params![entity_id(*new), -entity_id(*old) - 1])?;
+      }
+    }
+
+    tx.commit()?;
+    Ok(())
+  }
+
+  fn save(&self, scene: &DynamicScene, registry: &TypeRegistry) -> anyhow::Result<()> {
+    let mut conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+    self.backup(&conn)?;
+    let tx = conn.transaction()?;
+
+    // Entities that are no longer in the world take their components with
+    // them via `on delete cascade`.
+    tx.execute(
+      "CREATE TEMP TABLE IF NOT EXISTS live_entity (id integer primary key)",
+      [],
+    )?;
+    tx.execute("DELETE FROM live_entity", [])?;
+    for entity in &scene.entities {
+      tx.prepare_cached("INSERT INTO live_entity (id) VALUES (?)")?
+        .execute(params![entity_id(entity.entity)])?;
+    }
+    tx.execute(
+      "DELETE FROM character WHERE entity NOT IN (SELECT id FROM live_entity)",
+      [],
+    )?;
+    tx.execute(
+      "DELETE FROM entity WHERE id NOT IN (SELECT id FROM live_entity)",
+      [],
+    )?;
+
+    write_scene(&tx, scene, registry)?;
+
+    tx.commit()?;
+    Ok(())
+  }
+
+  fn incremental(&self) -> bool {
+    true
+  }
+
+  fn save_changes(
+    &self,
+    scene: &DynamicScene,
+    removed: &[Entity],
+    registry: &TypeRegistry,
+  ) -> anyhow::Result<()> {
+    let mut conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+    let tx = conn.transaction()?;
+
+    for entity in removed.iter().copied().map(entity_id) {
+      tx.prepare_cached("DELETE FROM character WHERE entity = ?")?
+        .execute(params![entity])?;
+      tx.prepare_cached("DELETE FROM entity WHERE id = ?")?
+        .execute(params![entity])?;
+    }
+
+    write_scene(&tx, scene, registry)?;
+
+    tx.commit()?;
+    Ok(())
+  }
+}
+
+fn write_scene(
+  tx: &Transaction,
+  scene: &DynamicScene,
+  registry: &TypeRegistry,
+) -> anyhow::Result<()> {
+  let mut cache = HashMap::default();
+
+  for entity in &scene.entities {
+    write_entity(tx, &mut cache, entity, registry)?;
+  }
+
+  for resource in &scene.resources {
+    let Some(info) = resource.get_represented_type_info() else {
+      continue;
+    };
+    if info.type_path() == UserDb::type_path() {
+      let users = UserDb::from_reflect(resource.as_partial_reflect())
+        .ok_or_else(|| anyhow!("invalid user database"))?;
+      write_users(tx, &users)?;
+    } else if info.type_path() == BanList::type_path() {
+      let bans = BanList::from_reflect(resource.as_partial_reflect())
+        .ok_or_else(|| anyhow!("invalid ban list"))?;
+      write_bans(tx, &bans)?;
+    } else {
+      write_resource(tx, resource.as_partial_reflect(), info, registry)?;
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::map::Map;
+
+  #[derive(Component, Reflect, Default)]
+  #[reflect(Component)]
+  struct Tag(u32);
+
+  #[derive(Resource, Reflect, Default)]
+  #[reflect(Resource)]
+  struct Motd(String);
+
+  #[test]
+  fn round_trip() {
+    let mut registry = TypeRegistry::default();
+    registry.register::<Map>();
+    registry.register::<UserDb>();
+    registry.register::<BanList>();
+
+    let entity = Entity::from_raw(42);
+    let mut users = UserDb::default();
+    users.users.insert(
+      "user".into(),
+      UserEntry {
+        hashed_password: "hunter2".into(),
+        character: entity,
+      },
+    );
+    let mut bans = BanList::default();
+    bans.ban(
+      &"10.0.0.0/8".parse().unwrap(),
+      BanEntry::new("spam", "admin"),
+    );
+    let scene = DynamicScene {
+      resources: vec![Box::new(users), Box::new(bans)],
+      entities: vec![DynamicEntity {
+        entity,
+        components: vec![Box::new(Map("default".into()))],
+      }],
+    };
+
+    let backend = SqliteBackend::open_in_memory().unwrap();
+    backend.save(&scene, &registry).unwrap();
+    // Saving twice shouldn't duplicate anything.
+    backend.save(&scene, &registry).unwrap();
+
+    let loaded = backend.load(&registry).unwrap();
+    assert_eq!(loaded.entities.len(), 1);
+    assert_eq!(loaded.entities[0].entity, entity);
+    let map = Map::from_reflect(&*loaded.entities[0].components[0]).unwrap();
+    assert_eq!(*map, "default");
+
+    let users = UserDb::from_reflect(&*loaded.resources[0]).unwrap();
+    assert_eq!(users.users["user"].character, entity);
+    assert_eq!(users.users["user"].hashed_password, "hunter2");
+    let bans = BanList::from_reflect(&*loaded.resources[1]).unwrap();
+    assert_eq!(bans.bans["10.0.0.0/8"].reason, "spam");
+  }
+
+  #[test]
+  fn incremental() {
+    let mut registry = TypeRegistry::default();
+    registry.register::<Map>();
+
+    let a = Entity::from_raw(1);
+    let b = Entity::from_raw(2);
+    let map_entity = |entity, name: &str| DynamicEntity {
+      entity,
+      components: vec![Box::new(Map(name.into()))],
+    };
+
+    let backend = SqliteBackend::open_in_memory().unwrap();
+    backend
+      .save(
+        &DynamicScene {
+          resources: vec![],
+          entities: vec![map_entity(a, "a"), map_entity(b, "b")],
+        },
+        &registry,
+      )
+      .unwrap();
+
+    backend
+      .save_changes(
+        &DynamicScene {
+          resources: vec![],
+          entities: vec![map_entity(a, "changed")],
+        },
+        &[b],
+        &registry,
+      )
+      .unwrap();
+
+    let loaded = backend.load(&registry).unwrap();
+    assert_eq!(loaded.entities.len(), 1);
+    assert_eq!(loaded.entities[0].entity, a);
+    let map = Map::from_reflect(&*loaded.entities[0].components[0]).unwrap();
+    assert_eq!(*map, "changed");
+  }
+
+  #[test]
+  fn full_sync() {
+    let mut registry = TypeRegistry::default();
+    registry.register::<Map>();
+    registry.register::<Tag>();
+    registry.register::<UserDb>();
+    registry.register::<BanList>();
+    registry.register::<Motd>();
+
+    let entity = Entity::from_raw(7);
+    let users = |names: &[&str]| {
+      let mut users = UserDb::default();
+      for name in names {
+        users.users.insert(
+          name.to_string(),
+          UserEntry {
+            hashed_password: "pw".into(),
+            character: entity,
+          },
+        );
+      }
+      users
+    };
+    let backend = SqliteBackend::open_in_memory().unwrap();
+    backend
+      .save(
+        &DynamicScene {
+          resources: vec![
+            Box::new(users(&["alice", "bob"])),
+            Box::new(Motd("hi".into())),
+          ],
+          entities: vec![DynamicEntity {
+            entity,
+            components: vec![Box::new(Map("default".into())), Box::new(Tag(3))],
+          }],
+        },
+        &registry,
+      )
+      .unwrap();
+
+    // Save again without `Tag` registered and without bob.
+    let mut partial = TypeRegistry::default();
+    partial.register::<Map>();
+    partial.register::<UserDb>();
+    backend
+      .save_changes(
+        &DynamicScene {
+          resources: vec![Box::new(users(&["alice"]))],
+          entities: vec![DynamicEntity {
+            entity,
+            components: vec![Box::new(Map("changed".into()))],
+          }],
+        },
+        &[],
+        &partial,
+      )
+      .unwrap();
+
+    let loaded = backend.load(&registry).unwrap();
+    let components = &loaded.entities[0].components;
+    assert_eq!(components.len(), 2);
+    assert!(components
+      .iter()
+      .any(|c| Tag::from_reflect(&**c).is_some_and(|tag| tag.0 == 3)));
+    assert!(components
+      .iter()
+      .any(|c| Map::from_reflect(&**c).is_some_and(|map| *map == "changed")));
+
+    let users = UserDb::from_reflect(&*loaded.resources[0]).unwrap();
+    assert!(users.users.contains_key("alice"));
+    assert!(!users.users.contains_key("bob"));
+    let motd = loaded
+      .resources
+      .iter()
+      .find_map(|r| Motd::from_reflect(&**r))
+      .unwrap();
+    assert_eq!(motd.0, "hi");
+  }
+
+  #[test]
+  fn keeps_unregistered_components_across_loads() {
+    let mut registry = TypeRegistry::default();
+    registry.register::<Map>();
+    registry.register::<Tag>();
+
+    let old = Entity::from_raw(7);
+    let backend = SqliteBackend::open_in_memory().unwrap();
+    backend
+      .save(
+        &DynamicScene {
+          resources: vec![],
+          entities: vec![DynamicEntity {
+            entity: old,
+            components: vec![Box::new(Map("default".into())), Box::new(Tag(3))],
+          }],
+        },
+        &registry,
+      )
+      .unwrap();
+
+    // Load it without `Tag` into a world where its old id is taken.
+    let partial = AppTypeRegistry::default();
+    partial.write().register::<Map>();
+    let mut world = World::new();
+    world.insert_resource(partial.clone());
+    for _ in 0..10 {
+      world.spawn_empty();
+    }
+    let scene = backend.load(&partial.read()).unwrap();
+    let mut entities = EntityHashMap::default();
+    scene.write_to_world(&mut world, &mut entities).unwrap();
+    backend.remap(&entities).unwrap();
+    let new = entities[&old];
+    assert_ne!(new, old);
+
+    let scene = DynamicSceneBuilder::from_world(&world)
+      .extract_entities([new].into_iter())
+      .build();
+    backend.save(&scene, &partial.read()).unwrap();
+
+    let loaded = backend.load(&registry).unwrap();
+    assert_eq!(loaded.entities.len(), 1);
+    assert_eq!(loaded.entities[0].entity, new);
+    let components = &loaded.entities[0].components;
+    assert_eq!(components.len(), 2);
+    assert!(components
+      .iter()
+      .any(|c| Tag::from_reflect(&**c).is_some_and(|tag| tag.0 == 3)));
+  }
+
+  #[test]
+  fn keeps_backups() {
+    let mut registry = TypeRegistry::default();
+    registry.register::<Map>();
+
+    let dir = std::env::temp_dir().join(format!("bevy_mud_sqlite_backups_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("db.sqlite");
+    let backend = SqliteBackend::open(&path).unwrap().with_backups(2);
+    let scene = DynamicScene {
+      resources: vec![],
+      entities: vec![DynamicEntity {
+        entity: Entity::from_raw(1),
+        components: vec![Box::new(Map("default".into()))],
+      }],
+    };
+    for _ in 0..4 {
+      backend.save(&scene, &registry).unwrap();
+      std::thread::sleep(std::time::Duration::from_millis(2));
+    }
+
+    let backups = list_backups(&path).unwrap();
+    assert_eq!(backups.len(), 2);
+    let restored = SqliteBackend::open(&backups[0]).unwrap().with_backups(0);
+    assert_eq!(restored.load(&registry).unwrap().entities.len(), 1);
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn imports_ron_save() {
+    let mut registry = TypeRegistry::default();
+    registry.register::<Map>();
+
+    let dir = std::env::temp_dir().join(format!("bevy_mud_import_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let ron = dir.join("world.ron");
+    let entity = Entity::from_raw(3);
+    RonFileBackend::new(&ron)
+      .save(
+        &DynamicScene {
+          resources: vec![],
+          entities: vec![DynamicEntity {
+            entity,
+            components: vec![Box::new(Map("default".into()))],
+          }],
+        },
+        &registry,
+      )
+      .unwrap();
+
+    let backend = SqliteBackend::open(dir.join("db.sqlite"))
+      .unwrap()
+      .with_backups(0)
+      .with_import(&ron);
+    assert_eq!(backend.load(&registry).unwrap().entities.len(), 1);
+    assert!(!ron.exists());
+    assert!(dir.join("world.ron.imported").exists());
+    // It's in the database now.
+    let loaded = backend.load(&registry).unwrap();
+    assert_eq!(loaded.entities[0].entity, entity);
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -1,7 +1,10 @@
 use std::path::PathBuf;
 
 use bevy::{
-  ecs::entity::EntityHashMap,
+  ecs::{
+    component::Tick,
+    entity::EntityHashMap,
+  },
   prelude::*,
 };
 use serde::{
@@ -22,4 +25,11 @@ pub struct SavedEntityState {
   /// Mappings from the save file to the world.
   pub entity_mappings: EntityHashMap<Entity>,
   pub new_entity_mappings: EntityHashMap<Entity>,
+  /// The subtree as it was last loaded from or written to the file. `None`
+  /// until the file has been loaded, which keeps write-back from clobbering
+  /// it.
+  pub written: Option<String>,
+  /// The change tick as of which the world matched `written`. Write-back
+  /// leaves the file alone unless something in the subtree changed after it.
+  pub synced: Tick,
 }
@@ -1,15 +1,31 @@
-use std::sync::PoisonError;
+use std::{
+  any::TypeId,
+  sync::PoisonError,
+};
 
 use bevy::{
+  ecs::{
+    component::Tick,
+    entity::{
+      EntityHashMap,
+      EntityHashSet,
+    },
+  },
   prelude::*,
-  scene::DynamicEntity,
+  reflect::ReflectMut,
+  scene::{
+    serialize_ron,
+    DynamicEntity,
+  },
   utils::HashSet,
 };
 
 use super::{
-  assets::SavedEntity,
+  assets::{
+    SavedEntity,
+    SavedEntitySerializer,
+  },
   components::Save,
-  resources::SavedEntityStates,
 };
 use crate::savestate::{
   components::SavedEntityState,
@@ -55,10 +71,7 @@ pub fn write_saved_entity(
 
     if let Err(error) = scene.write_to_world(world, new_mappings) {
       warn!(%error, "error spawning saved entity");
-      std::mem::swap(
-        &mut state,
-        &mut *world.get_mut::<SavedEntityState>(entity).unwrap(),
-      );
+      restore_state(world, entity, state);
       return;
     }
 
@@ -120,10 +133,345 @@ pub fn write_saved_entity(
     }
 
     std::mem::swap(current_mappings, new_mappings);
-    std::mem::swap(
-      &mut state,
-      &mut *world.get_mut::<SavedEntityState>(entity).unwrap(),
-    );
+
+    // Remember what the file looks like as far as write-back is concerned, so
+    // that it's only rewritten once something actually changes.
+    drop((registry_read, persist_read));
+    state.written = match serialize_saved_entity(world, entity, &mut state) {
+      Ok(serialized) => Some(serialized),
+      Err(error) => {
+        warn!(%error, ?entity, "failed to serialize saved entity");
+        None
+      }
+    };
+
+    // Everything up to now came from the file.
+    state.synced = world.read_change_tick();
+
+    restore_state(world, entity, state);
     debug!(?entity, "finished writing saved entity");
   }
 }
+
+fn restore_state(world: &mut World, entity: Entity, state: SavedEntityState) {
+  match world.get_mut::<SavedEntityState>(entity) {
+    Some(mut world_state) => *world_state = state,
+    None => warn!(?entity, "saved entity lost its state while loading"),
+  }
+}
+
+/// The entities whose components go in `root`'s file, starting with `root`,
+/// and the subset of them that are the roots of their own files.
+fn saved_subtree(world: &World, root: Entity) -> (Vec<Entity>, EntityHashSet) {
+  let mut subtree = vec![root];
+  let mut nested = EntityHashSet::default();
+  let mut i = 0;
+  while let Some(entity) = subtree.get(i).copied() {
+    i += 1;
+    if entity != root && world.get::<Save>(entity).is_some() {
+      nested.insert(entity);
+      continue;
+    }
+    if let Some(children) = world.get::<Children>(entity) {
+      subtree.extend(children.iter().copied());
+    }
+  }
+  (subtree, nested)
+}
+
+/// Whether anything that goes in `root`'s file has changed since `since`, or
+/// is in `removed`.
+pub fn saved_subtree_changed(
+  world: &World,
+  root: Entity,
+  since: Tick,
+  removed: &EntityHashSet,
+) -> bool {
+  let (subtree, nested) = saved_subtree(world, root);
+  let this_run = world.read_change_tick();
+  let component_ids = |types: &[TypeId]| {
+    types
+      .iter()
+      .filter_map(|type_id| world.components().get_id(*type_id))
+      .collect::<Vec<_>>()
+  };
+  let structural = component_ids(&[TypeId::of::<Save>(), TypeId::of::<Parent>()]);
+  let mut types = world
+    .resource::<PersistentComponents>()
+    .components
+    .read()
+    .unwrap_or_else(PoisonError::into_inner)
+    .iter()
+    .copied()
+    .collect::<Vec<_>>();
+  types.extend([TypeId::of::<Parent>(), TypeId::of::<Children>()]);
+  let all = component_ids(&types);
+
+  subtree.iter().any(|entity| {
+    if removed.contains(entity) {
+      return true;
+    }
+    let Some(entity_ref) = world.get_entity(*entity) else {
+      return false;
+    };
+    // Nested roots only contribute their place in the hierarchy.
+    let ids = if nested.contains(entity) {
+      &structural
+    } else {
+      &all
+    };
+    ids.iter().any(|id| {
+      entity_ref
+        .get_change_ticks_by_id(*id)
+        .is_some_and(|ticks| ticks.is_changed(since, this_run))
+    })
+  })
+}
+
+/// Serialize the subtree rooted at `root` in the [SavedEntity] format.
+///
+/// Entities are written with their ids from the file, as recorded in `state`.
+/// Entities that were spawned in-game are assigned fresh ids, which are added
+/// to the mappings so that they line up with the same entities when the file
+/// is reloaded. Descendants with their own [Save] are written as just their
+/// [Save] and [Parent] and left to their own files.
+pub fn serialize_saved_entity(
+  world: &World,
+  root: Entity,
+  state: &mut SavedEntityState,
+) -> anyhow::Result<String> {
+  let (subtree, nested) = saved_subtree(world, root);
+
+  let mut to_file = state
+    .entity_mappings
+    .iter()
+    .map(|(file, world)| (*world, *file))
+    .collect::<EntityHashMap<_>>();
+  to_file.insert(root, Entity::PLACEHOLDER);
+  let mut next_id = state
+    .entity_mappings
+    .keys()
+    .filter(|id| **id != Entity::PLACEHOLDER)
+    .map(|id| id.index() + 1)
+    .max()
+    .unwrap_or_default();
+  for entity in &subtree {
+    if !to_file.contains_key(entity) {
+      let id = Entity::from_raw(next_id);
+      next_id += 1;
+      state.entity_mappings.insert(id, *entity);
+      to_file.insert(*entity, id);
+    }
+  }
+
+  let mut components = world
+    .resource::<PersistentComponents>()
+    .components
+    .read()
+    .unwrap_or_else(PoisonError::into_inner)
+    .clone();
+  components.insert(TypeId::of::<Parent>());
+  components.insert(TypeId::of::<Children>());
+
+  let scene = DynamicSceneBuilder::from_world(world)
+    .deny_all_resources()
+    .with_component_filter(SceneFilter::Allowlist(components))
+    .extract_entities(subtree.iter().copied())
+    .build();
+
+  let mut root_components = vec![];
+  let mut entities = vec![];
+  for mut entity in scene.entities {
+    let is_root = entity.entity == root;
+    let is_nested = nested.contains(&entity.entity);
+    entity.components.retain(|component| {
+      let Some(type_id) = component.get_represented_type_info().map(|i| i.type_id()) else {
+        return false;
+      };
+      let structural = type_id == TypeId::of::<Save>() || type_id == TypeId::of::<Parent>();
+      // The root's Save is the file itself, and its Parent is outside of it.
+      (is_root && !structural) || (is_nested && structural) || (!is_root && !is_nested)
+    });
+    for component in &mut entity.components {
+      map_entity_refs(component.as_partial_reflect_mut(), &to_file);
+    }
+    if is_root {
+      root_components = entity.components;
+    } else {
+      entity.entity = to_file[&entity.entity];
+      entities.push(entity);
+    }
+  }
+  entities.sort_by_key(|e| e.entity);
+
+  let registry = world.resource::<AppTypeRegistry>().read();
+  Ok(serialize_ron(SavedEntitySerializer {
+    components: &root_components,
+    entities: &entities,
+    registry: &registry,
+  })?)
+}
+
+/// Replace every [Entity] reachable through `value`'s reflection with its
+/// mapping. Entities without one, i.e. references to outside of a saved
+/// subtree, are left alone.
+fn map_entity_refs(value: &mut dyn PartialReflect, mappings: &EntityHashMap<Entity>) {
+  if let Some(entity) = value.try_downcast_mut::<Entity>() {
+    if let Some(mapped) = mappings.get(entity) {
+      *entity = *mapped;
+    }
+    return;
+  }
+
+  match value.reflect_mut() {
+    ReflectMut::Struct(value) => {
+      for i in 0..value.field_len() {
+        if let Some(field) = value.field_at_mut(i) {
+          map_entity_refs(field, mappings);
+        }
+      }
+    }
+    ReflectMut::TupleStruct(value) => {
+      for i in 0..value.field_len() {
+        if let Some(field) = value.field_mut(i) {
+          map_entity_refs(field, mappings);
+        }
+      }
+    }
+    ReflectMut::Tuple(value) => {
+      for i in 0..value.field_len() {
+        if let Some(field) = value.field_mut(i) {
+          map_entity_refs(field, mappings);
+        }
+      }
+    }
+    ReflectMut::List(value) => {
+      for i in 0..value.len() {
+        if let Some(item) = value.get_mut(i) {
+          map_entity_refs(item, mappings);
+        }
+      }
+    }
+    ReflectMut::Array(value) => {
+      for i in 0..value.len() {
+        if let Some(item) = value.get_mut(i) {
+          map_entity_refs(item, mappings);
+        }
+      }
+    }
+    ReflectMut::Map(value) => {
+      for i in 0..value.len() {
+        if let Some((_, item)) = value.get_at_mut(i) {
+          map_entity_refs(item, mappings);
+        }
+      }
+    }
+    ReflectMut::Enum(value) => {
+      for i in 0..value.field_len() {
+        if let Some(field) = value.field_at_mut(i) {
+          map_entity_refs(field, mappings);
+        }
+      }
+    }
+    _ => {}
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::map::Map;
+
+  fn test_world() -> World {
+    let mut world = World::new();
+    world.init_resource::<AppTypeRegistry>();
+    world.init_resource::<PersistentComponents>();
+    {
+      let mut registry = world.resource::<AppTypeRegistry>().write();
+      registry.register::<Map>();
+      registry.register::<Save>();
+      registry.register::<Parent>();
+      registry.register::<Children>();
+    }
+    {
+      let persistent = world.resource::<PersistentComponents>();
+      let mut components = persistent.components.write().unwrap();
+      components.insert(TypeId::of::<Map>());
+      components.insert(TypeId::of::<Save>());
+    }
+    world
+  }
+
+  #[test]
+  fn serializes_subtree_with_file_ids() {
+    let mut world = test_world();
+
+    let root = world
+      .spawn((Save("root.ron".into()), Map("root".into())))
+      .id();
+    let loaded = world.spawn(Map("loaded".into())).set_parent(root).id();
+    let nested = world
+      .spawn((Save("nested.ron".into()), Map("nested".into())))
+      .set_parent(root)
+      .id();
+    world.spawn(Map("not saved here".into())).set_parent(nested);
+    let spawned = world.spawn(Map("spawned".into())).set_parent(loaded).id();
+
+    let file_id = Entity::from_raw(3);
+    let mut state = SavedEntityState::default();
+    state.entity_mappings.insert(Entity::PLACEHOLDER, root);
+    state.entity_mappings.insert(file_id, loaded);
+
+    let serialized = serialize_saved_entity(&world, root, &mut state).unwrap();
+
+    // New entities get ids after the existing ones and keep them.
+    let spawned_id = Entity::from_raw(4);
+    assert_eq!(state.entity_mappings[&spawned_id], spawned);
+    assert_eq!(state.entity_mappings.len(), 4);
+    let again = serialize_saved_entity(&world, root, &mut state).unwrap();
+    assert_eq!(serialized, again);
+
+    assert!(serialized.contains(&Entity::PLACEHOLDER.to_bits().to_string()));
+    assert!(serialized.contains(&file_id.to_bits().to_string()));
+    assert!(serialized.contains("nested.ron"));
+    assert!(!serialized.contains("root.ron"));
+    assert!(!serialized.contains("\"nested\""));
+    assert!(!serialized.contains("not saved here"));
+  }
+
+  #[test]
+  fn detects_subtree_changes() {
+    let mut world = test_world();
+    let root = world
+      .spawn((Save("root.ron".into()), Map("root".into())))
+      .id();
+    let child = world.spawn(Map("child".into())).set_parent(root).id();
+    let nested = world
+      .spawn((Save("nested.ron".into()), Map("nested".into())))
+      .set_parent(root)
+      .id();
+    let none = EntityHashSet::default();
+
+    let synced = world.read_change_tick();
+    world.increment_change_tick();
+    assert!(!saved_subtree_changed(&world, root, synced, &none));
+
+    // Changes to the nested file's contents are its own business.
+    world.get_mut::<Map>(nested).unwrap().0 = "changed".into();
+    assert!(!saved_subtree_changed(&world, root, synced, &none));
+
+    world.get_mut::<Map>(child).unwrap().0 = "changed".into();
+    assert!(saved_subtree_changed(&world, root, synced, &none));
+
+    let synced = world.read_change_tick();
+    world.increment_change_tick();
+    world.spawn(Map("grandchild".into())).set_parent(child);
+    assert!(saved_subtree_changed(&world, root, synced, &none));
+
+    let synced = world.read_change_tick();
+    world.increment_change_tick();
+    assert!(!saved_subtree_changed(&world, root, synced, &none));
+    let removed = [child].into_iter().collect();
+    assert!(saved_subtree_changed(&world, root, synced, &removed));
+  }
+}
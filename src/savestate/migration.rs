@@ -0,0 +1,547 @@
+//! Schema versioning for persisted components.
+//!
+//! Components persisted with
+//! [AppWorldExt::persist_versioned](super::traits::AppWorldExt::persist_versioned)
+//! carry a version number which is written alongside their saved data. When
+//! loading data saved with an older version, it's deserialized as the legacy
+//! type registered for that version via
+//! [AppWorldExt::migrate](super::traits::AppWorldExt::migrate) and then
+//! converted to the current type.
+//!
+//! Data saved before a component was versioned is considered to be version 0.
+//!
+//! Types that have moved can be handled by giving the legacy type the old
+//! type path with `#[type_path = "..."]`. Since it has a migration registered,
+//! anything saved under that path gets converted on load.
+//!
+//! Migrations can also be chained with
+//! [AppWorldExt::migrate_via](super::traits::AppWorldExt::migrate_via), which
+//! converts to another legacy type rather than the current one. Its migration
+//! is applied in turn, and so on until the data reaches the current version.
+
+use std::{
+  any::TypeId,
+  collections::BTreeMap,
+  fmt,
+  sync::Arc,
+};
+
+use anyhow::anyhow;
+use bevy::{
+  prelude::*,
+  reflect::{
+    serde::TypedReflectDeserializer,
+    TypeRegistration,
+    TypeRegistry,
+  },
+  scene::{
+    serde::{
+      EntitiesSerializer,
+      SceneMapSerializer,
+    },
+    DynamicEntity,
+  },
+  utils::HashMap,
+};
+use serde::{
+  de::{
+    self,
+    DeserializeSeed,
+    MapAccess,
+    Visitor,
+  },
+  ser::SerializeStruct,
+  Deserialize,
+  Serialize,
+};
+
+/// Saved schema versions by type path.
+pub type Versions = BTreeMap<String, u32>;
+
+/// Type data for a persisted component recording its current schema version.
+#[derive(Clone, Default, Debug)]
+pub struct PersistVersion {
+  pub version: u32,
+  /// Legacy types to deserialize older versions as.
+  pub legacy: HashMap<u32, TypeId>,
+}
+
+/// Type data for a legacy type that knows how to turn itself into the current
+/// version of the component it was migrated from.
+#[derive(Clone)]
+pub struct ReflectMigration {
+  pub target: TypeId,
+  convert: Arc<dyn Fn(&dyn PartialReflect) -> Option<Box<dyn PartialReflect>> + Send + Sync>,
+}
+
+impl ReflectMigration {
+  pub fn new<Old, New>(migrate: impl Fn(Old) -> New + Send + Sync + 'static) -> Self
+  where
+    Old: FromReflect,
+    New: PartialReflect,
+  {
+    ReflectMigration {
+      target: TypeId::of::<New>(),
+      convert: Arc::new(move |value| {
+        let old = Old::from_reflect(value)?;
+        Some(Box::new(migrate(old)))
+      }),
+    }
+  }
+
+  pub fn convert(&self, value: &dyn PartialReflect) -> anyhow::Result<Box<dyn PartialReflect>> {
+    (self.convert)(value).ok_or_else(|| {
+      anyhow!(
+        "failed to migrate {}",
+        value
+          .get_represented_type_info()
+          .map(|i| i.type_path())
+          .unwrap_or("<unknown>")
+      )
+    })
+  }
+}
+
+/// The current schema version of a type. Unversioned types are version 0.
+pub fn current_version(registry: &TypeRegistry, type_id: TypeId) -> u32 {
+  registry
+    .get_type_data::<PersistVersion>(type_id)
+    .map(|v| v.version)
+    .unwrap_or_default()
+}
+
+/// The versions of all of the versioned types in a scene.
+pub fn scene_versions(scene: &DynamicScene, registry: &TypeRegistry) -> Versions {
+  versions(
+    scene
+      .resources
+      .iter()
+      .chain(scene.entities.iter().flat_map(|e| e.components.iter())),
+    registry,
+  )
+}
+
+/// The versions of all of the versioned types in a set of components.
+pub fn versions<'a>(
+  components: impl IntoIterator<Item = &'a Box<dyn PartialReflect>>,
+  registry: &TypeRegistry,
+) -> Versions {
+  components
+    .into_iter()
+    .filter_map(|c| c.get_represented_type_info())
+    .filter_map(|info| {
+      let version = current_version(registry, info.type_id());
+      (version > 0).then(|| (info.type_path().to_string(), version))
+    })
+    .collect()
+}
+
+/// Find the registration to deserialize data saved as `path` at `version`.
+fn resolve<'a>(
+  registry: &'a TypeRegistry,
+  path: &str,
+  version: u32,
+) -> Option<&'a TypeRegistration> {
+  let registration = registry.get_with_type_path(path)?;
+  let legacy = registration
+    .data::<PersistVersion>()
+    .filter(|v| version < v.version)
+    .and_then(|v| v.legacy.get(&version))
+    .and_then(|id| registry.get(*id));
+  Some(legacy.unwrap_or(registration))
+}
+
+/// Deserializes a single component saved as `path` at `version`, migrating it
+/// to the current version if needed.
+pub struct ComponentSeed<'a> {
+  pub registry: &'a TypeRegistry,
+  pub path: &'a str,
+  pub version: u32,
+}
+
+impl<'a, 'de> DeserializeSeed<'de> for ComponentSeed<'a> {
+  type Value = Box<dyn PartialReflect>;
+
+  fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    let mut registration = resolve(self.registry, self.path, self.version)
+      .ok_or_else(|| de::Error::custom(format!("no registration found for `{}`", self.path)))?;
+    let mut value =
+      TypedReflectDeserializer::new(registration, self.registry).deserialize(deserializer)?;
+    // Every step should bring it closer to the current version, so a chain
+    // this long has to be going in circles.
+    for _ in 0..MAX_MIGRATIONS {
+      let Some(migration) = registration.data::<ReflectMigration>() else {
+        return Ok(value);
+      };
+      debug!(
+        from = registration.type_info().type_path(),
+        version = self.version,
+        "migrating saved component"
+      );
+      value = migration
+        .convert(value.as_partial_reflect())
+        .map_err(de::Error::custom)?;
+      registration = self.registry.get(migration.target).ok_or_else(|| {
+        de::Error::custom(format!(
+          "migration from `{}` is to an unregistered type",
+          registration.type_info().type_path()
+        ))
+      })?;
+    }
+    Err(de::Error::custom(format!(
+      "too many migrations for `{}`",
+      self.path
+    )))
+  }
+}
+
+/// The longest chain of migrations to follow before giving up.
+const MAX_MIGRATIONS: usize = 64;
+
+/// Deserializes a map of type paths to component data, like the one used for
+/// scene resources and entity components.
+pub struct MigratingMapSeed<'a> {
+  pub registry: &'a TypeRegistry,
+  pub versions: &'a Versions,
+}
+
+impl<'a, 'de> DeserializeSeed<'de> for MigratingMapSeed<'a> {
+  type Value = Vec<Box<dyn PartialReflect>>;
+
+  fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    deserializer.deserialize_map(self)
+  }
+}
+
+impl<'a, 'de> Visitor<'de> for MigratingMapSeed<'a> {
+  type Value = Vec<Box<dyn PartialReflect>>;
+
+  fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    formatter.write_str("map of reflect types")
+  }
+
+  fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+  where
+    A: MapAccess<'de>,
+  {
+    let mut entries = vec![];
+    while let Some(path) = map.next_key::<String>()? {
+      let version = self.versions.get(&path).copied().unwrap_or_default();
+      entries.push(map.next_value_seed(ComponentSeed {
+        registry: self.registry,
+        path: &path,
+        version,
+      })?);
+    }
+    Ok(entries)
+  }
+}
+
+/// Deserializes the `entities` map of a scene.
+pub struct MigratingEntitiesSeed<'a> {
+  pub registry: &'a TypeRegistry,
+  pub versions: &'a Versions,
+}
+
+impl<'a, 'de> DeserializeSeed<'de> for MigratingEntitiesSeed<'a> {
+  type Value = Vec<DynamicEntity>;
+
+  fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    deserializer.deserialize_map(self)
+  }
+}
+
+impl<'a, 'de> Visitor<'de> for MigratingEntitiesSeed<'a> {
+  type Value = Vec<DynamicEntity>;
+
+  fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    formatter.write_str("map of entities")
+  }
+
+  fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+  where
+    A: MapAccess<'de>,
+  {
+    let mut entities = vec![];
+    while let Some(bits) = map.next_key::<u64>()? {
+      let components = map.next_value_seed(MigratingEntitySeed {
+        registry: self.registry,
+        versions: self.versions,
+      })?;
+      entities.push(DynamicEntity {
+        entity: Entity::from_bits(bits),
+        components,
+      });
+    }
+    Ok(entities)
+  }
+}
+
+struct MigratingEntitySeed<'a> {
+  registry: &'a TypeRegistry,
+  versions: &'a Versions,
+}
+
+impl<'a, 'de> DeserializeSeed<'de> for MigratingEntitySeed<'a> {
+  type Value = Vec<Box<dyn PartialReflect>>;
+
+  fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    deserializer.deserialize_struct("Entity", &["components"], self)
+  }
+}
+
+impl<'a, 'de> Visitor<'de> for MigratingEntitySeed<'a> {
+  type Value = Vec<Box<dyn PartialReflect>>;
+
+  fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    formatter.write_str("entities")
+  }
+
+  fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+  where
+    A: MapAccess<'de>,
+  {
+    #[derive(Deserialize)]
+    #[serde(field_identifier, rename_all = "lowercase")]
+    enum EntityField {
+      Components,
+    }
+
+    let mut components = None;
+    while let Some(EntityField::Components) = map.next_key()? {
+      components = Some(map.next_value_seed(MigratingMapSeed {
+        registry: self.registry,
+        versions: self.versions,
+      })?);
+    }
+    components.ok_or_else(|| de::Error::missing_field("components"))
+  }
+}
+
+/// Deserializes a [DynamicScene] with an optional leading `versions` map.
+///
+/// Files without one are treated as entirely version 0.
+pub struct VersionedSceneSeed<'a> {
+  pub registry: &'a TypeRegistry,
+}
+
+impl<'a, 'de> DeserializeSeed<'de> for VersionedSceneSeed<'a> {
+  type Value = DynamicScene;
+
+  fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    deserializer.deserialize_struct("Scene", &["versions", "resources", "entities"], self)
+  }
+}
+
+impl<'a, 'de> Visitor<'de> for VersionedSceneSeed<'a> {
+  type Value = DynamicScene;
+
+  fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    formatter.write_str("scene struct")
+  }
+
+  fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+  where
+    A: MapAccess<'de>,
+  {
+    #[derive(Deserialize)]
+    #[serde(field_identifier, rename_all = "lowercase")]
+    enum SceneField {
+      Versions,
+      Resources,
+      Entities,
+    }
+
+    let mut versions = Versions::default();
+    let mut scene = DynamicScene::default();
+
+    while let Some(key) = map.next_key()? {
+      match key {
+        SceneField::Versions => versions = map.next_value()?,
+        SceneField::Resources => {
+          scene.resources = map.next_value_seed(MigratingMapSeed {
+            registry: self.registry,
+            versions: &versions,
+          })?;
+        }
+        SceneField::Entities => {
+          scene.entities = map.next_value_seed(MigratingEntitiesSeed {
+            registry: self.registry,
+            versions: &versions,
+          })?;
+        }
+      }
+    }
+
+    Ok(scene)
+  }
+}
+
+/// Serializes a [DynamicScene] along with the versions of its types.
+pub struct VersionedSceneSerializer<'a> {
+  pub scene: &'a DynamicScene,
+  pub registry: &'a TypeRegistry,
+}
+
+impl<'a> Serialize for VersionedSceneSerializer<'a> {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    let mut state = serializer.serialize_struct("Scene", 3)?;
+    state.serialize_field("versions", &scene_versions(self.scene, self.registry))?;
+    state.serialize_field(
+      "resources",
+      &SceneMapSerializer {
+        entries: &self.scene.resources,
+        registry: self.registry,
+      },
+    )?;
+    state.serialize_field(
+      "entities",
+      &EntitiesSerializer {
+        entities: &self.scene.entities,
+        registry: self.registry,
+      },
+    )?;
+    state.end()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[derive(Reflect, Debug, PartialEq)]
+  #[type_path = "test"]
+  struct ThingV0 {
+    name: String,
+  }
+
+  #[derive(Reflect, Debug, PartialEq)]
+  #[type_path = "test"]
+  struct ThingV1 {
+    full_name: String,
+  }
+
+  #[test]
+  fn migrates_old_versions() {
+    let mut registry = TypeRegistry::default();
+    registry.register::<ThingV1>();
+    registry.register::<ThingV0>();
+    registry
+      .get_mut(TypeId::of::<ThingV0>())
+      .unwrap()
+      .insert(ReflectMigration::new(|old: ThingV0| ThingV1 {
+        full_name: old.name,
+      }));
+    let mut version = PersistVersion {
+      version: 1,
+      ..default()
+    };
+    version.legacy.insert(0, TypeId::of::<ThingV0>());
+    registry
+      .get_mut(TypeId::of::<ThingV1>())
+      .unwrap()
+      .insert(version);
+
+    let old = r#"(
+      resources: {},
+      entities: {
+        1: (components: { "test::ThingV1": (name: "bob") }),
+      },
+    )"#;
+    let scene = VersionedSceneSeed {
+      registry: &registry,
+    }
+    .deserialize(&mut ron::Deserializer::from_str(old).unwrap())
+    .unwrap();
+    let thing = ThingV1::from_reflect(&*scene.entities[0].components[0]).unwrap();
+    assert_eq!(thing.full_name, "bob");
+
+    // Round trip at the current version.
+    let serialized = ron::to_string(&VersionedSceneSerializer {
+      scene: &scene,
+      registry: &registry,
+    })
+    .unwrap();
+    let scene = VersionedSceneSeed {
+      registry: &registry,
+    }
+    .deserialize(&mut ron::Deserializer::from_str(&serialized).unwrap())
+    .unwrap();
+    let thing = ThingV1::from_reflect(&*scene.entities[0].components[0]).unwrap();
+    assert_eq!(thing.full_name, "bob");
+  }
+
+  #[derive(Reflect, Debug, PartialEq)]
+  #[type_path = "chain"]
+  struct ThingV2 {
+    first: String,
+    last: String,
+  }
+
+  #[test]
+  fn chains_migrations() {
+    let mut registry = TypeRegistry::default();
+    registry.register::<ThingV2>();
+    registry.register::<ThingV1>();
+    registry.register::<ThingV0>();
+    registry
+      .get_mut(TypeId::of::<ThingV0>())
+      .unwrap()
+      .insert(ReflectMigration::new(|old: ThingV0| ThingV1 {
+        full_name: old.name,
+      }));
+    registry
+      .get_mut(TypeId::of::<ThingV1>())
+      .unwrap()
+      .insert(ReflectMigration::new(|old: ThingV1| {
+        let (first, last) = old.full_name.split_once(' ').unwrap_or_default();
+        ThingV2 {
+          first: first.into(),
+          last: last.into(),
+        }
+      }));
+    let mut version = PersistVersion {
+      version: 2,
+      ..default()
+    };
+    version.legacy.insert(0, TypeId::of::<ThingV0>());
+    version.legacy.insert(1, TypeId::of::<ThingV1>());
+    registry
+      .get_mut(TypeId::of::<ThingV2>())
+      .unwrap()
+      .insert(version);
+
+    let thing = ComponentSeed {
+      registry: &registry,
+      path: "chain::ThingV2",
+      version: 0,
+    }
+    .deserialize(&mut ron::Deserializer::from_str(r#"(name: "bob smith")"#).unwrap())
+    .unwrap();
+    assert_eq!(
+      ThingV2::from_reflect(&*thing).unwrap(),
+      ThingV2 {
+        first: "bob".into(),
+        last: "smith".into(),
+      }
+    );
+  }
+}
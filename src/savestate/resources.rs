@@ -1,7 +1,9 @@
 use std::{
   any::TypeId,
+  path::PathBuf,
   sync::{
     Arc,
+    Mutex,
     RwLock,
   },
 };
@@ -22,15 +24,54 @@ use bevy::{
 };
 
 use super::assets::SavedEntity;
+use crate::config::DEFAULT_ASSETS;
 
 #[derive(Resource, Copy, Clone, Deref)]
 pub struct SaveInterval(pub f32);
 
+/// The directory that [Save](super::components::Save) paths are relative to
+/// when writing them back.
+#[derive(Resource, Clone, Deref)]
+pub struct AssetDir(pub PathBuf);
+
+impl Default for AssetDir {
+  fn default() -> Self {
+    AssetDir(DEFAULT_ASSETS.into())
+  }
+}
+
 #[derive(Resource, Default, Clone)]
 pub struct PersistentComponents {
   pub components: Arc<RwLock<HashSet<TypeId>>>,
 }
 
+/// Persistent entities that have changed since the last save.
+///
+/// Populated by the change tracking systems added by
+/// [AppWorldExt::persist](super::traits::AppWorldExt::persist) and drained by
+/// each save. Shared with the save task so that a failed save can fall back to
+/// a full one.
+#[derive(Resource, Default, Clone)]
+pub struct DirtyEntities {
+  pub dirty: Arc<Mutex<Dirty>>,
+}
+
+#[derive(Debug, Default)]
+pub struct Dirty {
+  /// Entities that need to be re-written.
+  pub changed: EntityHashSet,
+  /// Entities that have been despawned or are no longer
+  /// [Persistent](super::components::Persistent).
+  pub removed: EntityHashSet,
+  /// Entities that have lost persisted components or children since the last
+  /// write-back of [Save](super::components::Save) files. Unlike changes,
+  /// removals don't show up in change ticks.
+  pub saved_removed: EntityHashSet,
+  /// Set when the next save must write the whole world, e.g. after a failed
+  /// incremental save.
+  pub full: bool,
+}
+
 #[derive(Debug, Resource, Default)]
 pub struct SavedEntityStates {
   pub handle_entities: HashMap<AssetId<SavedEntity>, EntityHashSet>,
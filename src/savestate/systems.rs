@@ -1,5 +1,6 @@
 use std::{
-  io::Write,
+  mem,
+  sync::PoisonError,
   time::{
     Duration,
     Instant,
@@ -7,33 +8,47 @@ use std::{
 };
 
 use bevy::{
-  ecs::entity::{
-    EntityHashMap,
-    MapEntities,
-    SceneEntityMapper,
+  ecs::{
+    entity::{
+      EntityHashMap,
+      MapEntities,
+      SceneEntityMapper,
+    },
+    system::SystemState,
   },
   prelude::*,
-  scene::serde::SceneDeserializer,
   tasks::AsyncComputeTaskPool,
   time::Stopwatch,
 };
-use serde::de::DeserializeSeed;
 
 use super::{
   assets::SavedEntity,
+  backend::{
+    ron_file::write_atomic,
+    Backend,
+  },
   components::{
     self,
     Persistent,
+    Save,
+    SavedEntityState,
+  },
+  helpers::{
+    saved_subtree_changed,
+    serialize_saved_entity,
+    write_saved_entity,
   },
-  helpers::write_saved_entity,
   resources::{
     self,
+    AssetDir,
+    DirtyEntities,
     PersistentComponents,
     SavedEntityStates,
   },
 };
 use crate::{
   account::UserDb,
+  net::BanList,
   savestate::events,
 };
 
@@ -68,14 +83,9 @@ pub fn handle_asset_events(
 
 pub fn load_system(world: &mut World) {
   let registry = world.resource::<AppTypeRegistry>().clone();
-  let reg_read = registry.read();
-  let seed = SceneDeserializer {
-    type_registry: &reg_read,
-  };
+  let backend = world.resource::<Backend>().clone();
   let res = (|| {
-    let serialized = std::fs::read_to_string("world.ron")?;
-    let mut de = ron::Deserializer::from_str(&serialized)?;
-    let mut scene = seed.deserialize(&mut de)?;
+    let mut scene = backend.load(&registry.read())?;
 
     for entity in &mut scene.entities {
       entity.components.push(components::Persistent.clone_value());
@@ -83,58 +93,170 @@ pub fn load_system(world: &mut World) {
     let mut entities = EntityHashMap::default();
 
     scene.write_to_world(world, &mut entities)?;
+    backend.remap(&entities)?;
     world.resource_scope::<UserDb, ()>(|world, mut db| {
       SceneEntityMapper::world_scope(&mut entities, world, |_, mapper| {
         db.map_entities(mapper);
       });
     });
 
+    // Write everything back on the first save, so anything the backend has
+    // that didn't make it into the world goes away.
+    world
+      .resource::<DirtyEntities>()
+      .dirty
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .full = true;
+
     Result::<_, anyhow::Error>::Ok(())
   })();
 
   if let Err(error) = res {
-    error!(%error, "failed to load world from file, exiting");
+    error!(%error, "failed to load world, exiting");
     world.send_event(events::LoadFailed);
     world.send_event(AppExit::Error(1.try_into().unwrap()));
   }
 }
 
-fn extract_save(world: &World, entities: Query<Entity, With<Persistent>>) -> DynamicScene {
+/// Load the saved world and immediately write it back out, bringing every
+/// component up to its current schema version.
+///
+/// Doesn't touch the live world, so it's safe to run before the app starts.
+pub fn migrate_saved_world(world: &World) -> anyhow::Result<usize> {
+  let registry = world.resource::<AppTypeRegistry>().read();
+  let backend = world.resource::<Backend>();
+  let scene = backend.load(&registry)?;
+  backend.save(&scene, &registry)?;
+  Ok(scene.entities.len())
+}
+
+fn extract_save(world: &World, entities: impl Iterator<Item = Entity>) -> DynamicScene {
   let persistent_components = world.resource::<PersistentComponents>();
   DynamicSceneBuilder::from_world(world)
     .deny_all()
     .deny_all_resources()
     .allow_resource::<UserDb>()
+    .allow_resource::<BanList>()
     .with_component_filter(SceneFilter::Allowlist(
       persistent_components.components.read().unwrap().clone(),
     ))
     .extract_resources()
-    .extract_entities(entities.iter())
+    .extract_entities(entities)
     .build()
 }
 
-pub fn final_save_system(world: &World, entities: Query<Entity, With<Persistent>>) {
-  let registry = world.resource::<AppTypeRegistry>().clone();
-  let scene = extract_save(world, entities);
-  let serialized = match scene.serialize(&registry.read()) {
-    Ok(s) => s,
-    Err(error) => {
-      warn!(%error, "error serializing world");
-      return;
+/// Marks persistent entities as needing to be saved when `C` changes or is
+/// removed.
+pub fn track_changes<C: Component>(
+  dirty: Res<DirtyEntities>,
+  changed: Query<Entity, (Changed<C>, With<Persistent>)>,
+  mut removed: RemovedComponents<C>,
+) {
+  let mut dirty = dirty.dirty.lock().unwrap_or_else(PoisonError::into_inner);
+  dirty.changed.extend(changed.iter());
+  for entity in removed.read() {
+    dirty.changed.insert(entity);
+    dirty.saved_removed.insert(entity);
+  }
+}
+
+/// Marks entities that lost their last child, which takes them out of a
+/// [Save] subtree without a change to their [Children].
+pub fn track_hierarchy(dirty: Res<DirtyEntities>, mut removed: RemovedComponents<Children>) {
+  let mut dirty = dirty.dirty.lock().unwrap_or_else(PoisonError::into_inner);
+  dirty.saved_removed.extend(removed.read());
+}
+
+/// Tracks entities entering and leaving the persistent set.
+pub fn track_persistent(
+  dirty: Res<DirtyEntities>,
+  added: Query<Entity, Added<Persistent>>,
+  mut removed: RemovedComponents<Persistent>,
+) {
+  let mut dirty = dirty.dirty.lock().unwrap_or_else(PoisonError::into_inner);
+  for entity in removed.read() {
+    dirty.removed.insert(entity);
+  }
+  for entity in added.iter() {
+    dirty.removed.remove(&entity);
+    dirty.changed.insert(entity);
+  }
+}
+
+/// A snapshot of the world, ready to be handed off to the [Backend].
+struct SaveJob {
+  backend: Backend,
+  registry: AppTypeRegistry,
+  dirty: DirtyEntities,
+  scene: DynamicScene,
+  /// Entities to remove from the save. `None` if this is a full save.
+  removed: Option<Vec<Entity>>,
+}
+
+impl SaveJob {
+  fn extract(world: &World, entities: &Query<Entity, With<Persistent>>) -> Self {
+    let registry = world.resource::<AppTypeRegistry>().clone();
+    let backend = world.resource::<Backend>().clone();
+    let dirty = world.resource::<DirtyEntities>().clone();
+
+    let mut state = dirty.dirty.lock().unwrap_or_else(PoisonError::into_inner);
+    let changed = mem::take(&mut state.changed);
+    let removed = mem::take(&mut state.removed);
+    let full = mem::take(&mut state.full) || !backend.incremental();
+    drop(state);
+
+    let (scene, removed) = if full {
+      (extract_save(world, entities.iter()), None)
+    } else {
+      let changed = changed.into_iter().filter(|e| entities.contains(*e));
+      let removed = removed.into_iter().filter(|e| !entities.contains(*e));
+      (extract_save(world, changed), Some(removed.collect()))
+    };
+
+    SaveJob {
+      backend,
+      registry,
+      dirty,
+      scene,
+      removed,
     }
-  };
-  let mut f = match std::fs::File::create("world.ron") {
-    Ok(f) => f,
-    Err(error) => {
-      warn!(%error, "error opening world file for writing");
-      return;
+  }
+
+  fn run(self) -> anyhow::Result<()> {
+    let registry = self.registry.read();
+    let res = match &self.removed {
+      Some(removed) => self.backend.save_changes(&self.scene, removed, &registry),
+      None => self.backend.save(&self.scene, &registry),
+    };
+    if res.is_err() {
+      // The changes we took are gone, so write everything next time.
+      self
+        .dirty
+        .dirty
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .full = true;
     }
-  };
-  if let Err(error) = f.write_all(serialized.as_bytes()) {
-    warn!(%error, "error writing world file");
+    res
   }
 }
 
+pub fn final_save_system(world: &World, entities: Query<Entity, With<Persistent>>) {
+  if let Err(error) = SaveJob::extract(world, &entities).run() {
+    warn!(%error, "error saving world");
+  }
+}
+
+/// Save the whole world and wait for it to be written, for when the process
+/// is about to be replaced.
+pub fn save_now(world: &mut World) -> anyhow::Result<()> {
+  let mut entities = SystemState::<Query<Entity, With<Persistent>>>::new(world);
+  SaveJob::extract(world, &entities.get(world)).run()?;
+  write_back_saved_entities(world);
+  Ok(())
+}
+
 pub fn save_system(
   interval: Res<resources::SaveInterval>,
   time: Res<Time>,
@@ -147,41 +269,90 @@ pub fn save_system(
     let extract_start = Instant::now();
     info!("saving world");
     stop.reset();
-    let registry = world.resource::<AppTypeRegistry>().clone();
-    let scene = extract_save(world, entities);
+    let job = SaveJob::extract(world, &entities);
+    let entity_count = job.scene.entities.len();
+    let interval = Duration::from_secs_f32(**interval);
     let extract_time = Instant::now().duration_since(extract_start);
     AsyncComputeTaskPool::get()
       .spawn(async move {
-        let serialize_start = Instant::now();
-        let serialized = match scene.serialize(&registry.read()) {
-          Ok(s) => s,
-          Err(error) => {
-            warn!(%error, "error serializing world");
-            return;
-          }
-        };
-        let serialize_time = Instant::now().duration_since(serialize_start);
-        let mut f = match std::fs::File::create("world.ron") {
-          Ok(f) => f,
-          Err(error) => {
-            warn!(%error, "error opening world file for writing");
-            return;
-          }
-        };
         let write_start = Instant::now();
-        if let Err(error) = f.write_all(serialized.as_bytes()) {
-          warn!(%error, "error writing world file");
+        if let Err(error) = job.run() {
+          warn!(%error, "error saving world");
+          return;
         }
-        let _ = f.flush();
-        drop(f);
         let write_time = Instant::now().duration_since(write_start);
-        info!(?extract_time, ?serialize_time, ?write_time, "world saved");
-        if extract_time + serialize_time + write_time
-          > Duration::from_secs_f32(super::SAVE_INTERVAL)
-        {
+        info!(?extract_time, ?write_time, entity_count, "world saved");
+        if extract_time + write_time > interval {
           warn!("save took longer than save interval");
         }
       })
       .detach();
   }
 }
+
+/// Write each [Save]-rooted subtree back to its file if it has changed since
+/// it was loaded or last written.
+fn write_back_saved_entities(world: &mut World) {
+  let dir = world.resource::<AssetDir>().clone();
+  let dirty = world.resource::<DirtyEntities>().clone();
+  let removed = mem::take(
+    &mut dirty
+      .dirty
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .saved_removed,
+  );
+  let roots = world
+    .query::<(Entity, &Save, &SavedEntityState)>()
+    .iter(world)
+    // Still loading, don't overwrite it with an empty entity.
+    .filter(|(_, _, state)| state.written.is_some())
+    .filter(|(root, _, state)| saved_subtree_changed(world, *root, state.synced, &removed))
+    .map(|(entity, save, _)| (entity, dir.join(&save.0)))
+    .collect::<Vec<_>>();
+
+  for (root, path) in roots {
+    let Some(mut state) = world
+      .get_mut::<SavedEntityState>(root)
+      .map(|mut state| mem::take(&mut *state))
+    else {
+      continue;
+    };
+    let res = serialize_saved_entity(world, root, &mut state).and_then(|serialized| {
+      if state.written.as_ref() != Some(&serialized) {
+        write_atomic(&path, serialized.as_bytes())?;
+        debug!(?root, path = %path.display(), "wrote back saved entity");
+        state.written = Some(serialized);
+      }
+      Ok(())
+    });
+    match res {
+      Ok(()) => state.synced = world.read_change_tick(),
+      Err(error) => {
+        warn!(%error, ?root, path = %path.display(), "error writing back saved entity");
+        // Try again next time even if nothing else changes.
+        dirty
+          .dirty
+          .lock()
+          .unwrap_or_else(PoisonError::into_inner)
+          .saved_removed
+          .insert(root);
+      }
+    }
+    if let Some(mut world_state) = world.get_mut::<SavedEntityState>(root) {
+      *world_state = state;
+    }
+  }
+}
+
+pub fn final_write_back_system(world: &mut World) {
+  write_back_saved_entities(world);
+}
+
+pub fn write_back_system(world: &mut World, mut stop: Local<Stopwatch>) {
+  stop.tick(world.resource::<Time>().delta());
+  if stop.elapsed_secs() > **world.resource::<resources::SaveInterval>() {
+    stop.reset();
+    write_back_saved_entities(world);
+  }
+}
@@ -5,8 +5,40 @@ use bevy::{
   reflect::GetTypeRegistration,
 };
 
+use super::migration::{
+  PersistVersion,
+  ReflectMigration,
+};
+
 pub trait AppWorldExt {
   fn persist<C: Component + GetTypeRegistration>(&mut self) -> &mut Self;
+
+  /// Persist a component, recording `version` as its schema version in saves.
+  fn persist_versioned<C: Component + GetTypeRegistration>(&mut self, version: u32) -> &mut Self;
+
+  /// Register a migration from data saved at version `from` of `C`, which is
+  /// deserialized as `Old` and then converted by `migrate`.
+  fn migrate<C, Old>(
+    &mut self,
+    from: u32,
+    migrate: impl Fn(Old) -> C + Send + Sync + 'static,
+  ) -> &mut Self
+  where
+    C: Component + PartialReflect,
+    Old: FromReflect + GetTypeRegistration;
+
+  /// Register a migration from data saved at version `from` of `C`, which is
+  /// deserialized as `Old` and then converted by `migrate` to `Next`. `Next`
+  /// is another legacy type of `C` whose own migration is applied in turn.
+  fn migrate_via<C, Old, Next>(
+    &mut self,
+    from: u32,
+    migrate: impl Fn(Old) -> Next + Send + Sync + 'static,
+  ) -> &mut Self
+  where
+    C: Component,
+    Old: FromReflect + GetTypeRegistration,
+    Next: PartialReflect;
 }
 
 impl AppWorldExt for World {
@@ -19,12 +51,107 @@ impl AppWorldExt for World {
       .insert(TypeId::of::<C>());
     self
   }
+
+  fn persist_versioned<C: Component + GetTypeRegistration>(&mut self, version: u32) -> &mut Self {
+    {
+      let mut registry = self.resource::<AppTypeRegistry>().write();
+      let registration = registry
+        .get_mut(TypeId::of::<C>())
+        .expect("persisted components must be registered");
+      let mut data = registration
+        .data::<PersistVersion>()
+        .cloned()
+        .unwrap_or_default();
+      data.version = version;
+      registration.insert(data);
+    }
+    self.persist::<C>()
+  }
+
+  fn migrate<C, Old>(
+    &mut self,
+    from: u32,
+    migrate: impl Fn(Old) -> C + Send + Sync + 'static,
+  ) -> &mut Self
+  where
+    C: Component + PartialReflect,
+    Old: FromReflect + GetTypeRegistration,
+  {
+    self.migrate_via::<C, Old, C>(from, migrate)
+  }
+
+  fn migrate_via<C, Old, Next>(
+    &mut self,
+    from: u32,
+    migrate: impl Fn(Old) -> Next + Send + Sync + 'static,
+  ) -> &mut Self
+  where
+    C: Component,
+    Old: FromReflect + GetTypeRegistration,
+    Next: PartialReflect,
+  {
+    let mut registry = self.resource::<AppTypeRegistry>().write();
+    registry.register::<Old>();
+    registry
+      .get_mut(TypeId::of::<Old>())
+      .unwrap()
+      .insert(ReflectMigration::new(migrate));
+
+    let registration = registry
+      .get_mut(TypeId::of::<C>())
+      .expect("migrated components must be registered");
+    let mut data = registration
+      .data::<PersistVersion>()
+      .cloned()
+      .unwrap_or_default();
+    data.legacy.insert(from, TypeId::of::<Old>());
+    registration.insert(data);
+    drop(registry);
+    self
+  }
 }
 
 impl AppWorldExt for App {
   fn persist<C: Component + GetTypeRegistration>(&mut self) -> &mut Self {
     self.register_type::<C>();
     self.world_mut().persist::<C>();
+    self.add_systems(
+      Last,
+      super::systems::track_changes::<C>.in_set(super::SaveSet::Track),
+    );
+    self
+  }
+
+  fn persist_versioned<C: Component + GetTypeRegistration>(&mut self, version: u32) -> &mut Self {
+    self.persist::<C>();
+    self.world_mut().persist_versioned::<C>(version);
+    self
+  }
+
+  fn migrate<C, Old>(
+    &mut self,
+    from: u32,
+    migrate: impl Fn(Old) -> C + Send + Sync + 'static,
+  ) -> &mut Self
+  where
+    C: Component + PartialReflect,
+    Old: FromReflect + GetTypeRegistration,
+  {
+    self.world_mut().migrate(from, migrate);
+    self
+  }
+
+  fn migrate_via<C, Old, Next>(
+    &mut self,
+    from: u32,
+    migrate: impl Fn(Old) -> Next + Send + Sync + 'static,
+  ) -> &mut Self
+  where
+    C: Component,
+    Old: FromReflect + GetTypeRegistration,
+    Next: PartialReflect,
+  {
+    self.world_mut().migrate_via::<C, Old, Next>(from, migrate);
     self
   }
 }
@@ -0,0 +1,228 @@
+//! Running the game in-process and scripting clients against it.
+//!
+//! [TestMud] builds a headless app from [CorePlugin] with an in-memory save
+//! backend and no network listeners, and connects [LoopbackClient]s to it.
+//! Its `expect` methods run the app until a client has received something,
+//! failing if it doesn't show up in time.
+
+use std::{
+  fmt::Debug,
+  thread,
+  time::{
+    Duration,
+    Instant,
+  },
+};
+
+use bevy::prelude::*;
+use tellem::{
+  Event,
+  KnownOpt,
+  Opt,
+};
+
+use crate::{
+  account::{
+    Session,
+    StartLogin,
+  },
+  config::ServerConfig,
+  core::CorePlugin,
+  net::{
+    gmcp::GmcpMessage,
+    ClientConn,
+    Inherited,
+    LoopbackClient,
+    LoopbackListener,
+  },
+  savestate::backend::{
+    Backend,
+    SqliteBackend,
+  },
+};
+
+/// How long to wait for something to show up before giving up.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait between updates while waiting, so that the connection
+/// tasks get a chance to run.
+const FRAME: Duration = Duration::from_millis(1);
+
+/// A headless game for clients to connect to.
+pub struct TestMud {
+  app: App,
+  listener: LoopbackListener,
+}
+
+impl Default for TestMud {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl TestMud {
+  pub fn new() -> Self {
+    Self::with_config(ServerConfig::default())
+  }
+
+  /// Start the game with `config`, minus its network listeners.
+  pub fn with_config(mut config: ServerConfig) -> Self {
+    config.listeners.clear();
+    config.tls.listeners.clear();
+    config.websocket.listeners.clear();
+
+    let mut app = App::new();
+    app
+      .add_plugins(CorePlugin::with_config(config))
+      .insert_resource(Backend::new(
+        SqliteBackend::open_in_memory().expect("failed to open in-memory database"),
+      ))
+      .add_systems(Update, start_login);
+    app.finish();
+    app.cleanup();
+    // Run startup.
+    app.update();
+
+    let listener = LoopbackListener::spawn(app.world_mut(), true);
+    TestMud { app, listener }
+  }
+
+  pub fn app(&mut self) -> &mut App {
+    &mut self.app
+  }
+
+  pub fn world(&self) -> &World {
+    self.app.world()
+  }
+
+  pub fn world_mut(&mut self) -> &mut World {
+    self.app.world_mut()
+  }
+
+  /// Run one frame.
+  pub fn update(&mut self) {
+    self.app.update();
+  }
+
+  /// Open a new connection. It shows up in the world on the next update.
+  pub fn connect(&mut self) -> LoopbackClient {
+    self
+      .listener
+      .connect()
+      .expect("failed to open loopback connection")
+  }
+
+  /// Run the game until `f` returns something for `client`, and return it.
+  ///
+  /// # Panics
+  ///
+  /// If it takes longer than [TIMEOUT]. `what` describes what was expected in
+  /// the message.
+  pub fn wait_for<T, F>(&mut self, client: &mut LoopbackClient, what: impl Debug, mut f: F) -> T
+  where
+    F: FnMut(&mut LoopbackClient, &mut World) -> Option<T>,
+  {
+    let start = Instant::now();
+    loop {
+      self.app.update();
+      client.poll();
+      if let Some(found) = f(client, self.app.world_mut()) {
+        return found;
+      }
+      if start.elapsed() > TIMEOUT {
+        panic!(
+          "timed out waiting for {what:?}\nreceived text: {:?}\nreceived events: {:?}",
+          client.text(),
+          client.events().collect::<Vec<_>>(),
+        );
+      }
+      thread::sleep(FRAME);
+    }
+  }
+
+  /// Run the game until `f` holds.
+  pub fn run_until(&mut self, what: impl Debug, mut f: impl FnMut(&mut World) -> bool) {
+    let start = Instant::now();
+    while !f(self.app.world_mut()) {
+      if start.elapsed() > TIMEOUT {
+        panic!("timed out waiting for {what:?}");
+      }
+      self.app.update();
+      thread::sleep(FRAME);
+    }
+  }
+
+  /// Wait for `client` to receive `pattern`, and return all of the text up
+  /// to and including it.
+  pub fn expect(&mut self, client: &mut LoopbackClient, pattern: &str) -> String {
+    self.wait_for(client, pattern, |client, _| client.take_until(pattern))
+  }
+
+  /// Wait for `client` to receive an event matching `f`.
+  pub fn expect_event(
+    &mut self,
+    client: &mut LoopbackClient,
+    what: impl Debug,
+    mut f: impl FnMut(&Event) -> bool,
+  ) -> Event {
+    self.wait_for(client, what, |client, _| client.take_event(&mut f))
+  }
+
+  /// Wait for `client` to receive the GMCP message for `package`.
+  pub fn expect_gmcp(&mut self, client: &mut LoopbackClient, package: &str) -> GmcpMessage {
+    let event = self.expect_event(client, package, |event| {
+      matches!(
+        event,
+        Event::Subnegotiation(Opt::Known(KnownOpt::GMCP), data)
+          if GmcpMessage::parse(data).is_ok_and(|message| message.is(package))
+      )
+    });
+    let Event::Subnegotiation(_, data) = event else {
+      unreachable!();
+    };
+    GmcpMessage::parse(&data).unwrap()
+  }
+
+  /// Create an account for `client`, which is left at the command prompt.
+  pub fn create_account(&mut self, client: &mut LoopbackClient, name: &str, password: &str) {
+    self.expect(client, "Account name: ");
+    client.send_line(name);
+    self.expect(client, "Creating new account.");
+    self.expect(client, "Password: ");
+    client.send_line(password);
+    self.expect(client, "Confirm password: ");
+    client.send_line(password);
+    self.expect(client, " done!");
+    self.expect(client, "> ");
+  }
+
+  /// Log `client` in to an existing account, leaving it at the command
+  /// prompt.
+  pub fn log_in(&mut self, client: &mut LoopbackClient, name: &str, password: &str) {
+    self.expect(client, "Account name: ");
+    client.send_line(name);
+    self.expect(client, "Password: ");
+    client.send_line(password);
+    self.expect(client, "Welcome back!");
+    self.expect(client, "> ");
+  }
+
+  /// The connection logged in as `username`, if there is one.
+  pub fn session(&mut self, username: &str) -> Option<Entity> {
+    self
+      .app
+      .world_mut()
+      .query::<(Entity, &Session)>()
+      .iter(self.app.world())
+      .find(|(_, session)| session.username == username)
+      .map(|(entity, _)| entity)
+  }
+}
+
+/// Start logging in every new connection, like a game would after greeting
+/// it.
+fn start_login(mut cmd: Commands, new: Query<Entity, (Added<ClientConn>, Without<Inherited>)>) {
+  for entity in new.iter() {
+    cmd.queue(StartLogin(entity));
+  }
+}